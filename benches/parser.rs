@@ -0,0 +1,50 @@
+//! Measure `.osu` parser throughput on a large synthetic mania beatmap.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use osu2sm::osufile::Beatmap;
+use std::fmt::Write;
+
+/// A 7K mania beatmap with 50000 hitobjects, about an hour of dense notes.
+///
+/// If `decimal` is set, coordinates are written with a fractional part, which forces the general
+/// (slow) hitobject parser.
+fn fixture(decimal: bool) -> String {
+    let mut osu = String::from(
+        "osu file format v14\n\n[General]\nAudioFilename: audio.mp3\nMode: 3\n\n\
+         [Metadata]\nTitle:Fixture\nVersion:Large\n\n\
+         [Difficulty]\nCircleSize:7\nOverallDifficulty:8\n\n\
+         [TimingPoints]\n0,300,4,2,0,50,1,0\n\n[HitObjects]\n",
+    );
+    let frac = if decimal { ".5" } else { "" };
+    for i in 0..50000 {
+        let x = 36 + (i * 5 % 7) * 73;
+        let time = i * 75;
+        if i % 10 == 0 {
+            writeln!(
+                osu,
+                "{}{},192,{},128,0,{}:0:0:0:0:",
+                x,
+                frac,
+                time,
+                time + 150
+            )
+            .unwrap();
+        } else {
+            writeln!(osu, "{}{},192,{},1,0,0:0:0:0:", x, frac, time).unwrap();
+        }
+    }
+    osu
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for &(name, decimal) in &[("integer", false), ("decimal", true)] {
+        let osu = fixture(decimal);
+        group.throughput(Throughput::Bytes(osu.len() as u64));
+        group.bench_function(name, |b| b.iter(|| Beatmap::parse_str(0., &osu).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
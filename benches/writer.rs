@@ -0,0 +1,53 @@
+//! Measure `.sm` serializer throughput on a synthetic marathon chart.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use osu2sm::simfile::{BeatPos, ControlPoint, Gamemode, Note, SaveOpts, Simfile};
+
+/// A ~10 minute 180 BPM chart made of 16th streams, with a section of 24ths every few measures
+/// to force finer measure subdivisions.
+fn marathon(gamemode: Gamemode) -> Simfile {
+    let key_count = gamemode.key_count();
+    let mut notes = Vec::new();
+    for measure in 0..450 {
+        let subdiv = if measure % 8 == 7 { 24 } else { 16 };
+        for row in 0..subdiv {
+            let beat = measure as f64 * 4. + row as f64 * 4. / subdiv as f64;
+            notes.push(Note {
+                kind: Note::KIND_HIT,
+                beat: BeatPos::from(beat),
+                key: (row * 7 + measure) % key_count,
+            });
+        }
+    }
+    Simfile {
+        title: "Marathon".to_string(),
+        bpms: vec![ControlPoint {
+            beat: BeatPos::from(0.),
+            beat_len: 60. / 180.,
+        }],
+        gamemode,
+        notes,
+        ..Default::default()
+    }
+}
+
+fn serialize(c: &mut Criterion) {
+    let opts = SaveOpts::default();
+    let mut group = c.benchmark_group("serialize");
+    for &gamemode in &[Gamemode::DanceSingle, Gamemode::PumpDouble] {
+        let sm = marathon(gamemode);
+        let mut out = Vec::new();
+        Simfile::write_to(&mut out, Some(&sm), &opts).unwrap();
+        group.throughput(Throughput::Bytes(out.len() as u64));
+        group.bench_function(gamemode.id(), |b| {
+            b.iter(|| {
+                out.clear();
+                Simfile::write_to(&mut out, Some(&sm), &opts).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize);
+criterion_main!(benches);
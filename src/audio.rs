@@ -0,0 +1,101 @@
+//! Query the length of audio files.
+//!
+//! `.mp3` files are measured by scanning their frame headers, and every other format (`.ogg`,
+//! `.wav`, ...) is measured with symphonia, from the container headers if possible or by reading
+//! through its packets otherwise.
+
+use crate::prelude::*;
+use symphonia::core::{
+    errors::Error as AudioError, formats::FormatOptions, io::MediaSourceStream,
+    meta::MetadataOptions, probe::Hint,
+};
+
+/// Get the length of an audio file in seconds.
+///
+/// If the file is only readable up to some point, the length up to that point is returned along
+/// with the error.
+pub fn duration(path: &Path) -> (f64, Result<()>) {
+    let is_mp3 = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| ext.eq_ignore_ascii_case("mp3"))
+        .unwrap_or(false);
+    if is_mp3 {
+        match mp3_duration::from_path(path) {
+            Ok(len) => (len.as_secs_f64(), Ok(())),
+            Err(err) => (err.at_duration.as_secs_f64(), Err(err.into())),
+        }
+    } else {
+        match decoded_duration(path) {
+            Ok(result) => result,
+            Err(err) => (0., Err(err)),
+        }
+    }
+}
+
+/// A fingerprint of the contents of an audio file, to tell whether two audio files are copies of
+/// each other without reading them whole.
+/// Only the size and the first and last 64KiB of the file are hashed.
+pub fn fingerprint(path: &Path) -> Result<u64> {
+    use std::{hash::Hasher, io::Seek};
+    const CHUNK: u64 = 64 * 1024;
+    let mut file = File::open(path).context("failed to open audio file")?;
+    let size = file.metadata()?.len();
+    let mut hasher = fxhash::FxHasher64::default();
+    hasher.write_u64(size);
+    let mut buf = Vec::with_capacity(CHUNK as usize);
+    (&mut file).take(CHUNK).read_to_end(&mut buf)?;
+    if size > CHUNK {
+        file.seek(io::SeekFrom::Start(size.saturating_sub(CHUNK).max(CHUNK)))?;
+        file.take(CHUNK).read_to_end(&mut buf)?;
+    }
+    hasher.write(&buf);
+    Ok(hasher.finish())
+}
+
+fn decoded_duration(path: &Path) -> Result<(f64, Result<()>)> {
+    let file = File::open(path).context("failed to open audio file")?;
+    let stream = MediaSourceStream::new(Box::new(file), default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(OsStr::to_str) {
+        hint.with_extension(ext);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .context("unsupported audio format")?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow!("audio file has no tracks"))?;
+    let track_id = track.id;
+    let params = &track.codec_params;
+    //Most containers know their length upfront
+    if let (Some(frames), Some(sample_rate)) = (params.n_frames, params.sample_rate) {
+        return Ok((frames as f64 / sample_rate as f64, Ok(())));
+    }
+    //Otherwise, find the end of the last packet without decoding anything
+    let time_base = params
+        .time_base
+        .ok_or_else(|| anyhow!("unknown audio time base"))?;
+    let mut end = 0;
+    let result = loop {
+        match format.next_packet() {
+            Ok(packet) => {
+                if packet.track_id() == track_id {
+                    end = end.max(packet.ts() + packet.dur());
+                }
+            }
+            Err(AudioError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                break Ok(());
+            }
+            Err(err) => break Err(err).context("failed to read audio"),
+        }
+    };
+    let time = time_base.calc_time(end);
+    Ok((time.seconds as f64 + time.frac, result))
+}
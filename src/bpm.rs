@@ -0,0 +1,208 @@
+//! Detect the tempo of an audio file, to salvage beatmaps with broken timing, and the first
+//! onset of the music, to fine-tune offsets.
+//!
+//! The audio is reduced to an onset envelope (how sharply the loudness rises over time), whose
+//! autocorrelation gives a rough beat period.
+//! The period and phase are then refined by matching a comb of beats against the envelope.
+
+use crate::prelude::*;
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as AudioError,
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+/// The approximate amount of envelope frames per second of audio.
+const FRAMES_PER_SEC: f64 = 200.;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Tempo {
+    /// Beats per minute.
+    pub bpm: f64,
+    /// The time of the first beat, in milliseconds since the start of the audio.
+    pub offset_ms: f64,
+}
+
+/// Detect the tempo of an audio file, looking for BPMs in the given range.
+///
+/// Only the first `max_secs` seconds of audio are analyzed.
+pub fn detect(path: &Path, bpm_range: (f64, f64), max_secs: f64) -> Result<Tempo> {
+    ensure!(
+        bpm_range.0 > 0. && bpm_range.0 < bpm_range.1,
+        "invalid bpm range {:?}",
+        bpm_range
+    );
+    let (envelope, fps) = onset_envelope(path, max_secs)?;
+    let min_lag = (60. * fps / bpm_range.1).floor().max(1.) as usize;
+    let max_lag = (60. * fps / bpm_range.0).ceil() as usize;
+    ensure!(
+        envelope.len() > max_lag * 4,
+        "audio is too short to detect its tempo"
+    );
+
+    //Find a rough beat period through autocorrelation
+    let autocorr = |lag: usize| -> f64 {
+        let sum: f64 = envelope
+            .iter()
+            .zip(&envelope[lag..])
+            .map(|(a, b)| (a * b) as f64)
+            .sum();
+        sum / (envelope.len() - lag) as f64
+    };
+    let lag = (min_lag..=max_lag)
+        .max_by_key(|&lag| SortableFloat(autocorr(lag)))
+        .unwrap();
+    //Interpolate the peak for sub-frame precision
+    let rough_period = if lag > min_lag && lag < max_lag {
+        let (a, b, c) = (autocorr(lag - 1), autocorr(lag), autocorr(lag + 1));
+        let denom = a - 2. * b + c;
+        if denom < 0. {
+            lag as f64 + 0.5 * (a - c) / denom
+        } else {
+            lag as f64
+        }
+    } else {
+        lag as f64
+    };
+    trace!(
+        "    rough beat period of {:.2} frames ({:.2} BPM)",
+        rough_period,
+        60. * fps / rough_period
+    );
+
+    //Refine the period and find the phase by matching a comb of beats against the envelope
+    let comb = |period: f64, phase: f64| -> f64 {
+        let mut sum = 0.;
+        let mut count = 0;
+        let mut t = phase;
+        while (t as usize) < envelope.len() {
+            sum += envelope[(t.round() as usize).min(envelope.len() - 1)] as f64;
+            count += 1;
+            t += period;
+        }
+        sum / count.max(1) as f64
+    };
+    let mut best = (f64::NEG_INFINITY, rough_period, 0.);
+    for step in -40..=40 {
+        let period = rough_period * (1. + step as f64 * 0.0005);
+        let mut phase = 0.;
+        while phase < period {
+            let score = comb(period, phase);
+            if score > best.0 {
+                best = (score, period, phase);
+            }
+            phase += 1.;
+        }
+    }
+    let (_, period, phase) = best;
+    Ok(Tempo {
+        bpm: 60. * fps / period,
+        offset_ms: phase * 1000. / fps,
+    })
+}
+
+/// Find the time of the first strong onset of an audio file, in milliseconds since the start of
+/// the audio, or `None` if it has no onsets at all.
+///
+/// Only the first `max_secs` seconds of audio are analyzed, and onsets count as strong if they
+/// are at least `strength` times as strong as the strongest onset within them.
+pub fn first_onset(path: &Path, max_secs: f64, strength: f64) -> Result<Option<f64>> {
+    let (envelope, fps) = onset_envelope(path, max_secs)?;
+    let max = envelope.iter().copied().fold(0f32, f32::max);
+    if max <= 0. {
+        return Ok(None);
+    }
+    let threshold = max * strength as f32;
+    Ok(envelope
+        .iter()
+        .position(|&x| x > 0. && x >= threshold)
+        .map(|frame| frame as f64 * 1000. / fps))
+}
+
+/// Decode an audio file and compute its onset envelope: the positive changes in log-energy,
+/// sampled at roughly `FRAMES_PER_SEC`.
+///
+/// Returns the envelope along with its exact amount of frames per second.
+fn onset_envelope(path: &Path, max_secs: f64) -> Result<(Vec<f32>, f64)> {
+    let file = File::open(path).context("failed to open audio file")?;
+    let stream = MediaSourceStream::new(Box::new(file), default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(OsStr::to_str) {
+        hint.with_extension(ext);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .context("unsupported audio format")?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow!("audio file has no tracks"))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| anyhow!("unknown sample rate"))?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .context("unsupported audio codec")?;
+    let hop = (sample_rate as f64 / FRAMES_PER_SEC).round().max(1.) as usize;
+    let max_frames = (max_secs * FRAMES_PER_SEC) as usize;
+
+    //Compute the energy of every frame
+    let mut energy = Vec::new();
+    let mut acc = 0f32;
+    let mut acc_len = 0;
+    let mut buf: Option<SampleBuffer<f32>> = None;
+    while energy.len() < max_frames {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(AudioError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err).context("failed to read audio"),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            //Skip over corrupted packets
+            Err(AudioError::DecodeError(_)) => continue,
+            Err(err) => return Err(err).context("failed to decode audio"),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let buf = match &mut buf {
+            Some(buf) if buf.capacity() >= decoded.capacity() * channels => buf,
+            buf => buf.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(decoded);
+        for frame in buf.samples().chunks_exact(channels) {
+            let sample = frame.iter().sum::<f32>() / channels as f32;
+            acc += sample * sample;
+            acc_len += 1;
+            if acc_len == hop {
+                energy.push(acc / hop as f32);
+                acc = 0.;
+                acc_len = 0;
+            }
+        }
+    }
+
+    //Convert to an onset envelope
+    let mut envelope = Vec::with_capacity(energy.len());
+    let mut last = 0f32;
+    for (i, &e) in energy.iter().enumerate() {
+        let log = (e + 1e-9).ln();
+        envelope.push(if i == 0 { 0. } else { (log - last).max(0.) });
+        last = log;
+    }
+    //Remove the mean, so that silence does not correlate with everything
+    let mean = envelope.iter().sum::<f32>() / envelope.len().max(1) as f32;
+    for x in envelope.iter_mut() {
+        *x -= mean;
+    }
+    Ok((envelope, sample_rate as f64 / hop as f64))
+}
@@ -0,0 +1,19 @@
+//! Stop conversion cleanly when asked to (eg. with Ctrl+C), once the current beatmapset is done,
+//! instead of dying halfway through writing a simfile.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask conversion to stop after the current beatmapset.
+///
+/// Returns whether cancellation had already been requested before.
+/// Only touches an atomic flag, so it is safe to call from a signal handler.
+pub fn request() -> bool {
+    REQUESTED.swap(true, Ordering::SeqCst)
+}
+
+/// Whether cancellation was requested.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
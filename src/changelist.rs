@@ -0,0 +1,93 @@
+//! Keep track of the osu2sm versions that changed the converted output, so that upgrading only
+//! converts the affected beatmapsets again instead of every beatmapset.
+
+use crate::prelude::*;
+
+/// Which beatmaps a version changed the output of.
+#[derive(Copy, Clone, Debug)]
+pub enum Affects {
+    /// Every beatmap (eg. because the simfile writer changed).
+    All,
+    /// Only beatmaps of the given osu! gamemodes (see `osufile::MODE_*`).
+    Modes(&'static [i32]),
+}
+impl Affects {
+    fn affects(&self, mode: i32) -> bool {
+        match self {
+            Affects::All => true,
+            Affects::Modes(modes) => modes.contains(&mode),
+        }
+    }
+}
+
+/// Every osu2sm version that changed the converted output, oldest first.
+///
+/// Whenever a release changes how simfiles are generated, add an entry with the new version and
+/// the beatmaps it affects.
+/// Beatmapsets converted by an older version are converted again if and only if a later entry
+/// affects any of their beatmaps.
+pub const CHANGELIST: &[(&str, Affects)] = &[("0.1.0", Affects::All)];
+
+/// The last version that changed the output of a beatmapset with beatmaps in the given osu!
+/// gamemodes.
+pub fn generation(modes: &[i32]) -> &'static str {
+    CHANGELIST
+        .iter()
+        .rev()
+        .find(|(_, affects)| {
+            matches!(affects, Affects::All) || modes.iter().any(|&mode| affects.affects(mode))
+        })
+        .or_else(|| CHANGELIST.first())
+        .map(|(version, _)| *version)
+        .unwrap_or(env!("CARGO_PKG_VERSION"))
+}
+
+/// The changes made to the output since the given version, oldest first.
+/// An unknown version returns every change.
+pub fn changes_since(version: &str) -> &'static [(&'static str, Affects)] {
+    let version = parse_version(version);
+    let first_new = CHANGELIST
+        .iter()
+        .position(|(changed, _)| {
+            version
+                .as_ref()
+                .map(|version| parse_version(changed).as_ref() > Some(version))
+                .unwrap_or(true)
+        })
+        .unwrap_or(CHANGELIST.len());
+    &CHANGELIST[first_new..]
+}
+
+/// Parse a `major.minor.patch` version into comparable numbers.
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version
+        .split('.')
+        .map(|num| num.parse().ok())
+        .collect::<Option<Vec<u32>>>()
+        .filter(|nums| !nums.is_empty())
+}
+
+/// Describe a set of changes, eg. `"0.2.0 (taiko), 0.3.0 (everything)"`.
+pub fn describe(changes: &[(&str, Affects)]) -> String {
+    changes
+        .iter()
+        .map(|(version, affects)| {
+            let what = match affects {
+                Affects::All => "everything".to_string(),
+                Affects::Modes(modes) => modes
+                    .iter()
+                    .map(|&mode| match mode {
+                        osufile::MODE_STD => "standard",
+                        osufile::MODE_TAIKO => "taiko",
+                        osufile::MODE_CATCH => "catch",
+                        osufile::MODE_MANIA => "mania",
+                        _ => "unknown",
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            };
+            format!("{} ({})", version, what)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,89 @@
+//! Write a crash report when a panic occurs, including what was being processed at the time.
+
+use crate::prelude::*;
+use std::{panic, sync::Mutex};
+
+/// Short descriptions of every node, indexed by node.
+static NODES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static BEATMAPSET: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    static NODE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Set the descriptions of the nodes, to be referred to by `set_node`.
+pub fn set_nodes(nodes: Vec<String>) {
+    *NODES.lock().unwrap_or_else(|err| err.into_inner()) = nodes;
+}
+
+/// Set the beatmapset being processed by the current thread.
+pub fn set_beatmapset(path: Option<&Path>) {
+    BEATMAPSET.with(|bmset| *bmset.borrow_mut() = path.map(Path::to_path_buf));
+}
+
+/// Set the index of the node being executed by the current thread.
+pub fn set_node(idx: Option<usize>) {
+    NODE.with(|node| node.set(idx));
+}
+
+/// Get the index of the node being executed by the current thread.
+pub fn current_node() -> Option<usize> {
+    NODE.with(Cell::get)
+}
+
+/// Install a panic hook that writes a crash report to the given folder, in addition to the usual
+/// panic message.
+pub fn install_hook(dir: PathBuf) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let path = dir.join(format!(
+            "osu2sm-crash-{}.txt",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        ));
+        let report = crash_report(&info.to_string());
+        error!("{}", report);
+        match fs::write(&path, report) {
+            Ok(()) => eprintln!("wrote crash report to \"{}\"", path.display()),
+            Err(err) => eprintln!(
+                "failed to write crash report to \"{}\": {}",
+                path.display(),
+                err
+            ),
+        }
+    }));
+}
+
+fn crash_report(panic: &str) -> String {
+    let mut report = String::new();
+    writeln!(report, "osu2sm {} crashed", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(
+        report,
+        "thread: {}",
+        std::thread::current().name().unwrap_or("<unnamed>")
+    )
+    .unwrap();
+    writeln!(report, "{}", panic).unwrap();
+    BEATMAPSET.with(|bmset| match &*bmset.borrow() {
+        Some(path) => writeln!(report, "beatmapset: \"{}\"", path.display()).unwrap(),
+        None => writeln!(report, "beatmapset: none").unwrap(),
+    });
+    match NODE.with(Cell::get) {
+        Some(idx) => {
+            let nodes = NODES.lock().unwrap_or_else(|err| err.into_inner());
+            let desc = nodes.get(idx).map(|s| &s[..]).unwrap_or("<unknown>");
+            writeln!(report, "node {}: {}", idx + 1, desc).unwrap();
+        }
+        None => writeln!(report, "node: none").unwrap(),
+    }
+    writeln!(
+        report,
+        "backtrace:\n{}",
+        std::backtrace::Backtrace::force_capture()
+    )
+    .unwrap();
+    report
+}
@@ -0,0 +1,368 @@
+//! Convert osu! beatmaps into StepMania simfiles.
+//!
+//! The conversion is organized as a graph of nodes (see the `node` module), which load, transform
+//! and write simfiles.
+//! To convert a single beatmap, see `convert_beatmap` (or `convert_beatmap_str` for uses that do
+//! not need the filesystem).
+
+use crate::prelude::*;
+
+pub(crate) mod prelude {
+    pub(crate) use crate::{
+        linear_map, long_path,
+        node::{Node, SimfileStore},
+        osufile::{self, Beatmap, TimingPoint},
+        simfile::{
+            BeatPos, ControlPoint, Difficulty, DisplayBpm, Gamemode, Note, Simfile, SimfileSet,
+            ToTime, Video,
+        },
+        simfile_rng, symlink_dir, symlink_file, BaseDirFinder,
+    };
+    pub use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+    pub use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
+    pub use log::{debug, error, info, trace, warn};
+    pub use rand::{
+        seq::{IteratorRandom, SliceRandom},
+        Rng, RngCore, SeedableRng,
+    };
+    pub use rand_xoshiro::Xoshiro256Plus as FastRng;
+    pub use serde::{Deserialize, Serialize};
+    pub use std::{
+        borrow::Cow,
+        cell::{Cell, RefCell},
+        cmp,
+        convert::TryInto,
+        ffi::OsStr,
+        fmt::{self, Write as _},
+        fs::{self, File},
+        io::{self, BufRead, BufReader, BufWriter, Read, Write},
+        iter, mem, ops,
+        path::{Path, PathBuf},
+    };
+    #[cfg(feature = "fs")]
+    pub use walkdir::WalkDir;
+    pub fn default<T: Default>() -> T {
+        T::default()
+    }
+    #[derive(Debug, Clone, Copy)]
+    pub struct SortableFloat(pub f64);
+    impl Ord for SortableFloat {
+        fn cmp(&self, rhs: &Self) -> cmp::Ordering {
+            self.0.partial_cmp(&rhs.0).unwrap_or_else(|| {
+                if self.0.is_nan() == rhs.0.is_nan() {
+                    cmp::Ordering::Equal
+                } else if self.0.is_nan() {
+                    cmp::Ordering::Less
+                } else {
+                    cmp::Ordering::Greater
+                }
+            })
+        }
+    }
+    impl PartialOrd for SortableFloat {
+        fn partial_cmp(&self, rhs: &Self) -> Option<cmp::Ordering> {
+            Some(self.cmp(rhs))
+        }
+    }
+    impl PartialEq for SortableFloat {
+        fn eq(&self, rhs: &Self) -> bool {
+            self.cmp(rhs) == cmp::Ordering::Equal
+        }
+    }
+    impl Eq for SortableFloat {}
+}
+
+#[cfg(feature = "fs")]
+pub mod audio;
+#[cfg(feature = "bpm-detect")]
+pub mod bpm;
+pub mod cancel;
+pub mod changelist;
+pub mod crash;
+pub mod logger;
+pub mod node;
+pub mod osudb;
+pub mod osufile;
+pub mod pack;
+pub mod progress;
+pub mod prompt;
+pub mod rating;
+pub mod repair;
+pub mod simfile;
+#[cfg(feature = "watch")]
+pub mod watch;
+
+#[cfg(feature = "fs")]
+pub use crate::node::osuload::convert_beatmap;
+pub use crate::{
+    node::osuload::{convert_beatmap_str, OsuLoad},
+    osufile::{Beatmap, HitObject, TimingPoint},
+    simfile::Simfile,
+};
+
+pub(crate) fn symlink_file(src: &Path, dst: &Path) -> io::Result<()> {
+    let result = {
+        #[cfg(target_family = "windows")]
+        {
+            std::os::windows::fs::symlink_file(src, dst)
+        }
+        #[cfg(target_family = "unix")]
+        {
+            std::os::unix::fs::symlink(src, dst)
+        }
+    };
+    if result.is_err() {
+        if let Ok(link_src) = fs::read_link(dst) {
+            if link_src.canonicalize().ok() == src.canonicalize().ok() {
+                //Link already exists
+                trace!(
+                    "  link \"{}\" <- \"{}\" already exists",
+                    src.display(),
+                    dst.display()
+                );
+                return Ok(());
+            }
+        }
+    }
+    result
+}
+
+/// Make a path that is too long for regular Windows APIs usable anyway, by turning it into an
+/// extended-length (`\\?\`) path.
+///
+/// Does nothing on other platforms, or with short or relative paths.
+pub(crate) fn long_path(path: &Path) -> Cow<Path> {
+    #[cfg(target_family = "windows")]
+    {
+        use std::{
+            ffi::OsString,
+            path::{Component, Prefix},
+        };
+        //`MAX_PATH`, minus some room for the file names of temporary files
+        if path.as_os_str().len() < 240 || !path.is_absolute() {
+            return path.into();
+        }
+        let mut components = path.components();
+        let mut long = match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::Disk(_) => {
+                    let mut long = OsString::from(r"\\?\");
+                    long.push(prefix.as_os_str());
+                    long
+                }
+                Prefix::UNC(server, share) => {
+                    let mut long = OsString::from(r"\\?\UNC\");
+                    long.push(server);
+                    long.push(r"\");
+                    long.push(share);
+                    long
+                }
+                //Already extended-length, or a device path
+                _ => return path.into(),
+            },
+            _ => return path.into(),
+        };
+        //Extended-length paths are not normalized, so do it by hand
+        for component in components {
+            match component {
+                Component::Normal(name) => {
+                    long.push(r"\");
+                    long.push(name);
+                }
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return path.into(),
+            }
+        }
+        PathBuf::from(long).into()
+    }
+    #[cfg(not(target_family = "windows"))]
+    {
+        path.into()
+    }
+}
+
+pub(crate) fn symlink_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    let result = {
+        #[cfg(target_family = "windows")]
+        {
+            std::os::windows::fs::symlink_dir(src, dst)
+        }
+        #[cfg(target_family = "unix")]
+        {
+            std::os::unix::fs::symlink(src, dst)
+        }
+    };
+    if result.is_err() {
+        if src.canonicalize().ok() == dst.canonicalize().ok() {
+            //Paths are equivalent!
+            debug!(
+                "  link \"{}\" <- \"{}\" already exists (canonical paths are equivalent)",
+                src.display(),
+                dst.display()
+            );
+            return Ok(());
+        }
+        if src.canonicalize().ok() == fs::read_link(dst).and_then(|p| p.canonicalize()).ok() {
+            //Link already exists
+            debug!(
+                "  link \"{}\" <- \"{}\" already exists",
+                src.display(),
+                dst.display()
+            );
+            return Ok(());
+        }
+    }
+    result
+}
+
+/// Write a file through a temporary `*.tmp` file next to it, which is then renamed over the
+/// destination, so that a crash midway never leaves a half-written file behind.
+///
+/// If `fsync` is set, the data is also flushed to disk before renaming.
+pub(crate) fn write_atomic(
+    path: &Path,
+    fsync: bool,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = File::create(&tmp_path)
+        .context("create file")
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            write(&mut file)?;
+            file.flush().context("flush file")?;
+            if fsync {
+                file.get_ref().sync_all().context("sync file")?;
+            }
+            Ok(())
+        })
+        .and_then(|()| fs::rename(&tmp_path, path).context("replace file"));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Get the folder where nodes generate files for the given beatmapset folder (eg. silent audio or
+/// resized banners), creating it if it does not exist.
+///
+/// Input folders are never written to, so generated files are kept in a cache folder instead, and
+/// writers copy them into the output folder (see `Simfile::generated`).
+pub(crate) fn generated_dir(set_path: &Path) -> Result<PathBuf> {
+    let dir = std::env::temp_dir()
+        .join("osu2sm-generated")
+        .join(format!("{:016x}", fxhash::hash64(set_path)));
+    fs::create_dir_all(&dir)
+        .with_context(|| anyhow!("create generated files folder at \"{}\"", dir.display()))?;
+    Ok(dir)
+}
+
+/// Generate a file by having `make` write it to a temporary path next to the destination, which
+/// is then renamed over the destination, so that an interrupted tool (eg. a killed ffmpeg) never
+/// leaves a truncated file behind that looks finished.
+///
+/// The temporary path keeps the extension of the destination, since tools such as ffmpeg pick the
+/// output format from it.
+pub(crate) fn generate_atomic(path: &Path, make: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    use std::{
+        ffi::OsString,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp_name = OsString::from(format!(
+        "tmp{}-{}-",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    tmp_name.push(path.file_name().unwrap_or_default());
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = make(&tmp_path).and_then(|()| fs::rename(&tmp_path, path).context("replace file"));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+pub(crate) struct BaseDirFinder<'a> {
+    base_files: &'a [&'a str],
+    threshold: f64,
+    default_main_path: &'a str,
+}
+impl BaseDirFinder<'_> {
+    /// Returns a `(base, main)` path tuple.
+    pub(crate) fn find_base(
+        &self,
+        main_path: &Path,
+        should_exist: bool,
+    ) -> Result<(PathBuf, PathBuf)> {
+        let mut base_path = main_path.to_path_buf();
+        let mut cur_depth = 0;
+        loop {
+            //Check whether this path is the base path
+            let score = self
+                .base_files
+                .iter()
+                .map(|filename| base_path.join(filename).exists() as u8 as f64)
+                .sum::<f64>()
+                / self.base_files.len() as f64;
+            if score >= self.threshold {
+                //Base path!
+                break;
+            } else {
+                //Keep looking
+                if !base_path.pop() {
+                    //Ran out of ancestors
+                    bail!("could not find installation base");
+                }
+                cur_depth += 1;
+            }
+        }
+        //Fix up main folder if depth is not correct
+        let default_main_path: &Path = self.default_main_path.as_ref();
+        let main_depth = default_main_path.iter().count();
+        let mut tmp_main = main_path.to_path_buf();
+        if cur_depth < main_depth {
+            //Dig deeper
+            tmp_main.extend(default_main_path.iter().skip(cur_depth));
+            if should_exist && !tmp_main.is_dir() {
+                //Undo the work, this folder does not exist
+                tmp_main = main_path.to_path_buf();
+            }
+        } else if cur_depth > main_depth {
+            //Go higher
+            for _ in main_depth..cur_depth {
+                tmp_main.pop();
+            }
+        }
+        Ok((base_path, tmp_main))
+    }
+}
+
+pub(crate) fn read_path_from_stdin() -> Result<String> {
+    let mut path = String::new();
+    io::stdin().read_line(&mut path).context("read stdin")?;
+    let mut path = path.trim();
+    if (path.starts_with('\'') && path.ends_with('\''))
+        || (path.starts_with('"') && path.ends_with('"'))
+    {
+        path = path[1..path.len() - 1].trim();
+    }
+    Ok(path.to_string())
+}
+
+pub(crate) fn simfile_rng(sm: &Simfile, name: &str) -> FastRng {
+    let seed = fxhash::hash64(&(&sm.music, &sm.title_trans, &sm.desc, name));
+    FastRng::seed_from_u64(seed)
+}
+
+pub(crate) fn linear_map(
+    in_min: f64,
+    in_max: f64,
+    out_min: f64,
+    out_max: f64,
+) -> impl Fn(f64) -> f64 {
+    let m = (out_max - out_min) / (in_max - in_min);
+    move |input| (input - in_min) * m + out_min
+}
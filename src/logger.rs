@@ -0,0 +1,168 @@
+//! Console log output.
+//!
+//! Wraps the file logger so that console lines can be colored by severity and held back while a
+//! beatmapset is being processed, printing the output of each beatmapset as a single contiguous
+//! block even if several beatmapsets are processed at the same time.
+
+use crate::prelude::*;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+static TO_STDOUT: AtomicBool = AtomicBool::new(false);
+static BATCHING: AtomicBool = AtomicBool::new(false);
+/// The status line shown below the console output on stderr, if any.
+static STATUS: Mutex<String> = Mutex::new(String::new());
+
+thread_local! {
+    /// Console output held back by the current thread, if batching.
+    static BATCH: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A logger that forwards records to an inner logger, and prints them to the console.
+pub struct ConsoleLogger {
+    /// The underlying logger, which decides which records are enabled and writes them to the log
+    /// file.
+    pub inner: Box<dyn Log>,
+    /// Whether to color console lines with ANSI escape codes.
+    pub color: bool,
+    pub stderr: bool,
+    pub stdout: bool,
+    /// Whether to hold back console output within `batched` calls.
+    pub batch: bool,
+}
+impl ConsoleLogger {
+    /// Set this logger as the global logger.
+    pub fn install(self) -> Result<()> {
+        TO_STDERR.store(self.stderr, Ordering::Relaxed);
+        TO_STDOUT.store(self.stdout, Ordering::Relaxed);
+        BATCHING.store(self.batch, Ordering::Relaxed);
+        log::set_boxed_logger(Box::new(self)).map_err(|err| anyhow!("{}", err))?;
+        //The inner logger filters records by itself
+        log::set_max_level(LevelFilter::Trace);
+        Ok(())
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let line = format!(
+            "{} [{}] {}",
+            record.level(),
+            record.module_path().unwrap_or("<unnamed>"),
+            record.args()
+        );
+        let color = match record.level() {
+            Level::Error => "\x1b[1;31m",
+            Level::Warn => "\x1b[33m",
+            Level::Info => "",
+            Level::Debug => "\x1b[36m",
+            Level::Trace => "\x1b[2m",
+        };
+        if self.color && !color.is_empty() {
+            format!("{}{}\x1b[0m\n", color, line)
+        } else {
+            line + "\n"
+        }
+    }
+}
+impl Log for ConsoleLogger {
+    fn enabled(&self, meta: &Metadata) -> bool {
+        self.inner.enabled(meta)
+    }
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        if !self.stderr && !self.stdout {
+            return;
+        }
+        let line = self.format(record);
+        let held = BATCH.with(|batch| match &mut *batch.borrow_mut() {
+            Some(batch) => {
+                batch.push_str(&line);
+                true
+            }
+            None => false,
+        });
+        if !held {
+            print_console(&line);
+        }
+    }
+    fn flush(&self) {
+        self.inner.flush();
+        let _ = io::stderr().flush();
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Write text to the console in one go, so that it is not interleaved with other threads.
+fn print_console(text: &str) {
+    if TO_STDERR.load(Ordering::Relaxed) {
+        let status = STATUS.lock().unwrap_or_else(|err| err.into_inner());
+        let mut stderr = io::stderr().lock();
+        if status.is_empty() {
+            let _ = stderr.write_all(text.as_bytes());
+        } else {
+            //Print the text over the status line, and draw it again below
+            let _ = write!(stderr, "{}{}{}", erase(&status), text, status);
+        }
+    }
+    if TO_STDOUT.load(Ordering::Relaxed) {
+        let _ = io::stdout().lock().write_all(text.as_bytes());
+    }
+}
+
+/// Show a status line (such as a progress bar) below the console output, which stays at the
+/// bottom as log lines are printed.
+/// An empty line removes the status line.
+///
+/// Only shown if logging to stderr and stderr is a terminal.
+pub fn set_status(line: &str) {
+    use std::io::IsTerminal;
+    if !TO_STDERR.load(Ordering::Relaxed) || !io::stderr().is_terminal() {
+        return;
+    }
+    let mut status = STATUS.lock().unwrap_or_else(|err| err.into_inner());
+    if *status == line {
+        return;
+    }
+    let mut stderr = io::stderr().lock();
+    let _ = write!(stderr, "{}{}", erase(&status), line);
+    let _ = stderr.flush();
+    status.clear();
+    status.push_str(line);
+}
+
+/// Get the text that erases a status line, leaving the cursor at the start of the line.
+fn erase(status: &str) -> String {
+    if status.is_empty() {
+        String::new()
+    } else {
+        format!("\r{:1$}\r", "", status.chars().count())
+    }
+}
+
+/// Run the given closure, holding back all console output it produces until it finishes.
+///
+/// Does nothing special if batching is disabled or if a batch is already in progress on this
+/// thread.
+pub fn batched<T>(f: impl FnOnce() -> T) -> T {
+    struct Flush;
+    impl Drop for Flush {
+        fn drop(&mut self) {
+            //Also flush if `f` panics, so that the context leading to the panic is not lost
+            if let Some(text) = BATCH.with(|batch| batch.borrow_mut().take()) {
+                print_console(&text);
+            }
+        }
+    }
+    if !BATCHING.load(Ordering::Relaxed) || BATCH.with(|batch| batch.borrow().is_some()) {
+        return f();
+    }
+    BATCH.with(|batch| *batch.borrow_mut() = Some(String::new()));
+    let _flush = Flush;
+    f()
+}
@@ -1,88 +1,82 @@
-use crate::prelude::*;
+use anyhow::{anyhow, ensure, Context, Error, Result};
+use log::{debug, error, info, trace, warn};
+use osu2sm::{
+    cancel, crash, logger,
+    node::{self, simfilewrite::GroupSplit, ConcreteNode, Node, SanityCheck, SimfileStore},
+    simfile::Gamemode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
-mod prelude {
-    pub(crate) use crate::{
-        linear_map,
-        node::{ConcreteNode, Node, SimfileStore},
-        osufile::{self, Beatmap, TimingPoint},
-        simfile::{BeatPos, ControlPoint, Difficulty, DisplayBpm, Gamemode, Note, Simfile, ToTime},
-        simfile_rng, symlink_dir, symlink_file, BaseDirFinder,
-    };
-    pub use anyhow::{anyhow, bail, ensure, Context, Error, Result};
-    pub use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
-    pub use log::{debug, error, info, trace, warn};
-    pub use rand::{
-        seq::{IteratorRandom, SliceRandom},
-        Rng, RngCore, SeedableRng,
-    };
-    pub use rand_xoshiro::Xoshiro256Plus as FastRng;
-    pub use serde::{Deserialize, Serialize};
-    pub use std::{
-        borrow::Cow,
-        cell::{Cell, RefCell},
-        cmp,
-        convert::{TryFrom, TryInto},
-        ffi::{OsStr, OsString},
-        fmt::{self, Write as _},
-        fs::{self, File},
-        io::{self, BufRead, BufReader, BufWriter, Read, Write},
-        iter, mem, ops,
-        path::{Path, PathBuf},
-        time::Instant,
-    };
-    pub use walkdir::WalkDir;
-    pub fn default<T: Default>() -> T {
-        T::default()
-    }
-    #[derive(Debug, Clone, Copy)]
-    pub struct SortableFloat(pub f64);
-    impl Ord for SortableFloat {
-        fn cmp(&self, rhs: &Self) -> cmp::Ordering {
-            self.0.partial_cmp(&rhs.0).unwrap_or_else(|| {
-                if self.0.is_nan() == rhs.0.is_nan() {
-                    cmp::Ordering::Equal
-                } else if self.0.is_nan() {
-                    cmp::Ordering::Less
-                } else {
-                    cmp::Ordering::Greater
-                }
-            })
-        }
-    }
-    impl PartialOrd for SortableFloat {
-        fn partial_cmp(&self, rhs: &Self) -> Option<cmp::Ordering> {
-            Some(self.cmp(rhs))
-        }
-    }
-    impl PartialEq for SortableFloat {
-        fn eq(&self, rhs: &Self) -> bool {
-            self.cmp(rhs) == cmp::Ordering::Equal
-        }
-    }
-    impl Eq for SortableFloat {}
+fn default<T: Default>() -> T {
+    T::default()
 }
 
-pub mod node;
-pub mod osufile;
-pub mod simfile;
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 struct Opts {
     /// A graph of nodes to load, transform and save simfiles.
     nodes: Vec<ConcreteNode>,
-    /// Whether to carry out redundant sanity checks.
-    /// (Will likely error on kinda-correct, mistimed and simultaneous-slider beatmaps).
-    sanity_check: bool,
+    /// Ask the user how to resolve ambiguous decisions (such as overwriting foreign files in the
+    /// output folder), instead of guessing.
+    ///
+    /// Answers are remembered in `decisions`, and saved back to the config file.
+    /// Note that saving the config file removes any comments in it.
+    interactive: bool,
+    /// Remembered answers to interactive decisions.
+    decisions: BTreeMap<String, String>,
+    /// Whether to carry out redundant sanity checks after every node, and what to do with the
+    /// simfiles that fail them.
+    /// (`Strict` will likely error on kinda-correct, mistimed and simultaneous-slider beatmaps).
+    sanity_check: SanityCheck,
     /// A logspec string (see
     /// https://https://docs.rs/flexi_logger/0.16.1/flexi_logger/struct.LogSpecification.html).
     log: String,
     /// Whether to log to a file.
     log_file: bool,
+    /// Start a new log file once the current one grows over this many megabytes.
+    /// `0` disables log rotation.
+    log_rotate_mb: u64,
+    /// How many old log files to keep when rotating logs.
+    log_keep: usize,
     /// Enable logging to stderr.
     log_stderr: bool,
     /// Enable logging to stdout.
     log_stdout: bool,
+    /// Color console log lines by severity, using ANSI escape codes.
+    log_color: bool,
+    /// Hold back console output while processing each beatmapset, and print it all at once when
+    /// done.
+    /// Keeps the output of each beatmapset contiguous when processing several at the same time.
+    log_batch: bool,
+    /// Cache the resolved node graph (including autodetected osu! and StepMania folders) in a
+    /// file next to the config, and reuse it on the next run as long as the nodes in the config
+    /// do not change.
+    /// Delete the cache file to autodetect folders again.
+    cache_graph: bool,
+    /// Only convert the beatmapsets whose beatmaps or conversion settings changed since they
+    /// were last converted, as recorded in the manifest of the output folders.
+    /// Has no effect on outputs with `cleanup` enabled, which are always converted from scratch,
+    /// nor on graphs with a `TopN` node, which picks charts from every beatmapset.
+    incremental: bool,
+    /// How many beatmapsets to convert at the same time.
+    /// `0` uses one thread per CPU core.
+    /// Enable `log_batch` to keep the console output of each beatmapset together.
+    threads: usize,
+    /// Show a progress bar below the console output, with how many beatmapsets were converted
+    /// so far.
+    /// Only shown if `log_stderr` is enabled and the console is a terminal.
+    progress_bar: bool,
+    /// Keep track of how many simfiles each bucket holds and how often it is cloned, and report
+    /// it after converting.
+    /// Useful for finding the nodes responsible for high memory usage.
+    bucket_stats: bool,
 }
 impl Default for Opts {
     fn default() -> Opts {
@@ -111,11 +105,22 @@ impl Default for Opts {
                 }
                 .into(),
             ],
-            sanity_check: false,
+            interactive: false,
+            decisions: default(),
+            sanity_check: SanityCheck::Off,
             log: "info".to_string(),
             log_file: true,
+            log_rotate_mb: 16,
+            log_keep: 4,
             log_stderr: true,
             log_stdout: false,
+            log_color: false,
+            log_batch: false,
+            cache_graph: true,
+            incremental: true,
+            threads: 0,
+            progress_bar: true,
+            bucket_stats: false,
         }
     }
 }
@@ -126,114 +131,112 @@ impl Opts {
         } else {
             flexi_logger::LogTarget::DevNull
         };
-        let log_stderr = if self.log_stderr {
-            flexi_logger::Duplicate::All
-        } else {
-            flexi_logger::Duplicate::None
-        };
-        let log_stdout = if self.log_stdout {
-            flexi_logger::Duplicate::All
-        } else {
-            flexi_logger::Duplicate::None
-        };
-
-        if let Err(err) = flexi_logger::Logger::with_str(&self.log)
-            .log_target(log_target)
-            .duplicate_to_stderr(log_stderr)
-            .duplicate_to_stdout(log_stdout)
-            .start()
-        {
+        let mut logger = flexi_logger::Logger::with_str(&self.log).log_target(log_target);
+        if self.log_rotate_mb > 0 {
+            logger = logger.rotate(
+                flexi_logger::Criterion::Size(self.log_rotate_mb * 1024 * 1024),
+                flexi_logger::Naming::Numbers,
+                flexi_logger::Cleanup::KeepLogFiles(self.log_keep),
+            );
+        }
+        let result = logger
+            .build()
+            .map_err(Error::from)
+            .and_then(|(inner, _handle)| {
+                logger::ConsoleLogger {
+                    inner,
+                    color: self.log_color,
+                    stderr: self.log_stderr,
+                    stdout: self.log_stdout,
+                    batch: self.log_batch,
+                }
+                .install()
+            });
+        if let Err(err) = result {
             eprintln!("error initializing logger: {:#}", err);
         }
+        if self.progress_bar {
+            osu2sm::progress::set_callback(|progress| logger::set_status(&progress_bar(progress)));
+        }
+        crash::install_hook(std::env::current_dir().unwrap_or_default());
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .thread_name(|idx| format!("worker {}", idx + 1))
+            .build_global();
+        if let Err(err) = pool {
+            warn!("failed to set up worker threads: {:#}", err);
+        }
+    }
+}
+
+/// Draw a progress bar, eg. `[#####-----] 50/100 beatmapsets`.
+fn progress_bar(progress: osu2sm::progress::Progress) -> String {
+    const WIDTH: usize = 30;
+    if progress.total == 0 {
+        return String::new();
+    }
+    let filled = (progress.fraction() * WIDTH as f64).round() as usize;
+    let mut bar = format!(
+        "[{}{}] {}/{} beatmapsets",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        progress.processed,
+        progress.total
+    );
+    if progress.errored > 0 {
+        bar += &format!(" ({} with errors)", progress.errored);
     }
+    bar
 }
 
 struct Ctx {
-    sm_store: RefCell<SimfileStore>,
+    sm_store: SimfileStore,
     nodes: Vec<Box<dyn Node>>,
     opts: Opts,
 }
 
-fn run_nodes(ctx: &Ctx) -> Result<()> {
-    let mut store = ctx.sm_store.borrow_mut();
-    for (i, node) in ctx.nodes.iter().enumerate() {
+fn run_nodes(ctx: &mut Ctx) -> Result<()> {
+    let Ctx {
+        sm_store: store,
+        nodes,
+        opts,
+    } = ctx;
+    let (nodes, opts) = (&*nodes, &*opts);
+    if opts.bucket_stats {
+        store.track_stats();
+    }
+    for (i, node) in nodes.iter().enumerate() {
         store.reset();
-        node.entry(&mut *store, &mut |store| {
-            for node in ctx.nodes.iter().skip(i + 1) {
-                if ctx.opts.sanity_check {
-                    store.check()?;
-                }
+        crash::set_node(Some(i));
+        //Beatmapsets may be processed in parallel, each worker with its own store
+        node.entry(store, &|store| {
+            let mut prev = i;
+            for (j, node) in nodes.iter().enumerate().skip(i + 1) {
+                store.sanity_check(opts.sanity_check, &format!("node {}", prev))?;
                 trace!("  applying node {:?}", node);
+                crash::set_node(Some(j));
                 node.apply(store)?;
+                prev = j;
             }
-            if ctx.opts.sanity_check {
-                store.check()?;
-            }
+            crash::set_node(Some(i));
+            store.sanity_check(opts.sanity_check, &format!("node {}", prev))?;
             Ok(())
         })?;
     }
-    Ok(())
-}
-
-fn symlink_file(src: &Path, dst: &Path) -> io::Result<()> {
-    let result = {
-        #[cfg(target_family = "windows")]
-        {
-            std::os::windows::fs::symlink_file(src, dst)
-        }
-        #[cfg(target_family = "unix")]
-        {
-            std::os::unix::fs::symlink(src, dst)
-        }
-    };
-    if result.is_err() {
-        if let Ok(link_src) = fs::read_link(dst) {
-            if link_src.canonicalize().ok() == src.canonicalize().ok() {
-                //Link already exists
-                trace!(
-                    "  link \"{}\" <- \"{}\" already exists",
-                    src.display(),
-                    dst.display()
-                );
-                return Ok(());
-            }
-        }
+    for (i, node) in nodes.iter().enumerate() {
+        crash::set_node(Some(i));
+        node.finish()?;
     }
-    result
-}
-
-fn symlink_dir(src: &Path, dst: &Path) -> io::Result<()> {
-    let result = {
-        #[cfg(target_family = "windows")]
-        {
-            std::os::windows::fs::symlink_dir(src, dst)
-        }
-        #[cfg(target_family = "unix")]
-        {
-            std::os::unix::fs::symlink(src, dst)
-        }
-    };
-    if result.is_err() {
-        if src.canonicalize().ok() == dst.canonicalize().ok() {
-            //Paths are equivalent!
-            debug!(
-                "  link \"{}\" <- \"{}\" already exists (canonical paths are equivalent)",
-                src.display(),
-                dst.display()
-            );
-            return Ok(());
-        }
-        if src.canonicalize().ok() == fs::read_link(dst).and_then(|p| p.canonicalize()).ok() {
-            //Link already exists
-            debug!(
-                "  link \"{}\" <- \"{}\" already exists",
-                src.display(),
-                dst.display()
-            );
-            return Ok(());
-        }
+    crash::set_node(None);
+    store.report_stats();
+    let progress = osu2sm::progress::get();
+    if progress.errored > 0 {
+        warn!(
+            "{} out of {} beatmapsets had errors, see the log above for details",
+            progress.errored, progress.total
+        );
     }
-    result
+    Ok(())
 }
 
 fn load_cfg(path: &Path) -> Result<Opts> {
@@ -280,89 +283,467 @@ fn save_cfg(path: &Path, opts: &Opts) -> Result<()> {
     Ok(())
 }
 
-struct BaseDirFinder<'a> {
-    base_files: &'a [&'a str],
-    threshold: f64,
-    default_main_path: &'a str,
+/// The resolved and prepared node graph of a previous run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GraphCache {
+    /// The hash of the config that generated this graph.
+    config_hash: String,
+    nodes: Vec<ConcreteNode>,
 }
-impl BaseDirFinder<'_> {
-    /// Returns a `(base, main)` path tuple.
-    fn find_base(&self, main_path: &Path, should_exist: bool) -> Result<(PathBuf, PathBuf)> {
-        let mut base_path = main_path.to_path_buf();
-        let mut cur_depth = 0;
-        loop {
-            //Check whether this path is the base path
-            let score = self
-                .base_files
+
+fn load_graph_cache(path: &Path) -> Result<GraphCache> {
+    let txt = fs::read_to_string(path)
+        .with_context(|| anyhow!("failed to read graph cache at \"{}\"", path.display()))?;
+    ron::de::from_str(&txt)
+        .with_context(|| anyhow!("failed to parse graph cache at \"{}\"", path.display()))
+}
+
+/// Resolve and prepare the node graph, reusing the graph cached by a previous run if the config
+/// did not change since.
+fn load_nodes(
+    node_cfg: &[ConcreteNode],
+    config_hash: &str,
+    cache_path: Option<&Path>,
+) -> Result<Vec<ConcreteNode>> {
+    if let Some(cache_path) = cache_path {
+        match load_graph_cache(cache_path) {
+            Ok(cache) if cache.config_hash == config_hash => {
+                let mut nodes = cache.nodes;
+                match node::prepare_nodes(&mut nodes) {
+                    Ok(()) => {
+                        info!("reusing node graph cached at \"{}\"", cache_path.display());
+                        return Ok(nodes);
+                    }
+                    Err(err) => {
+                        warn!("failed to prepare cached node graph: {:#}", err);
+                    }
+                }
+            }
+            Ok(_) => {
+                debug!("config changed since the node graph was cached");
+            }
+            Err(err) => {
+                debug!("no cached node graph: {:#}", err);
+            }
+        }
+    }
+    let mut nodes = node::resolve_graph(node_cfg)?;
+    node::prepare_nodes(&mut nodes)?;
+    if let Some(cache_path) = cache_path {
+        let cache = GraphCache {
+            config_hash: config_hash.to_string(),
+            nodes: nodes
                 .iter()
-                .map(|filename| base_path.join(filename).exists() as u8 as f64)
-                .sum::<f64>()
-                / self.base_files.len() as f64;
-            if score >= self.threshold {
-                //Base path!
-                break;
-            } else {
-                //Keep looking
-                if !base_path.pop() {
-                    //Ran out of ancestors
-                    bail!("could not find installation base");
+                .cloned()
+                .map(|mut node| {
+                    node.as_dyn_mut().settle();
+                    node
+                })
+                .collect(),
+        };
+        let result = ron::ser::to_string_pretty(&cache, default())
+            .context("failed to serialize")
+            .and_then(|txt| fs::write(cache_path, txt).context("failed to write file"));
+        if let Err(err) = result {
+            warn!("failed to cache node graph: {:#}", err);
+        }
+    }
+    Ok(nodes)
+}
+
+/// Set up the prepared nodes to skip the beatmapsets that all `SimfileWrite` nodes already
+/// converted from the same source files and settings.
+fn incremental_nodes(nodes: &mut [ConcreteNode]) {
+    if picks_from_library(nodes) {
+        debug!("not converting incrementally, since a `TopN` node needs every beatmapset");
+        return;
+    }
+    let mut known: Option<BTreeMap<String, String>> = None;
+    for node in nodes.iter() {
+        if let ConcreteNode::SimfileWrite(write) = node {
+            let sources = write.manifest.lock().sources.clone();
+            known = Some(match known {
+                Some(known) => known
+                    .into_iter()
+                    .filter(|(set, hash)| sources.get(set) == Some(hash))
+                    .collect(),
+                None => sources,
+            });
+        }
+    }
+    let known = match known {
+        Some(known) if !known.is_empty() => known,
+        _ => return,
+    };
+    debug!(
+        "converting incrementally, {} beatmapsets were converted before",
+        known.len()
+    );
+    for node in nodes.iter_mut() {
+        if let ConcreteNode::OsuLoad(load) = node {
+            load.known_sources = known
+                .iter()
+                .map(|(set, hash)| (PathBuf::from(set), hash.clone()))
+                .collect();
+        }
+    }
+}
+
+/// Whether the graph has nodes that pick charts from the whole library, whose output changes if
+/// only some beatmapsets are converted.
+fn picks_from_library(nodes: &[ConcreteNode]) -> bool {
+    nodes
+        .iter()
+        .any(|node| matches!(node, ConcreteNode::TopN(_)))
+}
+
+/// Make sure that converting only some beatmapsets gives the same output as converting all of
+/// them, which is not the case for nodes that pick charts from the whole library.
+fn ensure_partial(nodes: &[ConcreteNode], action: &str) -> Result<()> {
+    ensure!(
+        !picks_from_library(nodes),
+        "cannot {} a conversion with a `TopN` node, since it selects charts from every beatmapset, convert everything again instead",
+        action
+    );
+    Ok(())
+}
+
+/// Check the output of all `SimfileWrite` nodes, and set up the nodes to only convert the
+/// beatmapsets with broken output again.
+///
+/// Returns `None` if there is nothing to repair.
+fn repair_nodes(nodes: &[ConcreteNode]) -> Result<Option<Vec<ConcreteNode>>> {
+    ensure_partial(nodes, "repair")?;
+    let mut nodes = nodes.to_vec();
+    //`None` means that everything has to be converted again
+    let mut broken = Some(BTreeSet::new());
+    let mut outputs = 0;
+    for node in nodes.iter_mut() {
+        if let ConcreteNode::SimfileWrite(write) = node {
+            ensure!(
+                !write.output.is_empty(),
+                "cannot repair an output folder that is not set in the config"
+            );
+            outputs += 1;
+            //Never remove the simfiles that are fine
+            write.cleanup = false;
+            match osu2sm::repair::find_broken(write.output.as_ref())? {
+                Some(sets) => {
+                    if let Some(broken) = &mut broken {
+                        broken.extend(sets);
+                    }
                 }
-                cur_depth += 1;
+                None => broken = None,
             }
         }
-        //Fix up main folder if depth is not correct
-        let default_main_path: &Path = self.default_main_path.as_ref();
-        let main_depth = default_main_path.iter().count();
-        let mut tmp_main = main_path.to_path_buf();
-        if cur_depth < main_depth {
-            //Dig deeper
-            tmp_main.extend(default_main_path.iter().skip(cur_depth));
-            if should_exist && !tmp_main.is_dir() {
-                //Undo the work, this folder does not exist
-                tmp_main = main_path.to_path_buf();
+    }
+    ensure!(outputs > 0, "there are no `SimfileWrite` nodes to repair");
+    match broken {
+        Some(sets) if sets.is_empty() => {
+            info!("nothing to repair");
+            return Ok(None);
+        }
+        Some(sets) => {
+            info!("repairing {} beatmapsets", sets.len());
+            for node in nodes.iter_mut() {
+                if let ConcreteNode::OsuLoad(load) = node {
+                    load.only_sets = Some(sets.iter().cloned().collect());
+                }
             }
-        } else if cur_depth > main_depth {
-            //Go higher
-            for _ in main_depth..cur_depth {
-                tmp_main.pop();
+        }
+        None => {
+            info!("repairing by converting everything again");
+        }
+    }
+    Ok(Some(nodes))
+}
+
+/// List the charts in the output folders of all `SimfileWrite` nodes that are most similar to
+/// the charts of the given simfile.
+fn list_similar(nodes: &[ConcreteNode], path: &Path) -> Result<()> {
+    const COUNT: usize = 10;
+    let outputs = nodes
+        .iter()
+        .filter_map(|node| match node {
+            ConcreteNode::SimfileWrite(write) if !write.output.is_empty() => {
+                Some(Path::new(&write.output))
             }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    ensure!(
+        !outputs.is_empty(),
+        "there are no `SimfileWrite` output folders to search"
+    );
+    for (name, similar) in osu2sm::repair::find_similar(&outputs, path, COUNT)? {
+        info!("charts similar to \"{}\":", name);
+        if similar.is_empty() {
+            info!("  none");
+        }
+        for other in similar {
+            info!(
+                "  {:.0}% \"{}\" in \"{}\"",
+                other.similarity * 100.,
+                other.name,
+                other.simfile.display()
+            );
         }
-        Ok((base_path, tmp_main))
     }
+    Ok(())
 }
 
-fn read_path_from_stdin() -> Result<String> {
-    let mut path = String::new();
-    io::stdin().read_line(&mut path).context("read stdin")?;
-    let mut path = path.trim();
-    if (path.starts_with('\'') && path.ends_with('\''))
-        || (path.starts_with('"') && path.ends_with('"'))
-    {
-        path = path[1..path.len() - 1].trim();
-    }
-    Ok(path.to_string())
+/// Set up the nodes to convert a single beatmap (or a single beatmapset folder), writing the
+/// simfiles into the beatmapset folder itself.
+fn single_nodes(nodes: &[ConcreteNode], path: &Path) -> Result<Vec<ConcreteNode>> {
+    let meta =
+        fs::metadata(path).with_context(|| anyhow!("failed to open \"{}\"", path.display()))?;
+    let (set_path, beatmap) = if meta.is_dir() {
+        (path, None)
+    } else {
+        ensure!(
+            path.extension() == Some("osu".as_ref()),
+            "\"{}\" is not a .osu file or a beatmapset folder",
+            path.display()
+        );
+        let set_path = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        (set_path, path.file_name().map(PathBuf::from))
+    };
+    let set_path = set_path
+        .to_str()
+        .ok_or_else(|| anyhow!("invalid non-utf8 path \"{}\"", set_path.display()))?
+        .to_string();
+    let mut nodes = nodes.to_vec();
+    let mut loads = 0;
+    for node in nodes.iter_mut() {
+        match node {
+            ConcreteNode::OsuLoad(load) => {
+                loads += 1;
+                load.input = set_path.clone();
+                load.fix_input = false;
+                load.database.clear();
+                load.collections.clear();
+                load.blacklist.clear();
+                load.whitelist.clear();
+                load.only_beatmap = beatmap.clone();
+            }
+            ConcreteNode::SimfileWrite(write) => {
+                //Pretend that the beatmapset folder is already linked in-place, so that the
+                //simfiles are written right into it, without copying or linking anything
+                write.output = set_path.clone();
+                write.fix_output = false;
+                write.cleanup = false;
+                write.in_place = true;
+                write.in_place_split = GroupSplit::Single;
+                *write.in_place_from.get_mut() = Some(PathBuf::from(&set_path));
+            }
+            _ => {}
+        }
+    }
+    ensure!(
+        loads > 0,
+        "there are no `OsuLoad` nodes to convert \"{}\" with",
+        path.display()
+    );
+    match &beatmap {
+        Some(beatmap) => info!(
+            "converting only \"{}\" into \"{}\"",
+            beatmap.display(),
+            set_path
+        ),
+        None => info!("converting only \"{}\"", set_path),
+    }
+    Ok(nodes)
 }
 
-fn simfile_rng(sm: &Simfile, name: &str) -> FastRng {
-    let seed = fxhash::hash64(&(&sm.music, &sm.title_trans, &sm.desc, name));
-    FastRng::seed_from_u64(seed)
+/// Keep converting the beatmapsets that change in the input folders of all `OsuLoad` nodes, until
+/// interrupted.
+#[cfg(feature = "watch")]
+fn watch_nodes(nodes: &[ConcreteNode], opts: &Opts, store: &SimfileStore) -> Result<()> {
+    use std::time::Duration;
+    let roots = nodes
+        .iter()
+        .filter_map(|node| match node {
+            ConcreteNode::OsuLoad(load) => Some(PathBuf::from(&load.input)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    ensure!(
+        !roots.is_empty(),
+        "there are no `OsuLoad` nodes with input folders to watch"
+    );
+    let watcher = osu2sm::watch::Watcher::new(roots.iter().map(PathBuf::as_path))?;
+    info!("waiting for new beatmaps, press Ctrl+C to stop");
+    while let Some(changed) = watcher.wait(Duration::from_secs(3)) {
+        info!("converting {} changed beatmapsets", changed.len());
+        osu2sm::progress::reset();
+        let mut nodes = nodes.to_vec();
+        for node in nodes.iter_mut() {
+            node.as_dyn_mut().settle();
+            match node {
+                ConcreteNode::OsuLoad(load) => {
+                    let input = Path::new(&load.input);
+                    load.only_sets = Some(
+                        changed
+                            .iter()
+                            .filter_map(|set_path| set_path.strip_prefix(input).ok())
+                            .map(Path::to_path_buf)
+                            .collect(),
+                    );
+                }
+                ConcreteNode::SimfileWrite(write) => {
+                    //Keep everything converted so far
+                    write.cleanup = false;
+                }
+                _ => {}
+            }
+        }
+        let result = node::prepare_nodes(&mut nodes).and_then(|()| {
+            let mut ctx = Ctx {
+                sm_store: store.clone(),
+                nodes: nodes.into_iter().map(ConcreteNode::into_dyn).collect(),
+                opts: opts.clone(),
+            };
+            run_nodes(&mut ctx)
+        });
+        //Keep watching, the next beatmapsets may be fine
+        if let Err(err) = result {
+            error!("failed to convert changed beatmapsets: {:#}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Set up the nodes to skip the beatmapsets that were already written by all `SimfileWrite`
+/// nodes, to continue an interrupted conversion.
+fn resume_nodes(nodes: &[ConcreteNode]) -> Result<Vec<ConcreteNode>> {
+    ensure_partial(nodes, "resume")?;
+    let mut nodes = nodes.to_vec();
+    let mut done: Option<BTreeSet<PathBuf>> = None;
+    for node in nodes.iter_mut() {
+        if let ConcreteNode::SimfileWrite(write) = node {
+            //Keep the simfiles written before the interruption
+            write.cleanup = false;
+            let manifest = osu2sm::repair::Manifest::load(write.output.as_ref())?;
+            let sets = manifest
+                .simfiles
+                .values()
+                .map(|entry| PathBuf::from(&entry.set))
+                .collect::<BTreeSet<_>>();
+            done = Some(match done {
+                Some(done) => done.intersection(&sets).cloned().collect(),
+                None => sets,
+            });
+        }
+    }
+    let done = done.ok_or_else(|| anyhow!("there are no `SimfileWrite` nodes to resume"))?;
+    info!("resuming, skipping {} converted beatmapsets", done.len());
+    for node in nodes.iter_mut() {
+        if let ConcreteNode::OsuLoad(load) = node {
+            load.skip_sets = done.iter().cloned().collect();
+        }
+    }
+    Ok(nodes)
 }
 
-fn linear_map(in_min: f64, in_max: f64, out_min: f64, out_max: f64) -> impl Fn(f64) -> f64 {
-    let m = (out_max - out_min) / (in_max - in_min);
-    move |input| (input - in_min) * m + out_min
+/// Finish the current beatmapset and stop on Ctrl+C or termination, instead of dying midway.
+/// A second signal exits immediately.
+fn install_signal_handlers() {
+    for &signal in signal_hook::consts::TERM_SIGNALS {
+        //Only an atomic flag is touched, which is safe within a signal handler
+        let result = unsafe {
+            signal_hook::low_level::register(signal, || {
+                if cancel::request() {
+                    signal_hook::low_level::exit(130);
+                }
+            })
+        };
+        if let Err(err) = result {
+            warn!("failed to install handler for signal {}: {:#}", signal, err);
+        }
+    }
 }
 
 fn run() -> Result<()> {
-    let load_cfg_from = std::env::args_os()
-        .skip(1)
-        .next()
-        .map(|path| PathBuf::from(path));
+    let mut args = std::env::args_os().skip(1).collect::<Vec<_>>();
+    //`--repair` only converts again the beatmapsets with broken output
+    let repair = match args.iter().position(|arg| arg == "--repair") {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    };
+    //`--resume` skips the beatmapsets converted before an interruption
+    let resume = match args.iter().position(|arg| arg == "--resume") {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    };
+    //`--single <path>` converts a single beatmap or beatmapset folder, writing the simfiles
+    //next to it
+    let single = match args.iter().position(|arg| arg == "--single") {
+        Some(idx) => {
+            args.remove(idx);
+            ensure!(
+                idx < args.len(),
+                "`--single` expects a beatmap or beatmapset folder"
+            );
+            Some(PathBuf::from(args.remove(idx)))
+        }
+        None => None,
+    };
+    //`--similar <simfile>` lists the converted charts most similar to the charts of a simfile
+    let similar = match args.iter().position(|arg| arg == "--similar") {
+        Some(idx) => {
+            args.remove(idx);
+            ensure!(idx < args.len(), "`--similar` expects a converted simfile");
+            Some(PathBuf::from(args.remove(idx)))
+        }
+        None => None,
+    };
+    //`--watch` keeps converting new beatmapsets as they show up in the input folder
+    let watch = match args.iter().position(|arg| arg == "--watch") {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    };
+    #[cfg(not(feature = "watch"))]
+    ensure!(
+        !watch,
+        "`--watch` requires osu2sm to be built with the `watch` feature"
+    );
+    ensure!(
+        !(watch && single.is_some()),
+        "`--watch` and `--single` cannot be used together"
+    );
+    ensure!(
+        [repair, resume, single.is_some(), similar.is_some()]
+            .iter()
+            .filter(|&&flag| flag)
+            .count()
+            <= 1,
+        "`--repair`, `--resume`, `--single` and `--similar` cannot be used together"
+    );
+    ensure!(
+        !(watch && similar.is_some()),
+        "`--watch` and `--similar` cannot be used together"
+    );
+    let load_cfg_from = args.into_iter().next().map(|path| PathBuf::from(path));
+    //Where to save the config to if decisions change
+    let mut save_to = None;
     let opts = if let Some(cfg_path) = load_cfg_from {
         //Load from here
         let opts = load_cfg(&cfg_path)?;
         opts.apply();
         info!("loaded config from \"{}\"", cfg_path.display());
+        save_to = Some(cfg_path);
         opts
     } else {
         //Load/save config from default path
@@ -376,6 +757,7 @@ fn run() -> Result<()> {
             Ok(opts) => {
                 opts.apply();
                 info!("loaded config from \"{}\"", cfg_path.display());
+                save_to = Some(cfg_path);
                 opts
             }
             Err(err) => {
@@ -388,6 +770,7 @@ fn run() -> Result<()> {
                     match save_cfg(&cfg_path, &opts) {
                         Ok(()) => {
                             info!("saved default config file");
+                            save_to = Some(cfg_path);
                         }
                         Err(err) => {
                             warn!("failed to save default config: {:#}", err);
@@ -398,18 +781,106 @@ fn run() -> Result<()> {
             }
         }
     };
-    let ctx = Ctx {
-        sm_store: RefCell::new(default()),
-        nodes: node::resolve_buckets(&opts.nodes).context("failed to resolve nodes")?,
-        opts,
+    osu2sm::prompt::init(opts.interactive, opts.decisions.clone());
+    let mut sm_store = SimfileStore::default();
+    let cfg_txt =
+        ron::ser::to_string_pretty(&opts, default()).context("failed to serialize config")?;
+    //Only hash the settings that affect the output, so that eg. changing the log level or
+    //answering a prompt does not convert everything again
+    let output_cfg = ron::ser::to_string(&(&opts.nodes, &opts.sanity_check))
+        .context("failed to serialize config")?;
+    let config_hash = format!("{:016x}", fxhash::hash64(&output_cfg));
+    sm_store.global_set_persistent("config_hash", config_hash.clone());
+    sm_store.global_set_persistent("config", cfg_txt);
+    if let Some(path) = &similar {
+        return list_similar(&opts.nodes, path);
+    }
+    let node_cfg = if repair {
+        match repair_nodes(&opts.nodes)? {
+            Some(nodes) => nodes,
+            None => return Ok(()),
+        }
+    } else if resume {
+        resume_nodes(&opts.nodes)?
+    } else if let Some(path) = &single {
+        single_nodes(&opts.nodes, path)?
+    } else {
+        opts.nodes.clone()
     };
-    run_nodes(&ctx)?;
-    Ok(())
+    //Repairing, resuming and single conversions tweak the nodes, so never cache them
+    let partial = repair || resume || single.is_some();
+    let cache_path = save_to
+        .as_ref()
+        .filter(|_| opts.cache_graph && !partial)
+        .map(|cfg_path| cfg_path.with_extension("cache.txt"));
+    if watch {
+        ensure_partial(&node_cfg, "watch")?;
+    }
+    let nodes = load_nodes(&node_cfg, &config_hash, cache_path.as_deref())
+        .context("failed to resolve nodes");
+    let result = nodes.and_then(|mut nodes| {
+        //Keep the resolved nodes around to convert changed beatmapsets later on
+        #[cfg(feature = "watch")]
+        let watched = if watch {
+            Some((nodes.clone(), sm_store.clone()))
+        } else {
+            None
+        };
+        if opts.incremental && !partial {
+            incremental_nodes(&mut nodes);
+        }
+        let nodes = nodes
+            .into_iter()
+            .map(ConcreteNode::into_dyn)
+            .collect::<Vec<_>>();
+        crash::set_nodes(nodes.iter().map(|node| format!("{:?}", node)).collect());
+        let mut ctx = Ctx {
+            sm_store,
+            nodes,
+            opts: opts.clone(),
+        };
+        install_signal_handlers();
+        run_nodes(&mut ctx)?;
+        #[cfg(feature = "watch")]
+        {
+            if let Some((nodes, store)) = watched {
+                if !cancel::requested() {
+                    watch_nodes(&nodes, &opts, &store)?;
+                }
+            }
+        }
+        Ok(())
+    });
+    if cancel::requested() {
+        if watch {
+            info!("stopped watching for new beatmaps");
+        } else {
+            warn!("conversion was interrupted, run again with `--resume` to convert the rest");
+        }
+    }
+    //Remember decisions, even if conversion failed
+    if let (Some(answers), Some(cfg_path)) = (osu2sm::prompt::new_answers(), save_to) {
+        let opts = Opts {
+            decisions: answers,
+            ..opts
+        };
+        match save_cfg(&cfg_path, &opts) {
+            Ok(()) => {
+                info!("saved decisions to \"{}\"", cfg_path.display());
+            }
+            Err(err) => {
+                warn!("failed to save decisions: {:#}", err);
+            }
+        }
+    }
+    result
 }
 
 fn main() {
     let start = Instant::now();
-    match run() {
+    let result = run();
+    logger::set_status("");
+    match result {
         Ok(()) => {
             info!(
                 "finished in {}s",
@@ -423,3 +894,88 @@ fn main() {
     eprintln!("hit enter to close this window");
     let _ = std::io::stdin().read_line(&mut String::new());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a 4K osu!mania beatmap with a note every `gap` milliseconds.
+    fn write_beatmap(dir: &Path, title: &str, gap: usize) {
+        let mut osu = format!(
+            "osu file format v14\n\n[General]\nAudioFilename: missing.mp3\nMode: 3\n\n\
+             [Metadata]\nTitle:{0}\nArtist:Test\nVersion:{0}\n\n\
+             [Difficulty]\nCircleSize:4\nOverallDifficulty:8\n\n\
+             [TimingPoints]\n0,500,4,2,0,50,1,0\n\n[HitObjects]\n",
+            title
+        );
+        for (i, time) in (0..20_000).step_by(gap).enumerate() {
+            osu += &format!("{},192,{},1,0,0:0:0:0:\n", 64 + 128 * (i % 4), time);
+        }
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("test.osu"), osu).unwrap();
+    }
+
+    /// Run the node graph like a normal conversion would.
+    fn convert(opts: &Opts) -> Vec<PathBuf> {
+        let mut nodes = load_nodes(&opts.nodes, "test", None).unwrap();
+        if opts.incremental {
+            incremental_nodes(&mut nodes);
+        }
+        let mut sm_store = SimfileStore::default();
+        sm_store.global_set_persistent("config_hash", "test".to_string());
+        let mut ctx = Ctx {
+            sm_store,
+            nodes: nodes.into_iter().map(ConcreteNode::into_dyn).collect(),
+            opts: opts.clone(),
+        };
+        run_nodes(&mut ctx).unwrap();
+        let output = match opts.nodes.last() {
+            Some(ConcreteNode::SimfileWrite(write)) => PathBuf::from(&write.output),
+            _ => unreachable!(),
+        };
+        let mut written = walkdir::WalkDir::new(&output)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.extension() == Some("sm".as_ref()))
+            .collect::<Vec<_>>();
+        written.sort();
+        written
+    }
+
+    #[test]
+    fn topn_converts_again_from_every_beatmapset() {
+        let dir = std::env::temp_dir().join(format!("osu2sm-test-topn-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (title, gap) in [("Easy", 500), ("Medium", 250), ("Hard", 125)] {
+            write_beatmap(&dir.join("input").join(title), title, gap);
+        }
+        let graph = |topn: &str| -> Opts {
+            ron::de::from_str(&format!(
+                r#"(
+                    nodes: [
+                        OsuLoad((input: "{}", query_audio_len: false)),
+                        Rekey((gamemode: DanceSingle)),
+                        Rate(()),
+                        {}
+                        SimfileWrite((output: "{}", in_place: false, copy: [Copy])),
+                    ],
+                )"#,
+                dir.join("input").display(),
+                topn,
+                dir.join("output").display()
+            ))
+            .unwrap()
+        };
+        //Record every beatmapset as converted in the manifest of the output, but remove the
+        //simfiles, so that only the ones written by `TopN` are found afterwards
+        for path in convert(&graph("")) {
+            fs::remove_file(path).unwrap();
+        }
+        let opts = graph("TopN((count: 2, min_secs: 0)),");
+        let first = convert(&opts);
+        let second = convert(&opts);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first, second);
+    }
+}
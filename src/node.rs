@@ -1,38 +1,75 @@
 //! Create, modify and transform in-memory simfiles.
 
 use crate::node::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, MutexGuard},
+};
 
 mod prelude {
     pub use crate::{
         node::{
-            align::Align, filter::Filter, osuload::OsuLoad, pipe::Pipe, rate::Rate, rekey::Rekey,
-            remap::Remap, select::Select, simfilewrite::SimfileWrite, simultaneous::Simultaneous,
-            space::Space, BucketId, BucketIter, BucketKind,
+            align::Align, artwork::Artwork, bmswrite::BmsWrite, concat::Concat, crop::Crop,
+            editmeta::EditMeta, exec::Exec, filter::Filter, holdkind::HoldKind,
+            interpolate::Interpolate, mcwrite::McWrite, mines::Mines, osuload::OsuLoad, pipe::Pipe,
+            practice::Practice, preview::Preview, previewclip::PreviewClip, quawrite::QuaWrite,
+            rate::Rate, ratechange::RateChange, ratings::Ratings, rekey::Rekey, remap::Remap,
+            report::Report, resample::Resample, script::Script, select::Select, setmeta::SetMeta,
+            simfileload::SimfileLoad, simfilewrite::SimfileWrite, simultaneous::Simultaneous,
+            space::Space, split::Split, stretch::Stretch, topn::TopN, BucketId, BucketIter,
+            BucketKind, Shared,
         },
         prelude::*,
     };
 }
 
 pub mod align;
+pub mod artwork;
+pub mod bmswrite;
+pub mod concat;
+pub mod crop;
+pub mod editmeta;
+pub mod exec;
 pub mod filter;
+pub mod holdkind;
+pub mod interpolate;
+pub mod mcwrite;
+pub mod mines;
 pub mod osuload;
 pub mod pipe;
+pub mod practice;
+pub mod preview;
+pub mod previewclip;
+pub mod quawrite;
 pub mod rate;
+pub mod ratechange;
+pub mod ratings;
 pub mod rekey;
 pub mod remap;
+pub mod report;
+pub mod resample;
+pub mod script;
 pub mod select;
+pub mod setmeta;
+pub mod simfileload;
 pub mod simfilewrite;
 pub mod simultaneous;
 pub mod space;
+pub mod split;
+pub mod stretch;
+pub mod topn;
 
 #[derive(Clone, Default)]
 struct Bucket {
     simfiles: Vec<Box<Simfile>>,
     lists: Vec<usize>,
+    /// Estimated size of the simfiles, only kept up to date while collecting bucket statistics.
+    bytes: usize,
 }
 impl Bucket {
     fn take_all(&mut self) -> Vec<Box<Simfile>> {
         self.lists.clear();
+        self.bytes = 0;
         mem::replace(&mut self.simfiles, default())
     }
 
@@ -42,6 +79,7 @@ impl Bucket {
         mut consume: impl FnMut(&mut Vec<Box<Simfile>>) -> Result<()>,
     ) -> Result<()> {
         let mut flat_simfiles = mem::replace(&mut self.simfiles, default());
+        self.bytes = 0;
         if self.lists.is_empty() {
             return Ok(());
         }
@@ -88,9 +126,35 @@ impl fmt::Debug for Bucket {
 pub struct SimfileStore {
     by_name: HashMap<String, Bucket>,
     globals: HashMap<String, String>,
+    /// Globals that survive `reset`, set once before running the nodes.
+    persistent: HashMap<String, String>,
     tmp_vec: Vec<Box<Simfile>>,
+    /// Bucket usage statistics, shared by all clones of the store.
+    stats: Option<Arc<Shared<BTreeMap<String, BucketStats>>>>,
 }
 impl SimfileStore {
+    /// Start collecting usage statistics for every bucket.
+    ///
+    /// Clones of this store made afterwards add to the same statistics.
+    pub fn track_stats(&mut self) {
+        self.stats = Some(default());
+    }
+
+    /// Get the bucket statistics collected so far, if tracking them.
+    pub fn stats(&self) -> Option<BTreeMap<String, BucketStats>> {
+        self.stats.as_ref().map(|stats| stats.lock().clone())
+    }
+
+    fn record_stats(&self, name: &str, record: impl FnOnce(&mut BucketStats)) {
+        if let Some(stats) = &self.stats {
+            let mut stats = stats.lock();
+            match stats.get_mut(name) {
+                Some(stat) => record(stat),
+                None => record(stats.entry(name.to_string()).or_default()),
+            }
+        }
+    }
+
     pub fn reset(&mut self) {
         self.by_name.clear();
         self.globals.clear();
@@ -107,13 +171,32 @@ impl SimfileStore {
         }
     }
 
+    /// Set a global that is not cleared by `reset`.
+    pub fn global_set_persistent(&mut self, name: &str, value: String) {
+        self.persistent.insert(name.to_string(), value);
+    }
+
     pub fn global_get_expect(&self, name: &str) -> Result<&str> {
         self.global_get(name)
             .ok_or(anyhow!("global \"{}\" not set", name))
     }
 
     pub fn global_get(&self, name: &str) -> Option<&str> {
-        self.globals.get(name).map(|s| &s[..])
+        self.globals
+            .get(name)
+            .or_else(|| self.persistent.get(name))
+            .map(|s| &s[..])
+    }
+
+    pub fn global_remove(&mut self, name: &str) -> Option<String> {
+        self.globals.remove(name)
+    }
+
+    /// Iterate over the globals of the current beatmapset, without the persistent globals.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.globals
+            .iter()
+            .map(|(name, value)| (&name[..], &value[..]))
     }
 
     pub fn get<F>(&mut self, bucket: &BucketId, mut visit: F) -> Result<()>
@@ -137,6 +220,9 @@ impl SimfileStore {
                 b.clone()
             })
         };
+        if let Some(b) = &b {
+            self.record_read(name, take, b.simfiles.len(), b.bytes);
+        }
         if let Some(mut b) = b {
             let mut tmp_vec = mem::replace(&mut self.tmp_vec, Vec::new());
             b.take_lists(&mut tmp_vec, |list| visit(self, list))?;
@@ -155,6 +241,9 @@ impl SimfileStore {
             trace!("    get flat null bucket");
             return Ok(());
         }
+        if let Some(b) = self.by_name.get(name) {
+            self.record_read(name, take, b.simfiles.len(), b.bytes);
+        }
         let all = if take {
             self.by_name.remove(name).map(|mut b| {
                 trace!("    take flat bucket \"{}\" ({:?})", name, b);
@@ -193,10 +282,80 @@ impl SimfileStore {
             return;
         }
         trace!("    put {} simfiles in bucket \"{}\"", simfiles.len(), name);
-        self.by_name
-            .entry(name.to_string())
-            .or_default()
-            .put_list(simfiles);
+        let bucket = self.by_name.entry(name.to_string()).or_default();
+        let added = bucket.put_list(simfiles);
+        if self.stats.is_some() {
+            let new = &bucket.simfiles[bucket.simfiles.len() - added..];
+            bucket.bytes += new.iter().map(|sm| sm.estimated_size()).sum::<usize>();
+            let (held, bytes) = (bucket.simfiles.len(), bucket.bytes);
+            self.record_stats(name, |stat| {
+                stat.put_simfiles += added;
+                stat.peak_simfiles = stat.peak_simfiles.max(held);
+                stat.peak_bytes = stat.peak_bytes.max(bytes);
+                if let Some(node) = crate::crash::current_node() {
+                    stat.written_by.insert(node);
+                }
+            });
+        }
+    }
+
+    fn record_read(&self, name: &str, take: bool, simfiles: usize, bytes: usize) {
+        self.record_stats(name, |stat| {
+            let node = crate::crash::current_node();
+            if take {
+                stat.taken_by.extend(node);
+            } else {
+                stat.clone_reads += 1;
+                stat.cloned_simfiles += simfiles;
+                stat.cloned_bytes += bytes;
+                stat.cloned_by.extend(node);
+            }
+        });
+    }
+
+    /// Log the collected bucket statistics, warning about buckets that are cloned a lot.
+    pub fn report_stats(&self) {
+        let stats = match self.stats() {
+            Some(stats) => stats,
+            None => return,
+        };
+        let nodes = |nodes: &BTreeSet<usize>| {
+            nodes
+                .iter()
+                .map(|idx| (idx + 1).to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        info!("bucket usage:");
+        for (name, stat) in stats.iter() {
+            info!(
+                "  bucket \"{}\" (written by node {}, read by node {}): peak of {} simfiles ({}), {} simfiles in total",
+                name,
+                nodes(&stat.written_by),
+                nodes(&stat.cloned_by.union(&stat.taken_by).copied().collect()),
+                stat.peak_simfiles,
+                fmt_bytes(stat.peak_bytes),
+                stat.put_simfiles,
+            );
+            if stat.clone_reads > 0 {
+                info!(
+                    "    cloned {} times by node {}, copying {} simfiles ({})",
+                    stat.clone_reads,
+                    nodes(&stat.cloned_by),
+                    stat.cloned_simfiles,
+                    fmt_bytes(stat.cloned_bytes),
+                );
+            }
+            //Every simfile being copied more than once is usually a sign of a misconfiguration
+            if stat.put_simfiles > 0 && stat.cloned_simfiles > stat.put_simfiles {
+                warn!(
+                    "  bucket \"{}\" is copied {:.1} times on average, consider chaining nodes {} so that they take their input instead of cloning it",
+                    name,
+                    stat.cloned_simfiles as f64 / stat.put_simfiles as f64,
+                    nodes(&stat.cloned_by),
+                );
+            }
+        }
     }
 
     pub fn check(&self) -> Result<()> {
@@ -213,6 +372,97 @@ impl SimfileStore {
         }
         Ok(())
     }
+
+    /// Sanity-check every simfile in the store, handling failures according to `level`.
+    /// `after` describes what the simfiles just went through, for logging.
+    pub fn sanity_check(&mut self, level: SanityCheck, after: &str) -> Result<()> {
+        match level {
+            SanityCheck::Off => Ok(()),
+            SanityCheck::Strict => self.check(),
+            SanityCheck::Warn | SanityCheck::Fix => {
+                for (bucket_name, bucket) in self.by_name.iter_mut() {
+                    for sm in bucket.simfiles.iter_mut() {
+                        let err = match sm.check() {
+                            Ok(()) => continue,
+                            Err(err) => err,
+                        };
+                        if level == SanityCheck::Warn {
+                            warn!(
+                                "  simfile \"{} [{}]\" at bucket \"{}\" failed the sanity check after {}: {:#}",
+                                sm.title, sm.desc, bucket_name, after, err
+                            );
+                            continue;
+                        }
+                        let changes = sm.fix();
+                        match sm.check() {
+                            Ok(()) => warn!(
+                                "  fixed simfile \"{} [{}]\" at bucket \"{}\" after {} ({}): {}",
+                                sm.title,
+                                sm.desc,
+                                bucket_name,
+                                after,
+                                err,
+                                changes.join(", ")
+                            ),
+                            Err(err) => warn!(
+                                "  failed to fix simfile \"{} [{}]\" at bucket \"{}\" after {}: {:#}",
+                                sm.title, sm.desc, bucket_name, after, err
+                            ),
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How to handle simfiles that fail the sanity checks between nodes.
+///
+/// Older config files used `true` and `false`, which are still accepted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SanityCheck {
+    /// Do not check simfiles.
+    #[serde(alias = "false")]
+    Off,
+    /// Log a warning for every broken simfile.
+    Warn,
+    /// Repair broken simfiles (see `Simfile::fix`), logging what was changed.
+    Fix,
+    /// Stop with an error on the first broken simfile.
+    #[serde(alias = "true")]
+    Strict,
+}
+
+/// Usage statistics of a single bucket, over all beatmapsets.
+#[derive(Clone, Debug, Default)]
+pub struct BucketStats {
+    /// The most simfiles held by the bucket at once.
+    pub peak_simfiles: usize,
+    /// The most bytes held by the bucket at once, roughly.
+    pub peak_bytes: usize,
+    /// How many simfiles were put into the bucket in total.
+    pub put_simfiles: usize,
+    /// How many times the bucket was read by cloning its simfiles instead of taking them.
+    pub clone_reads: usize,
+    /// How many simfiles were cloned in total.
+    pub cloned_simfiles: usize,
+    /// How many bytes were cloned in total, roughly.
+    pub cloned_bytes: usize,
+    /// The indices of the nodes that put simfiles into the bucket.
+    pub written_by: BTreeSet<usize>,
+    /// The indices of the nodes that cloned the bucket.
+    pub cloned_by: BTreeSet<usize>,
+    /// The indices of the nodes that took the bucket.
+    pub taken_by: BTreeSet<usize>,
+}
+
+fn fmt_bytes(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1}MB", bytes as f64 / (1024. * 1024.))
+    } else {
+        format!("{:.1}KB", bytes as f64 / 1024.)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -230,6 +480,15 @@ impl Default for BucketId {
     }
 }
 impl BucketId {
+    /// Whether simfiles put into this bucket are discarded.
+    pub fn is_null(&self) -> bool {
+        match self {
+            BucketId::Null => true,
+            BucketId::Resolved(name, _) => name.is_empty(),
+            _ => false,
+        }
+    }
+
     #[track_caller]
     fn unwrap_name(&self) -> &str {
         self.unwrap_resolved().0
@@ -244,7 +503,9 @@ impl BucketId {
     }
 }
 
-pub trait Node: fmt::Debug {
+/// Nodes are shared between the worker threads that process beatmapsets, so any state they
+/// mutate while applying must be behind a lock (see `Shared`).
+pub trait Node: fmt::Debug + Send + Sync {
     /// Must yield all `BucketIter::Input` values before all `BucketIter::Output` values.
     fn buckets_mut(&mut self) -> BucketIter;
     /// Run on all filters once before starting.
@@ -252,15 +513,58 @@ pub trait Node: fmt::Debug {
         Ok(())
     }
     /// Run on every filters once, so that entry point filters can load simfiles.
+    ///
+    /// `on_bmset` may be called from several threads at once, each with its own store (a clone
+    /// of `sm_store`).
     fn entry(
         &self,
         _sm_store: &mut SimfileStore,
-        _on_bmset: &mut dyn FnMut(&mut SimfileStore) -> Result<()>,
+        _on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
     ) -> Result<()> {
         Ok(())
     }
     /// Run on every filter once for each simfile set.
     fn apply(&self, sm_store: &mut SimfileStore) -> Result<()>;
+    /// Run on every filter once after all simfile sets have been processed.
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Run on prepared filters before they are cached for the next run, to skip any prompts or
+    /// autodetection that already happened while preparing.
+    /// Cached filters are still prepared again on the next run.
+    fn settle(&mut self) {}
+}
+
+/// Mutable state of a node, such as caches or collected statistics, that may be updated from
+/// several threads at once.
+///
+/// Cloning it clones the current state.
+#[derive(Default)]
+pub struct Shared<T>(Mutex<T>);
+impl<T> Shared<T> {
+    pub fn new(val: T) -> Self {
+        Self(Mutex::new(val))
+    }
+
+    /// Lock the state, even if another thread panicked while holding it.
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Access the state without locking, since no other thread can hold it.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap_or_else(|err| err.into_inner())
+    }
+}
+impl<T: Clone> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.lock().clone())
+    }
+}
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.lock().fmt(f)
+    }
 }
 
 pub type BucketIter<'a> = Box<dyn 'a + Iterator<Item = (BucketKind, &'a mut BucketId)>>;
@@ -285,9 +589,26 @@ impl BucketKind {
     }
 }
 
+/// Resolve all buckets and prepare the nodes to run.
 pub fn resolve_buckets(nodes: &[ConcreteNode]) -> Result<Vec<Box<dyn Node>>> {
+    let mut nodes = resolve_graph(nodes)?;
+    prepare_nodes(&mut nodes)?;
+    Ok(nodes.into_iter().map(ConcreteNode::into_dyn).collect())
+}
+
+/// Prepare resolved nodes to run.
+pub fn prepare_nodes(nodes: &mut [ConcreteNode]) -> Result<()> {
+    for node in nodes.iter_mut() {
+        node.as_dyn_mut().prepare()?;
+    }
+    Ok(())
+}
+
+/// Resolve all buckets into plain names, flattening nested nodes into a single list in
+/// execution order.
+pub fn resolve_graph(nodes: &[ConcreteNode]) -> Result<Vec<ConcreteNode>> {
     struct State {
-        out: Vec<Box<dyn Node>>,
+        out: Vec<ConcreteNode>,
         next_id: u32,
     }
     impl State {
@@ -307,7 +628,7 @@ pub fn resolve_buckets(nodes: &[ConcreteNode]) -> Result<Vec<Box<dyn Node>>> {
         let mut last_magnetic_out = input.map(str::to_string);
         let in_node_count = nodes.len();
         for (i, orig_node) in nodes.iter().enumerate() {
-            let mut node = orig_node.clone().into_dyn();
+            let mut node = orig_node.clone();
             //The last node has its output automatically bound to the output
             //However, in non-chained mode the output is always bound to the parent output
             let mut magnetic_out = if !chained || i + 1 == in_node_count {
@@ -321,7 +642,7 @@ pub fn resolve_buckets(nodes: &[ConcreteNode]) -> Result<Vec<Box<dyn Node>>> {
             }
             let mut insert_idx = ctx.out.len();
             //Resolve each bucket
-            for (kind, bucket) in node.buckets_mut() {
+            for (kind, bucket) in node.as_dyn_mut().buckets_mut() {
                 let is_chained = match bucket {
                     BucketId::Chain(..) => true,
                     _ => false,
@@ -377,7 +698,7 @@ pub fn resolve_buckets(nodes: &[ConcreteNode]) -> Result<Vec<Box<dyn Node>>> {
             ensure!(
                 last_magnetic_out.is_none() || i == 0,
                 "output from previous node is not used as input (in node {:?})",
-                node
+                node.as_dyn()
             );
             last_magnetic_out = magnetic_out;
             ctx.out.insert(insert_idx, node);
@@ -393,7 +714,7 @@ pub fn resolve_buckets(nodes: &[ConcreteNode]) -> Result<Vec<Box<dyn Node>>> {
     //Optimize the last reads from each bucket, by taking the value instead of cloning it
     let mut last_reads: HashMap<String, &mut BucketId> = default();
     for node in ctx.out.iter_mut() {
-        for (kind, bucket) in node.buckets_mut() {
+        for (kind, bucket) in node.as_dyn_mut().buckets_mut() {
             if kind.is_input() {
                 last_reads.insert(bucket.unwrap_name().to_string(), bucket);
             }
@@ -407,10 +728,6 @@ pub fn resolve_buckets(nodes: &[ConcreteNode]) -> Result<Vec<Box<dyn Node>>> {
             _ => panic!("unresolved bucket"),
         }
     }
-    //Prepare nodes, allowing them to modify themselves
-    for node in ctx.out.iter_mut() {
-        node.prepare()?;
-    }
     //Finally, unwrap the output
     Ok(ctx.out)
 }
@@ -437,6 +754,14 @@ macro_rules! make_concrete {
                     )*
                 }
             }
+
+            pub fn as_dyn_mut(&mut self) -> &mut dyn Node {
+                match self {
+                    $(
+                        ConcreteNode::$node(node) => node,
+                    )*
+                }
+            }
         }
         $(
             impl From<$node> for ConcreteNode {
@@ -451,13 +776,37 @@ macro_rules! make_concrete {
 make_concrete!(
     Pipe,
     Filter,
+    HoldKind,
+    Mines,
     Remap,
     Rekey,
+    Resample,
     Simultaneous,
     Align,
     Select,
     Rate,
+    Ratings,
     Space,
+    Exec,
+    Script,
+    Stretch,
+    RateChange,
+    EditMeta,
+    Practice,
+    Crop,
+    Concat,
+    Report,
+    SetMeta,
+    Interpolate,
+    Split,
+    Preview,
+    PreviewClip,
+    Artwork,
+    TopN,
     OsuLoad,
+    SimfileLoad,
     SimfileWrite,
+    QuaWrite,
+    McWrite,
+    BmsWrite,
 );
@@ -6,6 +6,10 @@ pub struct Align {
     pub from: BucketId,
     pub into: BucketId,
     pub to: f64,
+    /// What to do with notes that are not aligned.
+    pub mode: AlignMode,
+    /// How far away (in beats) notes can be moved by `AlignMode::Snap`.
+    pub tolerance: f64,
 }
 impl Default for Align {
     fn default() -> Self {
@@ -13,10 +17,22 @@ impl Default for Align {
             from: default(),
             into: default(),
             to: 1.,
+            mode: AlignMode::Delete,
+            tolerance: 0.125,
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AlignMode {
+    /// Delete all unaligned notes.
+    Delete,
+    /// Move unaligned notes to the nearest aligned beat, if it is within `tolerance`.
+    /// Notes are only deleted if they are too far away, or if moving them would make them collide
+    /// with other notes.
+    Snap,
+}
+
 impl Node for Align {
     fn apply(&self, store: &mut SimfileStore) -> Result<()> {
         store.get(&self.from, |store, list| {
@@ -35,25 +51,82 @@ impl Node for Align {
     }
 }
 
+/// Find the tail of the hold note whose head is at `head_idx`.
+fn find_tail(notes: &[Note], head_idx: usize) -> Option<usize> {
+    let head_key = notes[head_idx].key;
+    (head_idx + 1..notes.len()).find(|&j| notes[j].key == head_key && notes[j].is_tail())
+}
+
+/// Remove a note (and its tail, if it is a hold head).
+fn remove_note(notes: &mut [Note], idx: usize) {
+    if notes[idx].is_head() {
+        if let Some(tail) = find_tail(notes, idx) {
+            notes[tail].key = -1;
+        }
+    }
+    notes[idx].key = -1;
+}
+
+/// Attempt to move an unaligned note to `target`, returning whether it was moved.
+fn snap_note(notes: &mut [Note], idx: usize, target: BeatPos) -> bool {
+    let note = notes[idx].clone();
+    let tail = if note.is_head() {
+        find_tail(notes, idx)
+    } else {
+        None
+    };
+    if let Some(tail) = tail {
+        if target >= notes[tail].beat {
+            //Would swallow its own tail
+            return false;
+        }
+    }
+    //The note must not cross or land on any other note in its key
+    let (lo, hi) = if target < note.beat {
+        (target, note.beat)
+    } else {
+        (note.beat, target)
+    };
+    let collides = notes.iter().enumerate().any(|(j, other)| {
+        j != idx && Some(j) != tail && other.key == note.key && other.beat >= lo && other.beat <= hi
+    });
+    if collides {
+        return false;
+    }
+    notes[idx].beat = target;
+    true
+}
+
 fn align(sm: &mut Simfile, conf: &Align) -> Result<()> {
     let align_to = BeatPos::from(conf.to);
+    let tolerance = BeatPos::from(conf.tolerance);
+    let mut moved = 0;
+    let mut removed = 0;
     for i in 0..sm.notes.len() {
-        let note = &mut sm.notes[i];
-        if !note.is_tail() && !note.beat.is_aligned(align_to) {
-            let head_key = note.key;
-            note.key = -1;
-            if note.is_head() {
-                //If note is a head, also remove its tail
-                for j in i + 1..sm.notes.len() {
-                    let note = &mut sm.notes[j];
-                    if note.key == head_key && note.is_tail() {
-                        note.key = -1;
-                        break;
-                    }
-                }
+        let note = sm.notes[i].clone();
+        if note.key < 0 || note.is_tail() || note.beat.is_aligned(align_to) {
+            continue;
+        }
+        if conf.mode == AlignMode::Snap {
+            let target = note.beat.round(align_to);
+            let dist = if target < note.beat {
+                note.beat - target
+            } else {
+                target - note.beat
+            };
+            if dist <= tolerance && snap_note(&mut sm.notes, i, target) {
+                moved += 1;
+                continue;
             }
         }
+        remove_note(&mut sm.notes, i);
+        removed += 1;
     }
     sm.notes.retain(|note| note.key >= 0);
+    if moved > 0 {
+        //Moved notes may be out of order
+        sm.notes.sort_by_key(|note| note.beat);
+        trace!("    snapped {} notes and removed {} notes", moved, removed);
+    }
     Ok(())
 }
@@ -0,0 +1,259 @@
+//! Make StepMania-friendly banners and backgrounds out of osu! background images.
+//!
+//! Images are processed with ffmpeg, and the new images are made in the generated files folder
+//! (see `crate::generated_dir`), from which writers copy them into the output.
+
+use crate::node::prelude::*;
+use std::process::Command;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Artwork {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// Whether to cut a banner out of the background of simfiles that have no banner (such as
+    /// every simfile converted from osu!).
+    pub banner: bool,
+    /// The size of generated banners, in pixels.
+    /// Common sizes are `(512, 160)` and `(418, 164)` (used by most StepMania 5 themes).
+    pub banner_size: (u32, u32),
+    /// Resize backgrounds to this size in pixels (eg. `(1920, 1080)`), fitting them to its
+    /// aspect ratio as given by `background_fit`.
+    /// `(0, 0)` leaves backgrounds as they are.
+    pub background_size: (u32, u32),
+    /// Fit backgrounds to this aspect ratio (eg. `(16, 9)` or `(4, 3)`) without resizing them,
+    /// so that StepMania themes do not stretch them.
+    /// `(0, 0)` leaves backgrounds as they are.
+    /// Ignored if `background_size` is set.
+    pub background_aspect: (u32, u32),
+    /// How to fit backgrounds to `background_size` or `background_aspect`.
+    pub background_fit: ImageFit,
+    /// The ffmpeg executable to use to resize images.
+    pub ffmpeg: String,
+}
+impl Default for Artwork {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            banner: true,
+            banner_size: (512, 160),
+            background_size: (0, 0),
+            background_aspect: (0, 0),
+            background_fit: ImageFit::Crop,
+            ffmpeg: "ffmpeg".into(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ImageFit {
+    /// Cut off the edges of the image that do not fit.
+    Crop,
+    /// Add black bars around the image (ie. letterbox or pillarbox it).
+    Pad,
+}
+
+/// The shape to fit an image into.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Shape {
+    /// Resize to this size in pixels.
+    Size(u32, u32, ImageFit),
+    /// Keep the size, but fit to this aspect ratio.
+    Aspect(u32, u32, ImageFit),
+}
+impl Shape {
+    /// Get a tag for the file names of images fitted to this shape.
+    fn tag(&self) -> String {
+        let (w, h, fit, sep) = match *self {
+            Shape::Size(w, h, fit) => (w, h, fit, 'x'),
+            Shape::Aspect(w, h, fit) => (w, h, fit, '-'),
+        };
+        match fit {
+            ImageFit::Crop => format!("{}{}{}", w, sep, h),
+            ImageFit::Pad => format!("{}{}{}-pad", w, sep, h),
+        }
+    }
+
+    /// Get the ffmpeg filter that fits an image to this shape.
+    pub(crate) fn filter(&self) -> String {
+        match *self {
+            Shape::Size(w, h, ImageFit::Crop) => format!(
+                "scale={0}:{1}:force_original_aspect_ratio=increase,crop={0}:{1}",
+                w, h
+            ),
+            Shape::Size(w, h, ImageFit::Pad) => format!(
+                "scale={0}:{1}:force_original_aspect_ratio=decrease,pad={0}:{1}:(ow-iw)/2:(oh-ih)/2",
+                w, h
+            ),
+            Shape::Aspect(w, h, ImageFit::Crop) => {
+                format!("crop='min(iw,ih*{0}/{1})':'min(ih,iw*{1}/{0})'", w, h)
+            }
+            Shape::Aspect(w, h, ImageFit::Pad) => format!(
+                "pad='max(iw,ih*{0}/{1})':'max(ih,iw*{1}/{0})':(ow-iw)/2:(oh-ih)/2",
+                w, h
+            ),
+        }
+    }
+}
+
+impl Node for Artwork {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            self.banner_size.0 > 0 && self.banner_size.1 > 0,
+            "banner size must be positive"
+        );
+        ensure!(
+            (self.background_size.0 > 0) == (self.background_size.1 > 0),
+            "background size must be either positive or (0, 0)"
+        );
+        ensure!(
+            (self.background_aspect.0 > 0) == (self.background_aspect.1 > 0),
+            "background aspect ratio must be either positive or (0, 0)"
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let base = store.global_get("base").map(PathBuf::from);
+        let banner_shape = Shape::Size(self.banner_size.0, self.banner_size.1, ImageFit::Crop);
+        let bg_shape = if self.background_size.0 > 0 {
+            Some(Shape::Size(
+                self.background_size.0,
+                self.background_size.1,
+                self.background_fit,
+            ))
+        } else if self.background_aspect.0 > 0 {
+            Some(Shape::Aspect(
+                self.background_aspect.0,
+                self.background_aspect.1,
+                self.background_fit,
+            ))
+        } else {
+            None
+        };
+        store.get(&self.from, |store, list| {
+            if let Some(base) = &base {
+                //Charts with the same background share the same images
+                let mut made: HashMap<(PathBuf, Shape), Option<(PathBuf, PathBuf)>> = default();
+                let mut fit = |sm: &mut Simfile, bg: &Path, shape: Shape, what: &str| {
+                    let key = (bg.to_path_buf(), shape);
+                    let image = match made.get(&key) {
+                        Some(image) => image.clone(),
+                        None => {
+                            let src = sm.dep_source(base, bg);
+                            let image = match fit_image(&self.ffmpeg, base, bg, &src, shape, what) {
+                                Ok(image) => Some(image),
+                                Err(err) => {
+                                    warn!(
+                                        "    failed to make {} out of \"{}\": {:#}",
+                                        what,
+                                        bg.display(),
+                                        err
+                                    );
+                                    None
+                                }
+                            };
+                            made.insert(key, image.clone());
+                            image
+                        }
+                    };
+                    image.map(|(rel, file)| {
+                        sm.set_generated(rel.clone(), file);
+                        rel
+                    })
+                };
+                for sm in list.iter_mut() {
+                    let bg = match sm.background.clone() {
+                        Some(bg) => bg,
+                        None => continue,
+                    };
+                    if self.banner && sm.banner.is_none() {
+                        sm.banner = fit(sm, &bg, banner_shape, "banner");
+                    }
+                    if let Some(bg_shape) = bg_shape {
+                        if let Some(resized) = fit(sm, &bg, bg_shape, "background") {
+                            sm.background = Some(resized);
+                        }
+                    }
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+/// Fit an image to the given shape around its center, returning the path of the new image
+/// relative to `base` along with the path it was generated at (see `crate::generated_dir`).
+///
+/// `src` is the file to read the image from, which may be a generated file itself.
+fn fit_image(
+    ffmpeg: &str,
+    base: &Path,
+    image: &Path,
+    src: &Path,
+    shape: Shape,
+    what: &str,
+) -> Result<(PathBuf, PathBuf)> {
+    ensure!(src.is_file(), "image file not found");
+    let name = format!(
+        "osu2sm-{}-{}-{}.png",
+        what,
+        image.file_stem().unwrap_or_default().to_string_lossy(),
+        shape.tag()
+    );
+    let rel_out = image.with_file_name(&name);
+    let dst = crate::generated_dir(base)?.join(&name);
+    if dst.exists() {
+        trace!("    {} \"{}\" already exists", what, dst.display());
+        return Ok((rel_out, dst));
+    }
+    debug!(
+        "    making {} \"{}\" out of \"{}\"",
+        what,
+        dst.display(),
+        src.display()
+    );
+    render_image(
+        ffmpeg,
+        |cmd| {
+            cmd.arg("-i").arg(src);
+        },
+        &shape.filter(),
+        &dst,
+    )?;
+    Ok((rel_out, dst))
+}
+
+/// Render the first frame of an ffmpeg input through a video filter into an image file, writing
+/// it through a temporary file (see `crate::generate_atomic`).
+///
+/// `input` adds the input options to the ffmpeg command.
+/// Also used to make pack banners (see `crate::pack`).
+pub(crate) fn render_image(
+    ffmpeg: &str,
+    input: impl FnOnce(&mut Command),
+    filter: &str,
+    dst: &Path,
+) -> Result<()> {
+    crate::generate_atomic(dst, |tmp| {
+        let mut cmd = Command::new(ffmpeg);
+        cmd.args(["-y", "-loglevel", "error"]);
+        input(&mut cmd);
+        let status = cmd
+            .arg("-vf")
+            .arg(filter)
+            .args(["-frames:v", "1"])
+            .arg(tmp)
+            .status()
+            .with_context(|| anyhow!("failed to run \"{}\"", ffmpeg))?;
+        ensure!(status.success(), "ffmpeg exited with {}", status);
+        Ok(())
+    })
+}
@@ -0,0 +1,385 @@
+//! Takes a bunch of simfiles as input and writes them out as BMS charts, for LR2 and beatoraja.
+
+use crate::node::{
+    prelude::*,
+    simfilewrite::{ChartFolder, CopyMethod},
+};
+use std::{collections::BTreeMap, process::Command};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BmsWrite {
+    pub from: BucketId,
+    /// The path to the output directory (usually a folder that LR2 or beatoraja scans for songs).
+    /// Every beatmapset is written into its own song folder within it.
+    pub output: String,
+    /// Which methods to try for copying "dependency" files, such as `.mp3` and `.jpg` files.
+    pub copy: Vec<CopyMethod>,
+    /// Whether to flush every chart to disk before moving it into place.
+    pub fsync: bool,
+    /// The ffmpeg executable to use to transcode music into `.ogg`, since LR2 only plays `.ogg`
+    /// and `.wav` files (beatoraja plays `.mp3` files too).
+    /// If empty, music is copied as-is.
+    pub ffmpeg: String,
+}
+
+impl Default for BmsWrite {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            output: "".into(),
+            copy: SimfileWrite::default().copy,
+            fsync: false,
+            ffmpeg: "ffmpeg".into(),
+        }
+    }
+}
+
+impl Node for BmsWrite {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            !self.output.is_empty(),
+            "no output folder to write BMS charts into"
+        );
+        info!("outputting BMS charts in \"{}\"", self.output);
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let mut folder = ChartFolder::new(store, &self.output)?;
+        //Charts of the same music share the same transcoded copy
+        let mut transcoded: HashMap<PathBuf, Option<(PathBuf, PathBuf)>> = default();
+        store.get_each(&self.from, |_, mut sm| {
+            let (ext, channels) = match sm.gamemode.key_count() {
+                5 => ("bms", &KEY_CHANNELS[..5]),
+                7 => ("bme", &KEY_CHANNELS[..]),
+                keys => {
+                    debug!(
+                        "  skipping {}K chart \"{}\", since only 5K and 7K charts are written as BMS",
+                        keys, sm.desc
+                    );
+                    return Ok(());
+                }
+            };
+            if !sm.warps.is_empty() || sm.bpms.iter().any(|cp| cp.beat_len <= 0.) {
+                debug!(
+                    "  skipping chart \"{}\", since BMS has no warps",
+                    sm.desc
+                );
+                return Ok(());
+            }
+            sm.fix_tails()?;
+            if let Some(music) = sm.music.clone().filter(|music| !self.ffmpeg.is_empty() && !lr2_plays(music)) {
+                let copy = transcoded
+                    .entry(music.clone())
+                    .or_insert_with(|| {
+                        match transcode_music(&self.ffmpeg, &folder.set_path, &sm, &music) {
+                            Ok(copy) => Some(copy),
+                            Err(err) => {
+                                warn!(
+                                    "  failed to transcode \"{}\", LR2 will not play it: {:#}",
+                                    music.display(),
+                                    err
+                                );
+                                None
+                            }
+                        }
+                    })
+                    .clone();
+                if let Some((rel, file)) = copy {
+                    sm.music = Some(rel.clone());
+                    sm.set_generated(rel, file);
+                }
+            }
+            let out_path = folder.chart_path(&sm, ext)?;
+            debug!("  writing BMS chart to \"{}\"", out_path.display());
+            crate::write_atomic(&out_path, self.fsync, |file| {
+                write_bms(file, &sm, channels)
+            })
+            .with_context(|| anyhow!("write BMS chart to \"{}\"", out_path.display()))?;
+            folder.add_deps(
+                &sm,
+                sm.music
+                    .iter()
+                    .chain(sm.background.iter())
+                    .chain(sm.banner.iter()),
+            );
+            Ok(())
+        })?;
+        //Copy over the audio and images
+        folder.copy_deps(&self.copy);
+        Ok(())
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(iter::once((BucketKind::Input, &mut self.from)))
+    }
+}
+
+/// The 1P channels of the 7 keys, from left to right.
+/// Long notes use these same channels plus 40.
+/// The scratch (channel 16) is left empty.
+const KEY_CHANNELS: [u32; 7] = [11, 12, 13, 14, 15, 18, 19];
+const BGM_CHANNEL: u32 = 1;
+const BPM_CHANNEL: u32 = 8;
+const STOP_CHANNEL: u32 = 9;
+/// The object that notes play when hit.
+/// No `#WAV` defines it, so notes are silent and the music plays on its own.
+const NOTE_OBJ: u32 = 36 * 36 - 1;
+/// The object that plays the music.
+const MUSIC_OBJ: u32 = 1;
+/// BMS positions are fractions of a measure, and simfile beats are multiples of 1/48 beat.
+const TICKS_PER_MEASURE: i64 = 4 * 48;
+
+/// Format an object index as two base-36 digits.
+fn obj_id(idx: u32) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut out = String::with_capacity(2);
+    out.push(DIGITS[(idx / 36 % 36) as usize] as char);
+    out.push(DIGITS[(idx % 36) as usize] as char);
+    out
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Assigns object indices to the distinct values of `#BPMxx` or `#STOPxx` definitions.
+#[derive(Default)]
+struct ValueDefs {
+    defs: Vec<f64>,
+}
+impl ValueDefs {
+    fn get(&mut self, value: f64) -> Result<u32> {
+        let idx = match self.defs.iter().position(|&def| def == value) {
+            Some(idx) => idx,
+            None => {
+                self.defs.push(value);
+                self.defs.len() - 1
+            }
+        };
+        ensure!(
+            idx < NOTE_OBJ as usize - 1,
+            "too many distinct BPMs or stops"
+        );
+        Ok(idx as u32 + 1)
+    }
+}
+
+/// Write a simfile as a BMS chart, with every key mapped to the given channel.
+///
+/// The music is a single background object, so to line it up exactly with the chart, the chart
+/// starts with a lead-in at whatever BPM makes the music start right at the first beat of the
+/// file.
+/// Whether LR2 can play the given audio file.
+fn lr2_plays(music: &Path) -> bool {
+    match music.extension().and_then(OsStr::to_str) {
+        Some(ext) => ext.eq_ignore_ascii_case("ogg") || ext.eq_ignore_ascii_case("wav"),
+        None => false,
+    }
+}
+
+/// Make an `.ogg` copy of the music of a simfile, returning its path relative to the beatmapset
+/// folder along with the path it was generated at (see `crate::generated_dir`).
+fn transcode_music(
+    ffmpeg: &str,
+    set_path: &Path,
+    sm: &Simfile,
+    music: &Path,
+) -> Result<(PathBuf, PathBuf)> {
+    let name = format!(
+        "osu2sm-{}.ogg",
+        music.file_stem().unwrap_or_default().to_string_lossy()
+    );
+    let rel_out = music.with_file_name(&name);
+    let src = sm.dep_source(set_path, music);
+    let dst = crate::generated_dir(set_path)?.join(&name);
+    if dst.exists() {
+        trace!("  transcoded music \"{}\" already exists", dst.display());
+        return Ok((rel_out, dst));
+    }
+    debug!(
+        "  transcoding music \"{}\" into \"{}\"",
+        src.display(),
+        dst.display()
+    );
+    crate::generate_atomic(&dst, |tmp| {
+        let status = Command::new(ffmpeg)
+            .args(["-y", "-loglevel", "error", "-i"])
+            .arg(&src)
+            .arg("-vn")
+            .arg(tmp)
+            .status()
+            .with_context(|| anyhow!("failed to run \"{}\"", ffmpeg))?;
+        ensure!(status.success(), "ffmpeg exited with {}", status);
+        Ok(())
+    })?;
+    Ok((rel_out, dst))
+}
+
+fn write_bms(file: &mut impl Write, sm: &Simfile, channels: &[u32]) -> Result<()> {
+    let bpm_at = |beat: BeatPos| {
+        sm.bpms
+            .iter()
+            .take_while(|cp| cp.beat <= beat)
+            .last()
+            .or_else(|| sm.bpms.first())
+            .map(|cp| cp.bpm())
+            .unwrap_or(120.)
+    };
+    //Start on a measure line, before any note
+    let first_beat = sm
+        .notes
+        .first()
+        .map(|note| note.beat)
+        .unwrap_or_else(|| BeatPos::from(0.))
+        .min(BeatPos::from(0.))
+        .floor(BeatPos::from(4.));
+    let first_time = sm.beat_to_time().beat_to_time(first_beat);
+    let first_bpm = bpm_at(first_beat);
+    //`(position in ticks, channel, object)` events
+    let mut events: Vec<(i64, u32, u32)> = Vec::new();
+    let mut bpm_defs = ValueDefs::default();
+    let mut stop_defs = ValueDefs::default();
+    let (start_bpm, lead_in) = if first_time > 0.001 {
+        //Play whole measures while the music gets to the first beat
+        let measures = (first_time * first_bpm / 240.).ceil().max(1.);
+        let lead_bpm = measures * 240. / first_time;
+        let lead_in = measures as i64 * TICKS_PER_MEASURE;
+        events.push((0, BGM_CHANNEL, MUSIC_OBJ));
+        events.push((lead_in, BPM_CHANNEL, bpm_defs.get(first_bpm)?));
+        (lead_bpm, lead_in)
+    } else {
+        //The chart starts before the music, so the music starts partway into it
+        //This is only as accurate as a 1/48 beat
+        let music_start = sm.time_to_beat(0.) - first_beat;
+        events.push((
+            (music_start.as_num() * 48.).round() as i64,
+            BGM_CHANNEL,
+            MUSIC_OBJ,
+        ));
+        (first_bpm, 0)
+    };
+    let ticks = |beat: BeatPos| lead_in + ((beat - first_beat).as_num() * 48.).round() as i64;
+    for cp in sm.bpms.iter().filter(|cp| cp.beat > first_beat) {
+        events.push((ticks(cp.beat), BPM_CHANNEL, bpm_defs.get(cp.bpm())?));
+    }
+    //Stops are measured in 1/192 of a measure at the current BPM
+    //Delays pause right before their beat instead of right after it
+    let pauses = sm
+        .stops
+        .iter()
+        .map(|&(beat, secs)| (BeatPos::from(beat), secs))
+        .chain(
+            sm.delays
+                .iter()
+                .map(|&(beat, secs)| (BeatPos::from(beat) - BeatPos::EPSILON, secs)),
+        );
+    for (beat, secs) in pauses {
+        let len = (secs * bpm_at(beat) / 60. * 48.).round();
+        if beat < first_beat || len < 1. {
+            continue;
+        }
+        events.push((ticks(beat), STOP_CHANNEL, stop_defs.get(len)?));
+    }
+    for note in sm.notes.iter() {
+        //BMS mines hurt too much to be worth it
+        if note.is_mine() {
+            continue;
+        }
+        let channel = *channels
+            .get(note.key as usize)
+            .ok_or_else(|| anyhow!("note key {} out of range", note.key))?;
+        let channel = if note.is_hit() { channel } else { channel + 40 };
+        events.push((ticks(note.beat), channel, NOTE_OBJ));
+    }
+    //Group objects by measure and channel
+    let mut lines: BTreeMap<(i64, u32), Vec<(i64, u32)>> = BTreeMap::new();
+    for (pos, channel, obj) in events {
+        lines
+            .entry((pos / TICKS_PER_MEASURE, channel))
+            .or_default()
+            .push((pos % TICKS_PER_MEASURE, obj));
+    }
+    if let Some(&(measure, _)) = lines.keys().next_back() {
+        ensure!(
+            measure < 1000,
+            "chart is too long for BMS ({} measures)",
+            measure + 1
+        );
+    }
+    //Write the header
+    let difficulty = match sm.difficulty {
+        Difficulty::Beginner => 1,
+        Difficulty::Easy => 2,
+        Difficulty::Medium => 3,
+        Difficulty::Hard => 4,
+        Difficulty::Challenge | Difficulty::Edit => 5,
+    };
+    let path_str = |path: &Option<PathBuf>| {
+        path.as_deref()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    write!(
+        file,
+        r#"
+*---------------------- HEADER FIELD
+#PLAYER 1
+#GENRE {genre}
+#TITLE {title}
+#SUBTITLE {desc}
+#ARTIST {artist}
+#SUBARTIST {credit}
+#BPM {bpm}
+#PLAYLEVEL {level}
+#DIFFICULTY {difficulty}
+#RANK 2
+#STAGEFILE {bg}
+#BANNER {banner}
+#LNTYPE 1
+#WAV{music_obj} {music}
+"#,
+        genre = sm.genre,
+        title = sm.title,
+        desc = sm.desc,
+        artist = sm.artist,
+        credit = sm.credit,
+        bpm = start_bpm,
+        level = if sm.difficulty_num.is_nan() {
+            0.
+        } else {
+            sm.difficulty_num.round()
+        },
+        difficulty = difficulty,
+        bg = path_str(&sm.background),
+        banner = path_str(&sm.banner),
+        music_obj = obj_id(MUSIC_OBJ),
+        music = path_str(&sm.music),
+    )?;
+    for (idx, bpm) in bpm_defs.defs.iter().enumerate() {
+        writeln!(file, "#BPM{} {}", obj_id(idx as u32 + 1), bpm)?;
+    }
+    for (idx, len) in stop_defs.defs.iter().enumerate() {
+        writeln!(file, "#STOP{} {}", obj_id(idx as u32 + 1), len)?;
+    }
+    //Write the objects, splitting every measure only as finely as its objects need
+    write!(file, "\n*---------------------- MAIN DATA FIELD\n")?;
+    for ((measure, channel), objs) in lines {
+        let step = objs
+            .iter()
+            .fold(TICKS_PER_MEASURE, |step, &(pos, _)| gcd(step, pos));
+        let mut slots = vec![0; (TICKS_PER_MEASURE / step) as usize];
+        for (pos, obj) in objs {
+            slots[(pos / step) as usize] = obj;
+        }
+        write!(file, "\n#{:03}{:02}:", measure, channel)?;
+        for obj in slots {
+            file.write_all(obj_id(obj).as_bytes())?;
+        }
+    }
+    writeln!(file)?;
+    Ok(())
+}
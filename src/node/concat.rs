@@ -0,0 +1,454 @@
+//! Join several charts into a single marathon chart, to play custom courses as a single song.
+//!
+//! Like `TopN`, `Concat` holds on to the charts it needs until all beatmapsets were loaded, and
+//! then passes on the marathon as if it was a beatmapset of its own.
+
+use crate::node::{prelude::*, simfilewrite::scrubbed_name};
+use std::{process::Command, sync::Arc};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Concat {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The charts to join, in order.
+    pub charts: Vec<ConcatChart>,
+    /// The title of the marathon, also used as the name of its folder.
+    /// The marathon is passed on as if its folder was in the input folder, next to the
+    /// beatmapsets, but the folder is only ever created in the output.
+    pub title: String,
+    /// The artist of the marathon.
+    pub artist: String,
+    /// How many seconds to wait between charts.
+    pub gap: f64,
+    /// Whether to join the music of every chart into a single audio file with ffmpeg.
+    /// Otherwise, the marathon has no music, which is only useful for silent practice.
+    pub join_audio: bool,
+    /// The ffmpeg executable to use to join the music.
+    pub ffmpeg: String,
+    /// Whether to output the original simfiles too, along with the marathon.
+    pub keep_original: bool,
+    /// The charts found so far, for every entry in `charts`.
+    #[serde(skip)]
+    pub found: Shared<Vec<Vec<Found>>>,
+}
+impl Default for Concat {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            charts: vec![],
+            title: "Marathon".into(),
+            artist: "Various Artists".into(),
+            gap: 2.,
+            join_audio: true,
+            ffmpeg: "ffmpeg".into(),
+            keep_original: true,
+            found: default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConcatChart {
+    /// The name of the beatmapset folder (or the osu! beatmapset id) to take the chart from.
+    pub set: String,
+    /// The osu! difficulty name (or the chart description) of the chart.
+    /// If empty, the hardest chart of the beatmapset is taken.
+    pub chart: String,
+}
+impl Default for ConcatChart {
+    fn default() -> Self {
+        Self {
+            set: "".into(),
+            chart: "".into(),
+        }
+    }
+}
+
+/// The globals of a beatmapset, shared by all of its matching charts.
+type Globals = Arc<Vec<(String, String)>>;
+
+/// A chart that matches an entry of `charts`, along with the globals of its beatmapset.
+#[derive(Clone, Debug)]
+pub struct Found {
+    globals: Globals,
+    sm: Box<Simfile>,
+}
+impl Found {
+    fn global(&self, name: &str) -> Option<&str> {
+        self.globals
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// How hard the chart is, to pick the hardest chart of a beatmapset.
+    /// Rated charts go by their meter, and unrated charts by their note count.
+    fn hardness(&self) -> (SortableFloat, usize) {
+        let meter = if self.sm.difficulty_num.is_nan() {
+            f64::NEG_INFINITY
+        } else {
+            self.sm.difficulty_num
+        };
+        (SortableFloat(meter), self.sm.notes.len())
+    }
+}
+
+/// A chart, cropped to its notes and placed in the marathon.
+struct Part {
+    sm: Box<Simfile>,
+    /// The beatmapset folder of the chart.
+    set_path: Option<PathBuf>,
+    /// The beat of the marathon at which the chart starts.
+    start_beat: f64,
+    /// The time into the music at which the first measure of the chart starts.
+    start_time: f64,
+    /// The time into the music at which the last measure of the chart ends.
+    end_time: f64,
+    /// The time into the music at which the audio of the chart is cut.
+    cut_time: f64,
+}
+
+impl Node for Concat {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(!self.charts.is_empty(), "no charts to join");
+        ensure!(
+            self.gap.is_finite() && self.gap > 0.,
+            "gap must be a positive number of seconds"
+        );
+        ensure!(!self.title.is_empty(), "the marathon needs a title");
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let set_folder = store
+            .global_get("set_folder")
+            .unwrap_or_default()
+            .to_string();
+        let set_id = store.global_get("set_id").unwrap_or_default().to_string();
+        let entries = self
+            .charts
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                entry.set == set_folder || (!set_id.is_empty() && entry.set == set_id)
+            })
+            .collect::<Vec<_>>();
+        if !entries.is_empty() {
+            //The marathon depends on this beatmapset, so it cannot be skipped when converting
+            //again
+            store.global_remove("source_hash");
+        }
+        let globals = Arc::new(
+            store
+                .globals()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>(),
+        );
+        store.get(&self.from, |store, list| {
+            if !entries.is_empty() {
+                let mut found = self.found.lock();
+                found.resize(self.charts.len(), Vec::new());
+                for &(idx, entry) in entries.iter() {
+                    for sm in list.iter() {
+                        if entry.chart.is_empty()
+                            || sm.version == entry.chart
+                            || sm.desc == entry.chart
+                        {
+                            found[idx].push(Found {
+                                globals: globals.clone(),
+                                sm: sm.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+            if self.keep_original {
+                store.put(&self.into, mem::replace(list, default()));
+            }
+            Ok(())
+        })
+    }
+    fn entry(
+        &self,
+        store: &mut SimfileStore,
+        on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
+    ) -> Result<()> {
+        let found = mem::take(&mut *self.found.lock());
+        if crate::cancel::requested() {
+            return Ok(());
+        }
+        //Pick a single chart for every entry, all of them with the same gamemode
+        let mut picked: Vec<Found> = Vec::with_capacity(self.charts.len());
+        for (idx, entry) in self.charts.iter().enumerate() {
+            let gamemode = picked.first().map(|first| first.sm.gamemode);
+            let best = found
+                .get(idx)
+                .into_iter()
+                .flatten()
+                .filter(|cand| gamemode.map(|gm| cand.sm.gamemode == gm).unwrap_or(true))
+                .filter(|cand| !cand.sm.notes.is_empty())
+                .max_by_key(|cand| cand.hardness());
+            match best {
+                Some(cand) => picked.push(cand.clone()),
+                None => warn!(
+                    "no chart \"{}\" found in beatmapset \"{}\", leaving it out of the marathon",
+                    entry.chart, entry.set
+                ),
+            }
+        }
+        if picked.is_empty() {
+            warn!("no charts to join into \"{}\"", self.title);
+            return Ok(());
+        }
+        let root = picked[0].global("root").unwrap_or_default().to_string();
+        let folder = scrubbed_name(&self.title).unwrap_or_else(|| self.title.clone());
+        let base = Path::new(&root).join(&folder);
+        crate::crash::set_beatmapset(Some(base.as_ref()));
+        info!(
+            "joining {} charts into \"{}\":",
+            picked.len(),
+            base.display()
+        );
+        let (sm, globals) = self.join(&base, picked);
+        store.reset();
+        for (name, value) in globals {
+            store.global_set(&name, value);
+        }
+        store.global_set("base", base.to_string_lossy().into_owned());
+        store.global_set("set_folder", folder.clone());
+        store.put(&self.into, vec![sm]);
+        let result = on_bmset(store);
+        crate::crash::set_beatmapset(None);
+        result
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+impl Concat {
+    /// Join the picked charts into a single simfile placed in `base`, and get the globals to pass
+    /// it on with.
+    /// `base` does not exist, so the files of the marathon are all generated files (see
+    /// `Simfile::generated`).
+    fn join(&self, base: &Path, picked: Vec<Found>) -> (Box<Simfile>, Vec<(String, String)>) {
+        let measure = BeatPos::from(4.);
+        //Globals that only make sense for a single beatmapset are left out
+        let globals = picked[0]
+            .globals
+            .iter()
+            .filter(|(name, _)| !matches!(&name[..], "base" | "set_folder" | "set_id"))
+            .cloned()
+            .collect::<Vec<_>>();
+        //Crop every chart to its notes, and lay them out one after another, with a one measure
+        //bridge in between
+        let mut parts: Vec<Part> = Vec::with_capacity(picked.len());
+        let mut next_beat = 0.;
+        for found in picked {
+            let mut sm = found.sm.clone();
+            let first = sm.notes[0].beat;
+            let last = sm.notes[sm.notes.len() - 1].beat;
+            sm.crop(first, last);
+            let len = last.floor(measure) - first.floor(measure) + measure;
+            let start_time = -sm.offset;
+            let end_time = sm.beat_to_time().beat_to_time(len);
+            debug!(
+                "  \"{}\" ({}): {:.2}s to {:.2}s into the music",
+                sm.title, sm.desc, start_time, end_time
+            );
+            parts.push(Part {
+                sm,
+                set_path: found.global("base").map(PathBuf::from),
+                start_beat: next_beat,
+                start_time,
+                end_time,
+                cut_time: (start_time - self.gap).max(0.),
+            });
+            next_beat += (len + measure).as_num();
+        }
+        let first = &parts[0];
+        let mut sm = Box::new(Simfile {
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+            gamemode: first.sm.gamemode,
+            desc: first.sm.desc.clone(),
+            difficulty: Difficulty::Challenge,
+            offset: first.cut_time - first.start_time,
+            sample_start: first
+                .sm
+                .sample_start
+                .map(|start| (start - first.cut_time).max(0.)),
+            sample_len: first.sm.sample_len,
+            ..default()
+        });
+        for (idx, part) in parts.iter().enumerate() {
+            let shift = |beat: f64| beat + part.start_beat;
+            let shift_pos = |beat: BeatPos| beat + BeatPos::from(part.start_beat);
+            if idx > 0 {
+                //The bridge from the previous chart lasts exactly `gap` seconds
+                sm.bpms.push(ControlPoint {
+                    beat: BeatPos::from(part.start_beat - 4.),
+                    beat_len: self.gap / 4.,
+                });
+                sm.subtitle.push_str(" / ");
+            }
+            sm.subtitle.push_str(&part.sm.title);
+            sm.bpms.extend(part.sm.bpms.iter().map(|cp| ControlPoint {
+                beat: shift_pos(cp.beat),
+                beat_len: cp.beat_len,
+            }));
+            sm.stops
+                .extend(part.sm.stops.iter().map(|&(beat, dur)| (shift(beat), dur)));
+            sm.delays
+                .extend(part.sm.delays.iter().map(|&(beat, dur)| (shift(beat), dur)));
+            sm.warps
+                .extend(part.sm.warps.iter().map(|&(beat, len)| (shift(beat), len)));
+            sm.spinners.extend(
+                part.sm
+                    .spinners
+                    .iter()
+                    .map(|&(from, to)| (shift_pos(from), shift_pos(to))),
+            );
+            sm.notes.extend(part.sm.notes.iter().map(|note| {
+                let mut note = note.clone();
+                note.beat = shift_pos(note.beat);
+                note
+            }));
+        }
+        //The banner and background of the first chart stand for the whole marathon
+        if let Some(set_path) = &first.set_path {
+            sm.banner = borrow_image(&mut sm, set_path, &first.sm, first.sm.banner.as_deref());
+            sm.background =
+                borrow_image(&mut sm, set_path, &first.sm, first.sm.background.as_deref());
+        }
+        if self.join_audio {
+            match self.join_audio(base, &parts) {
+                Ok((music, file, len)) => {
+                    sm.music = Some(music.clone());
+                    sm.set_generated(music, file);
+                    sm.music_len = len;
+                }
+                Err(err) => warn!(
+                    "  failed to join the music, leaving the marathon silent: {:#}",
+                    err
+                ),
+            }
+        }
+        (sm, globals)
+    }
+
+    /// Cut the music of every chart and join the cuts into a single audio file with ffmpeg.
+    /// Returns the path of the audio file relative to `base`, the path it was generated at, and
+    /// its length if known.
+    fn join_audio(&self, base: &Path, parts: &[Part]) -> Result<(PathBuf, PathBuf, Option<f64>)> {
+        let mut inputs = Vec::with_capacity(parts.len());
+        for part in parts.iter() {
+            match (&part.set_path, &part.sm.music) {
+                (Some(set_path), Some(music)) => inputs.push(part.sm.dep_source(set_path, music)),
+                _ => bail!("\"{}\" has no music", part.sm.title),
+            }
+        }
+        //Every cut lasts until the next chart starts exactly `gap` seconds after the end of the
+        //previous one, padded with silence if the music is too short
+        let lens = parts
+            .iter()
+            .zip(parts.iter().skip(1))
+            .map(|(cur, next)| {
+                cur.end_time - cur.cut_time + self.gap - (next.start_time - next.cut_time)
+            })
+            .collect::<Vec<_>>();
+        let last = &parts[parts.len() - 1];
+        let total_len = last
+            .sm
+            .music_len
+            .map(|len| lens.iter().sum::<f64>() + (len - last.cut_time).max(0.));
+        let name = PathBuf::from(format!(
+            "osu2sm-concat-{:016x}.ogg",
+            fxhash::hash64(&(
+                &inputs,
+                parts
+                    .iter()
+                    .map(|part| part.cut_time.to_bits())
+                    .chain(lens.iter().map(|len| len.to_bits()))
+                    .collect::<Vec<_>>()
+            ))
+        ));
+        let dst = crate::generated_dir(base)?.join(&name);
+        if dst.exists() {
+            trace!("  joined music \"{}\" already exists", dst.display());
+            return Ok((name, dst, total_len));
+        }
+        let mut graph = String::new();
+        let mut concat_inputs = String::new();
+        let mut cmd = Command::new(&self.ffmpeg);
+        cmd.args(["-y", "-loglevel", "error"]);
+        for (idx, (input, part)) in inputs.iter().zip(parts.iter()).enumerate() {
+            cmd.arg("-i").arg(input);
+            //Inputs may have different formats, but `concat` needs them all to match
+            write!(
+                graph,
+                "[{}:a]aformat=sample_rates=44100:channel_layouts=stereo,atrim=start={}",
+                idx, part.cut_time
+            )
+            .unwrap();
+            if let Some(len) = lens.get(idx) {
+                write!(graph, ":duration={0},apad=whole_dur={0}", len).unwrap();
+            }
+            writeln!(graph, ",asetpts=PTS-STARTPTS[a{}];", idx).unwrap();
+            write!(concat_inputs, "[a{}]", idx).unwrap();
+        }
+        write!(
+            graph,
+            "{}concat=n={}:v=0:a=1[out]",
+            concat_inputs,
+            inputs.len()
+        )
+        .unwrap();
+        debug!(
+            "  joining the music of {} charts into \"{}\"",
+            inputs.len(),
+            dst.display()
+        );
+        crate::generate_atomic(&dst, |tmp| {
+            let status = cmd
+                .arg("-filter_complex")
+                .arg(&graph)
+                .args(["-map", "[out]"])
+                .arg(tmp)
+                .status()
+                .with_context(|| anyhow!("failed to run \"{}\"", self.ffmpeg))?;
+            ensure!(status.success(), "ffmpeg exited with {}", status);
+            Ok(())
+        })?;
+        Ok((name, dst, total_len))
+    }
+}
+
+/// Use an image of a beatmapset (from the simfile `from`) in the marathon, returning its path
+/// relative to the marathon folder.
+/// The image is not copied anywhere, but rather recorded as a generated file of the marathon, so
+/// that writers copy it straight out of the beatmapset.
+fn borrow_image(
+    sm: &mut Simfile,
+    set_path: &Path,
+    from: &Simfile,
+    image: Option<&Path>,
+) -> Option<PathBuf> {
+    let image = image?;
+    let name = PathBuf::from(image.file_name()?);
+    let src = from.dep_source(set_path, image);
+    if !src.is_file() {
+        warn!(
+            "  image \"{}\" of the marathon does not exist",
+            src.display()
+        );
+        return None;
+    }
+    sm.set_generated(name.clone(), src);
+    Some(name)
+}
@@ -0,0 +1,107 @@
+//! Cut simfiles down to a part of their chart, eg. to make warmup packs or short previews.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Crop {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The part of the chart to keep.
+    ///
+    /// The kept part is moved to the start of the chart, and the timing is adjusted so that it
+    /// still plays along with the same part of the music.
+    pub range: CropRange,
+    /// Text to append to the description of cropped charts.
+    pub suffix: String,
+}
+impl Default for Crop {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            range: CropRange::Seconds(0., 30.),
+            suffix: "".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CropRange {
+    /// Keep the notes from the `start` beat to the `end` beat (inclusive).
+    Beats(f64, f64),
+    /// Keep the notes from the `start` measure up to the end of the `end` measure, where measures
+    /// are 4 beats long and the first one is measure `0`.
+    Measures(f64, f64),
+    /// Keep the notes from `start` to `end` seconds into the music.
+    Seconds(f64, f64),
+}
+impl CropRange {
+    fn bounds(&self) -> (f64, f64) {
+        match *self {
+            CropRange::Beats(start, end)
+            | CropRange::Measures(start, end)
+            | CropRange::Seconds(start, end) => (start, end),
+        }
+    }
+
+    /// Get the beat range to keep of the given simfile.
+    fn beats(&self, sm: &Simfile) -> (BeatPos, BeatPos) {
+        match *self {
+            CropRange::Beats(start, end) => {
+                (BeatPos::from_num_ceil(start), BeatPos::from_num_floor(end))
+            }
+            CropRange::Measures(start, end) => (
+                BeatPos::from_num_ceil(start * 4.),
+                BeatPos::from_num_ceil((end + 1.) * 4.) - BeatPos::EPSILON,
+            ),
+            CropRange::Seconds(start, end) => {
+                //Round the start up, so that no note before it is kept
+                let mut start_beat = sm.time_to_beat(start);
+                if sm.beat_to_time().beat_to_time(start_beat) < start {
+                    start_beat += BeatPos::EPSILON;
+                }
+                (start_beat, sm.time_to_beat(end))
+            }
+        }
+    }
+}
+
+impl Node for Crop {
+    fn prepare(&mut self) -> Result<()> {
+        let (start, end) = self.range.bounds();
+        ensure!(
+            start <= end,
+            "crop range start ({}) is after its end ({})",
+            start,
+            end
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            let mut out = Vec::with_capacity(list.len());
+            for mut sm in list.drain(..) {
+                let (start, end) = self.range.beats(&sm);
+                sm.crop(start, end);
+                if sm.notes.is_empty() {
+                    debug!(
+                        "    skipping \"{}\", since it has no notes within the crop range",
+                        sm.desc
+                    );
+                    continue;
+                }
+                sm.desc.push_str(&self.suffix);
+                out.push(sm);
+            }
+            store.put(&self.into, out);
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
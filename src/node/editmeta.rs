@@ -0,0 +1,159 @@
+//! Override simfile metadata from a CSV file, allowing community-maintained correction lists for
+//! beatmaps with bad metadata.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EditMeta {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// Path to the CSV file with the metadata corrections.
+    ///
+    /// The first line must be a header naming the columns, which can be given in any order and
+    /// may be left out:
+    ///
+    /// - `match_set_id`, `match_title`, `match_version`: Which simfiles to apply corrections to.
+    ///   A row applies to a simfile if all of its non-empty match columns match (case
+    ///   insensitive).
+    ///   `match_title` is compared to both the title and the transliterated title, and
+    ///   `match_version` to the osu! difficulty name.
+    /// - `title`, `subtitle`, `artist`, `genre`, `credit`, `meter`: The values to override.
+    ///   Empty cells leave the original value untouched.
+    /// - `title_trans`, `subtitle_trans`, `artist_trans`: The transliterated values to override.
+    ///   Transliterated values are only taken from their plain columns (eg. `title`) if they were
+    ///   empty.
+    pub csv: String,
+    /// The parsed CSV rows.
+    #[serde(skip)]
+    pub rows: Vec<MetaRow>,
+}
+impl Default for EditMeta {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            csv: "".into(),
+            rows: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MetaRow {
+    pub match_set_id: Option<i64>,
+    pub match_title: Option<String>,
+    pub match_version: Option<String>,
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub artist: Option<String>,
+    pub title_trans: Option<String>,
+    pub subtitle_trans: Option<String>,
+    pub artist_trans: Option<String>,
+    pub genre: Option<String>,
+    pub credit: Option<String>,
+    pub meter: Option<f64>,
+}
+impl MetaRow {
+    fn matches(&self, set_id: Option<i64>, sm: &Simfile) -> bool {
+        let eq = |a: &str, b: &str| a.trim().eq_ignore_ascii_case(b.trim());
+        if self.match_set_id.is_none() && self.match_title.is_none() && self.match_version.is_none()
+        {
+            return false;
+        }
+        if let Some(id) = self.match_set_id {
+            if set_id != Some(id) {
+                return false;
+            }
+        }
+        if let Some(title) = &self.match_title {
+            if !eq(title, &sm.title) && !eq(title, &sm.title_trans) {
+                return false;
+            }
+        }
+        if let Some(version) = &self.match_version {
+            //Other nodes may have changed the description, but not the version
+            let sm_version = if sm.version.is_empty() {
+                &sm.desc
+            } else {
+                &sm.version
+            };
+            if !eq(version, sm_version) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn apply(&self, sm: &mut Simfile) {
+        let set = |field: &mut String, val: &Option<String>| {
+            if let Some(val) = val {
+                *field = val.clone();
+            }
+        };
+        //Transliterations fall back to the plain values only if there were none, since plain
+        //values may well be in a non-latin script
+        let set_trans = |field: &mut String, val: &Option<String>, plain: &Option<String>| {
+            if val.is_some() {
+                set(field, val);
+            } else if field.is_empty() {
+                set(field, plain);
+            }
+        };
+        set(&mut sm.title, &self.title);
+        set_trans(&mut sm.title_trans, &self.title_trans, &self.title);
+        set(&mut sm.subtitle, &self.subtitle);
+        set_trans(&mut sm.subtitle_trans, &self.subtitle_trans, &self.subtitle);
+        set(&mut sm.artist, &self.artist);
+        set_trans(&mut sm.artist_trans, &self.artist_trans, &self.artist);
+        set(&mut sm.genre, &self.genre);
+        set(&mut sm.credit, &self.credit);
+        if let Some(meter) = self.meter {
+            sm.difficulty_num = meter;
+        }
+    }
+}
+
+impl Node for EditMeta {
+    fn prepare(&mut self) -> Result<()> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_path(&self.csv)
+            .with_context(|| anyhow!("failed to open metadata csv at \"{}\"", self.csv))?;
+        for (idx, row) in reader.deserialize().enumerate() {
+            let row: MetaRow = row.with_context(|| {
+                anyhow!("invalid row {} in metadata csv \"{}\"", idx + 2, self.csv)
+            })?;
+            self.rows.push(row);
+        }
+        info!(
+            "loaded {} metadata corrections from \"{}\"",
+            self.rows.len(),
+            self.csv
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let set_id = store.global_get("set_id").and_then(|id| id.parse().ok());
+        store.get(&self.from, |store, list| {
+            for sm in list.iter_mut() {
+                for row in self.rows.iter() {
+                    if row.matches(set_id, sm) {
+                        trace!("    applying metadata correction {:?}", row);
+                        row.apply(sm);
+                    }
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
@@ -0,0 +1,106 @@
+//! Pipe simfiles through an external command, allowing for custom transforms written in any
+//! language.
+
+use crate::node::prelude::*;
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Exec {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The program to run, followed by its arguments.
+    ///
+    /// Each list of simfiles is written to the standard input of the program as JSON (see
+    /// `SimfileSet` for the format), and the program is expected to write the (possibly
+    /// transformed) simfiles to its standard output in the same format.
+    pub cmd: Vec<String>,
+    /// The working directory of the program.
+    /// If empty, the current working directory is used.
+    pub dir: String,
+}
+impl Default for Exec {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            cmd: vec![],
+            dir: "".into(),
+        }
+    }
+}
+
+impl Node for Exec {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(!self.cmd.is_empty(), "no command specified for `Exec` node");
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            let out = run_command(self, mem::replace(list, default()))
+                .with_context(|| anyhow!("failed to run command {:?}", self.cmd))?;
+            store.put(&self.into, out);
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+fn run_command(conf: &Exec, list: Vec<Box<Simfile>>) -> Result<Vec<Box<Simfile>>> {
+    let count = list.len();
+    let input = SimfileSet::new(list).to_json()?;
+    let mut cmd = Command::new(&conf.cmd[0]);
+    cmd.args(&conf.cmd[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    if !conf.dir.is_empty() {
+        cmd.current_dir(&conf.dir);
+    }
+    trace!("    running {:?} with {} simfiles", conf.cmd, count);
+    let mut child = cmd.spawn().context("failed to spawn process")?;
+    //Write input from a separate thread, so that large outputs do not deadlock the pipes
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child
+        .wait_with_output()
+        .context("failed to wait for process")?;
+    match writer.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            return Err(err).context("failed to write simfiles to process stdin");
+        }
+        Err(_) => bail!("stdin writer thread panicked"),
+    }
+    ensure!(
+        output.status.success(),
+        "process exited with {}",
+        output.status
+    );
+    let mut list = SimfileSet::from_json(&output.stdout)
+        .context("failed to parse process output")?
+        .simfiles;
+    for sm in list.iter_mut() {
+        //Commands may move notes around
+        sm.notes.sort_by_key(|note| note.beat);
+        let key_count = sm.gamemode.key_count();
+        if let Some(note) = sm
+            .notes
+            .iter()
+            .find(|note| note.key < 0 || note.key >= key_count)
+        {
+            bail!(
+                "process output a note on key {} of \"{}\", which only has {} keys",
+                note.key,
+                sm.title,
+                key_count
+            );
+        }
+    }
+    Ok(list)
+}
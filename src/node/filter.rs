@@ -6,6 +6,8 @@ pub struct Filter {
     pub from: BucketId,
     pub into: BucketId,
     pub ops: Vec<(Property, FilterOp)>,
+    #[serde(skip)]
+    pub patterns: Patterns,
 }
 impl Default for Filter {
     fn default() -> Self {
@@ -13,6 +15,7 @@ impl Default for Filter {
             from: default(),
             into: default(),
             ops: vec![],
+            patterns: default(),
         }
     }
 }
@@ -35,10 +38,31 @@ pub enum Property {
     Offset,
     SampleStart,
     SampleLength,
+    /// The length of the music file in seconds, or empty if unknown.
+    MusicLength,
     Gamemode,
     Desc,
     Difficulty,
     Meter,
+    /// The amount of rows in the longest stream (see `Simfile::longest_stream`).
+    StreamRows,
+    /// The BPM of the longest stream, rounded to the nearest integer.
+    StreamBpm,
+    /// The sync confidence from 0 to 100, rounded to the nearest integer (see
+    /// `Simfile::sync_confidence`).
+    SyncConfidence,
+    /// The average sync error in milliseconds, with two decimals (see `Simfile::sync_error`).
+    SyncError,
+    /// The osu! star rating, with two decimals (see `Simfile::star_rating`).
+    StarRating,
+    /// The osu! ranked status (eg. `Ranked` or `Loved`), or empty if unknown.
+    RankedStatus,
+    /// The writing system of the original title (see `Simfile::title_native`), such as `Latin`,
+    /// `Kana`, `Han`, `Hangul` or `Cyrillic`, to filter songs by language.
+    /// Empty if the title has no letters (see `WritingSystem`).
+    TitleScript,
+    /// The writing system of the original artist, like `TitleScript`.
+    ArtistScript,
 }
 impl Property {
     fn get<'a>(&self, sm: &'a Simfile) -> Cow<'a, str> {
@@ -80,10 +104,118 @@ impl Property {
             Offset => Cow::Owned(sm.offset.to_string()),
             SampleStart => Cow::Owned(sm.sample_start.unwrap_or(0.).to_string()),
             SampleLength => Cow::Owned(sm.sample_len.unwrap_or(0.).to_string()),
+            MusicLength => sm
+                .music_len
+                .map(|len| Cow::Owned(len.to_string()))
+                .unwrap_or_default(),
             Gamemode => Cow::Owned(format!("{:?}", sm.gamemode)),
             Desc => Cow::Borrowed(&sm.desc),
             Difficulty => Cow::Owned(format!("{:?}", sm.difficulty)),
             Meter => Cow::Owned(sm.difficulty_num.to_string()),
+            StreamRows => Cow::Owned(sm.longest_stream().rows.to_string()),
+            StreamBpm => Cow::Owned(sm.longest_stream().bpm.round().to_string()),
+            SyncConfidence => Cow::Owned(sm.sync_confidence.round().to_string()),
+            SyncError => Cow::Owned(format!("{:.2}", sm.sync_error)),
+            StarRating => Cow::Owned(format!("{:.2}", sm.star_rating)),
+            RankedStatus => sm
+                .ranked_status
+                .map(|status| Cow::Owned(format!("{:?}", status)))
+                .unwrap_or_default(),
+            TitleScript => {
+                Cow::Borrowed(WritingSystem::detect(native(&sm.title_native, &sm.title)).name())
+            }
+            ArtistScript => {
+                Cow::Borrowed(WritingSystem::detect(native(&sm.artist_native, &sm.artist)).name())
+            }
+        }
+    }
+}
+
+/// Get the original name if known, or fall back to the possibly romanized one.
+fn native<'a>(native: &'a str, name: &'a str) -> &'a str {
+    if native.is_empty() {
+        name
+    } else {
+        native
+    }
+}
+
+/// The writing systems told apart by the `TitleScript` and `ArtistScript` properties.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum WritingSystem {
+    /// No letters at all.
+    Empty,
+    Latin,
+    Greek,
+    Cyrillic,
+    Hebrew,
+    Arabic,
+    Thai,
+    Hangul,
+    /// Hiragana and katakana.
+    Kana,
+    /// Chinese characters, including Japanese kanji.
+    Han,
+    Other,
+}
+impl WritingSystem {
+    fn of(c: char) -> WritingSystem {
+        use WritingSystem::*;
+        match c as u32 {
+            0x41..=0x5A | 0x61..=0x7A | 0xC0..=0x24F | 0x1E00..=0x1EFF | 0xFF21..=0xFF5A => Latin,
+            0x370..=0x3FF | 0x1F00..=0x1FFF => Greek,
+            0x400..=0x52F => Cyrillic,
+            0x590..=0x5FF => Hebrew,
+            0x600..=0x6FF | 0x750..=0x77F => Arabic,
+            0xE00..=0xE7F => Thai,
+            0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Hangul,
+            0x3040..=0x30FF | 0x31F0..=0x31FF | 0xFF66..=0xFF9F => Kana,
+            0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F => Han,
+            _ if c.is_alphabetic() => Other,
+            _ => Empty,
+        }
+    }
+
+    /// Find the main writing system of a text, by counting its letters.
+    ///
+    /// Any amount of kana makes a text `Kana`, since Japanese text usually mixes kana with kanji
+    /// (and often with latin letters too).
+    fn detect(text: &str) -> WritingSystem {
+        let mut counts: Vec<(WritingSystem, usize)> = Vec::new();
+        for script in text.chars().map(WritingSystem::of) {
+            if script == WritingSystem::Empty {
+                continue;
+            }
+            match counts.iter_mut().find(|(s, _)| *s == script) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((script, 1)),
+            }
+        }
+        if counts.iter().any(|&(s, _)| s == WritingSystem::Kana) {
+            return WritingSystem::Kana;
+        }
+        //Break ties in favor of the first script found
+        counts
+            .iter()
+            .rev()
+            .max_by_key(|&&(_, count)| count)
+            .map(|&(script, _)| script)
+            .unwrap_or(WritingSystem::Empty)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            WritingSystem::Empty => "",
+            WritingSystem::Latin => "Latin",
+            WritingSystem::Greek => "Greek",
+            WritingSystem::Cyrillic => "Cyrillic",
+            WritingSystem::Hebrew => "Hebrew",
+            WritingSystem::Arabic => "Arabic",
+            WritingSystem::Thai => "Thai",
+            WritingSystem::Hangul => "Hangul",
+            WritingSystem::Kana => "Kana",
+            WritingSystem::Han => "Han",
+            WritingSystem::Other => "Other",
         }
     }
 }
@@ -94,12 +226,18 @@ pub enum FilterOp {
     Deny(Vec<String>),
     LessThan(String),
     GreaterThan(String),
+    /// Whether the value contains the given text, ignoring case.
+    Contains(String),
+    /// Whether the value matches the given regular expression anywhere.
+    /// Case sensitive, unless the expression starts with `(?i)`.
+    /// Requires the `regex` feature.
+    Matches(String),
     Not(Box<FilterOp>),
     And(Vec<FilterOp>),
     Or(Vec<FilterOp>),
 }
 impl FilterOp {
-    pub fn matches(&self, val: &str) -> bool {
+    pub fn matches(&self, val: &str, patterns: &Patterns) -> bool {
         use FilterOp::*;
         match self {
             Allow(whitelist) => whitelist
@@ -110,17 +248,92 @@ impl FilterOp {
                 .any(|w| natord::compare_ignore_case(w, val) == cmp::Ordering::Equal),
             LessThan(top) => natord::compare_ignore_case(val, top) == cmp::Ordering::Less,
             GreaterThan(top) => natord::compare_ignore_case(val, top) == cmp::Ordering::Greater,
-            Not(op) => !op.matches(val),
-            And(ops) => ops.iter().all(|op| op.matches(val)),
-            Or(ops) => ops.iter().any(|op| op.matches(val)),
+            Contains(text) => val.to_lowercase().contains(&text.to_lowercase()),
+            Matches(pattern) => patterns.is_match(pattern, val),
+            Not(op) => !op.matches(val, patterns),
+            And(ops) => ops.iter().all(|op| op.matches(val, patterns)),
+            Or(ops) => ops.iter().any(|op| op.matches(val, patterns)),
+        }
+    }
+
+    fn visit(&self, f: &mut impl FnMut(&FilterOp) -> Result<()>) -> Result<()> {
+        use FilterOp::*;
+        f(self)?;
+        match self {
+            Not(op) => op.visit(f),
+            And(ops) | Or(ops) => ops.iter().try_for_each(|op| op.visit(f)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The compiled regular expressions used by `FilterOp::Matches` operators.
+#[derive(Clone, Debug, Default)]
+pub struct Patterns {
+    #[cfg(feature = "regex")]
+    compiled: HashMap<String, regex::Regex>,
+}
+impl Patterns {
+    /// Compile all regular expressions in the given operators.
+    pub fn compile<'a>(ops: impl IntoIterator<Item = &'a FilterOp>) -> Result<Patterns> {
+        let mut patterns = Patterns::default();
+        for op in ops {
+            op.visit(&mut |op| {
+                if let FilterOp::Matches(pattern) = op {
+                    patterns.add(pattern)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(patterns)
+    }
+
+    #[cfg(feature = "regex")]
+    fn add(&mut self, pattern: &str) -> Result<()> {
+        if !self.compiled.contains_key(pattern) {
+            let regex = regex::Regex::new(pattern)
+                .with_context(|| anyhow!("invalid regular expression \"{}\"", pattern))?;
+            self.compiled.insert(pattern.to_string(), regex);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn add(&mut self, pattern: &str) -> Result<()> {
+        bail!(
+            "cannot match regular expression \"{}\": osu2sm was built without the `regex` feature",
+            pattern
+        )
+    }
+
+    #[cfg(feature = "regex")]
+    fn is_match(&self, pattern: &str, val: &str) -> bool {
+        match self.compiled.get(pattern) {
+            Some(regex) => regex.is_match(val),
+            None => regex::Regex::new(pattern)
+                .map(|regex| regex.is_match(val))
+                .unwrap_or(false),
         }
     }
+
+    #[cfg(not(feature = "regex"))]
+    fn is_match(&self, _pattern: &str, _val: &str) -> bool {
+        false
+    }
 }
 
 impl Node for Filter {
+    fn prepare(&mut self) -> Result<()> {
+        self.patterns = Patterns::compile(self.ops.iter().map(|(_prop, op)| op))?;
+        Ok(())
+    }
     fn apply(&self, store: &mut SimfileStore) -> Result<()> {
         store.get(&self.from, |store, list| {
-            list.retain(|sm| self.ops.iter().all(|(prop, op)| op.matches(&*prop.get(sm))));
+            list.retain(|sm| {
+                self.ops
+                    .iter()
+                    .all(|(prop, op)| op.matches(&*prop.get(sm), &self.patterns))
+            });
             store.put(&self.into, mem::replace(list, Vec::new()));
             Ok(())
         })
@@ -0,0 +1,136 @@
+//! Turn hold notes into rolls (or rolls back into holds), depending on their length and the BPM
+//! they are in.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HoldKind {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// What to turn matching holds and rolls into.
+    pub to: HoldType,
+    /// Only convert holds that last at least this many seconds.
+    pub min_secs: f64,
+    /// Only convert holds that last at most this many seconds.
+    /// `0` means no limit.
+    pub max_secs: f64,
+    /// Only convert holds that start at a BPM of at least this much.
+    pub min_bpm: f64,
+    /// Only convert holds that start at a BPM of at most this much.
+    /// `0` means no limit.
+    pub max_bpm: f64,
+}
+impl Default for HoldKind {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            to: HoldType::Roll,
+            min_secs: 0.,
+            max_secs: 0.,
+            min_bpm: 0.,
+            max_bpm: 0.,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HoldType {
+    /// A regular hold note.
+    Hold,
+    /// A roll, which must be tapped repeatedly until it ends.
+    Roll,
+}
+impl HoldType {
+    fn kind(&self) -> char {
+        match self {
+            HoldType::Hold => Note::KIND_HEAD,
+            HoldType::Roll => Note::KIND_ROLL,
+        }
+    }
+}
+
+impl Node for HoldKind {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            self.max_secs <= 0. || self.max_secs >= self.min_secs,
+            "max_secs ({}) is shorter than min_secs ({})",
+            self.max_secs,
+            self.min_secs
+        );
+        ensure!(
+            self.max_bpm <= 0. || self.max_bpm >= self.min_bpm,
+            "max_bpm ({}) is lower than min_bpm ({})",
+            self.max_bpm,
+            self.min_bpm
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            for sm in list.iter_mut() {
+                let converted = convert(self, sm);
+                if converted > 0 {
+                    trace!(
+                        "    converted {} holds of \"{}\" into {:?}",
+                        converted,
+                        sm.desc,
+                        self.to
+                    );
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+/// Convert the matching holds of a simfile, returning how many were converted.
+fn convert(conf: &HoldKind, sm: &mut Simfile) -> usize {
+    let kind = conf.to.kind();
+    let key_count = sm.gamemode.key_count().max(0) as usize;
+    //The open head of every column, along with its start time and BPM
+    let mut open: Vec<Option<(usize, f64, f64)>> = vec![None; key_count];
+    let mut matching = Vec::new();
+    {
+        let mut to_time = sm.beat_to_time();
+        let mut bpm_idx = 0;
+        for (idx, note) in sm.notes.iter().enumerate() {
+            let time = to_time.beat_to_time(note.beat);
+            while bpm_idx + 1 < sm.bpms.len() && sm.bpms[bpm_idx + 1].beat <= note.beat {
+                bpm_idx += 1;
+            }
+            let slot = match open.get_mut(note.key as usize) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            if note.is_head() {
+                let bpm = sm.bpms.get(bpm_idx).map(|cp| cp.bpm()).unwrap_or(0.);
+                *slot = Some((idx, time, bpm));
+            } else if note.is_tail() {
+                if let Some((head, start, bpm)) = slot.take() {
+                    let secs = time - start;
+                    if sm.notes[head].kind != kind
+                        && secs >= conf.min_secs
+                        && (conf.max_secs <= 0. || secs <= conf.max_secs)
+                        && bpm >= conf.min_bpm
+                        && (conf.max_bpm <= 0. || bpm <= conf.max_bpm)
+                    {
+                        matching.push(head);
+                    }
+                }
+            }
+        }
+    }
+    for &head in matching.iter() {
+        sm.notes[head].kind = kind;
+    }
+    matching.len()
+}
@@ -0,0 +1,151 @@
+//! Generate intermediate difficulties between the easiest and hardest charts of a song, for songs
+//! with too few difficulties to have a proper spread.
+
+use crate::node::prelude::*;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Interpolate {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// Only generate charts for songs with at most this many charts (of the same gamemode).
+    pub max_charts: usize,
+    /// For every value in this list, an intermediate chart is generated with all of the notes
+    /// shared by the easiest and hardest charts, plus this fraction of the notes that are only
+    /// present in the hardest chart.
+    pub fractions: Vec<f64>,
+    /// The description of generated charts.
+    /// `{easy}` and `{hard}` are replaced by the descriptions of the source charts.
+    pub desc: String,
+}
+impl Default for Interpolate {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            max_charts: 2,
+            fractions: vec![0.5],
+            desc: "{easy} + {hard}".into(),
+        }
+    }
+}
+
+impl Node for Interpolate {
+    fn prepare(&mut self) -> Result<()> {
+        for &fraction in self.fractions.iter() {
+            ensure!(
+                (0. ..=1.).contains(&fraction),
+                "interpolation fraction {} is not between 0 and 1",
+                fraction
+            );
+        }
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            //Group charts by song and gamemode
+            let mut groups: BTreeMap<(Gamemode, Option<&Path>), Vec<&Simfile>> = default();
+            for sm in list.iter() {
+                groups
+                    .entry((sm.gamemode, sm.music.as_deref()))
+                    .or_default()
+                    .push(sm);
+            }
+            let mut out = Vec::new();
+            for charts in groups.values() {
+                if charts.len() < 2 || charts.len() > self.max_charts {
+                    continue;
+                }
+                let difficulty = |sm: &Simfile| {
+                    if sm.difficulty_num.is_nan() {
+                        sm.difficulty_naive()
+                    } else {
+                        sm.difficulty_num
+                    }
+                };
+                let easy = charts
+                    .iter()
+                    .min_by_key(|sm| SortableFloat(difficulty(sm)))
+                    .unwrap();
+                let hard = charts
+                    .iter()
+                    .max_by_key(|sm| SortableFloat(difficulty(sm)))
+                    .unwrap();
+                if easy.bpms != hard.bpms
+                    || easy.stops != hard.stops
+                    || easy.delays != hard.delays
+                    || easy.warps != hard.warps
+                    || easy.offset != hard.offset
+                {
+                    debug!(
+                        "    not interpolating \"{}\" and \"{}\", since their timing differs",
+                        easy.desc, hard.desc
+                    );
+                    continue;
+                }
+                for &fraction in self.fractions.iter() {
+                    out.push(interpolate(self, easy, hard, fraction));
+                }
+            }
+            list.extend(out);
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+fn interpolate(conf: &Interpolate, easy: &Simfile, hard: &Simfile, fraction: f64) -> Box<Simfile> {
+    let mut rng = simfile_rng(hard, &format!("interpolate {}", fraction));
+    let shared: HashSet<(BeatPos, i32)> = easy
+        .notes
+        .iter()
+        .filter(|note| !note.is_tail() && !note.is_mine())
+        .map(|note| (note.beat, note.key))
+        .collect();
+    //Holds are kept or dropped along with their head
+    let mut kept_holds = HashSet::default();
+    let mut notes = Vec::with_capacity(hard.notes.len());
+    for note in hard.notes.iter() {
+        let keep = if note.is_tail() {
+            kept_holds.remove(&note.key)
+        } else {
+            shared.contains(&(note.beat, note.key)) || rng.gen_bool(fraction)
+        };
+        if keep {
+            if note.is_head() {
+                kept_holds.insert(note.key);
+            }
+            notes.push(note.clone());
+        }
+    }
+    trace!(
+        "    interpolated {} notes between \"{}\" ({} notes) and \"{}\" ({} notes)",
+        notes.len(),
+        easy.desc,
+        easy.notes.len(),
+        hard.desc,
+        hard.notes.len()
+    );
+    let difficulty_num = if easy.difficulty_num.is_nan() || hard.difficulty_num.is_nan() {
+        f64::NAN
+    } else {
+        easy.difficulty_num + (hard.difficulty_num - easy.difficulty_num) * fraction
+    };
+    Box::new(Simfile {
+        desc: conf
+            .desc
+            .replace("{easy}", &easy.desc)
+            .replace("{hard}", &hard.desc),
+        difficulty: Difficulty::Edit,
+        difficulty_num,
+        notes,
+        ..hard.clone()
+    })
+}
@@ -0,0 +1,335 @@
+//! Takes a bunch of simfiles as input and writes them out as Malody `.mc` key mode charts.
+
+use crate::node::{
+    prelude::*,
+    simfilewrite::{ChartFolder, CopyMethod},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct McWrite {
+    pub from: BucketId,
+    /// The path to the output directory (usually the Malody `beatmap` folder).
+    /// Every beatmapset is written into its own song folder within it.
+    pub output: String,
+    /// Which methods to try for copying "dependency" files, such as `.mp3` and `.jpg` files.
+    pub copy: Vec<CopyMethod>,
+    /// Whether to flush every chart to disk before moving it into place.
+    pub fsync: bool,
+}
+
+impl Default for McWrite {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            output: "".into(),
+            copy: SimfileWrite::default().copy,
+            fsync: false,
+        }
+    }
+}
+
+impl Node for McWrite {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            !self.output.is_empty(),
+            "no output folder to write Malody charts into"
+        );
+        info!("outputting Malody charts in \"{}\"", self.output);
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let mut folder = ChartFolder::new(store, &self.output)?;
+        store.get_each(&self.from, |_, mut sm| {
+            sm.fix_tails()?;
+            let out_path = folder.chart_path(&sm, "mc")?;
+            debug!("  writing Malody chart to \"{}\"", out_path.display());
+            //Date charts by their source file rather than the current time, so that converting
+            //the same chart again writes the exact same file
+            let edited = sm
+                .source_file
+                .as_deref()
+                .and_then(|src| fs::metadata(folder.set_path.join(src)).ok())
+                .and_then(|meta| meta.modified().ok())
+                .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|dur| dur.as_secs())
+                .unwrap_or(0);
+            let chart = to_mc(&sm, edited)?;
+            crate::write_atomic(&out_path, self.fsync, |file| {
+                serde_json::to_writer(file, &chart).context("failed to serialize chart")
+            })
+            .with_context(|| anyhow!("write Malody chart to \"{}\"", out_path.display()))?;
+            folder.add_deps(&sm, sm.music.iter().chain(sm.background.iter()));
+            Ok(())
+        })?;
+        //Copy over the audio and backgrounds
+        folder.copy_deps(&self.copy);
+        Ok(())
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(iter::once((BucketKind::Input, &mut self.from)))
+    }
+}
+
+/// The layout of a `.mc` file, as far as key mode charts go.
+#[derive(Serialize)]
+struct McChart {
+    meta: McMeta,
+    time: Vec<McTime>,
+    effect: Vec<()>,
+    note: Vec<McNote>,
+}
+
+#[derive(Serialize)]
+struct McMeta {
+    #[serde(rename = "$ver")]
+    ver: i32,
+    creator: String,
+    background: String,
+    version: String,
+    preview: i64,
+    id: i32,
+    mode: i32,
+    time: u64,
+    song: McSong,
+    mode_ext: McModeExt,
+}
+
+#[derive(Serialize)]
+struct McSong {
+    title: String,
+    artist: String,
+    id: i32,
+    titleorg: String,
+    artistorg: String,
+}
+
+#[derive(Serialize)]
+struct McModeExt {
+    column: i32,
+    bar_begin: i32,
+}
+
+#[derive(Serialize)]
+struct McTime {
+    beat: [i64; 3],
+    bpm: f64,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum McNote {
+    Hit {
+        beat: [i64; 3],
+        column: i32,
+    },
+    Hold {
+        beat: [i64; 3],
+        endbeat: [i64; 3],
+        column: i32,
+    },
+    /// The music, which plays `offset` milliseconds ahead of the first beat.
+    Sound {
+        beat: [i64; 3],
+        sound: String,
+        vol: i32,
+        offset: i64,
+        #[serde(rename = "type")]
+        kind: i32,
+    },
+}
+
+/// Malody has no stops, delays or warps, so the beats of the chart are moved around to make room
+/// for them.
+///
+/// Every stop and delay gets a whole measure of its own, which plays during the pause at a very
+/// high BPM, and the beats within warps are collapsed onto the start of the warp (notes within
+/// warps are skipped anyway, just like StepMania does).
+struct Regrid {
+    /// Pauses and warps, sorted by beat.
+    /// Pauses push later beats forward and warps pull them back.
+    /// A stop only pushes the beats after it, while a delay pushes its own beat too.
+    shifts: Vec<(BeatPos, Shift)>,
+}
+
+#[derive(Copy, Clone)]
+enum Shift {
+    Stop,
+    Delay,
+    Warp(BeatPos),
+}
+
+impl Regrid {
+    const PAUSE_LEN: f64 = 4.;
+
+    fn new(sm: &Simfile) -> Regrid {
+        //Pauses too short to matter are left out, since they would need absurdly high BPMs
+        let mut shifts = sm
+            .stops
+            .iter()
+            .filter(|&&(_, secs)| secs >= 0.001)
+            .map(|&(beat, _)| (BeatPos::from(beat), Shift::Stop))
+            .chain(
+                sm.delays
+                    .iter()
+                    .filter(|&&(_, secs)| secs >= 0.001)
+                    .map(|&(beat, _)| (BeatPos::from(beat), Shift::Delay)),
+            )
+            .chain(
+                sm.warps
+                    .iter()
+                    .filter(|&&(_, len)| len > 0.)
+                    .map(|&(beat, len)| (BeatPos::from(beat), Shift::Warp(BeatPos::from(len)))),
+            )
+            .collect::<Vec<_>>();
+        shifts.sort_by_key(|&(beat, _)| beat);
+        Regrid { shifts }
+    }
+
+    /// Whether the given beat is skipped over by a warp.
+    fn warped(&self, beat: BeatPos) -> bool {
+        self.shifts.iter().any(|&(at, shift)| match shift {
+            Shift::Warp(len) => beat > at && beat < at + len,
+            _ => false,
+        })
+    }
+
+    /// Get the beat of the Malody chart at which the given beat plays.
+    fn beat(&self, beat: BeatPos) -> BeatPos {
+        let pause = BeatPos::from(Self::PAUSE_LEN);
+        let mut shifted = beat;
+        for &(at, shift) in self.shifts.iter() {
+            if at > beat {
+                break;
+            }
+            match shift {
+                Shift::Stop if beat > at => shifted += pause,
+                Shift::Delay => shifted += pause,
+                Shift::Warp(len) if beat >= at + len => shifted -= len,
+                Shift::Warp(_) if beat > at => return shifted - (beat - at),
+                _ => {}
+            }
+        }
+        shifted
+    }
+}
+
+/// Split a beat into whole beats and a fraction, as `.mc` files store them.
+fn mc_beat(beat: BeatPos) -> [i64; 3] {
+    let whole = beat.floor(BeatPos::from(1.));
+    let den = beat.denominator();
+    let num = ((beat - whole).as_num() * den as f64).round();
+    [whole.as_num() as i64, num as i64, den as i64]
+}
+
+/// Convert a simfile into a `.mc` chart, last edited at the given UNIX time.
+fn to_mc(sm: &Simfile, edited: u64) -> Result<McChart> {
+    let regrid = Regrid::new(sm);
+    //Every BPM change, along with the very fast measures that play during pauses
+    //Entries on the same beat override each other, from lowest to highest priority
+    let mut times: Vec<(BeatPos, i32, f64)> = Vec::new();
+    let bpm_at = |beat: BeatPos| {
+        sm.bpms
+            .iter()
+            .take_while(|cp| cp.beat <= beat)
+            .last()
+            .or_else(|| sm.bpms.first())
+            .map(|cp| cp.bpm())
+            .unwrap_or(120.)
+    };
+    for cp in sm.bpms.iter() {
+        times.push((regrid.beat(cp.beat), 1, cp.bpm()));
+    }
+    let pause = BeatPos::from(Regrid::PAUSE_LEN);
+    let pause_bpm = |secs: f64| 60. * Regrid::PAUSE_LEN / secs;
+    for &(beat, secs) in sm.stops.iter().filter(|&&(_, secs)| secs >= 0.001) {
+        let beat = BeatPos::from(beat);
+        let start = regrid.beat(beat);
+        times.push((start, 2, pause_bpm(secs)));
+        times.push((start + pause, 0, bpm_at(beat)));
+    }
+    for &(beat, secs) in sm.delays.iter().filter(|&&(_, secs)| secs >= 0.001) {
+        let beat = BeatPos::from(beat);
+        let end = regrid.beat(beat);
+        times.push((end - pause, 2, pause_bpm(secs)));
+        times.push((end, 0, bpm_at(beat)));
+    }
+    times.sort_by_key(|&(beat, prio, _)| (beat, prio));
+    let mut time: Vec<McTime> = Vec::with_capacity(times.len());
+    let mut last_beat = None;
+    for (beat, _, bpm) in times {
+        if last_beat == Some(beat) {
+            time.pop();
+        }
+        last_beat = Some(beat);
+        time.push(McTime {
+            beat: mc_beat(beat),
+            bpm,
+        });
+    }
+    //Notes, with holds spanning from their head to their tail
+    let mut note = Vec::with_capacity(sm.notes.len() + 1);
+    for (idx, n) in sm.notes.iter().enumerate() {
+        //Malody key mode charts have no mines
+        if n.is_tail() || n.is_mine() || regrid.warped(n.beat) {
+            continue;
+        }
+        let beat = mc_beat(regrid.beat(n.beat));
+        if n.is_head() {
+            let tail = sm.notes[idx + 1..]
+                .iter()
+                .find(|tail| tail.is_tail() && tail.key == n.key)
+                .ok_or_else(|| anyhow!("hold at beat {} has no tail", n.beat))?;
+            note.push(McNote::Hold {
+                beat,
+                endbeat: mc_beat(regrid.beat(tail.beat)),
+                column: n.key,
+            });
+        } else {
+            note.push(McNote::Hit {
+                beat,
+                column: n.key,
+            });
+        }
+    }
+    if let Some(music) = &sm.music {
+        note.push(McNote::Sound {
+            beat: [0, 0, 1],
+            sound: music.to_string_lossy().into_owned(),
+            vol: 100,
+            offset: (-sm.offset * 1000.).round() as i64,
+            kind: 1,
+        });
+    }
+    let path_str = |path: &Option<PathBuf>| {
+        path.as_deref()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    Ok(McChart {
+        meta: McMeta {
+            ver: 0,
+            creator: sm.credit.clone(),
+            background: path_str(&sm.background),
+            version: sm.desc.clone(),
+            preview: (sm.sample_start.unwrap_or(0.) * 1000.).round() as i64,
+            id: 0,
+            mode: 0,
+            time: edited,
+            song: McSong {
+                title: sm.title.clone(),
+                artist: sm.artist.clone(),
+                id: 0,
+                titleorg: sm.title_native.clone(),
+                artistorg: sm.artist_native.clone(),
+            },
+            mode_ext: McModeExt {
+                column: sm.gamemode.key_count(),
+                bar_begin: 0,
+            },
+        },
+        time,
+        effect: vec![],
+        note,
+    })
+}
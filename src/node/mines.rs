@@ -0,0 +1,161 @@
+//! Place mines on the empty columns of simfiles, either all along the chart or only during osu!
+//! spinners.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mines {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// How many mines to place per beat on average, all along the chart.
+    /// `0` disables mines outside of spinners.
+    pub density: f64,
+    /// How many mines to place per beat on average during the spinners (and banana showers) of
+    /// the source osu! beatmap, instead of `density`.
+    pub spinner_density: f64,
+    /// The distance in beats between the rows that mines can be placed on.
+    pub tick: f64,
+    /// The minimum distance in beats between a mine and any note or hold on the same column.
+    pub gap: f64,
+}
+impl Default for Mines {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            density: 0.,
+            spinner_density: 2.,
+            tick: 0.5,
+            gap: 0.5,
+        }
+    }
+}
+
+impl Node for Mines {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            BeatPos::from(self.tick) > BeatPos::from(0.),
+            "mine tick ({}) is too small",
+            self.tick
+        );
+        ensure!(
+            self.density >= 0. && self.spinner_density >= 0.,
+            "mine density cannot be negative"
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            for sm in list.iter_mut() {
+                let placed = place_mines(self, sm);
+                if placed > 0 {
+                    trace!("    placed {} mines on \"{}\"", placed, sm.desc);
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+/// Place mines on a simfile, returning how many were placed.
+fn place_mines(conf: &Mines, sm: &mut Simfile) -> usize {
+    let key_count = sm.gamemode.key_count().max(0) as usize;
+    let (first, last) = match (sm.notes.first(), sm.notes.last()) {
+        (Some(first), Some(last)) => (first.beat, last.beat),
+        _ => return 0,
+    };
+    if conf.density <= 0. && (conf.spinner_density <= 0. || sm.spinners.is_empty()) {
+        return 0;
+    }
+    //The beat ranges where each column is taken, merged and sorted
+    let gap = BeatPos::from(conf.gap);
+    let mut busy: Vec<Vec<(BeatPos, BeatPos)>> = vec![Vec::new(); key_count];
+    {
+        let mut open = vec![None; key_count];
+        for note in sm.notes.iter() {
+            let key = note.key as usize;
+            if key >= key_count {
+                continue;
+            }
+            if note.is_head() {
+                open[key] = Some(note.beat);
+            } else if note.is_tail() {
+                let start = open[key].take().unwrap_or(note.beat);
+                busy[key].push((start - gap, note.beat + gap));
+            } else {
+                busy[key].push((note.beat - gap, note.beat + gap));
+            }
+        }
+    }
+    for ranges in busy.iter_mut() {
+        ranges.sort_by_key(|&(start, _)| start);
+        let mut merged: Vec<(BeatPos, BeatPos)> = Vec::with_capacity(ranges.len());
+        for &(start, end) in ranges.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        *ranges = merged;
+    }
+    //Walk through the rows, placing mines on the free columns
+    let tick = BeatPos::from(conf.tick);
+    let mut rng = simfile_rng(sm, "mines");
+    let mut busy_idx = vec![0; key_count];
+    let mut spinner_idx = 0;
+    let mut free = Vec::with_capacity(key_count);
+    let mut mines = Vec::new();
+    let mut beat = first.ceil(tick);
+    while beat <= last {
+        while spinner_idx < sm.spinners.len() && sm.spinners[spinner_idx].1 < beat {
+            spinner_idx += 1;
+        }
+        let density = match sm.spinners.get(spinner_idx) {
+            Some(&(start, _)) if start <= beat => conf.spinner_density,
+            _ => conf.density,
+        };
+        //Mines per beat into mines per row, with the fractional part as a chance
+        let count = density * conf.tick;
+        let count = count.floor() as usize + (rng.gen::<f64>() < count.fract()) as usize;
+        if count > 0 {
+            free.clear();
+            for key in 0..key_count {
+                let ranges = &busy[key];
+                let idx = &mut busy_idx[key];
+                while *idx < ranges.len() && ranges[*idx].1 < beat {
+                    *idx += 1;
+                }
+                if ranges
+                    .get(*idx)
+                    .map(|&(start, _)| start > beat)
+                    .unwrap_or(true)
+                {
+                    free.push(key);
+                }
+            }
+            free.shuffle(&mut rng);
+            for &key in free.iter().take(count) {
+                mines.push(Note {
+                    kind: Note::KIND_MINE,
+                    beat,
+                    key: key as i32,
+                });
+            }
+        }
+        beat += tick;
+    }
+    let placed = mines.len();
+    if placed > 0 {
+        sm.notes.extend(mines);
+        sm.notes.sort_by_key(|note| (note.beat, !note.is_tail()));
+    }
+    placed
+}
@@ -1,6 +1,9 @@
 //! Take an osu! input directory and parse its beatmaps.
 
 use crate::node::prelude::*;
+#[cfg(feature = "fs")]
+use crate::osudb::RankedStatus;
+use std::process::Command;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -10,21 +13,78 @@ pub struct OsuLoad {
     /// Whether to attempt to automatically correct the path if it points to somewhere within an
     /// osu! installation.
     pub fix_input: bool,
+    /// Enumerate beatmaps from this `osu!.db` database instead of scanning the whole input folder,
+    /// which is much faster for large song folders.
+    /// Beatmaps loaded this way also get their osu! star rating and ranked status (see the
+    /// `Stars` rating method and the `StarRating` and `RankedStatus` filter properties).
+    ///
+    /// `"auto"` uses the database of the osu! installation that the input folder belongs to.
+    /// If empty, the input folder is scanned instead.
+    ///
+    /// Only beatmaps that osu! knows about are loaded, so `.osz` archives are not extracted and
+    /// beatmaps that were added since osu! last ran are skipped.
+    pub database: String,
+    /// Only convert the beatmaps in these osu! collections, by name.
+    /// If empty, every beatmap is converted.
+    ///
+    /// Collections are read from the `collection.db` file next to the osu! database, so setting
+    /// this option implies `database: "auto"` if no database is set.
+    pub collections: Vec<String>,
+    /// Whether to compute the star rating of osu!mania beatmaps that were not loaded through
+    /// `osu!.db`, using the osu!mania difficulty algorithm from before the 2022 rework.
+    /// Used by the `Stars` rating method and the `StarRating` filter.
+    pub recompute_stars: bool,
     /// The offset to apply to osu! files, in milliseconds.
     pub offset: f64,
-    /// Whether to read `.mp3` files to query audio length (for proper preview audio in the song
-    /// wheel select).
+    /// Whether to re-anchor the offset of every chart after converting it, so that the average
+    /// distance between notes and their osu! objects is as small as possible.
+    ///
+    /// By default the beat grid is anchored to the first timing point, so that any error caused
+    /// by rounding later timing points onto the grid accumulates in later notes.
+    pub reanchor: bool,
+    /// Whether to read audio files (`.mp3`, `.ogg` or `.wav`) to query audio length (for proper
+    /// preview audio in the song wheel select).
     pub query_audio_len: bool,
+    /// What to do with beatmaps that have no audio file, such as keysound-only beatmaps converted
+    /// from BMS.
+    pub no_audio: NoAudio,
+    /// The ffmpeg executable to use for `NoAudio::Mix`.
+    pub ffmpeg: String,
     /// Which gamemodes to generate.
     pub gamemodes: Vec<Gamemode>,
+    /// Instead of generating every gamemode in `gamemodes` with a matching keycount, generate
+    /// only the canonical gamemode for each keycount, as listed in `auto_gamemodes`.
+    /// Avoids duplicate charts in gamemodes that share a keycount.
+    pub auto_gamemode: bool,
+    /// The canonical gamemode for each keycount, used if `auto_gamemode` is enabled.
+    /// Keycounts that are not listed fall back to `gamemodes`.
+    pub auto_gamemodes: Vec<(i32, Gamemode)>,
     /// Options for mania beatmaps.
     pub mania: OsuMania,
     /// Options for beatmaps converted from osu!standard.
     pub standard: OsuStd,
+    /// Options for beatmaps converted from osu!taiko.
+    pub taiko: OsuTaiko,
+    /// Options for beatmaps converted from osu!catch.
+    pub catch: OsuCatch,
+    /// How to detect and handle "aspire" and joke beatmaps.
+    pub joke: JokeMaps,
+    /// How to salvage beatmaps with broken timing.
+    pub retime: Retime,
+    /// How to fine-tune offsets from the first onset of the music.
+    pub onset: OnsetSync,
+    /// How to convert pauses in the music.
+    pub pauses: Pauses,
     /// Whether to use the osu! unicode names or not.
     pub unicode: bool,
-    /// Whether to use or ignore video files.
+    /// Whether to play osu! background videos (through `#BGCHANGES`) or ignore them.
     pub video: bool,
+    /// Whether to extract `.osz` archives found in the input folder and convert them.
+    /// Like the osu! client does, each archive is extracted into a beatmapset folder with the
+    /// same name, next to the archive.
+    /// Archives with a folder of the same name next to them are assumed to be extracted already.
+    /// Disabled by default, since it writes into the input folder.
+    pub osz: bool,
     /// What is the chance to load a beatmapset.
     /// Defaults to `1` (of course).
     /// Intended for debug purposes.
@@ -37,12 +97,38 @@ pub struct OsuLoad {
     pub whitelist: Vec<String>,
     /// Whether to ignore "incompatible mode" errors, which may be _too_ numerous.
     pub ignore_mode_errors: bool,
+    /// Skip beatmaps with fewer hit objects than this, such as storyboard-only or troll
+    /// difficulties, before converting them.
+    pub min_notes: usize,
+    /// Skip beatmaps that play for less than this many seconds, from the first to the last hit
+    /// object.
+    pub min_secs: f64,
     /// What fraction of a beat do osu! timing points mark.
     /// Several alternatives can be given, which will be tried from first to last until there are
     /// no timing point conflicts or no more roundings are available.
     ///
     /// If no roundings are supplied, it is equivalent to `vec![0.]` (no rounding at all).
+    ///
+    /// Rounding to `4` beats keeps every bar of the song starting a StepMania measure.
+    /// Timing points that omit their first barline are never rounded to more than a beat.
     pub rounding: Vec<f64>,
+    /// If set, only load these beatmapsets (relative to the input folder).
+    /// Cannot be set from the config, it is used to repair the output.
+    #[serde(skip)]
+    pub only_sets: Option<HashSet<PathBuf>>,
+    /// If set, only load the beatmap with this file name out of every beatmapset.
+    /// Cannot be set from the config, it is used to convert a single beatmap.
+    #[serde(skip)]
+    pub only_beatmap: Option<PathBuf>,
+    /// Skip these beatmapsets (relative to the input folder).
+    /// Cannot be set from the config, it is used to resume an interrupted conversion.
+    #[serde(skip)]
+    pub skip_sets: HashSet<PathBuf>,
+    /// The source hashes of beatmapsets that were already converted (relative to the input
+    /// folder), which are skipped if their source hash did not change.
+    /// Cannot be set from the config, it is used to convert incrementally.
+    #[serde(skip)]
+    pub known_sources: HashMap<PathBuf, String>,
 }
 
 impl Default for OsuLoad {
@@ -50,8 +136,14 @@ impl Default for OsuLoad {
         Self {
             input: "".into(),
             fix_input: true,
+            database: "".into(),
+            collections: vec![],
+            recompute_stars: true,
             offset: 0.,
+            reanchor: false,
             query_audio_len: true,
+            no_audio: NoAudio::Silent,
+            ffmpeg: "ffmpeg".into(),
             gamemodes: {
                 use crate::simfile::Gamemode::*;
                 // Supported: 3K - 10K
@@ -68,20 +160,58 @@ impl Default for OsuLoad {
                     PnmNine,
                 ]
             },
+            auto_gamemode: false,
+            auto_gamemodes: {
+                use crate::simfile::Gamemode::*;
+                vec![
+                    (4, DanceSingle),
+                    (5, PumpSingle),
+                    (6, DanceSolo),
+                    (7, Kb7Single),
+                    (8, BmSingle7),
+                    (9, PnmNine),
+                    (10, PumpDouble),
+                ]
+            },
             mania: default(),
             standard: default(),
+            taiko: default(),
+            catch: default(),
+            joke: default(),
+            retime: default(),
+            onset: default(),
+            pauses: default(),
             unicode: false,
             video: true,
+            osz: false,
             debug_allow_chance: 1.,
             debug_allow_seed: 0,
             blacklist: vec![],
             whitelist: vec![],
             ignore_mode_errors: true,
+            min_notes: 0,
+            min_secs: 0.,
             rounding: vec![4., 1., 0.5, 0.25, 0.125, 0.],
+            only_sets: None,
+            only_beatmap: None,
+            skip_sets: default(),
+            known_sources: default(),
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoAudio {
+    /// Skip beatmaps without audio.
+    Skip,
+    /// Use a silent audio file as music, so that the chart is still playable (although without
+    /// any sound).
+    Silent,
+    /// Render the music by mixing the keysounds of all hitobjects using ffmpeg.
+    /// Falls back to `Silent` if there are no keysounds or ffmpeg fails.
+    Mix,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct OsuMania {
@@ -116,6 +246,16 @@ pub struct OsuStd {
     pub steps_per_spin: f64,
     /// The minimum length of a slider bounce (in beats).
     pub min_slider_bounce: f64,
+    /// Where each column of a gamemode sits on the pad or keyboard, as `(x, y)` pairs from
+    /// `(0, 0)` (top left) to `(1, 1)` (bottom right), in column order.
+    ///
+    /// If the gamemode generated from `keycount` has a layout, hit objects are more likely to be
+    /// mapped to the columns closest to their position in the osu! playfield, so that the chart
+    /// follows the movement of the original beatmap.
+    pub layouts: Vec<(Gamemode, Vec<(f64, f64)>)>,
+    /// How far away from a hit object (in layout units) a column can be before it gets half as
+    /// likely to be chosen.
+    pub layout_falloff: f64,
 }
 
 impl Default for OsuStd {
@@ -127,10 +267,282 @@ impl Default for OsuStd {
             dist_to_keycount: vec![0., 200., 350., 450.],
             steps_per_spin: 1.,
             min_slider_bounce: 0.25,
+            layouts: vec![],
+            layout_falloff: 0.25,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OsuTaiko {
+    pub into: BucketId,
+    /// How many keys to convert taiko beatmaps into.
+    /// Must be even: each hand gets half of the keys, with dons on the inner keys and kats on the
+    /// outer keys.
+    /// `0` by default, which disables the taiko gamemode parser.
+    pub keycount: i32,
+    /// Whether to convert drumrolls and spinners into holds.
+    /// Otherwise, they are converted into streams of notes.
+    pub roll_holds: bool,
+    /// The distance between the notes of drumrolls and spinners when they are converted into
+    /// streams (in beats).
+    pub roll_tick: f64,
+}
+
+impl Default for OsuTaiko {
+    fn default() -> Self {
+        Self {
+            into: default(),
+            keycount: 0,
+            roll_holds: true,
+            roll_tick: 0.25,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OsuCatch {
+    pub into: BucketId,
+    /// How many keys to convert catch beatmaps into.
+    /// The playfield is split into this many columns, similar to how osu!mania keys work.
+    /// `0` by default, which disables the catch gamemode parser.
+    pub keycount: i32,
+    /// The distance between the droplets of juice streams, in beats.
+    /// Droplets move along a straight line between the ends of the juice stream.
+    /// `0` converts only the fruits at the ends of each slide.
+    pub droplet_tick: f64,
+    /// Whether to convert banana showers into notes, sweeping back and forth across the keys.
+    pub bananas: bool,
+    /// The distance between bananas, in beats.
+    pub banana_tick: f64,
+}
+
+impl Default for OsuCatch {
+    fn default() -> Self {
+        Self {
+            into: default(),
+            keycount: 0,
+            droplet_tick: 0.5,
+            bananas: false,
+            banana_tick: 0.25,
+        }
+    }
+}
+
+/// Heuristics to detect "aspire" and joke beatmaps, which abuse the osu! format in ways that
+/// generate broken or gigantic simfiles.
+///
+/// A beatmap is flagged if it exceeds any of the limits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JokeMaps {
+    /// Where to output flagged beatmaps, regardless of their gamemode.
+    /// By default this is the null bucket, which skips flagged beatmaps without converting them.
+    pub into: BucketId,
+    /// Whether to flag beatmaps at all.
+    pub detect: bool,
+    /// Beatmapset ids (as declared by the beatmaps) that are never flagged, for legitimate
+    /// beatmaps that trip the heuristics.
+    pub allow_sets: Vec<i64>,
+    /// The highest BPM allowed.
+    pub max_bpm: f64,
+    /// The highest ratio between the highest and lowest BPM allowed.
+    pub max_bpm_ratio: f64,
+    /// The most hitobjects allowed within any one-second window.
+    pub max_nps: usize,
+    /// The most timing points with a negative beat length allowed.
+    /// osu! uses these for slider velocity changes, which aspire maps abuse.
+    pub max_negative_beat_len: usize,
+    /// The most timing points allowed.
+    pub max_timing_points: usize,
+}
+
+impl Default for JokeMaps {
+    fn default() -> Self {
+        Self {
+            into: BucketId::Null,
+            detect: true,
+            allow_sets: vec![],
+            max_bpm: 2000.,
+            max_bpm_ratio: 50.,
+            max_nps: 200,
+            max_negative_beat_len: 5000,
+            max_timing_points: 10000,
+        }
+    }
+}
+
+impl JokeMaps {
+    /// Find the reasons why a beatmap looks like a joke map, if any.
+    fn check(&self, bm: &Beatmap) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if bm.timing_points.len() > self.max_timing_points {
+            reasons.push(format!("{} timing points", bm.timing_points.len()));
+        }
+        let negative = bm
+            .timing_points
+            .iter()
+            .filter(|tp| tp.beat_len < 0.)
+            .count();
+        if negative > self.max_negative_beat_len {
+            reasons.push(format!("{} negative beat lengths", negative));
+        }
+        let (min_len, max_len) = bm
+            .timing_points
+            .iter()
+            .filter(|tp| tp.beat_len > 0.)
+            .fold((f64::INFINITY, 0f64), |(min, max), tp| {
+                (min.min(tp.beat_len), max.max(tp.beat_len))
+            });
+        if min_len.is_finite() {
+            let max_bpm = 60000. / min_len;
+            if max_bpm > self.max_bpm {
+                reasons.push(format!("{:.0} BPM", max_bpm));
+            }
+            if max_len / min_len > self.max_bpm_ratio {
+                reasons.push(format!("BPM range {:.0}-{:.0}", 60000. / max_len, max_bpm));
+            }
+        }
+        let mut start = 0;
+        let mut max_nps = 0;
+        for (end, obj) in bm.hit_objects.iter().enumerate() {
+            while obj.time - bm.hit_objects[start].time >= 1000. {
+                start += 1;
+            }
+            max_nps = max_nps.max(end + 1 - start);
+        }
+        if max_nps > self.max_nps {
+            reasons.push(format!("{} notes in a single second", max_nps));
+        }
+        reasons
+    }
+}
+
+/// Salvage beatmaps whose timing points are obviously broken (eg. a single 1ms beat length) by
+/// detecting the BPM from their audio, and retiming them to the detected beat grid.
+///
+/// Requires the `bpm-detect` feature.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retime {
+    /// Whether to retime beatmaps with broken timing.
+    /// If disabled, they are converted as-is (and usually flagged as joke maps).
+    pub enable: bool,
+    /// Timing is considered broken if the timing point that spans most of the beatmap has a BPM
+    /// outside of this range, or if there are no timing points at all.
+    pub valid_bpm: (f64, f64),
+    /// The range of BPMs to look for in the audio.
+    /// Keeping this range under a factor of 2 avoids confusing half and double tempos.
+    pub detect_bpm: (f64, f64),
+    /// How many seconds of audio to analyze at most.
+    pub max_secs: f64,
+}
+
+impl Default for Retime {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            valid_bpm: (10., 1000.),
+            detect_bpm: (80., 200.),
+            max_secs: 180.,
+        }
+    }
+}
+
+/// Fine-tune the offset of beatmaps by lining up their beat grid with the first strong onset of
+/// the music, to compensate for the latency differences between osu! and StepMania.
+///
+/// Requires the `bpm-detect` feature.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OnsetSync {
+    /// Whether to adjust offsets.
+    pub enable: bool,
+    /// How many seconds at the start of the audio to look for the first onset in.
+    pub max_secs: f64,
+    /// How strong an onset must be to count, relative to the strongest onset within `max_secs`.
+    pub strength: f64,
+    /// The largest adjustment to make, in milliseconds.
+    /// If the onset is further away from the nearest beat, it is assumed to be off the beat
+    /// (eg. a pickup note), and the offset is left alone.
+    pub tolerance_ms: f64,
+}
+
+impl Default for OnsetSync {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_secs: 15.,
+            strength: 0.6,
+            tolerance_ms: 25.,
+        }
+    }
+}
+
+/// Pauses are long breaks without objects (usually where the music stops for a moment), after
+/// which the timing points no longer line up with the beat grid.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pauses {
+    /// Whether to emit a `#STOPS` entry for every pause, instead of changing the BPM right
+    /// before the next timing point to get the beat grid back in sync.
+    /// Avoids short slow-scrolling sections in charts with breaks in the music.
+    pub stops: bool,
+    /// Emit a `#DELAYS` entry for every pause instead, right on the beat of the next timing
+    /// point, and fall back to a stop where that beat is already taken.
+    /// Delays need no room before the timing point, but only `.ssc` files support them (`.sm`
+    /// files get a stop instead).
+    pub delays: bool,
+    /// The shortest break without objects that counts as a pause, in seconds.
+    pub min_gap: f64,
+}
+
+impl Default for Pauses {
+    fn default() -> Self {
+        Self {
+            stops: false,
+            delays: false,
+            min_gap: 1.,
         }
     }
 }
 
+impl Retime {
+    /// Find the reason why the timing of a beatmap is broken, if it is.
+    fn check(&self, bm: &Beatmap) -> Option<String> {
+        let end = bm.hit_objects.last().map(|obj| obj.time).unwrap_or(0.);
+        let mut main: Option<(f64, &TimingPoint)> = None;
+        let mut absolute = bm
+            .timing_points
+            .iter()
+            .filter(|tp| tp.beat_len > 0.)
+            .peekable();
+        while let Some(tp) = absolute.next() {
+            let span = absolute.peek().map(|next| next.time).unwrap_or(end) - tp.time;
+            if main.map(|(main_span, _)| span > main_span).unwrap_or(true) {
+                main = Some((span, tp));
+            }
+        }
+        match main {
+            None => Some("no timing points".to_string()),
+            Some((_, tp)) => {
+                let bpm = 60000. / tp.beat_len;
+                if bpm < self.valid_bpm.0 || bpm > self.valid_bpm.1 {
+                    Some(format!("main timing point at {:.0} BPM", bpm))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Simfiles from beatmaps flagged as joke maps are output with this mode, instead of their
+/// osu! gamemode.
+const MODE_JOKE: usize = 4;
+
 const OSU_AUTODETECT: BaseDirFinder = BaseDirFinder {
     base_files: &[
         "collection.db",
@@ -144,8 +556,59 @@ const OSU_AUTODETECT: BaseDirFinder = BaseDirFinder {
     default_main_path: "Songs",
 };
 
+impl OsuLoad {
+    /// The gamemodes to generate for a given keycount.
+    fn gamemodes_for(&self, key_count: i32) -> Vec<Gamemode> {
+        if self.auto_gamemode {
+            if let Some((_, gamemode)) = self.auto_gamemodes.iter().find(|(k, _)| *k == key_count) {
+                return vec![*gamemode];
+            }
+        }
+        self.gamemodes
+            .iter()
+            .copied()
+            .filter(|gm| gm.key_count() == key_count)
+            .collect()
+    }
+}
+
 impl Node for OsuLoad {
     fn prepare(&mut self) -> Result<()> {
+        for (idx, &(key_count, gamemode)) in self.auto_gamemodes.iter().enumerate() {
+            ensure!(
+                gamemode.key_count() == key_count,
+                "auto gamemode {:?} has {} keys, not {}",
+                gamemode,
+                gamemode.key_count(),
+                key_count
+            );
+            ensure!(
+                self.auto_gamemodes[..idx]
+                    .iter()
+                    .all(|(other, _)| *other != key_count),
+                "keycount {} has several auto gamemodes",
+                key_count
+            );
+        }
+        for (gamemode, layout) in self.standard.layouts.iter() {
+            ensure!(
+                layout.len() == gamemode.key_count() as usize,
+                "layout for {:?} has {} columns, not {}",
+                gamemode,
+                layout.len(),
+                gamemode.key_count()
+            );
+        }
+        ensure!(
+            self.standard.layout_falloff > 0.,
+            "layout falloff must be positive"
+        );
+        if self.retime.enable && cfg!(not(feature = "bpm-detect")) {
+            bail!("retiming beatmaps with broken timing requires the `bpm-detect` feature");
+        }
+        if self.onset.enable && cfg!(not(feature = "bpm-detect")) {
+            bail!("adjusting offsets from audio onsets requires the `bpm-detect` feature");
+        }
         if self.input.is_empty() {
             eprintln!();
             eprintln!("drag and drop your osu! song folder into this window, then press enter");
@@ -167,8 +630,19 @@ impl Node for OsuLoad {
                     );
                     debug!("  songs dir at \"{}\"", main);
                     if self.input != main {
-                        info!("fixed input path: \"{}\" -> \"{}\"", self.input, main);
-                        self.input = main;
+                        let fix = crate::prompt::choose(
+                            "fix_input",
+                            &format!(
+                                "the input folder \"{}\" is within an osu! installation, but its song folder is \"{}\"",
+                                self.input, main
+                            ),
+                            &["use the osu! song folder", "keep the input folder as-is"],
+                            0,
+                        ) == 0;
+                        if fix {
+                            info!("fixed input path: \"{}\" -> \"{}\"", self.input, main);
+                            self.input = main;
+                        }
                     }
                 }
                 Err(err) => {
@@ -176,39 +650,93 @@ impl Node for OsuLoad {
                 }
             }
         }
-        info!("scanning for beatmaps in \"{}\"", self.input);
+        if !self.collections.is_empty() && self.database.is_empty() {
+            debug!("reading osu! collections requires the osu! database, using it");
+            self.database = "auto".into();
+        }
+        if self.database == "auto" {
+            let (base, _main) = OSU_AUTODETECT
+                .find_base(self.input.as_ref(), true)
+                .context("could not find the osu! installation to read osu!.db from")?;
+            self.database = base
+                .join("osu!.db")
+                .into_os_string()
+                .into_string()
+                .map_err(|path| {
+                    anyhow!(
+                        "invalid non-utf8 database path \"{}\"",
+                        path.to_string_lossy()
+                    )
+                })?;
+            debug!("  found osu! database at \"{}\"", self.database);
+        }
+        if self.database.is_empty() {
+            info!("scanning for beatmaps in \"{}\"", self.input);
+        } else {
+            info!(
+                "loading beatmaps in \"{}\" from \"{}\"",
+                self.input, self.database
+            );
+        }
+        if !self.collections.is_empty() {
+            info!(
+                "only converting beatmaps in the collections {}",
+                self.collections
+                    .iter()
+                    .map(|name| format!("\"{}\"", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
         Ok(())
     }
     fn apply(&self, _store: &mut SimfileStore) -> Result<()> {
         Ok(())
     }
+    fn settle(&mut self) {
+        //The input folder was already fixed
+        self.fix_input = false;
+    }
     fn buckets_mut(&mut self) -> BucketIter {
         Box::new(
             iter::once((BucketKind::Output, &mut self.mania.into))
-                .chain(iter::once((BucketKind::Output, &mut self.standard.into))),
+                .chain(iter::once((BucketKind::Output, &mut self.standard.into)))
+                .chain(iter::once((BucketKind::Output, &mut self.taiko.into)))
+                .chain(iter::once((BucketKind::Output, &mut self.catch.into)))
+                .chain(iter::once((BucketKind::Output, &mut self.joke.into))),
         )
     }
     fn entry(
         &self,
         store: &mut SimfileStore,
-        on_bmset: &mut dyn FnMut(&mut SimfileStore) -> Result<()>,
+        on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
     ) -> Result<()> {
-        scan_folder(self, store, on_bmset)
+        #[cfg(feature = "fs")]
+        {
+            scan_folder(self, store, on_bmset)
+        }
+        #[cfg(not(feature = "fs"))]
+        {
+            let _ = (store, on_bmset);
+            bail!("loading beatmaps from a folder requires the `fs` feature")
+        }
     }
 }
 
-fn scan_folder(
-    conf: &OsuLoad,
-    store: &mut SimfileStore,
-    on_bmset: &mut dyn FnMut(&mut SimfileStore) -> Result<()>,
-) -> Result<()> {
+/// Find all beatmapsets in the input folder by walking it, extracting any `.osz` archives on the
+/// way.
+#[cfg(feature = "fs")]
+fn walk_folder(conf: &OsuLoad, randtrim: &mut Option<FastRng>) -> Vec<(PathBuf, Vec<PathBuf>)> {
     let mut by_depth: Vec<Vec<PathBuf>> = Vec::new();
-    let mut randtrim = if conf.debug_allow_chance < 1. {
-        Some(FastRng::seed_from_u64(conf.debug_allow_seed))
-    } else {
-        None
-    };
-    for entry in WalkDir::new(&conf.input).contents_first(true) {
+    //Beatmapset folders and their beatmaps, in the order they were found
+    let mut sets: Vec<(PathBuf, Vec<PathBuf>)> = Vec::new();
+    //Beatmapset folders extracted from `.osz` archives, which are already listed
+    let mut extracted: HashSet<PathBuf> = default();
+    //Walk in a fixed order, so that conversions do not depend on the filesystem
+    let walk = WalkDir::new(&conf.input)
+        .contents_first(true)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for entry in walk {
         let entry = match entry {
             Ok(entry) => entry,
             Err(err) => {
@@ -222,41 +750,11 @@ fn scan_folder(
         if depth < by_depth.len() {
             //Close directories
             for dir in by_depth.drain(depth..) {
-                if let Some(rng) = &mut randtrim {
-                    if !rng.gen_bool(conf.debug_allow_chance) {
-                        continue;
-                    }
+                if extracted.contains(entry.path()) {
+                    continue;
                 }
-                if !conf.blacklist.is_empty() || !conf.whitelist.is_empty() {
-                    let path = entry
-                        .path()
-                        .strip_prefix(&conf.input)
-                        .ok()
-                        .and_then(Path::to_str)
-                        .unwrap_or_default()
-                        .to_lowercase();
-                    if conf.blacklist.iter().any(|black| path.contains(black)) {
-                        //Path contains blacklisted keywords
-                        continue;
-                    }
-                    if !conf.whitelist.is_empty()
-                        && !conf.whitelist.iter().any(|white| path.contains(white))
-                    {
-                        //Path is not whitelisted
-                        continue;
-                    }
-                }
-                if !dir.is_empty() {
-                    match process_beatmapset(conf, store, entry.path(), &dir[..], on_bmset) {
-                        Ok(()) => {}
-                        Err(e) => {
-                            error!(
-                                "  error processing beatmapset at \"{}\": {:#}",
-                                entry.path().display(),
-                                e
-                            );
-                        }
-                    }
+                if keep_beatmapset(conf, randtrim, entry.path(), &dir) {
+                    sets.push((entry.path().to_path_buf(), dir));
                 }
             }
         } else {
@@ -271,31 +769,398 @@ fn scan_folder(
                 if depth > 0 {
                     by_depth[depth - 1].push(bm_path);
                 } else {
-                    warn!("do not run on a .osu file, run on the beatmapset folder instead");
+                    warn!("do not run on a .osu file, run on the beatmapset folder instead (or convert it with `--single`)");
+                }
+            } else if conf.osz
+                && entry.path().extension() == Some("osz".as_ref())
+                && !crate::cancel::requested()
+            {
+                let set_path = entry.path().with_extension("");
+                if set_path.exists() {
+                    //Already extracted, the folder is converted on its own
+                    continue;
+                }
+                match extract_osz(entry.path(), &set_path) {
+                    Ok(bm_paths) => {
+                        info!("extracted \"{}\"", entry.path().display());
+                        extracted.insert(set_path.clone());
+                        if keep_beatmapset(conf, randtrim, &set_path, &bm_paths) {
+                            sets.push((set_path, bm_paths));
+                        }
+                    }
+                    Err(err) => {
+                        warn!(
+                            "failed to extract \"{}\": {:#}",
+                            entry.path().display(),
+                            err
+                        );
+                    }
                 }
             }
         }
     }
+    sets
+}
+
+/// Find all beatmapsets in the input folder that are listed in the osu! database, along with the
+/// star rating and ranked status of each beatmap.
+#[cfg(feature = "fs")]
+fn read_database(
+    conf: &OsuLoad,
+    randtrim: &mut Option<FastRng>,
+    db_info: &mut HashMap<PathBuf, (f64, RankedStatus)>,
+) -> Result<Vec<(PathBuf, Vec<PathBuf>)>> {
+    use crate::osudb::{CollectionDb, OsuDb};
+    use std::collections::BTreeMap;
+    let db = OsuDb::load(conf.database.as_ref())
+        .with_context(|| anyhow!("failed to load osu! database \"{}\"", conf.database))?;
+    debug!(
+        "read {} beatmaps from osu! database version {}",
+        db.beatmaps.len(),
+        db.version
+    );
+    //Collections list beatmaps by their MD5 hash
+    let collections = if conf.collections.is_empty() {
+        None
+    } else {
+        let path = Path::new(&conf.database).with_file_name("collection.db");
+        let collection_db = CollectionDb::load(&path)
+            .with_context(|| anyhow!("failed to load osu! collections \"{}\"", path.display()))?;
+        let mut md5s: HashSet<String> = default();
+        for name in conf.collections.iter() {
+            let collection = collection_db
+                .collections
+                .iter()
+                .find(|collection| collection.name == *name)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "no osu! collection named \"{}\" (available collections: {})",
+                        name,
+                        collection_db
+                            .collections
+                            .iter()
+                            .map(|collection| format!("\"{}\"", collection.name))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })?;
+            debug!(
+                "  collection \"{}\" has {} beatmaps",
+                name,
+                collection.md5s.len()
+            );
+            md5s.extend(collection.md5s.iter().cloned());
+        }
+        Some(md5s)
+    };
+    //Beatmapset folders and their beatmaps, sorted by folder
+    let mut by_folder: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+    let mut missing = 0;
+    for bm in db.beatmaps.iter() {
+        //Skip gamemodes that are not converted without even reading them
+        let enabled = match bm.mode {
+            osufile::MODE_STD => conf.standard.keycount != 0,
+            osufile::MODE_TAIKO => conf.taiko.keycount != 0,
+            osufile::MODE_CATCH => conf.catch.keycount != 0,
+            _ => true,
+        };
+        if !enabled || bm.folder.is_empty() || bm.osu_file.is_empty() {
+            continue;
+        }
+        if let Some(md5s) = &collections {
+            if !md5s.contains(bm.md5.as_str()) {
+                continue;
+            }
+        }
+        let bm_path = Path::new(&conf.input).join(&bm.folder).join(&bm.osu_file);
+        if !bm_path.is_file() {
+            trace!(
+                "skipping beatmap \"{}\" listed in the database, which does not exist",
+                bm_path.display()
+            );
+            missing += 1;
+            continue;
+        }
+        db_info.insert(bm_path.clone(), (bm.star_rating, bm.ranked_status));
+        by_folder.entry(&bm.folder).or_default().push(bm_path);
+    }
+    if missing > 0 {
+        warn!(
+            "skipped {} beatmaps listed in the osu! database that no longer exist",
+            missing
+        );
+    }
+    let mut sets = Vec::with_capacity(by_folder.len());
+    for (folder, bm_paths) in by_folder {
+        let set_path = Path::new(&conf.input).join(folder);
+        if keep_beatmapset(conf, randtrim, &set_path, &bm_paths) {
+            sets.push((set_path, bm_paths));
+        }
+    }
+    Ok(sets)
+}
+
+/// Find all beatmapsets in the input folder, and process them in parallel on the rayon thread
+/// pool.
+#[cfg(feature = "fs")]
+fn scan_folder(
+    conf: &OsuLoad,
+    store: &mut SimfileStore,
+    on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
+) -> Result<()> {
+    use rayon::prelude::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    let mut randtrim = if conf.debug_allow_chance < 1. {
+        Some(FastRng::seed_from_u64(conf.debug_allow_seed))
+    } else {
+        None
+    };
+    let mut db_info = default();
+    let mut sets = if conf.database.is_empty() {
+        walk_folder(conf, &mut randtrim)
+    } else {
+        read_database(conf, &mut randtrim, &mut db_info)?
+    };
+    if let Some(only) = &conf.only_beatmap {
+        for (_set_path, bm_paths) in sets.iter_mut() {
+            bm_paths.retain(|bm_path| bm_path.file_name() == Some(only.as_os_str()));
+        }
+        sets.retain(|(_set_path, bm_paths)| !bm_paths.is_empty());
+    }
+    info!(
+        "found {} beatmapsets, converting on {} threads",
+        sets.len(),
+        rayon::current_num_threads()
+    );
+    crate::progress::discovered(sets.len());
+    //Worker threads report the entry node in crash reports, like the main thread
+    let node = crate::crash::current_node();
+    let interrupted = AtomicBool::new(false);
+    let unchanged = AtomicUsize::new(0);
+    sets.par_iter().for_each_init(
+        || store.clone(),
+        |store, (set_path, bm_paths)| {
+            if crate::cancel::requested() {
+                if !interrupted.swap(true, Ordering::Relaxed) {
+                    warn!("interrupted, stopping before \"{}\"", set_path.display());
+                }
+                return;
+            }
+            crate::crash::set_node(node);
+            let hash = match source_hash(store, bm_paths) {
+                Ok(hash) => hash,
+                Err(err) => {
+                    error!(
+                        "error reading beatmapset at \"{}\": {:#}",
+                        set_path.display(),
+                        err
+                    );
+                    crate::progress::processed(false);
+                    return;
+                }
+            };
+            let rel = set_path.strip_prefix(&conf.input).unwrap_or(set_path);
+            if conf.known_sources.get(rel) == Some(&hash) {
+                trace!("skipping unchanged beatmapset \"{}\"", set_path.display());
+                unchanged.fetch_add(1, Ordering::Relaxed);
+                crate::progress::processed(true);
+                return;
+            }
+            visit_beatmapset(conf, store, set_path, bm_paths, &hash, &db_info, on_bmset);
+        },
+    );
+    let unchanged = unchanged.into_inner();
+    if unchanged > 0 {
+        info!("skipped {} unchanged beatmapsets", unchanged);
+    }
     Ok(())
 }
 
+/// Hash the beatmaps of a beatmapset along with the conversion settings and the last osu2sm
+/// version that changed their conversion (see `changelist`), to tell whether a beatmapset has to
+/// be converted again.
+#[cfg(feature = "fs")]
+fn source_hash(store: &SimfileStore, bm_paths: &[PathBuf]) -> Result<String> {
+    use std::hash::{Hash, Hasher};
+    let contents = bm_paths
+        .iter()
+        .map(|bm_path| {
+            fs::read(bm_path).with_context(|| anyhow!("failed to read \"{}\"", bm_path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let modes = contents
+        .iter()
+        .map(|contents| osufile::peek_mode(contents))
+        .collect::<Vec<_>>();
+    //Only versions that changed the output of these beatmaps change the hash
+    let mut hasher = fxhash::FxHasher64::default();
+    crate::changelist::generation(&modes).hash(&mut hasher);
+    store.global_get("config_hash").hash(&mut hasher);
+    for (bm_path, contents) in bm_paths.iter().zip(contents) {
+        bm_path.file_name().hash(&mut hasher);
+        contents.hash(&mut hasher);
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Whether a beatmapset folder passes the filters and should be processed.
+#[cfg(feature = "fs")]
+fn keep_beatmapset(
+    conf: &OsuLoad,
+    randtrim: &mut Option<FastRng>,
+    set_path: &Path,
+    bm_paths: &[PathBuf],
+) -> bool {
+    if let Some(rng) = randtrim {
+        if !rng.gen_bool(conf.debug_allow_chance) {
+            return false;
+        }
+    }
+    if !conf.blacklist.is_empty() || !conf.whitelist.is_empty() {
+        let path = set_path
+            .strip_prefix(&conf.input)
+            .ok()
+            .and_then(Path::to_str)
+            .unwrap_or_default()
+            .to_lowercase();
+        if conf.blacklist.iter().any(|black| path.contains(black)) {
+            //Path contains blacklisted keywords
+            return false;
+        }
+        if !conf.whitelist.is_empty() && !conf.whitelist.iter().any(|white| path.contains(white)) {
+            //Path is not whitelisted
+            return false;
+        }
+    }
+    let rel = set_path.strip_prefix(&conf.input).unwrap_or(set_path);
+    if let Some(only) = &conf.only_sets {
+        if !only.contains(rel) {
+            return false;
+        }
+    }
+    !conf.skip_sets.contains(rel) && !bm_paths.is_empty()
+}
+
+/// Process a single beatmapset folder, logging any errors.
+#[cfg(feature = "fs")]
+fn visit_beatmapset(
+    conf: &OsuLoad,
+    store: &mut SimfileStore,
+    set_path: &Path,
+    bm_paths: &[PathBuf],
+    source_hash: &str,
+    db_info: &HashMap<PathBuf, (f64, RankedStatus)>,
+    on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
+) {
+    crate::crash::set_beatmapset(Some(set_path));
+    let ok = crate::logger::batched(|| {
+        match process_beatmapset(
+            conf,
+            store,
+            set_path,
+            bm_paths,
+            source_hash,
+            db_info,
+            on_bmset,
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                error!(
+                    "  error processing beatmapset at \"{}\": {:#}",
+                    set_path.display(),
+                    e
+                );
+                false
+            }
+        }
+    });
+    crate::crash::set_beatmapset(None);
+    crate::progress::processed(ok);
+}
+
+/// Extract a `.osz` archive into the given beatmapset folder, and return the paths of the
+/// extracted `.osu` files.
+#[cfg(feature = "fs")]
+fn extract_osz(archive: &Path, set_path: &Path) -> Result<Vec<PathBuf>> {
+    let file = File::open(archive).context("failed to open archive")?;
+    let mut zip = zip::ZipArchive::new(BufReader::new(file)).context("failed to read archive")?;
+    //Extract into a temporary folder first, so that a failed extraction does not look like an
+    //extracted beatmapset on the next run
+    let mut tmp_name = set_path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".partial");
+    let tmp_path = set_path.with_file_name(tmp_name);
+    if tmp_path.exists() {
+        fs::remove_dir_all(&tmp_path).context("failed to remove leftover partial extraction")?;
+    }
+    let mut bm_names = Vec::new();
+    for idx in 0..zip.len() {
+        let mut file = zip.by_index(idx).context("failed to read archive entry")?;
+        let name = match file.enclosed_name() {
+            Some(name) => name.to_path_buf(),
+            None => {
+                warn!("  skipping unsafe archive entry \"{}\"", file.name());
+                continue;
+            }
+        };
+        if file.is_dir() {
+            continue;
+        }
+        let dst = tmp_path.join(&name);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).context("failed to create folder")?;
+        }
+        let mut out = File::create(&dst)
+            .with_context(|| anyhow!("failed to create \"{}\"", dst.display()))?;
+        io::copy(&mut file, &mut out)
+            .with_context(|| anyhow!("failed to extract \"{}\"", name.display()))?;
+        //Beatmaps in subfolders are not part of the beatmapset
+        if name.extension() == Some("osu".as_ref()) && name.components().count() == 1 {
+            bm_names.push(name);
+        }
+    }
+    if bm_names.is_empty() {
+        let _ = fs::remove_dir_all(&tmp_path);
+        bail!("archive has no .osu files");
+    }
+    fs::rename(&tmp_path, set_path).context("failed to move extracted files into place")?;
+    Ok(bm_names
+        .into_iter()
+        .map(|name| set_path.join(name))
+        .collect())
+}
+
+#[cfg(feature = "fs")]
 fn process_beatmapset(
     conf: &OsuLoad,
     store: &mut SimfileStore,
     bmset_path: &Path,
     bm_paths: &[PathBuf],
-    on_bmset: &mut dyn FnMut(&mut SimfileStore) -> Result<()>,
+    source_hash: &str,
+    db_info: &HashMap<PathBuf, (f64, RankedStatus)>,
+    on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
 ) -> Result<()> {
     info!("processing \"{}\":", bmset_path.display());
     //Parse and convert beatmaps
     let mut bmset_cache = BmsetCache::default();
-    let mut by_mode = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+    let mut by_mode = [Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()];
     for bm_path in bm_paths {
         let mut simfile_count = 0;
-        let result = process_beatmap(conf, &mut bmset_cache, bmset_path, bm_path, |mode, sm| {
-            simfile_count += 1;
-            by_mode[mode].push(sm)
-        });
+        let info = db_info.get(bm_path);
+        let result = process_beatmap(
+            conf,
+            &mut bmset_cache,
+            bmset_path,
+            bm_path,
+            |mode, mut sm| {
+                simfile_count += 1;
+                sm.source_file = bm_path.strip_prefix(bmset_path).ok().map(Path::to_path_buf);
+                if let Some(&(star_rating, ranked_status)) = info {
+                    sm.star_rating = star_rating;
+                    sm.ranked_status = Some(ranked_status);
+                }
+                by_mode[mode].push(sm)
+            },
+        );
         let bm_name = bm_path.file_name().unwrap_or_default().to_string_lossy();
         match result {
             Ok(()) => {
@@ -324,13 +1189,31 @@ fn process_beatmapset(
             ))?
             .to_string(),
     );
+    //Old beatmaps do not declare their set id, but their folder usually starts with it
+    let set_id = bmset_cache.set_id.or_else(|| {
+        let name = bmset_path.file_name()?.to_str()?;
+        let digits = name
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(name.len());
+        name[..digits].parse().ok()
+    });
+    if let Some(set_id) = set_id {
+        store.global_set("set_id", set_id.to_string());
+    }
+    if let Some(name) = bmset_path.file_name().and_then(OsStr::to_str) {
+        store.global_set("set_folder", name.to_string());
+    }
+    store.global_set("source_hash", source_hash.to_string());
     for (mode, simfiles) in by_mode.iter_mut().enumerate() {
         if simfiles.is_empty() {
             continue;
         }
         let bucket = match mode as i32 {
+            _ if mode == MODE_JOKE => &conf.joke.into,
             osufile::MODE_MANIA => &conf.mania.into,
             osufile::MODE_STD => &conf.standard.into,
+            osufile::MODE_TAIKO => &conf.taiko.into,
+            osufile::MODE_CATCH => &conf.catch.into,
             _ => panic!("mode {} is unimplemented", mode),
         };
         store.put(bucket, simfiles.drain(..));
@@ -342,6 +1225,15 @@ fn process_beatmapset(
 #[derive(Default)]
 struct BmsetCache {
     audio_len: HashMap<PathBuf, f64>,
+    audio_hash: HashMap<PathBuf, Option<u64>>,
+    /// The osu! beatmapset id, if any beatmap declares it.
+    set_id: Option<i64>,
+    /// Tempos detected from audio files, for retiming beatmaps.
+    #[cfg(feature = "bpm-detect")]
+    tempo: HashMap<PathBuf, crate::bpm::Tempo>,
+    /// The first onsets of audio files in seconds, for adjusting offsets.
+    #[cfg(feature = "bpm-detect")]
+    onset: HashMap<PathBuf, Option<f64>>,
 }
 impl BmsetCache {
     /// Get the length of an audio file in seconds.
@@ -350,23 +1242,67 @@ impl BmsetCache {
         let len = match self.audio_len.get(path) {
             Some(len) => *len,
             None => {
-                let len = match mp3_duration::from_path(path) {
-                    Ok(len) => len,
-                    Err(err) => {
-                        let len = err.at_duration;
-                        result = Err(err.into());
-                        len
-                    }
-                }
-                .as_secs_f64();
+                #[cfg(feature = "fs")]
+                let len = {
+                    let (len, res) = crate::audio::duration(path);
+                    result = res;
+                    len
+                };
+                #[cfg(not(feature = "fs"))]
+                let len = {
+                    result = Err(anyhow!("querying audio length requires the `fs` feature"));
+                    0.
+                };
                 self.audio_len.insert(path.to_path_buf(), len);
                 len
             }
         };
         (len, result)
     }
+
+    /// Get a fingerprint of the contents of an audio file, if it can be read.
+    fn get_audio_hash(&mut self, path: &Path) -> Option<u64> {
+        if let Some(hash) = self.audio_hash.get(path) {
+            return *hash;
+        }
+        #[cfg(feature = "fs")]
+        let hash = match crate::audio::fingerprint(path) {
+            Ok(hash) => Some(hash),
+            Err(err) => {
+                debug!(
+                    "    failed to fingerprint audio \"{}\": {:#}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        };
+        #[cfg(not(feature = "fs"))]
+        let hash = None;
+        self.audio_hash.insert(path.to_path_buf(), hash);
+        hash
+    }
 }
 
+/// The length of a StepMania measure, in beats.
+const MEASURE_BEATS: f64 = 4.;
+
+/// The rounding to use for a timing point.
+///
+/// Timing points that omit their first barline do not start a new bar, so they are not moved
+/// to the start of a measure.
+fn barline_rounding(rounding: BeatPos, tp: &TimingPoint) -> BeatPos {
+    if tp.omit_barline {
+        rounding.min(BeatPos::from(1.))
+    } else {
+        rounding
+    }
+}
+
+/// Synthetic BPM corrections that speed up or slow down the chart by more than this factor look
+/// broken, so stops are used instead.
+const MAX_CORRECTION_RATIO: f64 = 16.;
+
 struct ConvCtx<'a> {
     cur_tp: TimingPoint,
     rest_tp: &'a [TimingPoint],
@@ -377,7 +1313,27 @@ struct ConvCtx<'a> {
     out_beatlen_range: (f64, f64),
     out_offset: f64,
     out_bpms: Vec<ControlPoint>,
+    /// `(beat, duration in seconds)` pairs.
+    out_stops: Vec<(f64, f64)>,
+    /// `(beat, duration in seconds)` pairs.
+    out_delays: Vec<(f64, f64)>,
     out_notes: Vec<Note>,
+    /// `(start, end)` beats of every spinner.
+    out_spinners: Vec<(BeatPos, BeatPos)>,
+    /// The largest distance in milliseconds between an osu! object and its converted beat.
+    max_error: f64,
+    /// The signed distance in milliseconds between every osu! object and its converted beat,
+    /// positive if the converted beat is late.
+    errors: Vec<f64>,
+    /// How many control points had to be made up to keep the grid in sync.
+    synthetic_bpms: usize,
+    /// Whether the timing of the beatmap was detected from its audio.
+    retimed: bool,
+    /// The shortest gap in milliseconds to convert into a stop, if pauses are converted into
+    /// stops.
+    pause_gap: Option<f64>,
+    /// Whether to wait for timing points with delays instead of stops.
+    use_delays: bool,
 }
 impl ConvCtx<'_> {
     fn new<'a>(conf: &OsuLoad, bm: &'a Beatmap) -> Result<ConvCtx<'a>> {
@@ -409,10 +1365,25 @@ impl ConvCtx<'_> {
         };
 
         //Match the time of the first timing point to the time of the first hitobject
+        //If the first hitobject precedes the timing point, this extends the grid backwards as
+        //many measures as needed
+        //Whole 4-beat StepMania measures are used regardless of the osu! meter, so that the first
+        //downbeat starts a measure and measure lines match the bars of the song
         let first_tp = {
             let mut first_tp = bm.timing_points[first_tp_idx].clone();
-            let round_to = first_tp.beat_len * first_tp.meter as f64;
-            first_tp.time += ((first_hit_time - first_tp.time) / round_to).floor() * round_to;
+            let round_to = first_tp.beat_len * MEASURE_BEATS;
+            let measures = ((first_hit_time - first_tp.time) / round_to).floor();
+            first_tp.time += measures * round_to;
+            //Floating point error could leave the first hitobject slightly before beat 0
+            while first_tp.time > first_hit_time {
+                first_tp.time -= round_to;
+            }
+            if measures < 0. {
+                debug!(
+                    "    first hitobject precedes its timing point, extended grid backwards by {} measures",
+                    -measures
+                );
+            }
             first_tp
         };
         trace!(
@@ -431,9 +1402,8 @@ impl ConvCtx<'_> {
             for tp in bm.timing_points[first_tp_idx + 1..].iter() {
                 if tp.beat_len > 0. {
                     let last_beat = cur_beat;
-                    let beat_adv =
-                        BeatPos::from((tp.time - cur_tp.time) / cur_tp.beat_len).round(round_to);
-                    cur_beat += beat_adv;
+                    let beat_adv = BeatPos::from((tp.time - cur_tp.time) / cur_tp.beat_len);
+                    cur_beat = (cur_beat + beat_adv).round(barline_rounding(round_to, tp));
                     //Make sure there is no aliasing
                     if tp.time != cur_tp.time && cur_beat == last_beat {
                         no_aliasing = false;
@@ -465,7 +1435,20 @@ impl ConvCtx<'_> {
             out_beatlen_range: (first_tp.beat_len, first_tp.beat_len),
             out_offset: first_tp.time / -1000.,
             out_bpms: vec![first_controlpoint],
+            out_stops: Vec::new(),
+            out_delays: Vec::new(),
             out_notes: Vec::new(),
+            out_spinners: Vec::new(),
+            max_error: 0.,
+            errors: Vec::new(),
+            synthetic_bpms: 0,
+            retimed: false,
+            pause_gap: if conf.pauses.stops || conf.pauses.delays {
+                Some(conf.pauses.min_gap * 1000.)
+            } else {
+                None
+            },
+            use_delays: conf.pauses.delays,
             cur_tp: first_tp,
         })
     }
@@ -473,13 +1456,19 @@ impl ConvCtx<'_> {
     /// Convert from a point in time to a snapped beat number, taking into account changing BPM.
     /// Should never be called with a time smaller than the last call!
     fn get_beat(&mut self, time: f64) -> BeatPos {
-        /*
-        ensure!(
-            time >= self.last_time,
-            "object must monotonically increase in time",
-        );
-        */
-        //Advance timing points
+        self.advance(time);
+        //Use the current timing point to determine note beat
+        //Do not use `cur_time`; it is only used as an error accumulator
+        let beat_adv = BeatPos::from((time - self.cur_tp.time) / self.cur_tp.beat_len);
+        //`cur_time` is the actual time of `cur_beat` in the output grid
+        let out_time = self.cur_time + beat_adv.as_num() * self.cur_tp.beat_len;
+        self.max_error = self.max_error.max((out_time - time).abs());
+        self.errors.push(out_time - time);
+        self.cur_beat + beat_adv
+    }
+
+    /// Advance through every timing point up to the given time, placing them in the output grid.
+    fn advance(&mut self, time: f64) {
         while let Some(next_tp) = self.rest_tp.first() {
             if time >= next_tp.time {
                 if next_tp.beat_len <= 0. {
@@ -488,56 +1477,72 @@ impl ConvCtx<'_> {
                 } else {
                     //Advance to this timing point
                     let raw_beat_adv = (next_tp.time - self.cur_time) / self.cur_tp.beat_len;
-                    let beat_adv = BeatPos::from_num_ceil(raw_beat_adv).ceil(self.rounding);
-                    let tp_beat = self.cur_beat + beat_adv;
+                    let rounding = barline_rounding(self.rounding, next_tp);
+                    let mut tp_beat =
+                        (self.cur_beat + BeatPos::from_num_ceil(raw_beat_adv)).ceil(rounding);
+                    let beat_adv = tp_beat - self.cur_beat;
                     let mut tp_time = self.cur_time + beat_adv.as_num() * self.cur_tp.beat_len;
-                    if (tp_time - next_tp.time).abs() >= 4. {
-                        let last_beat = self
-                            .out_notes
-                            .last()
-                            .map(|note| note.beat)
-                            .unwrap_or(BeatPos::from(0.))
-                            .max(self.cur_beat);
-                        let pivot_max = self.cur_beat + BeatPos::from_num_ceil(raw_beat_adv);
-                        let mut pivot = None;
-                        for &beat_gap in [
-                            BeatPos::from(1.),
-                            BeatPos::from(0.5),
-                            BeatPos::from(0.25),
-                            BeatPos::from(1. / 8.),
-                            BeatPos::from(1. / 16.),
-                            BeatPos::EPSILON,
-                        ]
-                        .iter()
-                        {
-                            let potential_pivot = pivot_max.ceil(beat_gap) - beat_gap;
-                            if potential_pivot >= last_beat {
-                                pivot = Some(potential_pivot);
-                                break;
+                    let last_beat = self
+                        .out_notes
+                        .last()
+                        .map(|note| note.beat)
+                        .unwrap_or(BeatPos::from(0.))
+                        .max(self.cur_beat);
+                    let off_grid = (tp_time - next_tp.time).abs() >= 4.;
+                    let mut stop = if off_grid {
+                        self.find_pause(next_tp.time, raw_beat_adv, rounding, last_beat)
+                    } else {
+                        None
+                    };
+                    if off_grid && stop.is_none() {
+                        match self.find_correction(next_tp.time, raw_beat_adv, tp_beat, last_beat) {
+                            Some((pivot, beat_len)) => {
+                                self.out_bpms.push(ControlPoint {
+                                    beat: pivot,
+                                    beat_len: beat_len / 1000.,
+                                });
+                                //Timing points on the beat are only moved to start a new measure,
+                                //which does not affect sync
+                                let on_beat = (raw_beat_adv - raw_beat_adv.round()).abs()
+                                    * self.cur_tp.beat_len
+                                    < 4.;
+                                if !on_beat {
+                                    self.synthetic_bpms += 1;
+                                }
+                                tp_time = self.cur_time
+                                    + (pivot - self.cur_beat).as_num() * self.cur_tp.beat_len
+                                    + (tp_beat - pivot).as_num() * beat_len;
+                                trace!(
+                                    "      corrected bpm by inserting {}ms/beat control point at beat {}",
+                                    beat_len,
+                                    pivot
+                                );
+                            }
+                            None => {
+                                //No sensible BPM change gets back in sync, so wait with a stop
+                                stop = self.stop_before(raw_beat_adv, rounding, last_beat);
+                                if stop.is_none() {
+                                    warn!("found no bpm correction for timing points {:?} -> {:?}: last_beat = {}", self.cur_tp, next_tp, last_beat);
+                                }
                             }
                         }
-                        if let Some(pivot) = pivot {
-                            let target_time = next_tp.time - self.cur_time;
-                            let time_to_pivot =
-                                (pivot - self.cur_beat).as_num() * self.cur_tp.beat_len;
-                            let consume_time = target_time - time_to_pivot;
-                            let consume_beats = tp_beat - pivot;
-                            let beat_len = consume_time / consume_beats.as_num();
-                            self.out_bpms.push(ControlPoint {
-                                beat: pivot,
-                                beat_len: beat_len / 1000.,
-                            });
-                            tp_time = self.cur_time
-                                + (pivot - self.cur_beat).as_num() * self.cur_tp.beat_len
-                                + (tp_beat - pivot).as_num() * beat_len;
-                            trace!(
-                                "      corrected bpm by inserting {}ms/beat control point at beat {}",
-                                beat_len,
-                                pivot
-                            );
+                    }
+                    if let Some((stop_beat, stop_tp_beat)) = stop {
+                        //Fall short of the timing point, and wait for it with a stop
+                        tp_beat = stop_tp_beat;
+                        tp_time = self.cur_time
+                            + (tp_beat - self.cur_beat).as_num() * self.cur_tp.beat_len;
+                        let stop_len = next_tp.time - tp_time;
+                        if self.use_delays && tp_beat > last_beat {
+                            //Notes on the beat of a delay play after it, so it can go right on
+                            //the timing point
+                            self.out_delays.push((tp_beat.as_num(), stop_len / 1000.));
+                            trace!("      inserted {}ms delay at beat {}", stop_len, tp_beat);
                         } else {
-                            warn!("found no bpm correction pivot for timing points {:?} -> {:?}: last_beat = {}, pivot_max = {}", self.cur_tp, next_tp, last_beat, pivot_max);
+                            self.out_stops.push((stop_beat.as_num(), stop_len / 1000.));
+                            trace!("      inserted {}ms stop at beat {}", stop_len, stop_beat);
                         }
+                        tp_time = next_tp.time;
                     }
                     trace!("      advancing from timing point at beat {}, time {}, to beat {} ({:?} -> {:?})", self.cur_beat, self.cur_time, tp_beat, self.cur_tp, next_tp);
                     self.cur_beat = tp_beat;
@@ -557,9 +1562,189 @@ impl ConvCtx<'_> {
                 break;
             }
         }
-        //Use the current timing point to determine note beat
-        //Do not use `cur_time`; it is only used as an error accumulator
-        self.cur_beat + BeatPos::from((time - self.cur_tp.time) / self.cur_tp.beat_len)
+    }
+
+    /// Convert from a point in time to a beat number, using the output control points and stops
+    /// instead of the osu! timing points.
+    /// Unlike `get_beat`, this may be called in any order, but it is only exact up to the last
+    /// timing point that was advanced through.
+    fn lookup_beat(&self, time: f64) -> BeatPos {
+        //The output grid is in seconds
+        let time = time / 1000.;
+        //Stops and delays pause the beat at the same point in time
+        let mut pauses = self.out_stops.clone();
+        pauses.extend_from_slice(&self.out_delays);
+        pauses.sort_by_key(|&(beat, _)| SortableFloat(beat));
+        let mut bpm_idx = 0;
+        let mut stop_idx = 0;
+        let mut cur_beat = self.out_bpms[0].beat;
+        let mut cur_time = -self.out_offset;
+        loop {
+            let next_bpm = self.out_bpms.get(bpm_idx + 1).map(|bpm| bpm.beat);
+            let next_stop = pauses.get(stop_idx).map(|&(beat, _)| BeatPos::from(beat));
+            //Stops and control points are interleaved in beat order
+            let (next_beat, is_stop) = match (next_bpm, next_stop) {
+                (Some(bpm), Some(stop)) if bpm < stop => (bpm, false),
+                (_, Some(stop)) => (stop, true),
+                (Some(bpm), None) => (bpm, false),
+                (None, None) => break,
+            };
+            let next_time =
+                cur_time + (next_beat - cur_beat).as_num() * self.out_bpms[bpm_idx].beat_len;
+            if time < next_time {
+                break;
+            }
+            cur_beat = next_beat;
+            cur_time = next_time;
+            if is_stop {
+                let stop_len = pauses[stop_idx].1;
+                if time < cur_time + stop_len {
+                    return cur_beat;
+                }
+                cur_time += stop_len;
+                stop_idx += 1;
+            } else {
+                bpm_idx += 1;
+            }
+        }
+        cur_beat + BeatPos::from((time - cur_time) / self.out_bpms[bpm_idx].beat_len)
+    }
+
+    /// Find a pivot beat and a beat length to use from the pivot on, so that an off-grid timing
+    /// point falls on `tp_beat`.
+    ///
+    /// Returns `None` if there is no room for a pivot, or if the BPM would change too much.
+    fn find_correction(
+        &self,
+        tp_time: f64,
+        raw_beat_adv: f64,
+        tp_beat: BeatPos,
+        last_beat: BeatPos,
+    ) -> Option<(BeatPos, f64)> {
+        let pivot_max = self.cur_beat + BeatPos::from_num_ceil(raw_beat_adv);
+        let pivot = [
+            BeatPos::from(1.),
+            BeatPos::from(0.5),
+            BeatPos::from(0.25),
+            BeatPos::from(1. / 8.),
+            BeatPos::from(1. / 16.),
+            BeatPos::EPSILON,
+        ]
+        .iter()
+        .map(|&beat_gap| pivot_max.ceil(beat_gap) - beat_gap)
+        .find(|&pivot| pivot >= last_beat)?;
+        let target_time = tp_time - self.cur_time;
+        let time_to_pivot = (pivot - self.cur_beat).as_num() * self.cur_tp.beat_len;
+        let consume_time = target_time - time_to_pivot;
+        let consume_beats = tp_beat - pivot;
+        let beat_len = consume_time / consume_beats.as_num();
+        //Pivots past the timing point would need a negative BPM, which is rejected here too
+        let ratio = beat_len / self.cur_tp.beat_len;
+        if !(1. / MAX_CORRECTION_RATIO..=MAX_CORRECTION_RATIO).contains(&ratio) {
+            trace!(
+                "      rejected {}ms/beat bpm correction at beat {}",
+                beat_len,
+                pivot
+            );
+            return None;
+        }
+        Some((pivot, beat_len))
+    }
+
+    /// If the gap before an off-grid timing point is a pause, find where to stop (see
+    /// `stop_before`).
+    fn find_pause(
+        &self,
+        tp_time: f64,
+        raw_beat_adv: f64,
+        rounding: BeatPos,
+        last_beat: BeatPos,
+    ) -> Option<(BeatPos, BeatPos)> {
+        let min_gap = self.pause_gap?;
+        let last_time = self.cur_time + (last_beat - self.cur_beat).as_num() * self.cur_tp.beat_len;
+        if tp_time - last_time < min_gap {
+            return None;
+        }
+        self.stop_before(raw_beat_adv, rounding, last_beat)
+    }
+
+    /// Find the beat to place a stop at before an off-grid timing point, and the beat that the
+    /// timing point falls on after the stop.
+    fn stop_before(
+        &self,
+        raw_beat_adv: f64,
+        rounding: BeatPos,
+        last_beat: BeatPos,
+    ) -> Option<(BeatPos, BeatPos)> {
+        let tp_beat = (self.cur_beat + BeatPos::from_num_floor(raw_beat_adv))
+            .floor(rounding)
+            .max(self.cur_beat);
+        //Stop on a round beat before the timing point, since notes on the beat of a stop play
+        //before it
+        let stop_beat = [
+            BeatPos::from(1.),
+            BeatPos::from(0.5),
+            BeatPos::from(0.25),
+            BeatPos::from(1. / 8.),
+            BeatPos::from(1. / 16.),
+            BeatPos::EPSILON,
+        ]
+        .iter()
+        .map(|&beat_gap| tp_beat.ceil(beat_gap) - beat_gap)
+        .find(|&beat| beat >= last_beat)?;
+        Some((stop_beat, tp_beat))
+    }
+
+    /// Estimate how well synced the output is to the music, from 0 to 100.
+    fn sync_confidence(&self, audio_ok: bool) -> f64 {
+        let mut confidence = 100.;
+        //Up to 1ms of error is inaudible, over 20ms is quite noticeable
+        confidence -= ((self.max_error - 1.) * 2.5).clamp(0., 50.);
+        confidence -= (self.synthetic_bpms as f64 * 5.).min(25.);
+        if !audio_ok {
+            confidence -= 25.;
+        }
+        if self.retimed {
+            confidence -= 40.;
+        }
+        confidence.max(0.)
+    }
+
+    /// The average distance in milliseconds between osu! objects and their converted beats, or
+    /// `0` if there are no objects.
+    fn avg_error(&self) -> f64 {
+        if self.errors.is_empty() {
+            return 0.;
+        }
+        self.errors.iter().map(|err| err.abs()).sum::<f64>() / self.errors.len() as f64
+    }
+
+    /// Shift the offset so that the average distance between osu! objects and their converted
+    /// beats is minimal, instead of anchoring the grid to the first timing point.
+    fn reanchor(&mut self) {
+        if self.errors.is_empty() {
+            return;
+        }
+        //The median minimizes the average absolute error
+        let mut sorted = self.errors.clone();
+        sorted.sort_by_key(|&err| SortableFloat(err));
+        let shift = sorted[sorted.len() / 2];
+        if shift.abs() < 0.01 {
+            return;
+        }
+        let old_error = self.avg_error();
+        //A positive error means late notes, and a larger offset plays every note earlier
+        self.out_offset += shift / 1000.;
+        for err in self.errors.iter_mut() {
+            *err -= shift;
+        }
+        self.max_error = self.errors.iter().fold(0., |max, err| err.abs().max(max));
+        debug!(
+            "    re-anchored offset by {:.2}ms, average error {:.2}ms -> {:.2}ms",
+            -shift,
+            old_error,
+            self.avg_error()
+        );
     }
 
     /// Add an output note.
@@ -569,39 +1754,114 @@ impl ConvCtx<'_> {
 
     /// Output the final simfile in all supported gamemodes.
     fn finish(
-        self,
+        mut self,
         conf: &OsuLoad,
         bmset_cache: &mut BmsetCache,
-        bmset_path: &Path,
-        bm_path: &Path,
+        bmset_path: Option<&Path>,
+        bm_name: &str,
         bm: &Beatmap,
         key_count: i32,
         mut out: impl FnMut(Box<Simfile>),
     ) -> Result<()> {
+        //Make sure the grid anchoring worked
+        if let Some(note) = self
+            .out_notes
+            .iter()
+            .find(|note| note.beat < BeatPos::from(0.))
+        {
+            bail!(
+                "note at negative beat {} (key {}) after conversion",
+                note.beat,
+                note.key
+            );
+        }
+        //Make up audio for beatmaps without any
+        //Without filesystem access, just trust the beatmap
+        let has_audio = !bm.audio.trim().is_empty()
+            && bmset_path
+                .map(|base| base.join(&bm.audio).is_file())
+                .unwrap_or(true);
+        let (audio, generated) = match bmset_path {
+            Some(bmset_path) if !has_audio => {
+                let (name, file) = make_audio(conf, bmset_path, bm)?;
+                (name.clone(), vec![(name.into(), file)])
+            }
+            _ => (bm.audio.clone(), vec![]),
+        };
+        let mut audio_ok = has_audio;
         // Generate sample length from audio file
         let default_len = 60.;
-        let sample_len = if bm.audio.is_empty() || !conf.query_audio_len {
-            default_len
-        } else {
+        let mut music_len = None;
+        let sample_len = if !has_audio {
+            (song_len(bm) - bm.preview_start / 1000.).max(10.)
+        } else if let (Some(bmset_path), true) = (bmset_path, conf.query_audio_len) {
             let audio_path = bmset_path.join(&bm.audio);
             let (len, result) = bmset_cache.get_audio_len(&audio_path);
-            if let Err(err) = result {
-                warn!(
-                    "    failed to get full audio length for \"{}\": {:#}",
-                    audio_path.display(),
-                    err
-                );
+            match result {
+                Ok(()) => music_len = Some(len),
+                Err(err) => {
+                    audio_ok = false;
+                    warn!(
+                        "    failed to get full audio length for \"{}\": {:#}",
+                        audio_path.display(),
+                        err
+                    );
+                }
             }
             (len - bm.preview_start / 1000.).max(10.)
+        } else {
+            default_len
+        };
+        let music_hash = match bmset_path {
+            Some(bmset_path) if has_audio => {
+                bmset_cache.get_audio_hash(&bmset_path.join(&bm.audio))
+            }
+            _ => None,
+        };
+        if conf.reanchor {
+            self.reanchor();
+        }
+        let sync_confidence = self.sync_confidence(audio_ok);
+        let sync_error = self.avg_error();
+        trace!(
+            "    sync confidence {:.0} (average error {:.2}ms, max error {:.2}ms, {} synthetic control points)",
+            sync_confidence,
+            sync_error,
+            self.max_error,
+            self.synthetic_bpms
+        );
+        //Start the video on the beat that plays at its start time
+        let video = if conf.video && !bm.video.is_empty() {
+            let timing = Simfile {
+                offset: self.out_offset,
+                bpms: self.out_bpms.clone(),
+                stops: self.out_stops.clone(),
+                delays: self.out_delays.clone(),
+                ..default()
+            };
+            Some(Video {
+                path: bm.video.clone().into(),
+                beat: timing.time_to_beat(bm.video_start / 1000.).as_num(),
+                rate: 1.,
+            })
+        } else {
+            None
         };
         // Create the final SM file in all supported gamemodes
+        let star_rating = if conf.recompute_stars && bm.mode == osufile::MODE_MANIA {
+            crate::rating::mania_star_rating(&Simfile {
+                offset: self.out_offset,
+                bpms: self.out_bpms.clone(),
+                stops: self.out_stops.clone(),
+                delays: self.out_delays.clone(),
+                notes: self.out_notes.clone(),
+                ..default()
+            })
+        } else {
+            f64::NAN
+        };
         let mut at_least_one = false;
-        for gamemode in conf
-            .gamemodes
-            .iter()
-            .copied()
-            .filter(|gm| gm.key_count() == key_count as i32)
-        {
+        for gamemode in conf.gamemodes_for(key_count) {
             at_least_one = true;
             out(Box::new(Simfile {
                 title: if conf.unicode {
@@ -621,22 +1881,21 @@ impl ConvCtx<'_> {
                 genre: String::new(),
                 credit: bm.creator.clone(),
                 banner: None,
-                background: Some(
-                    if conf.video && !bm.video.is_empty() {
-                        Some(bm.video.clone().into())
-                    } else {
-                        None
-                    }
-                    .unwrap_or_else(|| bm.background.clone().into()),
-                ),
+                background: Some(bm.background.clone().into()),
+                video: video.clone(),
+                preview_video: None,
                 lyrics: None,
                 cdtitle: None,
-                music: Some(bm.audio.clone().into()),
+                music: Some(audio.clone().into()),
                 offset: self.out_offset,
                 bpms: self.out_bpms.clone(),
-                stops: vec![],
+                stops: self.out_stops.clone(),
+                delays: self.out_delays.clone(),
+                warps: vec![],
                 sample_start: Some(bm.preview_start / 1000.),
                 sample_len: Some(sample_len),
+                music_len,
+                music_hash,
                 display_bpm: if self.out_beatlen_range.0 == self.out_beatlen_range.1 {
                     DisplayBpm::Single(60000. / self.out_beatlen_range.0)
                 } else {
@@ -649,16 +1908,27 @@ impl ConvCtx<'_> {
                 },
                 gamemode,
                 desc: bm.version.clone(),
+                version: bm.version.clone(),
+                source_file: None,
+                spinners: self.out_spinners.clone(),
+                title_native: bm.title_unicode.clone(),
+                artist_native: bm.artist_unicode.clone(),
                 difficulty: Difficulty::Edit,
                 difficulty_num: f64::NAN,
                 radar: [0., 0., 0., 0., 0.],
+                sync_confidence,
+                sync_error,
+                star_rating,
+                ranked_status: None,
                 notes: self.out_notes.clone(),
+                extra_fields: vec![],
+                generated: generated.clone(),
             }));
         }
         if !at_least_one {
             warn!(
                 "  beatmap \"{}\" parsed correctly, but there are no compatible gamemodes with keycount {}",
-                bm_path.display(),
+                bm_name,
                 key_count
             );
         }
@@ -666,37 +1936,496 @@ impl ConvCtx<'_> {
     }
 }
 
+/// An estimate of the song length in seconds, from the beatmap hitobjects.
+fn song_len(bm: &Beatmap) -> f64 {
+    //Leave some room for long notes and sliders
+    bm.hit_objects
+        .last()
+        .map(|obj| obj.time / 1000.)
+        .unwrap_or(0.)
+        + 5.
+}
+
+/// Create an audio file for a beatmap without audio, according to `conf.no_audio`.
+/// Returns the name of the audio file, along with the path it was generated at (see
+/// `crate::generated_dir`).
+fn make_audio(conf: &OsuLoad, bmset_path: &Path, bm: &Beatmap) -> Result<(String, PathBuf)> {
+    if bm.audio.trim().is_empty() {
+        debug!("    beatmap has no audio file");
+    } else {
+        debug!("    beatmap audio file \"{}\" does not exist", bm.audio);
+    }
+    match conf.no_audio {
+        NoAudio::Skip => bail!("beatmap has no audio"),
+        NoAudio::Silent => {}
+        NoAudio::Mix => match mix_keysounds(conf, bmset_path, bm) {
+            Ok(audio) => return Ok(audio),
+            Err(err) => {
+                warn!("    failed to mix keysounds: {:#}", err);
+            }
+        },
+    }
+    warn!("    beatmap has no audio, using silence as music");
+    let name = "osu2sm-silence.wav";
+    let path = crate::generated_dir(bmset_path)?.join(name);
+    write_silence(&path, song_len(bm))?;
+    Ok((name.to_string(), path))
+}
+
+/// Write a silent `.wav` file of at least the given length in seconds.
+/// If the file already exists and is long enough, it is left as-is.
+fn write_silence(path: &Path, secs: f64) -> Result<()> {
+    //8-bit mono at 8KHz, the smallest reasonable format
+    const RATE: u32 = 8000;
+    const HEADER_LEN: u64 = 44;
+    let samples = (secs * RATE as f64).ceil() as u32;
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.len() >= HEADER_LEN + samples as u64 {
+            return Ok(());
+        }
+    }
+    trace!("    writing {}s of silence to \"{}\"", secs, path.display());
+    crate::write_atomic(path, false, |file| {
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + samples).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        //PCM format, 1 channel
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        //Sample rate, byte rate
+        file.write_all(&RATE.to_le_bytes())?;
+        file.write_all(&RATE.to_le_bytes())?;
+        //Block align, bits per sample
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&8u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&samples.to_le_bytes())?;
+        //8-bit samples are unsigned, so silence is at the midpoint
+        let chunk = [128u8; 4096];
+        let mut left = samples as usize;
+        while left > 0 {
+            let len = left.min(chunk.len());
+            file.write_all(&chunk[..len])?;
+            left -= len;
+        }
+        Ok(())
+    })
+    .with_context(|| anyhow!("failed to write silence to \"{}\"", path.display()))
+}
+
+/// Render the keysounds of all hitobjects into a single audio file using ffmpeg.
+/// Returns the name of the audio file, along with the path it was generated at.
+fn mix_keysounds(conf: &OsuLoad, bmset_path: &Path, bm: &Beatmap) -> Result<(String, PathBuf)> {
+    let name = format!(
+        "osu2sm-keysounds-{:016x}.ogg",
+        fxhash::hash64(&(&bm.title, &bm.version))
+    );
+    let dst = crate::generated_dir(bmset_path)?.join(&name);
+    if dst.exists() {
+        trace!("    mixed keysounds \"{}\" already exist", dst.display());
+        return Ok((name, dst));
+    }
+    //Gather the keysounds of every hitobject, grouped by sample file
+    let mut by_file: Vec<(&str, Vec<f64>)> = Vec::new();
+    let mut total = 0;
+    for obj in bm.hit_objects.iter() {
+        let file = match obj.sample_file() {
+            Some(file) => file,
+            None => continue,
+        };
+        if !bmset_path.join(file).is_file() {
+            continue;
+        }
+        let time = obj.time.max(0.);
+        match by_file.iter_mut().find(|(other, _)| *other == file) {
+            Some((_, times)) => times.push(time),
+            None => by_file.push((file, vec![time])),
+        }
+        total += 1;
+    }
+    ensure!(total > 0, "beatmap has no keysounds");
+    //Build an ffmpeg filter graph that delays every keysound to its time and mixes them all
+    //There may be thousands of keysounds, so pass the graph through a file
+    let mut graph = String::new();
+    let mut mix_inputs = String::new();
+    let mut cmd = Command::new(&conf.ffmpeg);
+    cmd.args(["-y", "-loglevel", "error"]);
+    for (input_idx, (file, times)) in by_file.iter().enumerate() {
+        cmd.arg("-i").arg(bmset_path.join(file));
+        write!(graph, "[{}]asplit={}", input_idx, times.len()).unwrap();
+        for time_idx in 0..times.len() {
+            write!(graph, "[s{}_{}]", input_idx, time_idx).unwrap();
+        }
+        graph.push_str(";\n");
+        for (time_idx, time) in times.iter().enumerate() {
+            writeln!(
+                graph,
+                "[s{0}_{1}]adelay={2}|{2}[d{0}_{1}];",
+                input_idx,
+                time_idx,
+                time.round()
+            )
+            .unwrap();
+            write!(mix_inputs, "[d{}_{}]", input_idx, time_idx).unwrap();
+        }
+    }
+    writeln!(
+        graph,
+        "{}amix=inputs={}:normalize=0[out]",
+        mix_inputs, total
+    )
+    .unwrap();
+    //Name the graph after the output, so that concurrent mixes never share a graph file
+    let graph_path = std::env::temp_dir().join(format!(
+        "osu2sm-mix-{}-{:016x}.txt",
+        std::process::id(),
+        fxhash::hash64(&dst)
+    ));
+    fs::write(&graph_path, graph).context("failed to write ffmpeg filter graph")?;
+    debug!(
+        "    mixing {} keysounds from {} files into \"{}\"",
+        total,
+        by_file.len(),
+        dst.display()
+    );
+    let result = crate::generate_atomic(&dst, |tmp| {
+        let status = cmd
+            .arg("-filter_complex_script")
+            .arg(&graph_path)
+            .args(["-map", "[out]"])
+            .arg(tmp)
+            .status()
+            .with_context(|| anyhow!("failed to run \"{}\"", conf.ffmpeg))?;
+        ensure!(status.success(), "ffmpeg exited with {}", status);
+        Ok(())
+    });
+    let _ = fs::remove_file(&graph_path);
+    result?;
+    Ok((name, dst))
+}
+
+#[cfg(feature = "fs")]
 fn process_beatmap(
     conf: &OsuLoad,
     bmset_cache: &mut BmsetCache,
     bmset_path: &Path,
     bm_path: &Path,
-    mut out: impl FnMut(usize, Box<Simfile>),
+    out: impl FnMut(usize, Box<Simfile>),
 ) -> Result<()> {
     let bm = Beatmap::parse(conf.offset, bm_path).context("read/parse beatmap file")?;
-    let mut conv = ConvCtx::new(conf, &bm)?;
+    convert_parsed(
+        conf,
+        bmset_cache,
+        Some(bmset_path),
+        &bm_path.display().to_string(),
+        &bm,
+        out,
+    )
+}
+
+/// Parse and convert a `.osu` file into simfiles.
+///
+/// Files referenced by the beatmap are looked up next to the `.osu` file, and audio is probed as
+/// configured in `conf`.
+/// Note that beatmaps without audio may get a made up audio file written into their folder (see
+/// `OsuLoad::no_audio`).
+#[cfg(feature = "fs")]
+pub fn convert_beatmap(path: &Path, conf: &OsuLoad) -> Result<Vec<Simfile>> {
+    let bm = Beatmap::parse(conf.offset, path).context("read/parse beatmap file")?;
+    let mut simfiles = Vec::new();
+    convert_parsed(
+        conf,
+        &mut BmsetCache::default(),
+        Some(path.parent().unwrap_or_else(|| Path::new(""))),
+        &path.display().to_string(),
+        &bm,
+        |_mode, sm| simfiles.push(*sm),
+    )?;
+    Ok(simfiles)
+}
+
+/// Parse and convert the contents of a `.osu` file into simfiles, without touching the
+/// filesystem.
+///
+/// Audio files are not probed, so the preview length is left at its default, and the audio file
+/// named by the beatmap is assumed to exist.
+/// All other conversion options in `conf` apply as usual.
+pub fn convert_beatmap_str(contents: &str, conf: &OsuLoad) -> Result<Vec<Simfile>> {
+    let bm = Beatmap::parse_str(conf.offset, contents).context("parse beatmap")?;
+    let mut simfiles = Vec::new();
+    convert_parsed(
+        conf,
+        &mut BmsetCache::default(),
+        None,
+        "<string>",
+        &bm,
+        |_mode, sm| simfiles.push(*sm),
+    )?;
+    Ok(simfiles)
+}
+
+/// Convert a parsed beatmap.
+/// If `bmset_path` is `None`, the filesystem is not accessed at all.
+fn convert_parsed(
+    conf: &OsuLoad,
+    bmset_cache: &mut BmsetCache,
+    bmset_path: Option<&Path>,
+    bm_name: &str,
+    bm: &Beatmap,
+    mut out: impl FnMut(usize, Box<Simfile>),
+) -> Result<()> {
+    if bm.set_id >= 0 {
+        bmset_cache.set_id.get_or_insert(bm.set_id);
+    }
+    if bm.hit_objects.len() < conf.min_notes {
+        info!(
+            "  skipping beatmap \"{}\", which has only {} hit objects",
+            bm_name,
+            bm.hit_objects.len()
+        );
+        return Ok(());
+    }
+    if conf.min_secs > 0. {
+        let secs = match (bm.hit_objects.first(), bm.hit_objects.last()) {
+            (Some(first), Some(last)) => (last.time - first.time) / 1000.,
+            _ => 0.,
+        };
+        if secs < conf.min_secs {
+            info!(
+                "  skipping beatmap \"{}\", which is only {:.1} seconds long",
+                bm_name, secs
+            );
+            return Ok(());
+        }
+    }
+    let broken_timing = if conf.retime.enable {
+        conf.retime.check(bm)
+    } else {
+        None
+    };
+    let retimed;
+    let mut was_retimed = false;
+    let bm = match broken_timing {
+        Some(reason) => match retime(conf, bmset_cache, bmset_path, bm) {
+            Ok(bm) => {
+                info!(
+                    "  retimed beatmap \"{}\" with broken timing ({}) to {:.2} BPM",
+                    bm_name,
+                    reason,
+                    60000. / bm.timing_points[0].beat_len
+                );
+                retimed = bm;
+                was_retimed = true;
+                &retimed
+            }
+            Err(err) => {
+                warn!(
+                    "  failed to retime beatmap \"{}\" with broken timing ({}): {:#}",
+                    bm_name, reason, err
+                );
+                bm
+            }
+        },
+        None => bm,
+    };
+    let mut joke = false;
+    if conf.joke.detect && !conf.joke.allow_sets.contains(&bm.set_id) {
+        let reasons = conf.joke.check(bm);
+        if !reasons.is_empty() {
+            if conf.joke.into.is_null() {
+                info!(
+                    "  skipping beatmap \"{}\", which looks like a joke map ({})",
+                    bm_name,
+                    reasons.join(", ")
+                );
+                return Ok(());
+            }
+            info!(
+                "  beatmap \"{}\" looks like a joke map ({})",
+                bm_name,
+                reasons.join(", ")
+            );
+            joke = true;
+        }
+    }
+    let mut conv = ConvCtx::new(conf, bm)?;
+    conv.retimed = was_retimed;
     let key_count = match bm.mode {
-        osufile::MODE_MANIA => process_mania(conf, &bm, &mut conv)?,
-        osufile::MODE_STD => process_standard(conf, &bm, &mut conv)?,
-        osufile::MODE_CATCH => bail!("mode not supported: catch the beat"),
-        osufile::MODE_TAIKO => bail!("mode not supported: taiko"),
+        osufile::MODE_MANIA => process_mania(conf, bm, &mut conv)?,
+        osufile::MODE_STD => process_standard(conf, bm, &mut conv)?,
+        osufile::MODE_CATCH => process_catch(conf, bm, &mut conv)?,
+        osufile::MODE_TAIKO => process_taiko(conf, bm, &mut conv)?,
         unknown => bail!("mode not supported: unknown osu! gamemode {}", unknown),
     };
     //Finish up
     if key_count != 0 {
+        let onset = if conf.onset.enable {
+            first_onset(conf, bmset_cache, bmset_path, bm)
+        } else {
+            None
+        };
         conv.finish(
             conf,
             bmset_cache,
             bmset_path,
-            bm_path,
-            &bm,
+            bm_name,
+            bm,
             key_count,
-            |sm| out(bm.mode as usize, sm),
+            |mut sm| {
+                if let Some(onset) = onset {
+                    sync_to_onset(conf, &mut sm, onset);
+                }
+                out(if joke { MODE_JOKE } else { bm.mode as usize }, sm)
+            },
         )?;
     }
     Ok(())
 }
 
+/// Find the first strong onset in the audio of a beatmap, in seconds.
+fn first_onset(
+    conf: &OsuLoad,
+    bmset_cache: &mut BmsetCache,
+    bmset_path: Option<&Path>,
+    bm: &Beatmap,
+) -> Option<f64> {
+    #[cfg(feature = "bpm-detect")]
+    {
+        let audio_path = bmset_path?.join(&bm.audio);
+        if bm.audio.is_empty() {
+            return None;
+        }
+        if let Some(onset) = bmset_cache.onset.get(&audio_path) {
+            return *onset;
+        }
+        let onset =
+            match crate::bpm::first_onset(&audio_path, conf.onset.max_secs, conf.onset.strength) {
+                Ok(Some(onset_ms)) => {
+                    debug!(
+                        "    first onset at {:.0}ms in \"{}\"",
+                        onset_ms,
+                        audio_path.display()
+                    );
+                    Some(onset_ms / 1000.)
+                }
+                Ok(None) => {
+                    debug!("    no onsets found in \"{}\"", audio_path.display());
+                    None
+                }
+                Err(err) => {
+                    warn!(
+                        "    failed to find the first onset of \"{}\": {:#}",
+                        audio_path.display(),
+                        err
+                    );
+                    None
+                }
+            };
+        bmset_cache.onset.insert(audio_path, onset);
+        onset
+    }
+    #[cfg(not(feature = "bpm-detect"))]
+    {
+        let _ = (conf, bmset_cache, bmset_path, bm);
+        None
+    }
+}
+
+/// Move the offset of a simfile so that the beat nearest to the given onset (in seconds) plays
+/// right on it, if it is close enough.
+fn sync_to_onset(conf: &OsuLoad, sm: &mut Simfile, onset: f64) {
+    let beat = sm.time_to_beat(onset).floor(BeatPos::from(1.));
+    let before = sm.beat_to_time().beat_to_time(beat);
+    let after = sm.beat_to_time().beat_to_time(beat + BeatPos::from(1.));
+    let nearest = if onset - before <= after - onset {
+        before
+    } else {
+        after
+    };
+    let error = onset - nearest;
+    if error.abs() * 1000. <= conf.onset.tolerance_ms {
+        trace!(
+            "    moving offset by {:+.0}ms to match the first onset",
+            error * 1000.
+        );
+        sm.offset -= error;
+    } else {
+        trace!(
+            "    leaving offset alone, since the first onset is {:+.0}ms away from the beat",
+            error * 1000.
+        );
+    }
+}
+
+/// Replace the timing of a beatmap with a single timing point, detected from its audio.
+/// Inherited timing points (slider velocity changes) are kept.
+fn retime(
+    conf: &OsuLoad,
+    bmset_cache: &mut BmsetCache,
+    bmset_path: Option<&Path>,
+    bm: &Beatmap,
+) -> Result<Beatmap> {
+    #[cfg(feature = "bpm-detect")]
+    {
+        let bmset_path =
+            bmset_path.ok_or_else(|| anyhow!("no audio available to detect the BPM"))?;
+        ensure!(!bm.audio.is_empty(), "beatmap has no audio");
+        let audio_path = bmset_path.join(&bm.audio);
+        let tempo = match bmset_cache.tempo.get(&audio_path) {
+            Some(tempo) => *tempo,
+            None => {
+                let tempo =
+                    crate::bpm::detect(&audio_path, conf.retime.detect_bpm, conf.retime.max_secs)
+                        .with_context(|| {
+                        anyhow!("failed to detect BPM of \"{}\"", audio_path.display())
+                    })?;
+                debug!(
+                    "    detected {:.2} BPM with offset {:.0}ms in \"{}\"",
+                    tempo.bpm,
+                    tempo.offset_ms,
+                    audio_path.display()
+                );
+                bmset_cache.tempo.insert(audio_path, tempo);
+                tempo
+            }
+        };
+        //Move the timing point back to the start, so that it precedes every other timing point
+        let beat_len = 60000. / tempo.bpm;
+        let mut time = tempo.offset_ms + bm.offset_ms;
+        let start = bm
+            .timing_points
+            .first()
+            .map(|tp| tp.time)
+            .unwrap_or(time)
+            .min(bm.hit_objects.first().map(|obj| obj.time).unwrap_or(time));
+        if time > start {
+            time -= ((time - start) / beat_len).ceil() * beat_len;
+        }
+        let mut timing_points = vec![TimingPoint {
+            time,
+            beat_len,
+            meter: 4,
+            omit_barline: false,
+        }];
+        timing_points.extend(
+            bm.timing_points
+                .iter()
+                .filter(|tp| tp.beat_len <= 0.)
+                .cloned(),
+        );
+        Ok(Beatmap {
+            timing_points,
+            ..bm.clone()
+        })
+    }
+    #[cfg(not(feature = "bpm-detect"))]
+    {
+        let _ = (conf, bmset_cache, bmset_path, bm);
+        bail!("retiming beatmaps requires the `bpm-detect` feature")
+    }
+}
+
 fn process_mania(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<i32> {
     let key_count = bm.circle_size.round();
     ensure!(
@@ -709,22 +2438,10 @@ fn process_mania(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<i32
         bm.hit_objects.len(),
         key_count
     );
-    //Keep track of pending long note tails, and add them when it's time
-    let mut pending_tails = Vec::new();
+    //Long note tails are placed once the whole grid is known, since they end out of order
+    let mut tails = Vec::new();
     //Go through every osu! hit object
     for obj in bm.hit_objects.iter() {
-        //Insert any pending long note tails
-        pending_tails.retain(|&(time, key)| {
-            if time <= obj.time {
-                //Insert now
-                let end_beat = conv.get_beat(time);
-                conv.push_note(end_beat, key, Note::KIND_TAIL);
-                false
-            } else {
-                //Keep waiting
-                true
-            }
-        });
         //Get data for this object
         let obj_beat = conv.get_beat(obj.time);
         let obj_key = (obj.x * key_count / 512.).floor();
@@ -752,12 +2469,8 @@ fn process_mania(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<i32
                     )
                 })?
                 + bm.offset_ms;
-            //Leave it for later insertion at the correct time
-            let insert_idx = pending_tails
-                .iter()
-                .position(|(t, _)| *t > end_time)
-                .unwrap_or(pending_tails.len());
-            pending_tails.insert(insert_idx, (end_time, obj_key));
+            //Leave it for later insertion
+            tails.push((end_time, obj_key));
             //Insert the long note head
             conv.push_note(obj_beat, obj_key, Note::KIND_HEAD);
         } else if obj.ty & osufile::TYPE_HIT != 0 {
@@ -765,39 +2478,29 @@ fn process_mania(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<i32
             conv.push_note(obj_beat, obj_key, Note::KIND_HIT);
         }
     }
-    // Push out any pending long note tails
-    for (time, key) in pending_tails {
-        let end_beat = conv.get_beat(time);
+    //Place the long note tails on the final grid
+    let last_tail = tails
+        .iter()
+        .map(|&(time, _)| time)
+        .fold(f64::NEG_INFINITY, f64::max);
+    if last_tail.is_finite() {
+        conv.advance(last_tail);
+    }
+    for (time, key) in tails {
+        let end_beat = conv.lookup_beat(time);
         conv.push_note(end_beat, key, Note::KIND_TAIL);
     }
+    //Tails go before any head on the same beat, as if a key was released before pressing it again
+    conv.out_notes
+        .sort_by_key(|note| (note.beat, !note.is_tail()));
     //Check precision
     if conf.mania.check_error {
         let sm = Simfile {
-            title: default(),
-            artist: default(),
-            subtitle: default(),
-            title_trans: default(),
-            artist_trans: default(),
-            subtitle_trans: default(),
-            genre: default(),
-            credit: default(),
-            banner: default(),
-            background: default(),
-            lyrics: default(),
-            cdtitle: default(),
-            music: default(),
             offset: conv.out_offset,
             bpms: conv.out_bpms.clone(),
-            stops: default(),
-            sample_start: default(),
-            sample_len: default(),
-            display_bpm: DisplayBpm::Random,
-            gamemode: Gamemode::DanceSingle,
-            desc: default(),
-            difficulty: Difficulty::Edit,
-            difficulty_num: f64::NAN,
-            radar: default(),
-            notes: vec![],
+            stops: conv.out_stops.clone(),
+            delays: conv.out_delays.clone(),
+            ..default()
         };
         let mut notes = conv.out_notes.clone();
         let mut check_dist = |key: i32, kind: char, time: f64| -> Result<f64> {
@@ -899,6 +2602,31 @@ fn process_standard(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<
         key_count
     );
 
+    //Prefer the columns closest to hit objects, if the layout of the columns is known
+    let layout = conf
+        .gamemodes_for(key_count as i32)
+        .into_iter()
+        .find_map(|gamemode| {
+            conf.standard
+                .layouts
+                .iter()
+                .find(|(gm, _)| *gm == gamemode)
+                .map(|(_, layout)| &layout[..])
+        });
+    let falloff = conf.standard.layout_falloff;
+    let column_bias = |pos: (f64, f64)| {
+        move |key: usize| -> f32 {
+            match layout {
+                Some(layout) => {
+                    //The osu! playfield is 512x384 osu!pixels
+                    let (dx, dy) = (pos.0 / 512. - layout[key].0, pos.1 / 384. - layout[key].1);
+                    (1. / (1. + (dx * dx + dy * dy) / (falloff * falloff))) as f32
+                }
+                None => 1.,
+            }
+        }
+    };
+
     let get_key_count = |last_pos: Option<(f64, f64)>, cur_pos: (f64, f64)| -> usize {
         let (x, y) = cur_pos;
         let (last_x, last_y) = last_pos.unwrap_or(cur_pos);
@@ -923,9 +2651,12 @@ fn process_standard(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<
                 tmp_choose_vec.clear();
                 tmp_choose_vec.extend(0..key_count);
                 for _ in 0..keys {
-                    if let Some((pos, out_key)) =
-                        key_alloc.alloc_idx(&tmp_choose_vec, obj.time / 1000., &mut rng)
-                    {
+                    if let Some((pos, out_key)) = key_alloc.alloc_idx_biased(
+                        &tmp_choose_vec,
+                        obj.time / 1000.,
+                        &mut rng,
+                        column_bias((obj.x, obj.y)),
+                    ) {
                         tmp_choose_vec.swap_remove(pos);
                         conv.push_note(beat, out_key as i32, Note::KIND_HIT);
                     } else {
@@ -975,10 +2706,11 @@ fn process_standard(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<
                     tmp_choose_vec.extend(0..key_count);
                     let mut available_keys = key_count;
                     for _ in 0..keys {
-                        if let Some((pos, out_key)) = key_alloc.alloc_idx(
+                        if let Some((pos, out_key)) = key_alloc.alloc_idx_biased(
                             &tmp_choose_vec[..available_keys],
                             obj.time / 1000.,
                             &mut rng,
+                            column_bias((obj.x, obj.y)),
                         ) {
                             tmp_choose_vec[pos..].rotate_left(1);
                             available_keys -= 1;
@@ -1046,6 +2778,7 @@ fn process_standard(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<
                 })?
                 + bm.offset_ms;
             let end_beat = conv.get_beat(end_time);
+            conv.out_spinners.push((beat, end_beat));
             //Taken from the osu! wiki
             let spins_per_sec = if bm.overall_difficulty < 5. {
                 5. - 2. * (5. - bm.overall_difficulty) / 5.
@@ -1081,3 +2814,298 @@ fn process_standard(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<
 
     Ok(key_count as i32)
 }
+
+fn process_taiko(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<i32> {
+    let key_count = conf.taiko.keycount;
+    if key_count == 0 {
+        //Disable the taiko parser
+        return Ok(0);
+    }
+    ensure!(
+        key_count > 0 && key_count % 2 == 0,
+        "taiko keycount must be a positive even number"
+    );
+    ensure!(
+        conf.taiko.roll_tick > 0.,
+        "taiko roll tick must be a positive amount of beats"
+    );
+    trace!(
+        "    processing {} osu!taiko hitobjects into {}K simfile",
+        bm.hit_objects.len(),
+        key_count
+    );
+
+    //The keys of each hand (left, right) and color (don, kat), from the inner keys outwards
+    let half = key_count / 2;
+    let mut layout: [[Vec<i32>; 2]; 2] = default();
+    for (hand, keys) in [
+        (0..half).rev().collect::<Vec<_>>(),
+        (key_count - half..key_count).collect::<Vec<_>>(),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let dons = keys.len() - keys.len() / 2;
+        layout[hand][0] = keys[..dons].to_vec();
+        layout[hand][1] = if dons < keys.len() {
+            keys[dons..].to_vec()
+        } else {
+            keys.clone()
+        };
+    }
+    //Alternate hands, and cycle through the keys of each hand and color
+    let mut hand = 0;
+    let mut cycle = [[0; 2]; 2];
+    let mut next_key = |hand: usize, kat: bool| -> i32 {
+        let keys = &layout[hand][kat as usize];
+        let idx = &mut cycle[hand][kat as usize];
+        let key = keys[*idx % keys.len()];
+        *idx += 1;
+        key
+    };
+    let roll_tick = BeatPos::from(conf.taiko.roll_tick);
+
+    for obj in bm.hit_objects.iter() {
+        let beat = conv.get_beat(obj.time);
+        let kat = obj.hitsound & (osufile::HITSOUND_WHISTLE | osufile::HITSOUND_CLAP) != 0;
+        let big = obj.hitsound & osufile::HITSOUND_FINISH != 0;
+        //Figure out the end of drumrolls and spinners
+        let end_time = if obj.ty & osufile::TYPE_SLIDER != 0 {
+            let mut extras = obj.extras.split(',');
+            let _curve = extras.next();
+            let slides = extras
+                .next()
+                .unwrap_or_default()
+                .parse::<i32>()
+                .map_err(|_| {
+                    anyhow!(
+                        "invalid drumroll extras \"{}\", expected slides",
+                        obj.extras
+                    )
+                })?
+                .max(1);
+            let length_pixels = extras
+                .next()
+                .unwrap_or_default()
+                .parse::<f64>()
+                .map_err(|_| {
+                    anyhow!(
+                        "invalid drumroll extras \"{}\", expected length",
+                        obj.extras
+                    )
+                })?;
+            //Same as osu!standard sliders
+            Some(
+                obj.time
+                    + slides as f64 * length_pixels / (100. * bm.slider_multiplier)
+                        * (conv.cur_tp.beat_len * conv.inherited_multiplier),
+            )
+        } else if obj.ty & osufile::TYPE_SPINNER != 0 {
+            Some(
+                obj.extras
+                    .split(',')
+                    .next()
+                    .unwrap_or_default()
+                    .parse::<f64>()
+                    .map_err(|_| {
+                        anyhow!(
+                            "invalid spinner extras \"{}\", expected endTime",
+                            obj.extras
+                        )
+                    })?
+                    + bm.offset_ms,
+            )
+        } else {
+            None
+        };
+        match end_time {
+            None => {
+                //Dons and kats, with big notes hit by both hands at once
+                if big {
+                    conv.push_note(beat, next_key(0, kat), Note::KIND_HIT);
+                    conv.push_note(beat, next_key(1, kat), Note::KIND_HIT);
+                } else {
+                    conv.push_note(beat, next_key(hand, kat), Note::KIND_HIT);
+                    hand = 1 - hand;
+                }
+            }
+            Some(end_time) => {
+                let end_beat = conv.get_beat(end_time).max(beat);
+                let spinner = obj.ty & osufile::TYPE_SPINNER != 0;
+                if spinner {
+                    conv.out_spinners.push((beat, end_beat));
+                }
+                if conf.taiko.roll_holds && end_beat > beat {
+                    //Drumrolls are held with one hand, spinners with both
+                    let hands: &[usize] = if spinner { &[0, 1] } else { &[hand] };
+                    let keys = hands
+                        .iter()
+                        .map(|&hand| next_key(hand, false))
+                        .collect::<Vec<_>>();
+                    for &key in keys.iter() {
+                        conv.push_note(beat, key, Note::KIND_HEAD);
+                    }
+                    for &key in keys.iter() {
+                        conv.push_note(end_beat, key, Note::KIND_TAIL);
+                    }
+                    if !spinner {
+                        hand = 1 - hand;
+                    }
+                } else {
+                    //Alternate hands, and also colors for spinners
+                    let mut tick_beat = beat;
+                    let mut tick_kat = false;
+                    while tick_beat <= end_beat {
+                        conv.push_note(tick_beat, next_key(hand, tick_kat), Note::KIND_HIT);
+                        hand = 1 - hand;
+                        if spinner {
+                            tick_kat = !tick_kat;
+                        }
+                        tick_beat += roll_tick;
+                    }
+                }
+            }
+        }
+    }
+    //Overlapping drumrolls in broken beatmaps could leave notes out of order
+    conv.out_notes.sort_by_key(|note| note.beat);
+
+    Ok(key_count)
+}
+
+fn process_catch(conf: &OsuLoad, bm: &Beatmap, conv: &mut ConvCtx) -> Result<i32> {
+    let key_count = conf.catch.keycount;
+    if key_count == 0 {
+        //Disable the catch parser
+        return Ok(0);
+    }
+    ensure!(key_count > 0, "keycount must be positive");
+    ensure!(
+        conf.catch.droplet_tick >= 0.,
+        "catch droplet tick must be a positive amount of beats"
+    );
+    ensure!(
+        conf.catch.banana_tick > 0.,
+        "catch banana tick must be a positive amount of beats"
+    );
+    trace!(
+        "    processing {} osu!catch hitobjects into {}K simfile",
+        bm.hit_objects.len(),
+        key_count
+    );
+
+    //Same as the mania parser, but fruits may lie at the very edge of the playfield
+    let get_key =
+        |x: f64| -> i32 { ((x * key_count as f64 / 512.).floor() as i32).clamp(0, key_count - 1) };
+
+    for obj in bm.hit_objects.iter() {
+        let beat = conv.get_beat(obj.time);
+        if obj.ty & osufile::TYPE_HIT != 0 {
+            //Fruit
+            conv.push_note(beat, get_key(obj.x), Note::KIND_HIT);
+        } else if obj.ty & osufile::TYPE_SLIDER != 0 {
+            //Juice stream
+            let mut extras = obj.extras.split(',');
+            let curve = extras.next().unwrap_or_default();
+            let slides = extras
+                .next()
+                .unwrap_or_default()
+                .parse::<i32>()
+                .map_err(|_| {
+                    anyhow!(
+                        "invalid juice stream extras \"{}\", expected slides",
+                        obj.extras
+                    )
+                })?
+                .max(1);
+            let length_pixels = extras
+                .next()
+                .unwrap_or_default()
+                .parse::<f64>()
+                .map_err(|_| {
+                    anyhow!(
+                        "invalid juice stream extras \"{}\", expected length",
+                        obj.extras
+                    )
+                })?;
+            //Same as osu!standard sliders
+            let end_time = obj.time
+                + slides as f64 * length_pixels / (100. * bm.slider_multiplier)
+                    * (conv.cur_tp.beat_len * conv.inherited_multiplier);
+            let end_beat = conv.get_beat(end_time).max(beat);
+            //Use the last control point as the end of the stream, like osu!standard sliders
+            let mut curve = curve.split('|');
+            let _curve_ty = curve.next();
+            let last_point = curve.next_back().unwrap_or_default();
+            let end_x = last_point
+                .split(':')
+                .next()
+                .unwrap_or_default()
+                .parse::<f64>()
+                .map_err(|_| {
+                    anyhow!("invalid juice stream point \"{}\", expected x", last_point)
+                })?;
+            //Walk the stream, bouncing back and forth between its ends
+            let slide_len = (end_beat - beat).as_num() / slides as f64;
+            for slide_idx in 0..slides {
+                let (from_x, to_x) = if slide_idx % 2 == 0 {
+                    (obj.x, end_x)
+                } else {
+                    (end_x, obj.x)
+                };
+                let slide_start = beat.as_num() + slide_idx as f64 * slide_len;
+                conv.push_note(BeatPos::from(slide_start), get_key(from_x), Note::KIND_HIT);
+                if conf.catch.droplet_tick > 0. {
+                    let mut offset = conf.catch.droplet_tick;
+                    while offset < slide_len {
+                        let x = from_x + (to_x - from_x) * offset / slide_len;
+                        conv.push_note(
+                            BeatPos::from(slide_start + offset),
+                            get_key(x),
+                            Note::KIND_HIT,
+                        );
+                        offset += conf.catch.droplet_tick;
+                    }
+                }
+            }
+            let last_x = if slides % 2 == 1 { end_x } else { obj.x };
+            conv.push_note(end_beat, get_key(last_x), Note::KIND_HIT);
+        } else if obj.ty & osufile::TYPE_SPINNER != 0 && conf.catch.bananas {
+            //Banana shower
+            let end_time = obj
+                .extras
+                .split(',')
+                .next()
+                .unwrap_or_default()
+                .parse::<f64>()
+                .map_err(|_| {
+                    anyhow!(
+                        "invalid banana shower extras \"{}\", expected endTime",
+                        obj.extras
+                    )
+                })?
+                + bm.offset_ms;
+            let end_beat = conv.get_beat(end_time);
+            conv.out_spinners.push((beat, end_beat));
+            let banana_tick = BeatPos::from(conf.catch.banana_tick);
+            let mut key = 0;
+            let mut dir = 1;
+            let mut next_beat = beat;
+            while next_beat <= end_beat {
+                conv.push_note(next_beat, key, Note::KIND_HIT);
+                next_beat += banana_tick;
+                if key + dir < 0 || key + dir >= key_count {
+                    dir = -dir;
+                }
+                key = (key + dir).clamp(0, key_count - 1);
+            }
+        }
+    }
+    //Droplets snapped to the same row as a fruit would duplicate it
+    //Sort by key too, so that duplicates end up next to each other even in chords
+    conv.out_notes.sort_by_key(|note| (note.beat, note.key));
+    conv.out_notes
+        .dedup_by(|a, b| a.beat == b.beat && a.key == b.key);
+
+    Ok(key_count)
+}
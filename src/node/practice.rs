@@ -0,0 +1,164 @@
+//! Extract sections of simfiles into separate practice charts, so that players can drill the
+//! difficult parts of a chart.
+
+use crate::{node::prelude::*, rating::RateMethod};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Practice {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// Which sections to extract.
+    ///
+    /// Each section is output as an `Edit` chart with the same timing as the original chart, but
+    /// only containing the notes within the section.
+    pub sections: PracticeSections,
+    /// Text to append to the description of practice charts.
+    pub suffix: String,
+    /// Whether to output the original simfiles too, alongside the practice charts.
+    pub keep_original: bool,
+}
+impl Default for Practice {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            sections: PracticeSections::Hardest {
+                length: 30.,
+                method: default(),
+            },
+            suffix: " (practice)".into(),
+            keep_original: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PracticeSections {
+    /// Extract the given `(start, end)` beat ranges (inclusive), each into a separate chart.
+    Beats(Vec<(f64, f64)>),
+    /// Extract the section lasting `length` seconds with the highest rating, according to the
+    /// given rating method (see the `Rate` node).
+    Hardest { length: f64, method: RateMethod },
+}
+
+impl Node for Practice {
+    fn prepare(&mut self) -> Result<()> {
+        if let PracticeSections::Hardest { length, .. } = &self.sections {
+            ensure!(
+                *length > 0.,
+                "practice section length must be a positive amount of seconds"
+            );
+        }
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            let mut out = Vec::with_capacity(list.len() * 2);
+            for sm in list.drain(..) {
+                for (start, end) in find_sections(self, &sm) {
+                    if let Some(practice) = make_practice(self, &sm, start, end) {
+                        out.push(practice);
+                    }
+                }
+                if self.keep_original {
+                    out.push(sm);
+                }
+            }
+            store.put(&self.into, out);
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+fn find_sections(conf: &Practice, sm: &Simfile) -> Vec<(BeatPos, BeatPos)> {
+    match &conf.sections {
+        PracticeSections::Beats(ranges) => ranges
+            .iter()
+            .map(|&(start, end)| (BeatPos::from_num_ceil(start), BeatPos::from_num_floor(end)))
+            .collect(),
+        PracticeSections::Hardest { length, method } => {
+            find_hardest(sm, *length, method).into_iter().collect()
+        }
+    }
+}
+
+/// Slide a window of `length` seconds over the notes, and find the window with the highest
+/// rating.
+fn find_hardest(sm: &Simfile, length: f64, method: &RateMethod) -> Option<(BeatPos, BeatPos)> {
+    let mut to_time = sm.beat_to_time();
+    let heads = sm
+        .notes
+        .iter()
+        .filter(|note| !note.is_tail() && !note.is_mine())
+        .map(|note| (note.beat, to_time.beat_to_time(note.beat)))
+        .collect::<Vec<_>>();
+    let mut best: Option<(f64, (BeatPos, BeatPos))> = None;
+    let mut window = Simfile {
+        offset: sm.offset,
+        bpms: sm.bpms.clone(),
+        stops: sm.stops.clone(),
+        delays: sm.delays.clone(),
+        warps: sm.warps.clone(),
+        ..default()
+    };
+    //Rating a window starting at every single note takes quadratic time on long charts, so
+    //windows start at least a fraction of their length apart instead
+    let step = length / 16.;
+    let mut next_start = f64::NEG_INFINITY;
+    let mut end = 0;
+    for start in 0..heads.len() {
+        if heads[start].1 < next_start {
+            continue;
+        }
+        next_start = heads[start].1 + step;
+        while end < heads.len() && heads[end].1 < heads[start].1 + length {
+            end += 1;
+        }
+        let range = (heads[start].0, heads[end - 1].0);
+        window.notes = sm.slice_beats(range.0, range.1);
+        let rating = crate::rating::compute(method, &window);
+        if best.map(|(best, _)| rating > best).unwrap_or(true) {
+            best = Some((rating, range));
+        }
+        if end >= heads.len() {
+            //All further windows are contained within this one
+            break;
+        }
+    }
+    best.map(|(rating, range)| {
+        trace!(
+            "    hardest section is beats {} to {} (rated {})",
+            range.0,
+            range.1,
+            rating
+        );
+        range
+    })
+}
+
+fn make_practice(
+    conf: &Practice,
+    sm: &Simfile,
+    start: BeatPos,
+    end: BeatPos,
+) -> Option<Box<Simfile>> {
+    let notes = sm.slice_beats(start, end);
+    if notes.is_empty() {
+        return None;
+    }
+    let mut to_time = sm.beat_to_time();
+    Some(Box::new(Simfile {
+        desc: format!("{}{}", sm.desc, conf.suffix),
+        difficulty: Difficulty::Edit,
+        sample_start: Some(to_time.beat_to_time(start)),
+        notes,
+        ..sm.clone()
+    }))
+}
@@ -0,0 +1,121 @@
+//! Generate short preview charts, covering only the preview section of a song (usually the first
+//! chorus), for themes that use them to quickly preview songs.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preview {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The length of the preview chart in seconds, if the simfile has no sample length.
+    pub length: f64,
+    /// Where the preview chart starts, as a fraction of the chart length, if the simfile has no
+    /// sample start.
+    pub fallback_start: f64,
+    /// Whether to always use `length`, even if the simfile has a sample length.
+    pub force_length: bool,
+    /// The difficulty of preview charts.
+    /// Usually `Beginner` or `Edit`.
+    pub difficulty: Difficulty,
+    /// The description of preview charts, which themes use to recognize them.
+    /// `{desc}` is replaced by the description of the source chart.
+    pub desc: String,
+}
+impl Default for Preview {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            length: 20.,
+            fallback_start: 0.4,
+            force_length: false,
+            difficulty: Difficulty::Beginner,
+            desc: "Preview".into(),
+        }
+    }
+}
+
+impl Node for Preview {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            self.length > 0.,
+            "preview length must be a positive amount of seconds"
+        );
+        ensure!(
+            (0. ..=1.).contains(&self.fallback_start),
+            "preview fallback start {} is not between 0 and 1",
+            self.fallback_start
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            //Make a single preview for each song and gamemode, out of its hardest chart
+            let mut hardest: HashMap<(Gamemode, Option<&Path>), usize> = default();
+            for (idx, sm) in list.iter().enumerate() {
+                let best = hardest
+                    .entry((sm.gamemode, sm.music.as_deref()))
+                    .or_insert(idx);
+                if sm.difficulty_naive() > list[*best].difficulty_naive() {
+                    *best = idx;
+                }
+            }
+            let mut hardest = hardest.into_values().collect::<Vec<_>>();
+            hardest.sort_unstable();
+            let out = hardest
+                .into_iter()
+                .filter_map(|idx| make_preview(self, &list[idx]))
+                .collect::<Vec<_>>();
+            list.extend(out);
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+fn make_preview(conf: &Preview, sm: &Simfile) -> Option<Box<Simfile>> {
+    let start = match sm.sample_start {
+        Some(start) => start,
+        None => {
+            let last = sm.notes.last()?;
+            let end = sm.beat_to_time().beat_to_time(last.beat);
+            end * conf.fallback_start
+        }
+    };
+    let length = match sm.sample_len {
+        Some(len) if len > 0. && !conf.force_length => len,
+        _ => conf.length,
+    };
+    let notes = sm.slice_time(start, start + length);
+    if notes.is_empty() {
+        debug!(
+            "    no notes in the preview section of \"{}\" ({:.1}s to {:.1}s)",
+            sm.desc,
+            start,
+            start + length
+        );
+        return None;
+    }
+    trace!(
+        "    generated preview of \"{}\" with {} notes ({:.1}s to {:.1}s)",
+        sm.desc,
+        notes.len(),
+        start,
+        start + length
+    );
+    Some(Box::new(Simfile {
+        desc: conf.desc.replace("{desc}", &sm.desc),
+        difficulty: conf.difficulty,
+        sample_start: Some(start),
+        sample_len: Some(length),
+        notes,
+        ..sm.clone()
+    }))
+}
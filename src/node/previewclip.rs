@@ -0,0 +1,184 @@
+//! Attach short preview videos to simfiles, for forks that play them in the song wheel (see
+//! `SimfileWrite::compat`).
+
+use crate::node::prelude::*;
+use std::process::Command;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreviewClip {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The length of the clips cut out of background videos, in seconds.
+    pub length: f64,
+    /// Background videos up to this many seconds long are used whole as preview videos, instead
+    /// of cutting a clip out of them.
+    pub max_whole: f64,
+    /// Whether to cut clips out of longer background videos.
+    /// If disabled, only short videos are used.
+    pub cut: bool,
+    /// The ffmpeg executable to use to probe and cut videos.
+    pub ffmpeg: String,
+}
+impl Default for PreviewClip {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            length: 15.,
+            max_whole: 20.,
+            cut: true,
+            ffmpeg: "ffmpeg".into(),
+        }
+    }
+}
+
+impl Node for PreviewClip {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            self.length.is_finite() && self.length > 0.,
+            "clip length must be a positive number"
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let base = store.global_get("base").map(PathBuf::from);
+        store.get(&self.from, |store, list| {
+            if let Some(base) = &base {
+                //Charts of the same video and preview share the same clip
+                let mut clips: Vec<((PathBuf, i64), Option<Clip>)> = Vec::new();
+                for sm in list.iter_mut() {
+                    if sm.preview_video.is_some() {
+                        continue;
+                    }
+                    let (video, start) = match &sm.video {
+                        Some(video) => {
+                            let video_start = sm.beat_to_time().beat_to_time(video.beat.into());
+                            let start = sm.sample_start.unwrap_or(0.) - video_start;
+                            (video.path.clone(), start.max(0.))
+                        }
+                        None => continue,
+                    };
+                    let key = (video, (start * 1000.).round() as i64);
+                    let clip = match clips.iter().find(|(other, _)| *other == key) {
+                        Some((_, clip)) => clip.clone(),
+                        None => {
+                            let src = sm.dep_source(base, &key.0);
+                            let clip = match preview_clip(self, base, &key.0, &src, start) {
+                                Ok(clip) => clip,
+                                Err(err) => {
+                                    warn!(
+                                        "    failed to make preview video out of \"{}\": {:#}",
+                                        key.0.display(),
+                                        err
+                                    );
+                                    None
+                                }
+                            };
+                            clips.push((key, clip.clone()));
+                            clip
+                        }
+                    };
+                    if let Some((rel, generated)) = clip {
+                        if let Some(file) = generated {
+                            sm.set_generated(rel.clone(), file);
+                        }
+                        sm.preview_video = Some(rel);
+                    }
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+/// A preview video path relative to the beatmapset folder, along with the path it was generated at
+/// if it was cut out of a longer video (see `crate::generated_dir`).
+type Clip = (PathBuf, Option<PathBuf>);
+
+/// Get a preview video for the given background video (read from `src`), starting roughly
+/// `start` seconds into it.
+/// Returns `None` if the video is too long and clips should not be cut.
+fn preview_clip(
+    conf: &PreviewClip,
+    base: &Path,
+    video: &Path,
+    src: &Path,
+    start: f64,
+) -> Result<Option<Clip>> {
+    ensure!(src.is_file(), "video file not found");
+    let len = video_duration(&conf.ffmpeg, src)?;
+    if len <= conf.max_whole {
+        trace!("    using short video \"{}\" as preview", video.display());
+        return Ok(Some((video.to_path_buf(), None)));
+    }
+    if !conf.cut {
+        return Ok(None);
+    }
+    //Keep the clip within the video
+    let start = start.min(len - conf.length).max(0.);
+    let name = format!(
+        "osu2sm-preview-{}-{}.mp4",
+        video.file_stem().unwrap_or_default().to_string_lossy(),
+        (start * 1000.).round(),
+    );
+    let rel_out = video.with_file_name(&name);
+    let dst = crate::generated_dir(base)?.join(&name);
+    if dst.exists() {
+        trace!("    preview video \"{}\" already exists", dst.display());
+        return Ok(Some((rel_out, Some(dst))));
+    }
+    debug!(
+        "    cutting preview video \"{}\" out of \"{}\" at {:.2}s",
+        dst.display(),
+        src.display(),
+        start
+    );
+    crate::generate_atomic(&dst, |tmp| {
+        let status = Command::new(&conf.ffmpeg)
+            .args(["-y", "-loglevel", "error", "-ss"])
+            .arg(start.to_string())
+            .arg("-i")
+            .arg(src)
+            .arg("-t")
+            .arg(conf.length.to_string())
+            .arg("-an")
+            .arg(tmp)
+            .status()
+            .with_context(|| anyhow!("failed to run \"{}\"", conf.ffmpeg))?;
+        ensure!(status.success(), "ffmpeg exited with {}", status);
+        Ok(())
+    })?;
+    Ok(Some((rel_out, Some(dst))))
+}
+
+/// Get the duration of a video in seconds, from the `Duration: HH:MM:SS.ss` line that ffmpeg
+/// prints when given an input without outputs.
+fn video_duration(ffmpeg: &str, path: &Path) -> Result<f64> {
+    let output = Command::new(ffmpeg)
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(path)
+        .output()
+        .with_context(|| anyhow!("failed to run \"{}\"", ffmpeg))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let dur = stderr
+        .lines()
+        .find_map(|line| {
+            let rest = line.trim().strip_prefix("Duration:")?;
+            let mut secs = 0.;
+            for part in rest.split(',').next()?.trim().split(':') {
+                secs = secs * 60. + part.parse::<f64>().ok()?;
+            }
+            Some(secs)
+        })
+        .ok_or_else(|| anyhow!("ffmpeg did not report a duration"))?;
+    Ok(dur)
+}
@@ -0,0 +1,186 @@
+//! Takes a bunch of simfiles as input and writes them out as Quaver `.qua` charts.
+
+use crate::node::{
+    prelude::*,
+    simfilewrite::{ChartFolder, CopyMethod},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuaWrite {
+    pub from: BucketId,
+    /// The path to the output directory (usually the Quaver `Songs` folder).
+    /// Every beatmapset is written into its own mapset folder within it.
+    pub output: String,
+    /// Which methods to try for copying "dependency" files, such as `.mp3` and `.jpg` files.
+    pub copy: Vec<CopyMethod>,
+    /// Whether to flush every chart to disk before moving it into place.
+    pub fsync: bool,
+}
+
+impl Default for QuaWrite {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            output: "".into(),
+            copy: SimfileWrite::default().copy,
+            fsync: false,
+        }
+    }
+}
+
+impl Node for QuaWrite {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            !self.output.is_empty(),
+            "no output folder to write Quaver charts into"
+        );
+        info!("outputting Quaver charts in \"{}\"", self.output);
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let mut folder = ChartFolder::new(store, &self.output)?;
+        store.get_each(&self.from, |_, mut sm| {
+            let mode = match sm.gamemode.key_count() {
+                4 => "Keys4",
+                7 => "Keys7",
+                keys => {
+                    debug!(
+                        "  skipping {}K chart \"{}\", since Quaver only supports 4K and 7K",
+                        keys, sm.desc
+                    );
+                    return Ok(());
+                }
+            };
+            sm.fix_tails()?;
+            let out_path = folder.chart_path(&sm, "qua")?;
+            debug!("  writing Quaver chart to \"{}\"", out_path.display());
+            crate::write_atomic(&out_path, self.fsync, |file| write_qua(file, &sm, mode))
+                .with_context(|| anyhow!("write Quaver chart to \"{}\"", out_path.display()))?;
+            folder.add_deps(&sm, sm.music.iter().chain(sm.background.iter()));
+            Ok(())
+        })?;
+        //Copy over the audio and backgrounds
+        folder.copy_deps(&self.copy);
+        Ok(())
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(iter::once((BucketKind::Input, &mut self.from)))
+    }
+}
+
+/// Quote a string as a YAML scalar.
+///
+/// JSON strings are valid double-quoted YAML scalars.
+fn yaml_str(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_else(|_| "''".to_string())
+}
+
+fn yaml_path(path: &Option<PathBuf>) -> String {
+    yaml_str(
+        &path
+            .as_deref()
+            .map(|path| path.to_string_lossy())
+            .unwrap_or_default(),
+    )
+}
+
+/// Write a simfile as a `.qua` chart, with all times in milliseconds.
+fn write_qua(file: &mut impl Write, sm: &Simfile, mode: &str) -> Result<()> {
+    let mut to_time = sm.beat_to_time();
+    write!(
+        file,
+        r#"AudioFile: {audio}
+SongPreviewTime: {preview}
+BackgroundFile: {bg}
+MapId: -1
+MapSetId: -1
+Mode: {mode}
+Title: {title}
+Artist: {artist}
+Source: ''
+Tags: ''
+Creator: {creator}
+DifficultyName: {diff}
+Description: ''
+Genre: {genre}
+EditorLayers: []
+CustomAudioSamples: []
+SoundEffects: []
+TimingPoints:
+"#,
+        audio = yaml_path(&sm.music),
+        preview = (sm.sample_start.unwrap_or(0.) * 1000.).round(),
+        bg = yaml_path(&sm.background),
+        mode = mode,
+        title = yaml_str(&sm.title),
+        artist = yaml_str(&sm.artist),
+        creator = yaml_str(&sm.credit),
+        diff = yaml_str(&sm.desc),
+        genre = yaml_str(&sm.genre),
+    )?;
+    //Quaver has no stops, so the beat grid is restarted after every stop instead
+    //Delays are already over by the time their beat plays, so they count as zero-length stops
+    //Warps restart the grid where they end, since they play no time at all
+    let mut pauses = sm
+        .stops
+        .iter()
+        .copied()
+        .chain(sm.delays.iter().map(|&(beat, _)| (beat, 0.)))
+        .chain(sm.warps.iter().map(|&(beat, len)| (beat + len, 0.)))
+        .collect::<Vec<_>>();
+    pauses.sort_by_key(|&(beat, _)| SortableFloat(beat));
+    let mut bpms = sm.bpms.iter().peekable();
+    let mut stops = pauses.iter().peekable();
+    let mut cur_bpm = None;
+    loop {
+        let next_bpm = bpms.peek().map(|cp| cp.beat.as_num());
+        let next_stop = stops.peek().map(|&&(beat, _)| beat);
+        let stop_first = match (next_bpm, next_stop) {
+            (Some(bpm_beat), Some(stop_beat)) => stop_beat < bpm_beat,
+            (None, Some(_)) => true,
+            (Some(_), None) => false,
+            (None, None) => break,
+        };
+        let (time, bpm) = if stop_first {
+            let &(beat, len) = stops.next().unwrap();
+            let bpm = match cur_bpm {
+                Some(bpm) => bpm,
+                None => continue,
+            };
+            (to_time.beat_to_time(BeatPos::from(beat)) + len, bpm)
+        } else {
+            let cp = bpms.next().unwrap();
+            cur_bpm = Some(cp.bpm());
+            (to_time.beat_to_time(cp.beat), cp.bpm())
+        };
+        write!(
+            file,
+            "- StartTime: {}\n  Bpm: {}\n",
+            (time * 1000.).round(),
+            bpm
+        )?;
+    }
+    writeln!(file, "SliderVelocities: []")?;
+    writeln!(file, "HitObjects:")?;
+    let mut to_time = sm.beat_to_time();
+    for (idx, note) in sm.notes.iter().enumerate() {
+        //Quaver has no mines
+        if note.is_tail() || note.is_mine() {
+            continue;
+        }
+        let time = (to_time.beat_to_time(note.beat) * 1000.).round();
+        write!(file, "- StartTime: {}\n  Lane: {}\n", time, note.key + 1)?;
+        if note.is_head() {
+            let tail = sm.notes[idx + 1..]
+                .iter()
+                .find(|tail| tail.is_tail() && tail.key == note.key)
+                .ok_or_else(|| anyhow!("hold at beat {} has no tail", note.beat))?;
+            //Use a separate converter, since tails come after other notes
+            let end = sm.beat_to_time().beat_to_time(tail.beat);
+            writeln!(file, "  EndTime: {}", (end * 1000.).round())?;
+        }
+        writeln!(file, "  KeySounds: []")?;
+    }
+    Ok(())
+}
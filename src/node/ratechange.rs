@@ -0,0 +1,99 @@
+//! Make sped up (or slowed down) variants of simfiles, for rate packs like the ones made out of
+//! osu! double time practice maps.
+
+use crate::node::{
+    prelude::*,
+    stretch::{scale_chart, stretch_music, StretchAudio},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateChange {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The music rates to make variants at (eg. `1.2` plays 20% faster).
+    pub rates: Vec<f64>,
+    /// Whether to output the original simfiles too, along with their variants.
+    pub keep_original: bool,
+    /// How to speed up the audio file.
+    /// Every rate gets its own audio file, which is kept pitch-corrected by ffmpeg.
+    /// Unlike `Stretch`, variants whose audio fails to stretch are skipped rather than left out
+    /// of sync.
+    pub audio: StretchAudio,
+    /// The ffmpeg executable to use for `StretchAudio::Ffmpeg`.
+    pub ffmpeg: String,
+    /// Appended to the title of every variant, so that they show up as separate songs.
+    /// `{rate}` is replaced by the rate (eg. `1.2`).
+    pub title_suffix: String,
+}
+impl Default for RateChange {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            rates: vec![1.1, 1.2, 1.3, 1.4, 1.5],
+            keep_original: true,
+            audio: StretchAudio::Ffmpeg,
+            ffmpeg: "ffmpeg".into(),
+            title_suffix: " ({rate}x)".into(),
+        }
+    }
+}
+
+impl Node for RateChange {
+    fn prepare(&mut self) -> Result<()> {
+        for &rate in self.rates.iter() {
+            ensure!(
+                rate.is_finite() && rate > 0.,
+                "rate {} is not a positive number",
+                rate
+            );
+        }
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let base = store.global_get("base").map(PathBuf::from);
+        store.get(&self.from, |store, list| {
+            let mut out = Vec::with_capacity(list.len() * (self.rates.len() + 1));
+            for &rate in self.rates.iter() {
+                trace!("    making x{} variants", rate);
+                //Variants of the same music share the same audio file, so skip the rest of them
+                //once it fails to stretch
+                let mut failed: Vec<Option<PathBuf>> = Vec::new();
+                for sm in list.iter() {
+                    let mut sm = sm.clone();
+                    if self.audio == StretchAudio::Ffmpeg {
+                        if failed.contains(&sm.music) {
+                            continue;
+                        }
+                        let tag = format!("{}x", rate);
+                        if let Err(err) =
+                            stretch_music(&self.ffmpeg, base.as_deref(), &mut sm, rate, &tag)
+                        {
+                            warn!(
+                                "    skipping x{} variant of \"{}\": {:#}",
+                                rate, sm.desc, err
+                            );
+                            failed.push(sm.music.clone());
+                            continue;
+                        }
+                    }
+                    scale_chart(&mut sm, rate);
+                    sm.title += &self.title_suffix.replace("{rate}", &rate.to_string());
+                    out.push(sm);
+                }
+            }
+            if self.keep_original {
+                out.splice(0..0, list.drain(..));
+            }
+            store.put(&self.into, out);
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
@@ -0,0 +1,223 @@
+//! Share chart ratings with other users of the tool.
+//!
+//! Charts are identified by a chart key, a hash of their notes and timing, so that the same
+//! conversion of the same beatmap has the same key on every machine.
+//! Exported rating files can be merged by importing several of them at once, and the averaged
+//! meters override the locally computed ones, so that popular converts end up rated
+//! consistently.
+
+use crate::node::prelude::*;
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+};
+
+/// The version of the rating file format.
+const RATINGS_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ratings {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// Where to write the chart keys and meters of every rated chart that passes through.
+    /// If empty, nothing is exported.
+    ///
+    /// The exported meters are always the ones computed locally, not the imported ones.
+    pub export: String,
+    /// Rating files to import meters from.
+    ///
+    /// If a chart appears in several files, the meters are averaged, weighted by the amount of
+    /// votes behind each one (`1` for a plain export).
+    pub import: Vec<String>,
+    /// The least amount of votes a chart needs before its imported meter is used.
+    pub min_votes: u32,
+    /// Whether to update the qualitative difficulty of charts with an imported meter.
+    ///
+    /// Works like the `set_diff` field of the `Rate` node: the numerically closest entry to the
+    /// imported meter is used.
+    /// If empty, the difficulty is not updated.
+    pub set_diff: Vec<(f64, Difficulty)>,
+    /// Averaged imported meters and their total votes, by chart key.
+    #[serde(skip)]
+    pub imported: HashMap<String, (f64, u32)>,
+    /// The ratings collected so far, by chart key.
+    #[serde(skip)]
+    pub exported: Shared<BTreeMap<String, RatingEntry>>,
+}
+impl Default for Ratings {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            export: "".into(),
+            import: vec![],
+            min_votes: 1,
+            set_diff: vec![],
+            imported: default(),
+            exported: default(),
+        }
+    }
+}
+
+/// A shareable rating file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RatingFile {
+    pub version: u32,
+    pub charts: BTreeMap<String, RatingEntry>,
+}
+
+/// The rating of a single chart.
+/// The title, artist and description are informative only.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RatingEntry {
+    pub title: String,
+    pub artist: String,
+    pub desc: String,
+    pub gamemode: String,
+    pub meter: f64,
+    pub votes: u32,
+}
+
+impl RatingFile {
+    fn load(path: &str) -> Result<RatingFile> {
+        let file = BufReader::new(File::open(path).context("failed to open rating file")?);
+        let ratings: RatingFile =
+            serde_json::from_reader(file).context("failed to parse rating file")?;
+        ensure!(
+            ratings.version <= RATINGS_VERSION,
+            "unsupported rating file version {} (max supported is {})",
+            ratings.version,
+            RATINGS_VERSION
+        );
+        Ok(ratings)
+    }
+}
+
+/// Identify a chart by its gamemode, notes and timing.
+///
+/// Only depends on the chart content, so metadata edits do not change it.
+pub fn chart_key(sm: &Simfile) -> String {
+    let mut hasher = fxhash::FxHasher64::default();
+    hasher.write(sm.gamemode.id().as_bytes());
+    for bpm in sm.bpms.iter() {
+        bpm.beat.hash(&mut hasher);
+        hasher.write_u64(bpm.beat_len.to_bits());
+    }
+    for &(beat, len) in sm.stops.iter() {
+        hasher.write_u64(beat.to_bits());
+        hasher.write_u64(len.to_bits());
+    }
+    for &(beat, len) in sm.delays.iter() {
+        hasher.write_u64(beat.to_bits());
+        hasher.write_u64(len.to_bits());
+    }
+    for &(beat, len) in sm.warps.iter() {
+        hasher.write_u64(beat.to_bits());
+        hasher.write_u64(len.to_bits());
+    }
+    for note in sm.notes.iter() {
+        note.beat.hash(&mut hasher);
+        hasher.write_i32(note.key);
+        hasher.write_u32(note.kind as u32);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+impl Node for Ratings {
+    fn prepare(&mut self) -> Result<()> {
+        let mut totals: HashMap<String, (f64, u32)> = default();
+        for path in self.import.iter() {
+            let ratings = RatingFile::load(path)
+                .with_context(|| anyhow!("failed to import ratings from \"{}\"", path))?;
+            for (key, entry) in ratings.charts {
+                if !entry.meter.is_finite() || entry.votes == 0 {
+                    continue;
+                }
+                let total = totals.entry(key).or_default();
+                total.0 += entry.meter * entry.votes as f64;
+                total.1 += entry.votes;
+            }
+        }
+        self.imported = totals
+            .into_iter()
+            .filter(|(_, (_, votes))| *votes >= self.min_votes)
+            .map(|(key, (sum, votes))| (key, (sum / votes as f64, votes)))
+            .collect();
+        if !self.import.is_empty() {
+            info!(
+                "imported meters for {} charts from {} rating files",
+                self.imported.len(),
+                self.import.len()
+            );
+        }
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            for sm in list.iter_mut() {
+                let key = chart_key(sm);
+                if !self.export.is_empty() && !sm.difficulty_num.is_nan() {
+                    self.exported.lock().insert(
+                        key.clone(),
+                        RatingEntry {
+                            title: sm.title.clone(),
+                            artist: sm.artist.clone(),
+                            desc: sm.desc.clone(),
+                            gamemode: sm.gamemode.id().to_string(),
+                            meter: sm.difficulty_num,
+                            votes: 1,
+                        },
+                    );
+                }
+                if let Some(&(meter, votes)) = self.imported.get(&key) {
+                    trace!(
+                        "    using imported meter {:.2} ({} votes) instead of {:.2} for \"{}\"",
+                        meter,
+                        votes,
+                        sm.difficulty_num,
+                        sm.desc
+                    );
+                    sm.difficulty_num = meter;
+                    if let Some((_num, diff)) = self
+                        .set_diff
+                        .iter()
+                        .min_by_key(|(num, _diff)| SortableFloat((*num - meter).abs()))
+                    {
+                        sm.difficulty = *diff;
+                    }
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn finish(&self) -> Result<()> {
+        if self.export.is_empty() {
+            return Ok(());
+        }
+        let ratings = RatingFile {
+            version: RATINGS_VERSION,
+            charts: self.exported.lock().clone(),
+        };
+        let path = Path::new(&self.export);
+        crate::write_atomic(path, false, |file| {
+            serde_json::to_writer_pretty(file, &ratings).map_err(Error::from)
+        })
+        .with_context(|| anyhow!("failed to export ratings to \"{}\"", self.export))?;
+        info!(
+            "exported ratings of {} charts to \"{}\"",
+            ratings.charts.len(),
+            self.export
+        );
+        Ok(())
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
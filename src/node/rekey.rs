@@ -88,17 +88,29 @@ impl KeyAlloc {
         self.default_weight
     }
 
+    /// The choose weight of a key at the given time.
+    pub fn weight(&self, key: usize, time: f64) -> f32 {
+        self.inactive_time_to_weight((time - self.last_active[key]) as f32)
+    }
+
     pub fn touch(&mut self, key: usize, time: f64) {
         self.last_active[key] = time;
     }
 
     /// The `keys` argument can be in an arbitrary order.
     pub fn alloc(&mut self, keys: &[usize], time: f64, rng: &mut FastRng) -> Option<usize> {
-        match keys.choose_weighted(rng, |&out_key| {
-            let time = (time - self.last_active[out_key]) as f32;
-            let weight = self.inactive_time_to_weight(time);
-            weight
-        }) {
+        self.alloc_biased(keys, time, rng, |_| 1.)
+    }
+
+    /// Like `alloc`, but multiplying the weight of each key by `bias(key)`.
+    pub fn alloc_biased(
+        &mut self,
+        keys: &[usize],
+        time: f64,
+        rng: &mut FastRng,
+        bias: impl Fn(usize) -> f32,
+    ) -> Option<usize> {
+        match keys.choose_weighted(rng, |&out_key| self.weight(out_key, time) * bias(out_key)) {
             Ok(&key) => {
                 self.touch(key, time);
                 Some(key)
@@ -113,7 +125,17 @@ impl KeyAlloc {
         time: f64,
         rng: &mut FastRng,
     ) -> Option<(usize, usize)> {
-        self.alloc(keys, time, rng)
+        self.alloc_idx_biased(keys, time, rng, |_| 1.)
+    }
+
+    pub fn alloc_idx_biased(
+        &mut self,
+        keys: &[usize],
+        time: f64,
+        rng: &mut FastRng,
+        bias: impl Fn(usize) -> f32,
+    ) -> Option<(usize, usize)> {
+        self.alloc_biased(keys, time, rng, bias)
             .map(|key| (keys.iter().position(|&k| k == key).unwrap(), key))
     }
 }
@@ -0,0 +1,305 @@
+//! Collect statistics about every simfile that passes through, and write them to a report file
+//! once all simfiles have been processed.
+
+use crate::node::prelude::*;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Report {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// Where to write the report.
+    ///
+    /// If the path ends in `.json` the report is written as a JSON array, otherwise it is written
+    /// as CSV.
+    pub output: String,
+    /// Where to write the per-gamemode summary, in the same format rules as `output`.
+    /// The summary is always logged, but it is only written to a file if this is not empty.
+    pub summary: String,
+    /// Where to write the column usage of every simfile (how many notes fall on each column and
+    /// on each hand), in the same format rules as `output`.
+    /// Useful to spot keymode conversions that overload some columns.
+    /// Only written if this is not empty.
+    pub columns: String,
+    /// The report rows collected so far.
+    #[serde(skip)]
+    pub rows: Shared<Vec<ReportRow>>,
+    /// The column usage rows collected so far.
+    #[serde(skip)]
+    pub column_rows: Shared<Vec<ColumnRow>>,
+}
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            output: "osu2sm-report.csv".into(),
+            summary: "".into(),
+            columns: "".into(),
+            rows: default(),
+            column_rows: default(),
+        }
+    }
+}
+
+/// Statistics about a single simfile.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReportRow {
+    pub beatmapset: String,
+    pub set_id: String,
+    pub title: String,
+    pub artist: String,
+    pub desc: String,
+    pub gamemode: String,
+    pub difficulty: String,
+    pub meter: f64,
+    /// Amount of non-tail notes.
+    pub notes: usize,
+    /// Rows in the longest stream.
+    pub stream_rows: usize,
+    /// BPM of the longest stream, in 16th notes.
+    pub stream_bpm: f64,
+    /// The most notes that start at the same time.
+    pub max_chord: usize,
+    /// Amount of hold notes.
+    pub holds: usize,
+}
+impl ReportRow {
+    fn new(store: &SimfileStore, sm: &Simfile) -> Self {
+        let stream = sm.longest_stream();
+        let mut max_chord = 0;
+        let mut chord = 0;
+        let mut last_beat = None;
+        for note in sm
+            .notes
+            .iter()
+            .filter(|note| !note.is_tail() && !note.is_mine())
+        {
+            if last_beat != Some(note.beat) {
+                chord = 0;
+                last_beat = Some(note.beat);
+            }
+            chord += 1;
+            max_chord = max_chord.max(chord);
+        }
+        Self {
+            beatmapset: store.global_get("base").unwrap_or_default().to_string(),
+            set_id: store.global_get("set_id").unwrap_or_default().to_string(),
+            title: sm.title.clone(),
+            artist: sm.artist.clone(),
+            desc: sm.desc.clone(),
+            gamemode: sm.gamemode.id().to_string(),
+            difficulty: format!("{:?}", sm.difficulty),
+            meter: sm.difficulty_num,
+            notes: sm
+                .notes
+                .iter()
+                .filter(|note| !note.is_tail() && !note.is_mine())
+                .count(),
+            stream_rows: stream.rows,
+            stream_bpm: stream.bpm,
+            max_chord,
+            holds: sm.notes.iter().filter(|note| note.is_head()).count(),
+        }
+    }
+}
+
+/// How the notes of a single simfile are spread across columns.
+#[derive(Clone, Debug, Serialize)]
+pub struct ColumnRow {
+    pub beatmapset: String,
+    pub title: String,
+    pub desc: String,
+    pub gamemode: String,
+    /// Amount of non-tail notes.
+    pub notes: usize,
+    /// The amount of non-tail notes on every column, from left to right, separated by spaces.
+    pub columns: String,
+    /// Percentage of notes on the left half of the columns.
+    pub left_percent: f64,
+    /// Percentage of notes on the right half of the columns.
+    /// The middle column of odd keycounts counts for neither hand.
+    pub right_percent: f64,
+    /// The column with the most notes.
+    pub busiest: i32,
+    /// How many times more notes the busiest column has than if notes were spread evenly.
+    pub busiest_ratio: f64,
+}
+impl ColumnRow {
+    fn new(store: &SimfileStore, sm: &Simfile) -> Self {
+        let key_count = sm.gamemode.key_count();
+        let mut counts = vec![0; key_count.max(0) as usize];
+        for note in sm
+            .notes
+            .iter()
+            .filter(|note| !note.is_tail() && !note.is_mine())
+        {
+            if let Some(count) = counts.get_mut(note.key as usize) {
+                *count += 1;
+            }
+        }
+        let notes: usize = counts.iter().sum();
+        let percent = |count: usize| {
+            if notes == 0 {
+                0.
+            } else {
+                count as f64 / notes as f64 * 100.
+            }
+        };
+        let half = counts.len() / 2;
+        let (busiest, &busiest_count) = counts
+            .iter()
+            .enumerate()
+            .max_by_key(|&(col, count)| (count, cmp::Reverse(col)))
+            .unwrap_or((0, &0));
+        Self {
+            beatmapset: store.global_get("base").unwrap_or_default().to_string(),
+            title: sm.title.clone(),
+            desc: sm.desc.clone(),
+            gamemode: sm.gamemode.id().to_string(),
+            notes,
+            columns: counts
+                .iter()
+                .map(|count| count.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+            left_percent: percent(counts[..half].iter().sum()),
+            right_percent: percent(counts[counts.len() - half..].iter().sum()),
+            busiest: busiest as i32,
+            busiest_ratio: if notes == 0 {
+                0.
+            } else {
+                busiest_count as f64 * counts.len() as f64 / notes as f64
+            },
+        }
+    }
+}
+
+/// Aggregate statistics about all simfiles of a gamemode.
+#[derive(Clone, Debug, Serialize)]
+pub struct SummaryRow {
+    pub gamemode: String,
+    pub simfiles: usize,
+    /// The largest chord in any simfile.
+    pub max_chord: usize,
+    /// Percentage of non-tail notes that are holds.
+    pub hold_percent: f64,
+    /// Average meter of the rated simfiles.
+    pub avg_meter: f64,
+}
+impl SummaryRow {
+    fn from_rows(rows: &[ReportRow]) -> Vec<SummaryRow> {
+        let mut by_mode: BTreeMap<&str, Vec<&ReportRow>> = BTreeMap::new();
+        for row in rows {
+            by_mode.entry(&row.gamemode).or_default().push(row);
+        }
+        by_mode
+            .into_iter()
+            .map(|(gamemode, rows)| {
+                let notes: usize = rows.iter().map(|row| row.notes).sum();
+                let holds: usize = rows.iter().map(|row| row.holds).sum();
+                let meters = rows
+                    .iter()
+                    .map(|row| row.meter)
+                    .filter(|meter| !meter.is_nan())
+                    .collect::<Vec<_>>();
+                SummaryRow {
+                    gamemode: gamemode.to_string(),
+                    simfiles: rows.len(),
+                    max_chord: rows.iter().map(|row| row.max_chord).max().unwrap_or(0),
+                    hold_percent: if notes == 0 {
+                        0.
+                    } else {
+                        holds as f64 / notes as f64 * 100.
+                    },
+                    avg_meter: if meters.is_empty() {
+                        f64::NAN
+                    } else {
+                        meters.iter().sum::<f64>() / meters.len() as f64
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+impl Node for Report {
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            {
+                let mut rows = self.rows.lock();
+                for sm in list.iter() {
+                    rows.push(ReportRow::new(store, sm));
+                }
+            }
+            if !self.columns.is_empty() {
+                let mut rows = self.column_rows.lock();
+                for sm in list.iter() {
+                    rows.push(ColumnRow::new(store, sm));
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn finish(&self) -> Result<()> {
+        let mut rows = self.rows.lock();
+        //Beatmapsets are processed in parallel, so sort rows to keep the report reproducible
+        rows.sort_by(|a, b| a.beatmapset.cmp(&b.beatmapset));
+        write_report(&self.output, &rows)
+            .with_context(|| anyhow!("failed to write report to \"{}\"", self.output))?;
+        info!(
+            "wrote report with {} simfiles to \"{}\"",
+            rows.len(),
+            self.output
+        );
+        let summary = SummaryRow::from_rows(&rows);
+        for row in summary.iter() {
+            info!(
+                "  {}: {} simfiles, max chord {}, {:.1}% holds, average meter {:.2}",
+                row.gamemode, row.simfiles, row.max_chord, row.hold_percent, row.avg_meter
+            );
+        }
+        if !self.summary.is_empty() {
+            write_report(&self.summary, &summary)
+                .with_context(|| anyhow!("failed to write summary to \"{}\"", self.summary))?;
+            info!("wrote summary to \"{}\"", self.summary);
+        }
+        if !self.columns.is_empty() {
+            let mut rows = self.column_rows.lock();
+            rows.sort_by(|a, b| a.beatmapset.cmp(&b.beatmapset));
+            write_report(&self.columns, &rows)
+                .with_context(|| anyhow!("failed to write column usage to \"{}\"", self.columns))?;
+            info!("wrote column usage to \"{}\"", self.columns);
+            let overloaded = rows.iter().filter(|row| row.busiest_ratio > 2.).count();
+            if overloaded > 0 {
+                warn!(
+                    "  {} simfiles have a column with more than twice its share of notes",
+                    overloaded
+                );
+            }
+        }
+        Ok(())
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+fn write_report<T: Serialize>(path: &str, rows: &[T]) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    if path.to_lowercase().ends_with(".json") {
+        serde_json::to_writer_pretty(file, rows)?;
+    } else {
+        let mut writer = csv::Writer::from_writer(file);
+        for row in rows {
+            writer.serialize(row)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,529 @@
+//! Change the keycount of simfiles by detecting patterns in the input and rewriting them according
+//! to a grammar of production rules.
+//!
+//! This is a higher-level alternative to `Rekey` (which shuffles notes at random) and `Remap`
+//! (which throws away the input patterns entirely).
+
+use crate::node::{prelude::*, rekey::KeyAlloc};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Resample {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// Into what gamemode to convert.
+    pub gamemode: Gamemode,
+    /// The production rules, as `(token, production)` pairs.
+    /// The first rule that matches a token is used, and tokens without a rule are allocated at
+    /// random (like `Production::Random`).
+    ///
+    /// If empty, a built-in grammar for the input and output keycounts is used, if there is one
+    /// (currently 7K -> 4K and 4K -> 7K).
+    pub rules: Vec<(Token, Production)>,
+    /// The longest gap in beats between the single notes of a run (ie. a stream, stair, trill or
+    /// jack).
+    pub run_gap: f64,
+    /// The least amount of notes in a run.
+    /// Single notes that are not part of a run are `Token::Single`.
+    pub min_run: usize,
+    /// Similar to `Rekey::weight_curve`, used for every random choice.
+    pub weight_curve: Vec<(f32, f32)>,
+}
+impl Default for Resample {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            gamemode: Gamemode::DanceSingle,
+            rules: vec![],
+            run_gap: 0.5,
+            min_run: 4,
+            weight_curve: vec![(0., 1.), (0.4, 10.), (0.8, 200.), (1.4, 300.)],
+        }
+    }
+}
+
+/// A pattern detected in the input notes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Token {
+    /// A single note that is not part of a run.
+    Single,
+    /// Two notes at once.
+    Jump,
+    /// Three notes at once.
+    Hand,
+    /// Four or more notes at once.
+    Quad,
+    /// A run of single notes with no particular shape.
+    Stream,
+    /// A run of single notes that always moves in the same direction.
+    Stair,
+    /// A run of single notes alternating between two keys.
+    Trill,
+    /// A run of single notes on the same key.
+    Jack,
+}
+
+/// How to place the notes of a token on the output keys.
+/// Keys are numbered from `0`, left to right.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Production {
+    /// Place every row on one of the given sets of keys, preferring keys that were not used
+    /// recently (eg. `Chords([[0, 1], [2, 3]])` turns jumps into `[12]` or `[34]`).
+    /// The sets may have a different amount of keys than the input rows.
+    Chords(Vec<Vec<usize>>),
+    /// Walk across the keys one step at a time, bouncing off the sides.
+    Stair,
+    /// Alternate between two keys.
+    Trill,
+    /// Repeat the same key.
+    Jack,
+    /// Keep the input keys, scaled proportionally to the output keycount.
+    Scale,
+    /// Allocate keys at random, like `Rekey`.
+    Random,
+}
+
+impl Resample {
+    /// The grammar to use for the given input keycount.
+    fn grammar(&self, in_keycount: usize) -> Cow<'_, [(Token, Production)]> {
+        if !self.rules.is_empty() {
+            return Cow::Borrowed(&self.rules[..]);
+        }
+        let out_keycount = self.gamemode.key_count() as usize;
+        Cow::Owned(default_grammar(in_keycount, out_keycount).unwrap_or_default())
+    }
+}
+
+/// The built-in grammars.
+fn default_grammar(in_keycount: usize, out_keycount: usize) -> Option<Vec<(Token, Production)>> {
+    use self::Token::*;
+    match (in_keycount, out_keycount) {
+        (7, 4) => Some(vec![
+            (Single, Production::Random),
+            (
+                Jump,
+                Production::Chords(vec![vec![0, 1], vec![2, 3], vec![1, 2], vec![0, 3]]),
+            ),
+            (
+                Hand,
+                Production::Chords(vec![
+                    vec![0, 1, 2],
+                    vec![1, 2, 3],
+                    vec![0, 1, 3],
+                    vec![0, 2, 3],
+                ]),
+            ),
+            (Quad, Production::Chords(vec![vec![0, 1, 2, 3]])),
+            (Stream, Production::Stair),
+            (Stair, Production::Stair),
+            (Trill, Production::Trill),
+            (Jack, Production::Jack),
+        ]),
+        (4, 7) => Some(vec![
+            (Single, Production::Random),
+            (
+                Jump,
+                Production::Chords(vec![
+                    vec![0, 6],
+                    vec![1, 5],
+                    vec![2, 4],
+                    vec![0, 2],
+                    vec![4, 6],
+                ]),
+            ),
+            (
+                Hand,
+                Production::Chords(vec![
+                    vec![0, 3, 6],
+                    vec![1, 3, 5],
+                    vec![0, 2, 4],
+                    vec![2, 4, 6],
+                ]),
+            ),
+            (
+                Quad,
+                Production::Chords(vec![vec![0, 2, 4, 6], vec![1, 2, 4, 5]]),
+            ),
+            (Stream, Production::Scale),
+            (Stair, Production::Stair),
+            (Trill, Production::Trill),
+            (Jack, Production::Jack),
+        ]),
+        _ => None,
+    }
+}
+
+impl Node for Resample {
+    fn prepare(&mut self) -> Result<()> {
+        let out_keycount = self.gamemode.key_count() as usize;
+        ensure!(out_keycount > 0, "cannot convert to 0-key map");
+        ensure!(self.min_run > 0, "runs must have at least one note");
+        for (token, prod) in self.rules.iter() {
+            if let Production::Chords(sets) = prod {
+                ensure!(
+                    !sets.is_empty(),
+                    "the `Chords` production of `{:?}` has no key sets",
+                    token
+                );
+                for set in sets.iter() {
+                    ensure!(
+                        !set.is_empty() && set.iter().all(|&key| key < out_keycount),
+                        "key set {:?} of `{:?}` is empty or out of range for {} keys",
+                        set,
+                        token,
+                        out_keycount
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        store.get(&self.from, |store, list| {
+            for sm in list.iter_mut() {
+                resample(sm, self)?;
+                sm.gamemode = self.gamemode;
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+/// A beat with at least one head or hit note.
+struct Row {
+    beat: BeatPos,
+    /// The input keys of the heads and hits, in note order.
+    keys: Vec<usize>,
+}
+
+/// Split the rows of a simfile into tokens.
+/// Returns the token of every row, and whether the row starts a new token.
+fn tokenize(rows: &[Row], conf: &Resample) -> Vec<(Token, bool)> {
+    let run_gap = BeatPos::from(conf.run_gap);
+    let mut tokens = Vec::with_capacity(rows.len());
+    let mut start = 0;
+    while start < rows.len() {
+        let token = match rows[start].keys.len() {
+            1 => None,
+            2 => Some(Token::Jump),
+            3 => Some(Token::Hand),
+            _ => Some(Token::Quad),
+        };
+        if let Some(token) = token {
+            tokens.push((token, true));
+            start += 1;
+            continue;
+        }
+        //Extend the run of single notes as much as possible
+        let mut end = start + 1;
+        while end < rows.len()
+            && rows[end].keys.len() == 1
+            && rows[end].beat - rows[end - 1].beat <= run_gap
+        {
+            end += 1;
+        }
+        if end - start < conf.min_run {
+            tokens.extend((start..end).map(|_| (Token::Single, true)));
+        } else {
+            let keys = rows[start..end]
+                .iter()
+                .map(|row| row.keys[0] as isize)
+                .collect::<Vec<_>>();
+            let token = classify_run(&keys);
+            tokens.push((token, true));
+            tokens.extend((start + 1..end).map(|_| (token, false)));
+        }
+        start = end;
+    }
+    tokens
+}
+
+fn classify_run(keys: &[isize]) -> Token {
+    if keys.windows(2).all(|pair| pair[0] == pair[1]) {
+        Token::Jack
+    } else if keys.windows(2).all(|pair| pair[0] != pair[1])
+        && keys.windows(3).all(|tri| tri[0] == tri[2])
+    {
+        Token::Trill
+    } else if keys.windows(2).all(|pair| pair[1] > pair[0])
+        || keys.windows(2).all(|pair| pair[1] < pair[0])
+    {
+        Token::Stair
+    } else {
+        Token::Stream
+    }
+}
+
+/// The state of the production being applied to the current token.
+struct ProdState {
+    /// The keys chosen at the start of the token (the trill keys or the jack key).
+    keys: Vec<usize>,
+    /// The current stair key and direction.
+    pos: isize,
+    dir: isize,
+    /// How many rows of the token were produced.
+    count: usize,
+}
+
+/// Everything that is needed to choose output keys.
+struct Producer {
+    in_keycount: usize,
+    out_keycount: usize,
+    key_alloc: KeyAlloc,
+    rng: FastRng,
+    /// Which output keys are being held by a long note.
+    held: Vec<bool>,
+    state: ProdState,
+}
+impl Producer {
+    /// Allocate a free key at random, that is not in `taken`.
+    fn alloc(&mut self, taken: &[usize], time: f64) -> Option<usize> {
+        let free = (0..self.out_keycount)
+            .filter(|&key| !self.held[key] && !taken.contains(&key))
+            .collect::<Vec<_>>();
+        self.key_alloc.alloc(&free, time, &mut self.rng)
+    }
+
+    /// Use `key` if it is free, or allocate another key at random otherwise.
+    fn use_or_alloc(&mut self, key: usize, taken: &[usize], time: f64) -> Option<usize> {
+        if key < self.out_keycount && !self.held[key] && !taken.contains(&key) {
+            Some(key)
+        } else {
+            self.alloc(taken, time)
+        }
+    }
+
+    /// Choose the output keys of a row.
+    fn produce(&mut self, prod: &Production, row: &Row, start: bool, time: f64) -> Vec<usize> {
+        let mut out = Vec::with_capacity(row.keys.len());
+        if start {
+            self.state.keys.clear();
+            self.state.count = 0;
+        }
+        match prod {
+            Production::Chords(sets) => {
+                let held = &self.held;
+                let free_sets = sets
+                    .iter()
+                    .filter(|set| set.iter().all(|&key| !held[key]))
+                    .collect::<Vec<_>>();
+                let key_alloc = &self.key_alloc;
+                if let Ok(set) = free_sets.choose_weighted(&mut self.rng, |set| {
+                    set.iter()
+                        .map(|&key| key_alloc.weight(key, time))
+                        .fold(f32::INFINITY, f32::min)
+                }) {
+                    for &key in set.iter() {
+                        if !out.contains(&key) {
+                            out.push(key);
+                        }
+                    }
+                }
+            }
+            Production::Stair => {
+                let out_keycount = self.out_keycount as isize;
+                if self.state.count == 0 {
+                    if let Some(key) = self.alloc(&out, time) {
+                        self.state.pos = key as isize;
+                        self.state.dir = if self.rng.gen() { 1 } else { -1 };
+                        out.push(key);
+                    }
+                } else if out_keycount > 1 {
+                    let mut next = self.state.pos + self.state.dir;
+                    if next < 0 || next >= out_keycount {
+                        self.state.dir = -self.state.dir;
+                        next = self.state.pos + self.state.dir;
+                    }
+                    self.state.pos = next;
+                    let key = self.use_or_alloc(next as usize, &out, time);
+                    out.extend(key);
+                } else {
+                    let key = self.use_or_alloc(0, &out, time);
+                    out.extend(key);
+                }
+            }
+            Production::Trill => {
+                if self.state.keys.is_empty() {
+                    let first = self.alloc(&[], time);
+                    let second = first.and_then(|first| self.alloc(&[first], time));
+                    self.state.keys.extend(first);
+                    self.state.keys.extend(second);
+                }
+                if !self.state.keys.is_empty() {
+                    let key = self.state.keys[self.state.count % self.state.keys.len()];
+                    let key = self.use_or_alloc(key, &out, time);
+                    out.extend(key);
+                }
+            }
+            Production::Jack => {
+                if self.state.keys.is_empty() {
+                    let key = self.alloc(&[], time);
+                    self.state.keys.extend(key);
+                }
+                if let Some(&key) = self.state.keys.first() {
+                    let key = self.use_or_alloc(key, &out, time);
+                    out.extend(key);
+                }
+            }
+            Production::Scale => {
+                for &in_key in row.keys.iter() {
+                    let key = in_key * self.out_keycount / self.in_keycount;
+                    let key = self.use_or_alloc(key, &out, time);
+                    out.extend(key);
+                }
+            }
+            Production::Random => {}
+        }
+        //Fill in any missing keys at random
+        while out.len() < row.keys.len() {
+            match self.alloc(&out, time) {
+                Some(key) => out.push(key),
+                None => break,
+            }
+        }
+        for &key in out.iter() {
+            self.key_alloc.touch(key, time);
+        }
+        self.state.count += 1;
+        out
+    }
+}
+
+/// Rewrite the notes of a simfile into the output keycount.
+fn resample(sm: &mut Simfile, conf: &Resample) -> Result<()> {
+    let in_keycount = sm.gamemode.key_count() as usize;
+    let out_keycount = conf.gamemode.key_count() as usize;
+    ensure!(in_keycount > 0, "cannot convert 0-key map");
+    let grammar = conf.grammar(in_keycount);
+    if grammar.is_empty() {
+        trace!(
+            "    no grammar for {}K -> {}K, allocating every note at random",
+            in_keycount,
+            out_keycount
+        );
+    } else {
+        trace!("    resampling {}K to {}K", in_keycount, out_keycount);
+    }
+
+    //Find the rows and tokenize them
+    let notes = mem::take(&mut sm.notes);
+    let mut rows: Vec<Row> = Vec::new();
+    for note in notes
+        .iter()
+        .filter(|note| !note.is_tail() && !note.is_mine())
+    {
+        ensure!(
+            note.key >= 0 && (note.key as usize) < in_keycount,
+            "note key {} out of range for {} keys",
+            note.key,
+            in_keycount
+        );
+        match rows.last_mut() {
+            Some(row) if row.beat == note.beat => row.keys.push(note.key as usize),
+            _ => rows.push(Row {
+                beat: note.beat,
+                keys: vec![note.key as usize],
+            }),
+        }
+    }
+    let tokens = tokenize(&rows, conf);
+
+    //Produce output notes
+    let mut producer = Producer {
+        in_keycount,
+        out_keycount,
+        key_alloc: KeyAlloc::new(out_keycount),
+        rng: simfile_rng(sm, "resample"),
+        held: vec![false; out_keycount],
+        state: ProdState {
+            keys: Vec::new(),
+            pos: 0,
+            dir: 1,
+            count: 0,
+        },
+    };
+    producer.key_alloc.set_weight_curve(&conf.weight_curve);
+    let mut to_time = sm.beat_to_time();
+    //The output key that the long note on each input key was placed on
+    let mut tail_keys: Vec<Option<usize>> = vec![None; in_keycount];
+    let mut out_notes = Vec::with_capacity(notes.len());
+    let mut row_idx = 0;
+    let mut idx = 0;
+    while idx < notes.len() {
+        let beat = notes[idx].beat;
+        let end = notes[idx..]
+            .iter()
+            .position(|note| note.beat != beat)
+            .map(|len| idx + len)
+            .unwrap_or(notes.len());
+        let time = to_time.beat_to_time(beat);
+        //Release long notes first, so that their keys can be pressed again
+        for note in notes[idx..end].iter().filter(|note| note.is_tail()) {
+            if let Some(key) = tail_keys.get_mut(note.key as usize).and_then(Option::take) {
+                producer.held[key] = false;
+                out_notes.push(Note {
+                    beat,
+                    key: key as i32,
+                    kind: Note::KIND_TAIL,
+                });
+            }
+        }
+        if rows.get(row_idx).map(|row| row.beat) == Some(beat) {
+            let row = &rows[row_idx];
+            let (token, start) = tokens[row_idx];
+            let prod = grammar
+                .iter()
+                .find(|(rule, _)| *rule == token)
+                .map(|(_, prod)| prod)
+                .unwrap_or(&Production::Random);
+            let keys = producer.produce(prod, row, start, time);
+            let mut heads = notes[idx..end]
+                .iter()
+                .filter(|note| !note.is_tail() && !note.is_mine());
+            for key in keys {
+                //Extra keys become hits, and input notes without a key are dropped
+                let kind = match heads.next() {
+                    Some(note) if note.is_head() => {
+                        producer.held[key] = true;
+                        tail_keys[note.key as usize] = Some(key);
+                        //Rolls stay rolls
+                        note.kind
+                    }
+                    _ => Note::KIND_HIT,
+                };
+                out_notes.push(Note {
+                    beat,
+                    key: key as i32,
+                    kind,
+                });
+            }
+            row_idx += 1;
+        }
+        //Mines stay on their key, as long as it exists in the output and is free
+        let row_start = out_notes
+            .iter()
+            .rposition(|note: &Note| note.beat != beat)
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        for mine in notes[idx..end].iter().filter(|note| note.is_mine()) {
+            let taken = (mine.key as usize) >= out_keycount
+                || out_notes[row_start..]
+                    .iter()
+                    .any(|note| note.key == mine.key);
+            if !taken {
+                out_notes.push(mine.clone());
+            }
+        }
+        idx = end;
+    }
+    sm.notes = out_notes;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! Run small user-supplied scripts on every simfile, for quick per-chart tweaks without
+//! recompiling.
+//!
+//! Requires the `script` feature, which embeds the [rhai](https://rhai.rs) scripting engine.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Script {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The source code of the script.
+    ///
+    /// The script can read and modify the simfile through the `sm` variable, which holds all
+    /// simfile fields (`sm.title`, `sm.difficulty_num`, `sm.notes`, etc...).
+    /// If the script evaluates to `false`, the simfile is dropped.
+    pub source: String,
+    /// Load the script source from this file instead of `source`.
+    pub file: String,
+    #[cfg(feature = "script")]
+    #[serde(skip)]
+    ast: Option<rhai::AST>,
+}
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            source: "".into(),
+            file: "".into(),
+            #[cfg(feature = "script")]
+            ast: None,
+        }
+    }
+}
+
+impl Node for Script {
+    #[cfg(feature = "script")]
+    fn prepare(&mut self) -> Result<()> {
+        if !self.file.is_empty() {
+            self.source = fs::read_to_string(&self.file)
+                .with_context(|| anyhow!("failed to read script at \"{}\"", self.file))?;
+        }
+        let ast = rhai::Engine::new()
+            .compile(&self.source)
+            .map_err(|err| anyhow!("failed to compile script: {}", err))?;
+        self.ast = Some(ast);
+        Ok(())
+    }
+    #[cfg(not(feature = "script"))]
+    fn prepare(&mut self) -> Result<()> {
+        bail!("the `Script` node requires osu2sm to be compiled with the `script` feature")
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        #[cfg(feature = "script")]
+        {
+            let engine = rhai::Engine::new();
+            store.get(&self.from, |store, list| {
+                let mut out = Vec::with_capacity(list.len());
+                for sm in list.drain(..) {
+                    if let Some(sm) = run_script(self, &engine, sm)? {
+                        out.push(sm);
+                    }
+                }
+                store.put(&self.into, out);
+                Ok(())
+            })
+        }
+        #[cfg(not(feature = "script"))]
+        {
+            let _ = store;
+            bail!("scripting requires the `script` feature")
+        }
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+#[cfg(feature = "script")]
+fn run_script(
+    conf: &Script,
+    engine: &rhai::Engine,
+    sm: Box<Simfile>,
+) -> Result<Option<Box<Simfile>>> {
+    let ast = conf.ast.as_ref().expect("script not compiled");
+    let mut scope = rhai::Scope::new();
+    scope.push(
+        "sm",
+        rhai::serde::to_dynamic(&*sm).map_err(|err| anyhow!("{}", err))?,
+    );
+    let result = engine
+        .eval_ast_with_scope::<rhai::Dynamic>(&mut scope, ast)
+        .map_err(|err| anyhow!("script error on \"{}\": {}", sm.title, err))?;
+    if result.as_bool() == Ok(false) {
+        trace!("    script dropped simfile \"{}\"", sm.title);
+        return Ok(None);
+    }
+    let sm_dyn = scope
+        .get_value::<rhai::Dynamic>("sm")
+        .ok_or_else(|| anyhow!("script removed the `sm` variable"))?;
+    let mut sm: Box<Simfile> =
+        rhai::serde::from_dynamic(&sm_dyn).map_err(|err| anyhow!("invalid simfile: {}", err))?;
+    //Scripts may move notes around
+    sm.notes.sort_by_key(|note| note.beat);
+    Ok(Some(sm))
+}
@@ -2,15 +2,18 @@
 //! amount of charts per song per gamemode to 6, tops.
 //! Damn good design.
 
-use crate::node::prelude::*;
+use crate::{node::prelude::*, simfile::row_similarity};
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Select {
     pub from: BucketId,
     pub into: BucketId,
-    /// Group input simfiles by music and gamemode before trimming difficulties.
+    /// Group input simfiles by gamemode (and by `merge_by`) before trimming difficulties.
     pub merge: bool,
+    /// What else charts must have in common to be grouped together when merging.
+    pub merge_by: MergeBy,
     /// The maximum amount of simfiles to select per list.
     /// Having a value larger than the length of `diff_names` makes no effect.
     pub max: usize,
@@ -22,11 +25,27 @@ pub struct Select {
     /// When deduplicating, which difficulty in the overlap range to choose.
     /// A value of `0` means "the easiest", while a value of `1` means "the hardest".
     pub dedup_bias: f64,
+    /// If greater than zero, difficulties closer than `dedup_dist` are only considered duplicates
+    /// if their note content is at least this similar (from `0` to `1`, where `1` means the exact
+    /// same notes).
+    ///
+    /// Combining a large `dedup_dist` with a similarity close to `1` removes copies of the same
+    /// chart without touching different charts with coincidentally close meters.
+    pub dedup_similarity: f64,
     /// Which difficulties can be present in the output, and how many times each.
     /// Difficulties should be sorted according to user preference.
     ///
     /// Defaults to the entire range of difficulties (`Beginner` - `Challenge`, `Edit`).
     pub diff_names: Vec<Difficulty>,
+    /// A template for the description of selected charts, so that both the StepMania difficulty
+    /// slot and the osu! difficulty name are visible.
+    ///
+    /// `{slot}` is replaced by the assigned difficulty slot (eg. `Hard`), `{version}` by the
+    /// original osu! difficulty name (eg. `Insane`) and `{desc}` by the current description.
+    /// For example, `"{version} ({slot})"`.
+    ///
+    /// If empty, descriptions are left untouched.
+    pub label: String,
 }
 impl Default for Select {
     fn default() -> Self {
@@ -35,11 +54,48 @@ impl Default for Select {
             from: default(),
             into: default(),
             merge: true,
+            merge_by: MergeBy::Music,
             max: 6,
             diff_names: vec![Beginner, Easy, Medium, Hard, Challenge, Edit],
             prefer: default(),
             dedup_dist: 0.,
             dedup_bias: 0.5,
+            dedup_similarity: 0.,
+            label: "".into(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum MergeBy {
+    /// Charts with the same music file.
+    Music,
+    /// Charts whose music files have the same contents, even if their names differ (eg. a copy
+    /// of the audio file).
+    /// Charts with unreadable music are grouped by music file instead.
+    MusicHash,
+    /// All charts of the same beatmapset, even if they use different music (eg. a TV Size and a
+    /// full version of the same song).
+    Beatmapset,
+}
+impl MergeBy {
+    /// The key that charts of the same group share.
+    fn key(&self, sm: &Simfile) -> String {
+        let music = || {
+            sm.music
+                .as_deref()
+                .unwrap_or_else(|| "".as_ref())
+                .to_string_lossy()
+                .into_owned()
+        };
+        match self {
+            MergeBy::Music => music(),
+            MergeBy::MusicHash => match sm.music_hash {
+                Some(hash) => format!("{:016x}", hash),
+                None => music(),
+            },
+            //Every store holds a single beatmapset
+            MergeBy::Beatmapset => String::new(),
         }
     }
 }
@@ -161,6 +217,13 @@ impl PreferDiff {
 }
 
 impl Node for Select {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            (0.0..=1.0).contains(&self.dedup_bias),
+            "dedup_bias must be between 0 and 1"
+        );
+        Ok(())
+    }
     fn apply(&self, store: &mut SimfileStore) -> Result<()> {
         let process_list = |store: &mut SimfileStore, mut list: Vec<Box<Simfile>>| -> Result<()> {
             trim_difficulties(self, &mut list)?;
@@ -168,15 +231,16 @@ impl Node for Select {
             Ok(())
         };
         if self.merge {
-            let mut by_music_gamemode: HashMap<(PathBuf, Gamemode), Vec<Box<Simfile>>> = default();
+            //Keep groups sorted, so that the output order does not depend on hashing
+            let mut by_key_gamemode: BTreeMap<(String, Gamemode), Vec<Box<Simfile>>> = default();
             store.get_each(&self.from, |_, sm| {
-                let list = by_music_gamemode
-                    .entry((sm.music.clone().unwrap_or_default(), sm.gamemode))
+                let list = by_key_gamemode
+                    .entry((self.merge_by.key(&sm), sm.gamemode))
                     .or_default();
                 list.push(sm);
                 Ok(())
             })?;
-            for (_, list) in by_music_gamemode {
+            for (_, list) in by_key_gamemode {
                 process_list(store, list)?;
             }
             Ok(())
@@ -194,6 +258,53 @@ impl Node for Select {
     }
 }
 
+/// Group charts that are close in difficulty _and_ similar in note content, keeping a single chart
+/// out of each group.
+///
+/// `order` must be sorted by difficulty.
+fn dedup_by_content(conf: &Select, simfiles: &[Box<Simfile>], order: &mut Vec<(usize, f64)>) {
+    let hashes = simfiles
+        .iter()
+        .map(|sm| sm.row_hashes())
+        .collect::<Vec<_>>();
+    //Each group holds indices into `order`, sorted by difficulty
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, &(sm_idx, diff)) in order.iter().enumerate() {
+        let group = groups.iter_mut().find(|group| {
+            let (first_idx, first_diff) = order[group[0]];
+            diff - first_diff < conf.dedup_dist
+                && row_similarity(&hashes[first_idx], &hashes[sm_idx]) >= conf.dedup_similarity
+        });
+        match group {
+            Some(group) => group.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+    for group in groups {
+        let min_diff = order[group[0]].1;
+        let max_diff = order[*group.last().unwrap()].1;
+        let mid_diff = min_diff + (max_diff - min_diff) * conf.dedup_bias;
+        let keep = group
+            .iter()
+            .copied()
+            .find(|&i| order[i].1 >= mid_diff)
+            .unwrap_or(*group.last().unwrap());
+        if group.len() > 1 {
+            trace!(
+                "    deduplicated {} similar charts around difficulty {}",
+                group.len(),
+                order[keep].1
+            );
+        }
+        for i in group {
+            if i != keep {
+                order[i].1 = f64::NAN;
+            }
+        }
+    }
+    order.retain(|&(_, diff)| !diff.is_nan());
+}
+
 /// There seems to be a max of 6 difficulties, so use them wisely and sort them.
 pub fn trim_difficulties(conf: &Select, simfiles: &mut Vec<Box<Simfile>>) -> Result<()> {
     //Exit early on the degenerate case, because weird stuff happens in these edge cases
@@ -223,7 +334,9 @@ pub fn trim_difficulties(conf: &Select, simfiles: &mut Vec<Box<Simfile>>) -> Res
 
     //Remove difficulties if they are too close
     //Note that `<` is used, so that `min_dist == 0` implies that no removals are made.
-    {
+    if conf.dedup_similarity > 0. {
+        dedup_by_content(conf, simfiles, &mut order);
+    } else {
         let mut idx = 0;
         macro_rules! diff_at {
             ($idx:expr) => {
@@ -362,6 +475,13 @@ pub fn trim_difficulties(conf: &Select, simfiles: &mut Vec<Box<Simfile>>) -> Res
     for (chart, diff_idx) in simfiles.iter_mut().zip(difficulties) {
         chart.difficulty = conf.diff_names[diff_idx as usize];
         chart.difficulty_num = chart.difficulty_num.round();
+        if !conf.label.is_empty() {
+            chart.desc = conf
+                .label
+                .replace("{slot}", chart.difficulty.name())
+                .replace("{version}", &chart.version)
+                .replace("{desc}", &chart.desc);
+        }
     }
     trace!(
         "    final chart difficulties: {:?}",
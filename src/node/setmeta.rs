@@ -0,0 +1,197 @@
+//! Set simfile metadata to fixed or templated values, such as a common genre for a whole pack.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SetMeta {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The fields to set, along with the value template to set them to.
+    ///
+    /// The following placeholders are replaced in every template, always using the values from
+    /// before this node was applied:
+    /// - `{title}`, `{subtitle}`, `{artist}`, `{title_translit}`, `{subtitle_translit}`,
+    ///   `{artist_translit}`, `{genre}`, `{credit}`, `{desc}`: The corresponding fields.
+    /// - `{version}`: The osu! difficulty name.
+    /// - `{meter}`: The numeric difficulty.
+    /// - `{set_id}`: The osu! beatmapset id, if known.
+    ///
+    /// For example, `(Genre, "osu! converts")` or `(Credit, "{credit} (osu!)")`.
+    pub set: Vec<(MetaField, String)>,
+    /// Extra header fields to add to the simfiles, along with their value templates (using the
+    /// same placeholders as `set`).
+    /// Fields that are already present are overwritten.
+    ///
+    /// For example, `("MUSICBPM", "180")`.
+    /// Note that extra fields are only written if enabled in `SimfileWrite`.
+    pub fields: Vec<(String, String)>,
+    /// Path to an image to use as the CDTITLE of all simfiles.
+    ///
+    /// Instead of being copied next to every simfile, the image is copied once into the song
+    /// group folder, and simfiles refer to it as `../<image name>`.
+    /// If empty, the CDTITLE is left untouched.
+    pub cdtitle: String,
+    /// The resolved absolute path to the CDTITLE image.
+    #[serde(skip)]
+    pub cdtitle_src: PathBuf,
+}
+impl Default for SetMeta {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            set: vec![],
+            fields: vec![],
+            cdtitle: "".into(),
+            cdtitle_src: default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum MetaField {
+    Title,
+    Subtitle,
+    Artist,
+    TitleTranslit,
+    SubtitleTranslit,
+    ArtistTranslit,
+    Genre,
+    Credit,
+    Desc,
+}
+impl MetaField {
+    const ALL: [MetaField; 9] = [
+        MetaField::Title,
+        MetaField::Subtitle,
+        MetaField::Artist,
+        MetaField::TitleTranslit,
+        MetaField::SubtitleTranslit,
+        MetaField::ArtistTranslit,
+        MetaField::Genre,
+        MetaField::Credit,
+        MetaField::Desc,
+    ];
+
+    fn placeholder(&self) -> &'static str {
+        use MetaField::*;
+        match self {
+            Title => "{title}",
+            Subtitle => "{subtitle}",
+            Artist => "{artist}",
+            TitleTranslit => "{title_translit}",
+            SubtitleTranslit => "{subtitle_translit}",
+            ArtistTranslit => "{artist_translit}",
+            Genre => "{genre}",
+            Credit => "{credit}",
+            Desc => "{desc}",
+        }
+    }
+
+    fn get<'a>(&self, sm: &'a Simfile) -> &'a str {
+        use MetaField::*;
+        match self {
+            Title => &sm.title,
+            Subtitle => &sm.subtitle,
+            Artist => &sm.artist,
+            TitleTranslit => &sm.title_trans,
+            SubtitleTranslit => &sm.subtitle_trans,
+            ArtistTranslit => &sm.artist_trans,
+            Genre => &sm.genre,
+            Credit => &sm.credit,
+            Desc => &sm.desc,
+        }
+    }
+
+    fn get_mut<'a>(&self, sm: &'a mut Simfile) -> &'a mut String {
+        use MetaField::*;
+        match self {
+            Title => &mut sm.title,
+            Subtitle => &mut sm.subtitle,
+            Artist => &mut sm.artist,
+            TitleTranslit => &mut sm.title_trans,
+            SubtitleTranslit => &mut sm.subtitle_trans,
+            ArtistTranslit => &mut sm.artist_trans,
+            Genre => &mut sm.genre,
+            Credit => &mut sm.credit,
+            Desc => &mut sm.desc,
+        }
+    }
+}
+
+/// Replace all placeholders in the given template.
+fn fill_template(template: &str, sm: &Simfile, set_id: &str) -> String {
+    let mut out = template
+        .replace("{version}", &sm.version)
+        .replace("{meter}", &sm.difficulty_num.round().to_string())
+        .replace("{set_id}", set_id);
+    for field in MetaField::ALL.iter() {
+        out = out.replace(field.placeholder(), field.get(sm));
+    }
+    out
+}
+
+impl Node for SetMeta {
+    fn prepare(&mut self) -> Result<()> {
+        if !self.cdtitle.is_empty() {
+            self.cdtitle_src = fs::canonicalize(&self.cdtitle)
+                .with_context(|| anyhow!("failed to find cdtitle image at \"{}\"", self.cdtitle))?;
+            ensure!(
+                self.cdtitle_src.file_name().is_some(),
+                "cdtitle image \"{}\" is not a file",
+                self.cdtitle
+            );
+        }
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let set_id = store.global_get("set_id").unwrap_or_default().to_string();
+        let cdtitle = self.cdtitle_src.file_name().map(|name| {
+            //Let the writer know where to copy the image from
+            store.global_set(
+                &format!("group_file:{}", name.to_string_lossy()),
+                self.cdtitle_src.to_string_lossy().into_owned(),
+            );
+            Path::new("..").join(name)
+        });
+        store.get(&self.from, |store, list| {
+            for sm in list.iter_mut() {
+                let values = self
+                    .set
+                    .iter()
+                    .map(|(field, template)| (*field, fill_template(template, sm, &set_id)))
+                    .collect::<Vec<_>>();
+                let fields = self
+                    .fields
+                    .iter()
+                    .map(|(name, template)| (name, fill_template(template, sm, &set_id)))
+                    .collect::<Vec<_>>();
+                for (field, value) in values {
+                    *field.get_mut(sm) = value;
+                }
+                for (name, value) in fields {
+                    match sm
+                        .extra_fields
+                        .iter_mut()
+                        .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+                    {
+                        Some((_, existing)) => *existing = value,
+                        None => sm.extra_fields.push((name.clone(), value)),
+                    }
+                }
+                if let Some(cdtitle) = &cdtitle {
+                    sm.cdtitle = Some(cdtitle.clone());
+                }
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
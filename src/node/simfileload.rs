@@ -0,0 +1,212 @@
+//! Take a StepMania song directory and parse its `.sm` and `.ssc` simfiles, so that existing
+//! packs can go through the same nodes as converted osu! beatmaps.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimfileLoad {
+    pub into: BucketId,
+    /// The input StepMania song folder.
+    /// Every folder with simfiles in it is loaded as a single song, like StepMania does.
+    pub input: String,
+    /// Whether to only read the `.ssc` file if a song has both an `.sm` and an `.ssc` file with
+    /// the same name, like StepMania does.
+    pub prefer_ssc: bool,
+}
+impl Default for SimfileLoad {
+    fn default() -> Self {
+        Self {
+            into: default(),
+            input: "".into(),
+            prefer_ssc: true,
+        }
+    }
+}
+
+impl Node for SimfileLoad {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            !self.input.is_empty(),
+            "no input folder to load simfiles from"
+        );
+        info!("scanning for simfiles in \"{}\"", self.input);
+        Ok(())
+    }
+    fn apply(&self, _store: &mut SimfileStore) -> Result<()> {
+        Ok(())
+    }
+    fn buckets_mut(&mut self) -> BucketIter<'_> {
+        Box::new(iter::once((BucketKind::Output, &mut self.into)))
+    }
+    fn entry(
+        &self,
+        store: &mut SimfileStore,
+        on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
+    ) -> Result<()> {
+        #[cfg(feature = "fs")]
+        {
+            scan_folder(self, store, on_bmset)
+        }
+        #[cfg(not(feature = "fs"))]
+        {
+            let _ = (store, on_bmset);
+            bail!("loading simfiles from a folder requires the `fs` feature")
+        }
+    }
+}
+
+/// Find all song folders in the input folder, and process them in parallel on the rayon thread
+/// pool.
+#[cfg(feature = "fs")]
+fn scan_folder(
+    conf: &SimfileLoad,
+    store: &mut SimfileStore,
+    on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
+) -> Result<()> {
+    use rayon::prelude::*;
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+    //Song folders and their simfiles, sorted by path
+    let mut songs: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for entry in WalkDir::new(&conf.input).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("failed to scan input directory: {:#}", err);
+                continue;
+            }
+        };
+        let is_simfile = entry
+            .path()
+            .extension()
+            .map(|ext| ext == "sm" || ext == "ssc")
+            .unwrap_or(false);
+        if !entry.file_type().is_file() || !is_simfile {
+            continue;
+        }
+        if let Some(parent) = entry.path().parent() {
+            songs
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(entry.into_path());
+        }
+    }
+    if conf.prefer_ssc {
+        for sm_paths in songs.values_mut() {
+            let ssc = sm_paths
+                .iter()
+                .filter(|path| path.extension() == Some("ssc".as_ref()))
+                .map(|path| path.with_extension(""))
+                .collect::<HashSet<_>>();
+            sm_paths.retain(|path| {
+                path.extension() == Some("ssc".as_ref()) || !ssc.contains(&path.with_extension(""))
+            });
+        }
+    }
+    let songs = songs.into_iter().collect::<Vec<_>>();
+    info!(
+        "found {} songs, loading on {} threads",
+        songs.len(),
+        rayon::current_num_threads()
+    );
+    crate::progress::discovered(songs.len());
+    let node = crate::crash::current_node();
+    let interrupted = AtomicBool::new(false);
+    songs.par_iter().for_each_init(
+        || store.clone(),
+        |store, (song_path, sm_paths)| {
+            if crate::cancel::requested() {
+                if !interrupted.swap(true, Ordering::Relaxed) {
+                    warn!("interrupted, stopping before \"{}\"", song_path.display());
+                }
+                return;
+            }
+            crate::crash::set_node(node);
+            crate::crash::set_beatmapset(Some(song_path));
+            let ok = crate::logger::batched(|| {
+                match process_song(conf, store, song_path, sm_paths, on_bmset) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        error!(
+                            "  error processing song at \"{}\": {:#}",
+                            song_path.display(),
+                            err
+                        );
+                        false
+                    }
+                }
+            });
+            crate::crash::set_beatmapset(None);
+            crate::progress::processed(ok);
+        },
+    );
+    Ok(())
+}
+
+#[cfg(feature = "fs")]
+fn process_song(
+    conf: &SimfileLoad,
+    store: &mut SimfileStore,
+    song_path: &Path,
+    sm_paths: &[PathBuf],
+    on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
+) -> Result<()> {
+    info!("processing \"{}\":", song_path.display());
+    let mut simfiles = Vec::new();
+    for sm_path in sm_paths {
+        let sm_name = sm_path.file_name().unwrap_or_default().to_string_lossy();
+        let charts = match Simfile::load(sm_path) {
+            Ok(charts) => charts,
+            Err(err) => {
+                error!("  error reading simfile \"{}\": {:#}", sm_name, err);
+                continue;
+            }
+        };
+        let total = charts.len();
+        let before = simfiles.len();
+        for sm in charts {
+            match sm.check() {
+                Ok(()) => simfiles.push(Box::new(sm)),
+                Err(err) => {
+                    warn!(
+                        "  skipping chart \"{}\" of \"{}\": {:#}",
+                        sm.desc, sm_name, err
+                    );
+                }
+            }
+        }
+        debug!(
+            "  loaded simfile \"{}\" with {} out of {} charts",
+            sm_name,
+            simfiles.len() - before,
+            total
+        );
+    }
+    //Fingerprint every music file once
+    let mut music_hashes: HashMap<PathBuf, Option<u64>> = default();
+    for sm in simfiles.iter_mut() {
+        if let Some(music) = &sm.music {
+            sm.music_hash = *music_hashes
+                .entry(music.clone())
+                .or_insert_with(|| crate::audio::fingerprint(&song_path.join(music)).ok());
+        }
+    }
+    store.reset();
+    store.global_set("root", conf.input.to_string());
+    store.global_set(
+        "base",
+        song_path
+            .to_str()
+            .ok_or(anyhow!("non utf-8 song path \"{}\"", song_path.display()))?
+            .to_string(),
+    );
+    if let Some(name) = song_path.file_name().and_then(OsStr::to_str) {
+        store.global_set("set_folder", name.to_string());
+    }
+    store.put(&conf.into, simfiles);
+    on_bmset(store)?;
+    Ok(())
+}
@@ -1,6 +1,17 @@
 //! Takes a bunch of simfiles as input and writes them out to the filesystem.
 
-use crate::node::prelude::*;
+use crate::{
+    node::prelude::*,
+    pack::PackMeta,
+    repair::Manifest,
+    simfile::{row_signature, Compat, SaveOpts, SimfileFormat},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -8,6 +19,25 @@ pub struct SimfileWrite {
     pub from: BucketId,
     /// Which methods to try for copying "dependency" files, such as `.mp3` and `.jpg` files.
     pub copy: Vec<CopyMethod>,
+    /// How to retry copy methods that fail with errors that might go away by themselves, such as
+    /// files locked by an antivirus or a cloud sync client.
+    pub copy_retry: CopyRetry,
+    /// The files that could not be copied, even after retrying, to list them when done.
+    /// Cannot be set from the config, it is only used as an internal cache.
+    #[serde(skip)]
+    pub copy_failures: Shared<Vec<PathBuf>>,
+    /// Whether to skip dependencies that are cloud placeholders (eg. OneDrive files that are
+    /// "available online only"), instead of downloading them while copying.
+    /// Only detected on Windows.
+    pub skip_placeholders: bool,
+    /// Whether to re-hash copied dependency files and compare them against their source, to catch
+    /// links pointing to the wrong file or truncated copies.
+    /// Slows down conversion, since every dependency has to be read twice.
+    pub verify_copies: bool,
+    /// Whether to copy the files that every simfile was converted from (ie. the original `.osu`
+    /// files) into an `osu-src` folder next to it, using the `copy` methods.
+    /// Does nothing when converting in-place, since simfiles are written next to their sources.
+    pub copy_sources: bool,
     /// Attempt to create a symlink from the input root directory to the output `output` directory.
     /// This allows for faster conversion, in a way that simfiles are output in the same input
     /// directory.
@@ -15,18 +45,112 @@ pub struct SimfileWrite {
     /// Note that on windows creating symlinks requires admin permissions!
     /// Once the symlink is created no special permissions are required though.
     pub in_place: bool,
+    /// How to split the input folder into StepMania song groups when converting in-place.
+    ///
+    /// StepMania does not cope well with huge song groups, so instead of linking the whole input
+    /// folder as a single group, a group can be created for every slice of the library, each
+    /// containing links to its beatmapset folders.
+    /// Groups are created next to the `output` folder, named after it.
+    pub in_place_split: GroupSplit,
     /// If the output directory is a symlink to somewhere, this is it.
     /// Cannot be set from the config, it is only used as an internal cache.
     #[serde(skip)]
-    pub in_place_from: RefCell<Option<PathBuf>>,
-    /// Remove all files in the output directory or subdirectories matching the `osu2sm-*.sm`
-    /// filename, where `*` stands for anything.
+    pub in_place_from: Shared<Option<PathBuf>>,
+    /// Files shared by the whole song group that were already copied.
+    /// Cannot be set from the config, it is only used as an internal cache.
+    #[serde(skip)]
+    pub group_copied: Shared<HashSet<PathBuf>>,
+    /// The manifest of the simfiles in the output folder, written when done.
+    /// Cannot be set from the config, it is only used as an internal cache.
+    #[serde(skip)]
+    pub manifest: Shared<Manifest>,
+    /// Locks for the output folders being written to, so that beatmapsets that end up in the
+    /// same folder are written one at a time.
+    /// Cannot be set from the config, it is only used as an internal cache.
+    #[serde(skip)]
+    pub folder_locks: Shared<HashMap<PathBuf, Arc<Mutex<()>>>>,
+    /// Remove all files in the output directory or subdirectories matching the `osu2sm-*.sm` or
+    /// `osu2sm-*.ssc` filenames, where `*` stands for anything.
     pub cleanup: bool,
     /// Whether to automatically correct output paths if they point somewhere within a StepMania
     /// installation.
     pub fix_output: bool,
     /// The path to the output directory (a StepMania song group).
     pub output: String,
+    /// The file format to write simfiles in.
+    pub format: SimfileFormat,
+    /// What to do with charts of the same song that have different timing data, since all charts
+    /// in a `.sm` file share the timing of the first one.
+    /// Does not matter when writing `.ssc` files, which store the timing of every chart.
+    pub timing_conflicts: TimingConflicts,
+    /// Whether to split the charts of the same song into several simfiles.
+    /// StepMania may skip charts of a gamemode when others are in the same file, so mixed
+    /// gamemodes (eg. dance-single and pump-single) are better split.
+    pub split_files: FileSplit,
+    /// What to do with charts of the same song that have different backgrounds (eg. osu!
+    /// difficulties with their own background image), since simfiles have a single background.
+    pub background_conflicts: BackgroundConflicts,
+    /// Whether to flush every simfile to disk before moving it into place.
+    /// Simfiles are always written to a temporary file first, so that crashing midway never
+    /// leaves broken simfiles behind, but without flushing a power loss still could.
+    /// Slows down conversion.
+    pub fsync: bool,
+    /// The sync convention of the target StepMania setup, used to compensate the `#OFFSET` of
+    /// written simfiles.
+    pub sync: SyncBasis,
+    /// Comment lines to write at the top of every simfile.
+    ///
+    /// The following placeholders are replaced in every line:
+    /// - `{version}`: The `osu2sm` version.
+    /// - `{date}`: The current date, in `YYYY-MM-DD` format.
+    /// - `{set_id}`: The osu! beatmapset id, if known.
+    /// - `{url}`: The osu! website URL of the source beatmapset, if known.
+    /// - `{config_hash}`: A hash of the conversion settings, to tell apart simfiles converted with
+    ///   different settings.
+    pub header: Vec<String>,
+    /// Whether to embed the full conversion settings as comments in every simfile, so that the
+    /// conversion can be reproduced.
+    pub embed_config: bool,
+    /// Whether to write the extra header fields of simfiles (such as `#INSTRUMENTTRACK` or
+    /// `#MUSICBPM`, set by nodes like `SetMeta`).
+    /// Only enable if the target StepMania fork or theme understands these fields, since
+    /// unknown fields may confuse other simfile parsers.
+    pub extra_fields: bool,
+    /// The StepMania version or fork to write simfiles for.
+    /// With `OutFox`, preview videos (see the `PreviewClip` node) are written and copied too.
+    pub compat: Compat,
+    /// A `Pack.ini` and a banner to write into the song group folder, so that the pack shows up
+    /// properly in the song wheel.
+    /// When splitting in-place groups, every group gets its own.
+    pub pack: PackMeta,
+    /// The song groups that beatmapsets were linked into, when splitting in-place groups.
+    /// Cannot be set from the config, it is only used as an internal cache.
+    #[serde(skip)]
+    pub split_groups: Shared<BTreeSet<PathBuf>>,
+    /// Go through the whole conversion, but only report which files would be written or copied
+    /// (and how many bytes), without touching the output folder.
+    /// Useful to check a new configuration safely.
+    pub dry_run: bool,
+    /// What would have been written so far, when doing a dry run.
+    /// Cannot be set from the config, it is only used as an internal cache.
+    #[serde(skip)]
+    pub dry_run_stats: Shared<DryRunStats>,
+    /// What to do with beatmapset folders whose names are reserved on Windows (such as `CON` or
+    /// `AUX`), which cannot be created there.
+    /// Only matters when not converting in-place, since the input folders already exist.
+    pub reserved_names: ReservedNames,
+    /// The kind of drive that the output folder is on.
+    pub profile: OutputProfile,
+    /// Whether to replace the characters in folder and file names that removable media (such as
+    /// FAT32 USB sticks) reject or mangle, such as `?`, `:`, emoji or trailing dots.
+    /// The original names are noted in the output manifest, to map them back.
+    /// Only matters when not converting in-place, since the input folders already exist.
+    pub scrub_names: bool,
+    /// Log how every measure of the charts is laid out (its note rows and where each note lands)
+    /// when writing songs whose title contains this text, ignoring case.
+    /// Meant to debug charts whose notes show up with the wrong snap.
+    /// Empty to disable.
+    pub trace_measures: String,
 }
 
 impl Default for SimfileWrite {
@@ -34,9 +158,18 @@ impl Default for SimfileWrite {
         Self {
             from: default(),
             output: "".into(),
+            format: SimfileFormat::Sm,
+            timing_conflicts: TimingConflicts::Split,
+            split_files: FileSplit::None,
+            background_conflicts: BackgroundConflicts::First,
+            fsync: false,
             fix_output: true,
             in_place: true,
-            in_place_from: RefCell::new(None),
+            in_place_split: GroupSplit::Single,
+            in_place_from: default(),
+            group_copied: default(),
+            manifest: default(),
+            folder_locks: default(),
             copy: {
                 //Leave symlinks for last on windows, since they require admin permissions
                 #[cfg(target_family = "windows")]
@@ -59,11 +192,391 @@ impl Default for SimfileWrite {
                     ]
                 }
             },
+            copy_retry: default(),
+            copy_failures: default(),
+            skip_placeholders: false,
+            verify_copies: false,
+            copy_sources: false,
             cleanup: false,
+            sync: SyncBasis::Null,
+            header: vec![
+                "Simfile converted from osu! automatically using `osu2sm` by negamartin".into(),
+            ],
+            embed_config: false,
+            extra_fields: false,
+            compat: Compat::StepMania,
+            pack: default(),
+            split_groups: default(),
+            dry_run: false,
+            dry_run_stats: default(),
+            reserved_names: ReservedNames::Rename,
+            profile: OutputProfile::Local,
+            scrub_names: false,
+            trace_measures: "".into(),
+        }
+    }
+}
+
+/// The files that a dry run would have written.
+#[derive(Clone, Debug, Default)]
+pub struct DryRunStats {
+    /// New simfiles.
+    pub created: usize,
+    /// Simfiles that would replace existing ones.
+    pub overwritten: usize,
+    /// The total size of all simfiles.
+    pub simfile_bytes: u64,
+    /// Dependencies and group files that would be copied or linked.
+    pub copied: usize,
+    /// The total size of the copied files.
+    pub copied_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum GroupSplit {
+    /// Link the whole input folder as a single song group.
+    Single,
+    /// Create a song group for every initial letter of the song titles (and a `#` group for any
+    /// other initial), such as `Osu A`, `Osu B`, ..., `Osu #`.
+    FirstLetter,
+    /// Create a song group for every range of initial characters of the song titles, given as a
+    /// single character (`"X"`) or an inclusive range (`"A-F"`).
+    /// Songs outside of all ranges go into a `#` group.
+    Ranges(Vec<String>),
+}
+impl GroupSplit {
+    /// Parse a `"A-F"` or `"X"` range into its inclusive bounds.
+    fn parse_range(range: &str) -> Option<(char, char)> {
+        let mut bounds = range.splitn(2, '-').map(|bound| {
+            let mut chars = bound.trim().chars().flat_map(char::to_uppercase);
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            Some(c)
+        });
+        let start = bounds.next()??;
+        let end = bounds.next().unwrap_or(Some(start))?;
+        Some((start, end))
+    }
+
+    /// Get the name of the group that a song with the given title belongs to, or `None` if not
+    /// splitting groups.
+    fn group_label(&self, title: &str) -> Option<String> {
+        let first = title
+            .trim()
+            .chars()
+            .flat_map(char::to_uppercase)
+            .next()
+            .unwrap_or('#');
+        match self {
+            GroupSplit::Single => None,
+            GroupSplit::FirstLetter if first.is_alphabetic() => Some(first.to_string()),
+            GroupSplit::FirstLetter => Some("#".to_string()),
+            GroupSplit::Ranges(ranges) => Some(
+                ranges
+                    .iter()
+                    .find(|range| {
+                        Self::parse_range(range)
+                            .map(|(start, end)| start <= first && first <= end)
+                            .unwrap_or(false)
+                    })
+                    .map(|range| range.trim().to_uppercase())
+                    .unwrap_or_else(|| "#".to_string()),
+            ),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SyncBasis {
+    /// Keep offsets as they are (StepMania's default "null" sync).
+    Null,
+    /// Add the 9ms bias that ITG-synced simfiles expect.
+    Itg,
+    /// Add an arbitrary amount of milliseconds to the offset.
+    Custom(f64),
+}
+impl SyncBasis {
+    /// How many milliseconds to add to the simfile offset.
+    pub fn offset_ms(&self) -> f64 {
+        match self {
+            SyncBasis::Null => 0.,
+            SyncBasis::Itg => 9.,
+            SyncBasis::Custom(ms) => *ms,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            SyncBasis::Null => "sync basis: null (no offset compensation)".to_string(),
+            SyncBasis::Itg => format!(
+                "sync basis: ITG (+{}ms offset compensation)",
+                self.offset_ms()
+            ),
+            SyncBasis::Custom(ms) => format!("sync basis: custom ({:+}ms offset compensation)", ms),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReservedNames {
+    /// Use the names as-is, which only works when not on Windows.
+    Keep,
+    /// Append an underscore to reserved names (eg. `CON` becomes `CON_`), and note the rename in
+    /// the output manifest.
+    Rename,
+    /// Skip beatmapsets with reserved names.
+    Refuse,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputProfile {
+    /// A regular drive, where any copy method and file name works.
+    Local,
+    /// A USB stick or memory card (eg. for an arcade cabinet), usually formatted as FAT32 or
+    /// exFAT.
+    /// These filesystems have no links, so dependencies are always copied and in-place conversion
+    /// is disabled, and folder and file names are scrubbed (see `scrub_names`).
+    Removable,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimingConflicts {
+    /// Write the charts of every distinct timing into their own `.sm` file (eg.
+    /// `osu2sm-audio.sm`, `osu2sm-audio (2).sm`).
+    Split,
+    /// Write the song as a `.ssc` file instead, which stores the timing of every chart.
+    Ssc,
+    /// Write a single `.sm` file anyway, with every chart using the timing of the first chart.
+    Ignore,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileSplit {
+    /// Write all charts of the same music into a single simfile.
+    None,
+    /// Write the charts of every gamemode into their own simfile (eg.
+    /// `osu2sm-audio-dance-single.sm`).
+    Gamemode,
+    /// Write the charts of every keycount into their own simfile (eg. `osu2sm-audio-4k.sm`).
+    KeyCount,
+}
+impl FileSplit {
+    /// Get the text that tells apart the simfile of the given chart from the other simfiles of
+    /// the same music.
+    fn tag(&self, sm: &Simfile) -> String {
+        match self {
+            FileSplit::None => String::new(),
+            FileSplit::Gamemode => format!("-{}", sm.gamemode.id()),
+            FileSplit::KeyCount => format!("-{}k", sm.gamemode.key_count()),
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BackgroundConflicts {
+    /// Use the background of the first chart.
+    First,
+    /// Use the background that most charts have, or the first one if tied.
+    Majority,
+    /// Use the background of the hardest chart.
+    Hardest,
+    /// Write the song as a `.ssc` file, with a `#BGCHANGES` tag for every chart whose background
+    /// differs from the first one.
+    /// Forks without per-chart backgrounds use the background of the first chart instead.
+    PerChart,
+}
+impl BackgroundConflicts {
+    /// Settle the backgrounds of the charts that go into the same simfile.
+    /// Returns whether the charts still have different backgrounds.
+    fn resolve(&self, music_path: &Path, simfiles: &mut [Box<Simfile>]) -> bool {
+        let first = &simfiles[0].background;
+        if simfiles.iter().all(|sm| sm.background == *first) {
+            return false;
+        }
+        let chosen = match self {
+            BackgroundConflicts::First => first.clone(),
+            BackgroundConflicts::Majority => {
+                let mut counts: Vec<(&Option<PathBuf>, usize)> = Vec::new();
+                for sm in simfiles.iter() {
+                    match counts.iter_mut().find(|(bg, _)| **bg == sm.background) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push((&sm.background, 1)),
+                    }
+                }
+                //Keep the first background among the tied ones
+                let max = counts.iter().map(|(_, count)| *count).max().unwrap_or(0);
+                counts
+                    .into_iter()
+                    .find(|(_, count)| *count == max)
+                    .and_then(|(bg, _)| bg.clone())
+            }
+            BackgroundConflicts::Hardest => {
+                let mut hardest = &simfiles[0];
+                for sm in simfiles.iter().skip(1) {
+                    if sm.difficulty_num > hardest.difficulty_num
+                        || (hardest.difficulty_num.is_nan() && !sm.difficulty_num.is_nan())
+                    {
+                        hardest = sm;
+                    }
+                }
+                hardest.background.clone()
+            }
+            BackgroundConflicts::PerChart => {
+                debug!(
+                    "  charts of \"{}\" have different backgrounds, keeping them per chart",
+                    music_path.display()
+                );
+                return true;
+            }
+        };
+        debug!(
+            "  charts of \"{}\" have different backgrounds, using \"{}\"",
+            music_path.display(),
+            chosen.as_deref().unwrap_or_else(|| Path::new("")).display()
+        );
+        for sm in simfiles.iter_mut() {
+            sm.background = chosen.clone();
+        }
+        false
+    }
+}
+
+/// Whether two charts can share the same timing data.
+fn same_timing(a: &Simfile, b: &Simfile) -> bool {
+    a.offset == b.offset
+        && a.bpms == b.bpms
+        && a.stops == b.stops
+        && a.delays == b.delays
+        && a.warps == b.warps
+}
+
+/// Device names that Windows reserves in every folder, regardless of extension.
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Get a name that Windows accepts for the given path component, or `None` if it is fine as-is.
+///
+/// Besides device names, Windows also chokes on names ending in a dot or a space.
+fn windows_safe_name(name: &str) -> Option<String> {
+    let trimmed = name.trim_end_matches(&['.', ' '][..]);
+    let (stem, ext) = match trimmed.find('.') {
+        Some(dot) => trimmed.split_at(dot),
+        None => (trimmed, ""),
+    };
+    let stem = stem.trim_end();
+    if WINDOWS_RESERVED
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        Some(format!("{}_{}", stem, ext))
+    } else if trimmed.len() != name.len() {
+        Some(format!("{}_", trimmed))
+    } else {
+        None
+    }
+}
+
+/// Get a name that removable media accepts for the given path component, or `None` if it is fine
+/// as-is.
+///
+/// Besides the characters that FAT32 and exFAT reject, emoji are replaced too, since many devices
+/// mangle characters outside of the basic multilingual plane.
+/// Trailing dots and spaces are removed, since FAT32 silently drops them.
+pub(crate) fn scrubbed_name(name: &str) -> Option<String> {
+    if name == "." || name == ".." {
+        return None;
+    }
+    let mut scrubbed = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|' => scrubbed.push('_'),
+            //Joiners and variation selectors only make sense next to emoji
+            '\u{200d}' | '\u{fe0e}' | '\u{fe0f}' => {}
+            c if c.is_control() || c as u32 > 0xffff || ('\u{2600}'..='\u{27bf}').contains(&c) => {
+                scrubbed.push('_')
+            }
+            c => scrubbed.push(c),
+        }
+    }
+    scrubbed.truncate(scrubbed.trim_end_matches(&['.', ' '][..]).len());
+    if scrubbed.is_empty() {
+        scrubbed.push('_');
+    }
+    if scrubbed == name {
+        None
+    } else {
+        Some(scrubbed)
+    }
+}
+
+/// Scrub every component of a relative path.
+fn scrub_path(path: &Path) -> PathBuf {
+    path.iter()
+        .map(|comp| match scrubbed_name(&comp.to_string_lossy()) {
+            Some(scrubbed) => PathBuf::from(scrubbed),
+            None => PathBuf::from(comp),
+        })
+        .collect()
+}
+
+/// Scrub the dependency paths of a simfile, remembering the original path of every scrubbed
+/// dependency in `originals`.
+///
+/// Different files might scrub to the same name, or to the name of a file in `kept` (the
+/// dependencies that need no scrubbing), so later ones get a number added, just like renamed
+/// output folders.
+/// Files shared by the whole song group are left alone, since they are looked up by name.
+fn scrub_deps(
+    sm: &mut Simfile,
+    originals: &mut HashMap<PathBuf, PathBuf>,
+    kept: &HashSet<PathBuf>,
+) {
+    let Simfile {
+        banner,
+        background,
+        video,
+        preview_video,
+        lyrics,
+        cdtitle,
+        music,
+        ..
+    } = sm;
+    let deps = [banner, background, preview_video, lyrics, cdtitle, music];
+    let deps = IntoIterator::into_iter(deps)
+        .filter_map(Option::as_mut)
+        .chain(video.as_mut().map(|video| &mut video.path));
+    for dep in deps {
+        if group_dep_name(dep).is_some() {
+            continue;
+        }
+        let scrubbed = scrub_path(dep);
+        if scrubbed == *dep {
+            continue;
+        }
+        //Charts sharing a file share its scrubbed name too
+        if let Some((name, _)) = originals.iter().find(|(_, original)| **original == *dep) {
+            *dep = name.clone();
+            continue;
+        }
+        let mut name = scrubbed.clone();
+        let mut n = 2;
+        while kept.contains(&name) || originals.contains_key(&name) {
+            let stem = scrubbed.file_stem().unwrap_or_default().to_string_lossy();
+            name = scrubbed.with_file_name(match scrubbed.extension() {
+                Some(ext) => format!("{} ({}).{}", stem, n, ext.to_string_lossy()),
+                None => format!("{} ({})", stem, n),
+            });
+            n += 1;
+        }
+        let original = mem::replace(dep, name.clone());
+        originals.insert(name, original);
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub enum CopyMethod {
     /// Create a hardlink from source to destination.
@@ -76,6 +589,24 @@ pub enum CopyMethod {
     AssertIdentical,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CopyRetry {
+    /// How many times to try every copy method before moving on to the next one.
+    pub attempts: u32,
+    /// How long to wait before the first retry, in milliseconds.
+    /// The wait doubles after every attempt.
+    pub backoff_ms: u64,
+}
+impl Default for CopyRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_ms: 200,
+        }
+    }
+}
+
 const STEPMANIA_AUTODETECT: BaseDirFinder = BaseDirFinder {
     base_files: &[
         "Announcers",
@@ -120,8 +651,19 @@ impl Node for SimfileWrite {
                     );
                     debug!("  songs dir at \"{}\"", main);
                     if self.output != main {
-                        info!("fixed output path: \"{}\" -> \"{}\"", self.output, main);
-                        self.output = main;
+                        let fix = crate::prompt::choose(
+                            "fix_output",
+                            &format!(
+                                "the output folder \"{}\" is within a StepMania installation, but not a song group folder (such as \"{}\")",
+                                self.output, main
+                            ),
+                            &["use the song group folder", "keep the output folder as-is"],
+                            0,
+                        ) == 0;
+                        if fix {
+                            info!("fixed output path: \"{}\" -> \"{}\"", self.output, main);
+                            self.output = main;
+                        }
                     }
                 }
                 Err(err) => {
@@ -129,58 +671,84 @@ impl Node for SimfileWrite {
                 }
             }
         }
+        if self.profile == OutputProfile::Removable {
+            //Removable media filesystems have no links
+            self.copy
+                .retain(|method| matches!(method, CopyMethod::Copy | CopyMethod::AssertIdentical));
+            if self.copy.is_empty() {
+                self.copy = vec![CopyMethod::Copy, CopyMethod::AssertIdentical];
+            }
+            //Unless the output is already linked (eg. when converting a single beatmap)
+            if self.in_place_from.get_mut().is_none() {
+                self.in_place = false;
+            }
+            self.scrub_names = true;
+            info!("writing to removable media, copying dependencies and scrubbing file names");
+        }
+        if let GroupSplit::Ranges(ranges) = &self.in_place_split {
+            for range in ranges {
+                ensure!(
+                    GroupSplit::parse_range(range).is_some(),
+                    "invalid song group range \"{}\" (expected a character or a range such as \"A-F\")",
+                    range
+                );
+            }
+        }
         //Cleanup output
         if self.cleanup {
-            info!(
-                "cleanup enabled, removing all `osu2sm-*.sm` files under \"{}\"",
-                self.output
-            );
-            let mut files_removed = 0;
-            for file in WalkDir::new(&self.output) {
-                let file = match file {
-                    Ok(f) => f,
-                    Err(err) => {
-                        warn!("  failed to list files for cleanup: {:#}", err);
-                        continue;
-                    }
-                };
-                if file.file_type().is_file() {
-                    let filename = file.file_name().to_string_lossy();
-                    if filename.starts_with("osu2sm-") && filename.ends_with(".sm") {
-                        match fs::remove_file(file.path()) {
-                            Ok(()) => {
-                                files_removed += 1;
-                            }
-                            Err(err) => {
-                                warn!(
-                                    "  failed to remove file \"{}\" while cleaning up: {:#}",
-                                    file.path().display(),
-                                    err
-                                );
-                            }
-                        }
+            cleanup_output(&self.output, self.dry_run);
+        } else {
+            //Keep track of simfiles from previous runs
+            match Manifest::load(self.output.as_ref()) {
+                Ok(manifest) => {
+                    let changes = crate::changelist::changes_since(&manifest.version);
+                    if !manifest.version.is_empty()
+                        && !manifest.sources.is_empty()
+                        && !changes.is_empty()
+                    {
+                        info!(
+                            "the output was written by osu2sm {}, converting the beatmapsets affected by the changes in {} again",
+                            manifest.version,
+                            crate::changelist::describe(changes)
+                        );
                     }
+                    *self.manifest.get_mut() = manifest;
                 }
+                Err(err) => warn!("discarding output manifest: {:#}", err),
             }
-            info!("  removed {} files", files_removed);
         }
-        info!("outputting simfiles in \"{}\"", self.output);
+        if self.dry_run {
+            info!(
+                "dry run, only reporting what would be written to \"{}\"",
+                self.output
+            );
+        } else {
+            info!("outputting simfiles in \"{}\"", self.output);
+        }
         Ok(())
     }
     fn apply(&self, store: &mut SimfileStore) -> Result<()> {
-        //Organize output simfiles
-        let mut by_music: HashMap<PathBuf, Vec<Box<Simfile>>> = HashMap::default();
+        //Organize output simfiles, sorted by music (and by gamemode or keycount if splitting
+        //files) so that they are always written in the same order
+        let mut by_music: BTreeMap<(PathBuf, String), Vec<Box<Simfile>>> = BTreeMap::new();
         store.get_each(&self.from, |_, mut sm| {
             //Fix some `.sm` quirks
             sm.fix_tails()?;
+            //Compensate for the target sync convention
+            sm.offset += self.sync.offset_ms() / 1000.;
+            //Do not copy preview videos that will not be written
+            if self.compat != Compat::OutFox {
+                sm.preview_video = None;
+            }
             //Append to the appropiate list
             let list = by_music
-                .entry(
+                .entry((
                     AsRef::<Path>::as_ref(
                         sm.music.as_ref().map(|p| p.as_os_str()).unwrap_or_default(),
                     )
                     .to_path_buf(),
-                )
+                    self.split_files.tag(&sm),
+                ))
                 .or_default();
             list.push(sm);
             Ok(())
@@ -190,12 +758,15 @@ impl Node for SimfileWrite {
         let set_path = store.global_get_expect("base")?;
         //Handle in-place-ness lazily on the first simfile
         if self.in_place {
-            let mut in_place_from = self.in_place_from.borrow_mut();
+            let mut in_place_from = self.in_place_from.lock();
             let in_place_from = in_place_from.get_or_insert_with(|| {
                 //Attempt to create symlink for in-place conversion
-                match symlink_dir(root_path.as_ref(), self.output.as_ref())
-                    .context("failed to create output symlink pointing to input")
-                {
+                let result = match self.in_place_split {
+                    GroupSplit::Single => link_dir(self, root_path.as_ref(), self.output.as_ref())
+                        .context("failed to create output symlink pointing to input"),
+                    _ => link_subgroup(self, set_path.as_ref(), by_music.values().flatten()),
+                };
+                match result {
                     Ok(()) => {
                         info!("  enabled in-place conversion");
                         root_path.clone().into()
@@ -219,49 +790,606 @@ impl Node for SimfileWrite {
                     in_place_from.display(),
                     root_path,
                 );
+                //Every beatmapset is linked separately into its group
+                if !matches!(self.in_place_split, GroupSplit::Single) {
+                    link_subgroup(self, set_path.as_ref(), by_music.values().flatten())?;
+                }
+            }
+        }
+        //Make dependency names safe for the output filesystem
+        let mut dep_originals = HashMap::default();
+        if self.scrub_names && !in_place_enabled(self) {
+            let kept = by_music
+                .values()
+                .flatten()
+                .flat_map(|sm| sm.file_deps())
+                .filter(|dep| scrub_path(dep) == *dep)
+                .map(Path::to_path_buf)
+                .collect::<HashSet<_>>();
+            for sm in by_music.values_mut().flatten() {
+                scrub_deps(sm, &mut dep_originals, &kept);
             }
         }
         //Write output simfiles
-        for (_music_path, simfiles) in by_music {
-            //Write a single `.sm` for these simfiles
-            write_sm(self, root_path.as_ref(), set_path.as_ref(), &simfiles)?;
+        let save_opts = SaveOpts {
+            comments: header_comments(self, store),
+            extra_fields: self.extra_fields,
+            format: self.format,
+            fsync: self.fsync,
+            compat: self.compat,
+            trace_measures: !self.trace_measures.is_empty()
+                && by_music.values().flatten().any(|sm| {
+                    sm.title
+                        .to_lowercase()
+                        .contains(&self.trace_measures.to_lowercase())
+                }),
+        };
+        let ssc_opts = SaveOpts {
+            format: SimfileFormat::Ssc,
+            ..save_opts.clone()
+        };
+        for ((music_path, tag), simfiles) in by_music {
+            //Charts in a `.sm` file share the timing of the first chart, so charts with other
+            //timing need special care
+            let mut parts = vec![simfiles];
+            let mut opts = &save_opts;
+            let conflict = self.format == SimfileFormat::Sm
+                && parts[0].iter().any(|sm| !same_timing(sm, &parts[0][0]));
+            if conflict {
+                match self.timing_conflicts {
+                    TimingConflicts::Split => {
+                        let mut by_timing: Vec<Vec<Box<Simfile>>> = Vec::new();
+                        for sm in parts.pop().unwrap_or_default() {
+                            match by_timing.iter_mut().find(|part| same_timing(&part[0], &sm)) {
+                                Some(part) => part.push(sm),
+                                None => by_timing.push(vec![sm]),
+                            }
+                        }
+                        info!(
+                            "  charts of \"{}\" have different timing, splitting them into {} simfiles",
+                            music_path.display(),
+                            by_timing.len()
+                        );
+                        parts = by_timing;
+                    }
+                    TimingConflicts::Ssc => {
+                        info!(
+                            "  charts of \"{}\" have different timing, writing them as `.ssc`",
+                            music_path.display()
+                        );
+                        opts = &ssc_opts;
+                    }
+                    TimingConflicts::Ignore => {
+                        debug!(
+                            "  charts of \"{}\" have different timing, using the timing of the first chart",
+                            music_path.display()
+                        );
+                    }
+                }
+            }
+            //Write a single simfile for every part
+            for (idx, simfiles) in parts.iter_mut().enumerate() {
+                let mut opts = opts;
+                if self.background_conflicts.resolve(&music_path, simfiles)
+                    && opts.format == SimfileFormat::Sm
+                {
+                    info!(
+                        "  charts of \"{}\" have different backgrounds, writing them as `.ssc`",
+                        music_path.display()
+                    );
+                    opts = &ssc_opts;
+                }
+                let name_suffix = if idx > 0 {
+                    format!("{} ({})", tag, idx + 1)
+                } else {
+                    tag.clone()
+                };
+                let target = WriteTarget {
+                    root_path: root_path.as_ref(),
+                    set_path: set_path.as_ref(),
+                    dep_originals: &dep_originals,
+                    name_suffix: &name_suffix,
+                    save_opts: opts,
+                };
+                write_sm(self, store, simfiles, &target)?;
+            }
+        }
+        //Remember the sources of this beatmapset, to skip it next time if nothing changed
+        if let Some(hash) = store.global_get("source_hash") {
+            let set_rel = Path::new(set_path)
+                .strip_prefix(root_path)
+                .unwrap_or(set_path.as_ref());
+            self.manifest
+                .lock()
+                .sources
+                .insert(set_rel.to_string_lossy().into_owned(), hash.to_string());
+        }
+        Ok(())
+    }
+    fn finish(&self) -> Result<()> {
+        if self.pack.enable {
+            write_packs(self);
+        }
+        if self.dry_run {
+            let stats = self.dry_run_stats.lock();
+            info!(
+                "dry run: would write {} simfiles ({} new, {} overwritten, {} bytes) and copy {} files ({} bytes) into \"{}\"",
+                stats.created + stats.overwritten,
+                stats.created,
+                stats.overwritten,
+                stats.simfile_bytes,
+                stats.copied,
+                stats.copied_bytes,
+                self.output
+            );
+            return Ok(());
+        }
+        let failures = self.copy_failures.lock();
+        if !failures.is_empty() {
+            error!("failed to copy {} files:", failures.len());
+            for path in failures.iter().take(20) {
+                error!("  \"{}\"", path.display());
+            }
+            if failures.len() > 20 {
+                error!("  and {} more", failures.len() - 20);
+            }
+        }
+        drop(failures);
+        let mut manifest = self.manifest.lock();
+        if manifest.simfiles.is_empty() && manifest.sources.is_empty() {
+            return Ok(());
         }
+        manifest.version = env!("CARGO_PKG_VERSION").to_string();
+        manifest
+            .save(self.output.as_ref(), self.fsync)
+            .context("failed to save output manifest")?;
+        debug!(
+            "saved manifest with {} simfiles into \"{}\"",
+            manifest.simfiles.len(),
+            self.output
+        );
         Ok(())
     }
+    fn settle(&mut self) {
+        //The output folder was already fixed
+        self.fix_output = false;
+    }
     fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
         Box::new(iter::once((BucketKind::Input, &mut self.from)))
     }
 }
 
+/// Link a beatmapset folder into the song group that it belongs to, creating the group if needed.
+///
+/// Does nothing if there are no simfiles to write.
+fn link_subgroup<'a>(
+    conf: &SimfileWrite,
+    set_path: &Path,
+    mut simfiles: impl Iterator<Item = &'a Box<Simfile>>,
+) -> Result<()> {
+    let sm = match simfiles.next() {
+        Some(sm) => sm,
+        None => return Ok(()),
+    };
+    let title = if sm.title_trans.is_empty() {
+        &sm.title
+    } else {
+        &sm.title_trans
+    };
+    let label = match conf.in_place_split.group_label(title) {
+        Some(label) => label,
+        None => return Ok(()),
+    };
+    let output = Path::new(&conf.output);
+    let group = output.with_file_name(format!(
+        "{} {}",
+        output.file_name().unwrap_or_default().to_string_lossy(),
+        label
+    ));
+    let set_name = set_path
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid beatmapset folder \"{}\"", set_path.display()))?;
+    if conf.dry_run {
+        if !group.exists() {
+            info!("  would create song group \"{}\"", group.display());
+        }
+    } else {
+        fs::create_dir_all(&group)
+            .with_context(|| anyhow!("failed to create song group at \"{}\"", group.display()))?;
+    }
+    conf.split_groups.lock().insert(group.clone());
+    let link = group.join(set_name);
+    link_dir(conf, set_path, &link).with_context(|| {
+        anyhow!(
+            "failed to create symlink at \"{}\" pointing to beatmapset",
+            link.display()
+        )
+    })?;
+    trace!(
+        "    linked beatmapset into song group \"{}\"",
+        group.display()
+    );
+    Ok(())
+}
+
+/// Write the pack metadata into every song group that was written to.
+fn write_packs(conf: &SimfileWrite) {
+    //Unsplit in-place conversions (including `--single`) write their song group as a link to the
+    //input folder, which must stay untouched
+    if in_place_enabled(conf) && matches!(conf.in_place_split, GroupSplit::Single) {
+        info!("not writing pack metadata, since the song group is the input folder");
+        return;
+    }
+    let groups = if in_place_enabled(conf) && !matches!(conf.in_place_split, GroupSplit::Single) {
+        conf.split_groups.lock().iter().cloned().collect()
+    } else {
+        vec![PathBuf::from(&conf.output)]
+    };
+    let sync = match conf.sync {
+        SyncBasis::Null => Some("NULL"),
+        SyncBasis::Itg => Some("ITG"),
+        SyncBasis::Custom(_) => None,
+    };
+    for group in groups {
+        if let Err(err) =
+            crate::pack::write_pack(&conf.pack, &group, sync, conf.fsync, conf.dry_run)
+        {
+            warn!(
+                "failed to write pack metadata into \"{}\": {:#}",
+                group.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Create a directory symlink, or only report it when doing a dry run.
+///
+/// Dry runs still fail if something else is in the way of the link.
+fn link_dir(conf: &SimfileWrite, src: &Path, dst: &Path) -> io::Result<()> {
+    if !conf.dry_run {
+        return symlink_dir(src, dst);
+    }
+    if fs::symlink_metadata(dst).is_err() {
+        info!(
+            "  would create link \"{}\" pointing to \"{}\"",
+            dst.display(),
+            src.display()
+        );
+        return Ok(());
+    }
+    let src = src.canonicalize().ok();
+    if src == dst.canonicalize().ok()
+        || src == fs::read_link(dst).and_then(|p| p.canonicalize()).ok()
+    {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "file already exists",
+    ))
+}
+
+/// Build the header comment lines from the configured template.
+fn header_comments(conf: &SimfileWrite, store: &SimfileStore) -> Vec<String> {
+    let set_id = store.global_get("set_id").unwrap_or_default();
+    let url = if set_id.is_empty() {
+        String::new()
+    } else {
+        format!("https://osu.ppy.sh/beatmapsets/{}", set_id)
+    };
+    let date = today();
+    let mut comments: Vec<String> = conf
+        .header
+        .iter()
+        .map(|line| {
+            line.replace("{version}", env!("CARGO_PKG_VERSION"))
+                .replace("{date}", &date)
+                .replace("{set_id}", set_id)
+                .replace("{url}", &url)
+                .replace(
+                    "{config_hash}",
+                    store.global_get("config_hash").unwrap_or_default(),
+                )
+        })
+        .collect();
+    comments.push(conf.sync.describe());
+    if conf.embed_config {
+        if let Some(cfg) = store.global_get("config") {
+            comments.push("conversion settings:".to_string());
+            comments.extend(cfg.lines().map(str::to_string));
+        }
+    }
+    comments
+}
+
+/// The current UTC date in `YYYY-MM-DD` format.
+fn today() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    //Convert days since the epoch to a civil date
+    //See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Remove all `osu2sm-*.sm` and `osu2sm-*.ssc` files under the output directory.
+#[cfg(feature = "fs")]
+fn cleanup_output(output: &str, dry_run: bool) {
+    info!(
+        "cleanup enabled, removing all `osu2sm-*.sm` and `osu2sm-*.ssc` files under \"{}\"",
+        output
+    );
+    let mut files_removed = 0;
+    for file in WalkDir::new(output) {
+        let file = match file {
+            Ok(f) => f,
+            Err(err) => {
+                warn!("  failed to list files for cleanup: {:#}", err);
+                continue;
+            }
+        };
+        if file.file_type().is_file() {
+            let filename = file.file_name().to_string_lossy();
+            if filename.starts_with("osu2sm-")
+                && (filename.ends_with(".sm") || filename.ends_with(".ssc"))
+            {
+                if dry_run {
+                    info!("  would remove \"{}\"", file.path().display());
+                    files_removed += 1;
+                    continue;
+                }
+                match fs::remove_file(file.path()) {
+                    Ok(()) => {
+                        files_removed += 1;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "  failed to remove file \"{}\" while cleaning up: {:#}",
+                            file.path().display(),
+                            err
+                        );
+                    }
+                }
+            }
+        }
+    }
+    if dry_run {
+        info!("  would remove {} files", files_removed);
+    } else {
+        info!("  removed {} files", files_removed);
+    }
+}
+
+#[cfg(not(feature = "fs"))]
+fn cleanup_output(_output: &str, _dry_run: bool) {
+    warn!("cleaning up the output directory requires the `fs` feature");
+}
+
 fn in_place_enabled(conf: &SimfileWrite) -> bool {
     conf.in_place_from
-        .borrow()
+        .lock()
         .as_ref()
         .map(|path| !path.as_os_str().is_empty())
         .unwrap_or(false)
 }
 
+/// If the given dependency is a file shared by the whole song group (ie. a file in the parent of
+/// the beatmapset folder, such as `../cdtitle.png`), get its file name.
+fn group_dep_name(dep_name: &Path) -> Option<&OsStr> {
+    use std::path::Component;
+    let mut comps = dep_name.components();
+    match (comps.next(), comps.next(), comps.next()) {
+        (Some(Component::ParentDir), Some(Component::Normal(name)), None) => Some(name),
+        _ => None,
+    }
+}
+
+/// Copy a file shared by the whole song group into the song group folder, if it was not copied
+/// already.
+///
+/// The source path of group files is given by the `group_file:<name>` global.
+fn copy_group_file(
+    conf: &SimfileWrite,
+    store: &SimfileStore,
+    name: &OsStr,
+    out_base: &Path,
+) -> Result<()> {
+    let dst = out_base
+        .parent()
+        .ok_or_else(|| anyhow!("output folder \"{}\" has no parent", out_base.display()))?
+        .join(name);
+    //Keep the lock while copying, so that the same group file is not copied by several threads
+    let mut group_copied = conf.group_copied.lock();
+    if group_copied.contains(&dst) {
+        return Ok(());
+    }
+    let src = store
+        .global_get(&format!("group_file:{}", name.to_string_lossy()))
+        .ok_or_else(|| anyhow!("unknown source for group file \"{}\"", dst.display()))?;
+    let src = Path::new(src);
+    if !dst.exists() || assert_identical(src, &dst).is_err() {
+        if conf.dry_run {
+            dry_copy(conf, src, &dst, "group file");
+        } else {
+            if dst.exists() {
+                fs::remove_file(&dst).with_context(|| {
+                    anyhow!(
+                        "failed to replace outdated group file \"{}\"",
+                        dst.display()
+                    )
+                })?;
+            }
+            let method = copy_file(conf, &conf.copy, src, &dst)?;
+            info!(
+                "  copied group file \"{}\" using {:?}",
+                name.to_string_lossy(),
+                method
+            );
+        }
+    }
+    group_copied.insert(dst);
+    Ok(())
+}
+
+/// Report a file that a dry run would copy.
+fn dry_copy(conf: &SimfileWrite, src: &Path, dst: &Path, what: &str) {
+    let len = match fs::metadata(src) {
+        Ok(meta) if meta.is_file() => meta.len(),
+        Ok(_) => {
+            warn!(
+                "  would fail to copy {} \"{}\": not a file",
+                what,
+                src.display()
+            );
+            return;
+        }
+        Err(err) => {
+            warn!(
+                "  would fail to copy {} \"{}\": {:#}",
+                what,
+                src.display(),
+                err
+            );
+            return;
+        }
+    };
+    info!(
+        "  would {} {} \"{}\" ({} bytes)",
+        if dst.exists() {
+            "overwrite foreign file with"
+        } else {
+            "copy"
+        },
+        what,
+        dst.display(),
+        len
+    );
+    let mut stats = conf.dry_run_stats.lock();
+    stats.copied += 1;
+    stats.copied_bytes += len;
+}
+
+/// Where to write a simfile of a beatmapset, and how.
+#[derive(Copy, Clone)]
+struct WriteTarget<'a> {
+    /// The root folder that the beatmapset was loaded from.
+    root_path: &'a Path,
+    /// The beatmapset folder.
+    set_path: &'a Path,
+    /// The original paths of the scrubbed dependencies (see `scrub_deps`).
+    dep_originals: &'a HashMap<PathBuf, PathBuf>,
+    /// Appended to the name of the simfile, to tell apart the simfiles of the same music.
+    name_suffix: &'a str,
+    save_opts: &'a SaveOpts,
+}
+
 fn write_sm(
     conf: &SimfileWrite,
-    root_path: &Path,
-    set_path: &Path,
+    store: &SimfileStore,
     sms: &[Box<Simfile>],
+    target: &WriteTarget,
 ) -> Result<()> {
+    let WriteTarget {
+        root_path,
+        set_path,
+        dep_originals,
+        name_suffix,
+        save_opts,
+    } = *target;
     if sms.is_empty() {
         //Skip empty beatmapsets
         return Ok(());
     }
     //Resolve output folder
+    let set_rel = set_path.strip_prefix(root_path).unwrap_or(set_path);
+    let mut out_rel = set_rel.to_path_buf();
     let out_base = if in_place_enabled(conf) {
         set_path.to_path_buf()
     } else {
         let rel = set_path
             .strip_prefix(root_path)
             .context("find path relative to base")?;
-        Path::new(&conf.output).join(rel)
+        let mut reason = None;
+        if conf.scrub_names {
+            out_rel = scrub_path(rel);
+            if out_rel != rel {
+                reason = Some("its name has characters that removable media rejects");
+            }
+        }
+        if conf.reserved_names != ReservedNames::Keep {
+            let mut renamed = false;
+            out_rel = out_rel
+                .iter()
+                .map(|comp| match windows_safe_name(&comp.to_string_lossy()) {
+                    Some(safe) => {
+                        renamed = true;
+                        PathBuf::from(safe)
+                    }
+                    None => PathBuf::from(comp),
+                })
+                .collect();
+            if renamed {
+                if conf.reserved_names == ReservedNames::Refuse {
+                    warn!(
+                        "  skipping beatmapset \"{}\", since its name is reserved on Windows",
+                        rel.display()
+                    );
+                    return Ok(());
+                }
+                reason = Some("its name is reserved on Windows");
+            }
+        }
+        if let Some(reason) = reason {
+            let rel = rel.to_string_lossy();
+            let mut manifest = conf.manifest.lock();
+            //Several beatmapsets might end up with the same name, so add a number to the later
+            //ones, keeping the names from previous runs
+            let base = out_rel.clone();
+            let mut n = 2;
+            while let Some(other) = manifest.renamed.get(&*out_rel.to_string_lossy()) {
+                if *other == rel {
+                    break;
+                }
+                out_rel = base.with_file_name(format!(
+                    "{} ({})",
+                    base.file_name().unwrap_or_default().to_string_lossy(),
+                    n
+                ));
+                n += 1;
+            }
+            info!(
+                "  renaming output folder \"{}\" to \"{}\", since {}",
+                rel,
+                out_rel.display(),
+                reason
+            );
+            manifest
+                .renamed
+                .insert(out_rel.to_string_lossy().into_owned(), rel.into_owned());
+        }
+        Path::new(&conf.output).join(&out_rel)
     };
+    //Write into the output folder one beatmapset at a time
+    let folder_lock = conf
+        .folder_locks
+        .lock()
+        .entry(out_base.clone())
+        .or_default()
+        .clone();
+    let _folder_guard = folder_lock.lock().unwrap_or_else(|err| err.into_inner());
     //Create base output folder
-    if !in_place_enabled(conf) {
+    if !in_place_enabled(conf) && !conf.dry_run {
         fs::create_dir_all(&out_base)
             .with_context(|| anyhow!("create output dir at \"{}\"", out_base.display()))?;
     }
@@ -269,78 +1397,499 @@ fn write_sm(
     let mut already_copied: HashSet<PathBuf> = HashSet::default();
     //Decide the output filename
     let filename = format!(
-        "osu2sm-{}.sm",
+        "osu2sm-{}{}.{}",
         sms[0]
             .music
             .as_ref()
             .map(|m| m.file_stem().unwrap_or_default().to_string_lossy())
-            .unwrap_or_default()
+            .unwrap_or_default(),
+        name_suffix,
+        save_opts.format.extension()
     );
     let out_path: PathBuf = out_base.join(&filename);
     //Write simfile
-    debug!("  writing simfile to \"{}\"", out_path.display());
-    Simfile::save(&out_path, sms.iter().map(|sm| &**sm))
-        .with_context(|| anyhow!("write simfile to \"{}\"", out_path.display()))?;
+    if conf.dry_run {
+        let mut buf = Vec::new();
+        Simfile::write_to(&mut buf, sms.iter().map(|sm| &**sm), save_opts)
+            .with_context(|| anyhow!("write simfile to \"{}\"", out_path.display()))?;
+        let exists = out_path.exists();
+        info!(
+            "  would {} simfile \"{}\" ({} bytes)",
+            if exists { "overwrite" } else { "create" },
+            out_path.display(),
+            buf.len()
+        );
+        let mut stats = conf.dry_run_stats.lock();
+        if exists {
+            stats.overwritten += 1;
+        } else {
+            stats.created += 1;
+        }
+        stats.simfile_bytes += buf.len() as u64;
+    } else {
+        debug!("  writing simfile to \"{}\"", out_path.display());
+        Simfile::save(&out_path, sms.iter().map(|sm| &**sm), save_opts)
+            .with_context(|| anyhow!("write simfile to \"{}\"", out_path.display()))?;
+    }
+    //Remember where this simfile came from, to repair it later if needed
+    {
+        let mut deps = sms
+            .iter()
+            .flat_map(|sm| sm.file_deps())
+            .filter(|dep| !dep.as_os_str().is_empty())
+            .map(|dep| dep.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        deps.sort();
+        deps.dedup();
+        let mut manifest = conf.manifest.lock();
+        manifest.root = root_path.to_string_lossy().into_owned();
+        manifest.in_place = in_place_enabled(conf);
+        manifest.simfiles.insert(
+            out_rel.join(&filename).to_string_lossy().into_owned(),
+            crate::repair::ManifestEntry {
+                set: set_rel.to_string_lossy().into_owned(),
+                deps,
+                sync_confidence: sms
+                    .iter()
+                    .map(|sm| sm.sync_confidence)
+                    .filter(|conf| !conf.is_nan())
+                    .min_by_key(|&conf| SortableFloat(conf)),
+                charts: sms
+                    .iter()
+                    .map(|sm| crate::repair::ManifestChart {
+                        name: sm.desc.clone(),
+                        gamemode: sm.gamemode.id().to_string(),
+                        signature: row_signature(&sm.row_hashes()),
+                    })
+                    .collect(),
+            },
+        );
+    }
+    //Copy over files shared by the whole song group, even if converting in-place
+    for sm in sms.iter() {
+        for name in sm.file_deps().filter_map(group_dep_name) {
+            if let Err(err) = copy_group_file(conf, store, name, &out_base) {
+                error!(
+                    "  failed to copy group file \"{}\": {:#}",
+                    name.to_string_lossy(),
+                    err
+                );
+            }
+        }
+    }
     //Copy over dependencies (backgrounds, audio, etc...)
-    if !in_place_enabled(conf) {
-        for sm in sms.iter() {
-            for dep_name in sm.file_deps() {
-                if already_copied.contains(dep_name) {
+    //Generated dependencies live outside the beatmapset folder, so they are copied over even when
+    //converting in-place
+    let in_place = in_place_enabled(conf);
+    let generated_methods = generated_copy_methods(&conf.copy);
+    let mut verified_count = 0;
+    let mut verified_bytes = 0;
+    for sm in sms.iter() {
+        for dep_name in sm.file_deps() {
+            if already_copied.contains(dep_name) || group_dep_name(dep_name).is_some() {
+                continue;
+            }
+            let original = dep_originals.get(dep_name).map_or(dep_name, |path| path);
+            let generated = sm.generated_file(original);
+            if in_place && generated.is_none() {
+                continue;
+            }
+            already_copied.insert(dep_name.to_path_buf());
+            //Make sure no rogue '..' or 'C:\System32' appear
+            for comp in dep_name.components() {
+                use std::path::Component;
+                match comp {
+                    Component::Normal(_) | Component::CurDir => {}
+                    _ => bail!("invalid simfile dependency \"{}\"", dep_name.display()),
+                }
+            }
+            //Copy the dependency over to the destination folder
+            let dep_src = match (generated, dep_originals.get(dep_name)) {
+                (Some(file), _) => file.to_path_buf(),
+                (None, Some(original)) => {
+                    conf.manifest.lock().renamed.insert(
+                        out_rel.join(dep_name).to_string_lossy().into_owned(),
+                        set_rel.join(original).to_string_lossy().into_owned(),
+                    );
+                    set_path.join(original)
+                }
+                (None, None) => set_path.join(dep_name),
+            };
+            let dep_dst = out_base.join(dep_name);
+            //Even comparing against the destination would download placeholders
+            if conf.skip_placeholders && is_cloud_placeholder(&dep_src) {
+                warn!(
+                    "  skipping dependency \"{}\", which is a cloud placeholder",
+                    dep_name.display()
+                );
+                continue;
+            }
+            if conf.dry_run {
+                if !dep_dst.exists() || assert_identical(&dep_src, &dep_dst).is_err() {
+                    dry_copy(conf, &dep_src, &dep_dst, "dependency");
+                }
+                continue;
+            }
+            if dep_dst.exists() && assert_identical(&dep_src, &dep_dst).is_err() {
+                //Some other file is in the way
+                let overwrite = crate::prompt::choose(
+                    "foreign_files",
+                    &format!(
+                        "output file \"{}\" already exists, but is not a copy of \"{}\"",
+                        dep_dst.display(),
+                        dep_src.display()
+                    ),
+                    &["overwrite foreign files", "keep foreign files"],
+                    0,
+                ) == 0;
+                if !overwrite {
+                    warn!(
+                        "  keeping foreign file \"{}\" instead of dependency",
+                        dep_dst.display()
+                    );
                     continue;
                 }
-                already_copied.insert(dep_name.to_path_buf());
-                //Make sure no rogue '..' or 'C:\System32' appear
-                for comp in dep_name.components() {
-                    use std::path::Component;
-                    match comp {
-                        Component::Normal(_) | Component::CurDir => {}
-                        _ => bail!("invalid simfile dependency \"{}\"", dep_name.display()),
-                    }
+                if let Err(err) = fs::remove_file(&dep_dst) {
+                    warn!(
+                        "  failed to remove foreign file \"{}\": {:#}",
+                        dep_dst.display(),
+                        err
+                    );
                 }
-                //Copy the dependency over to the destination folder
-                let dep_src = set_path.join(dep_name);
-                let dep_dst = out_base.join(dep_name);
-                match copy_with_methods(&conf.copy, &dep_src, &dep_dst) {
-                    Ok(method) => {
-                        info!(
-                            "  copied dependency \"{}\" using {:?}",
-                            dep_name.display(),
-                            method
-                        );
-                    }
-                    Err(err) => {
-                        error!(
-                            "  failed to copy dependency \"{}\": {:#}",
-                            dep_name.display(),
-                            err
-                        );
+            }
+            let methods = if generated.is_some() {
+                &generated_methods
+            } else {
+                &conf.copy
+            };
+            match copy_file(conf, methods, &dep_src, &dep_dst) {
+                Ok(method) => {
+                    info!(
+                        "  copied dependency \"{}\" using {:?}",
+                        dep_name.display(),
+                        method
+                    );
+                    if conf.verify_copies {
+                        match verify_copy(&dep_src, &dep_dst) {
+                            Ok(len) => {
+                                verified_count += 1;
+                                verified_bytes += len;
+                            }
+                            Err(err) => {
+                                error!(
+                                    "  copied dependency \"{}\" failed verification: {:#}",
+                                    dep_name.display(),
+                                    err
+                                );
+                            }
+                        }
                     }
                 }
+                Err(err) => {
+                    error!(
+                        "  failed to copy dependency \"{}\": {:#}",
+                        dep_name.display(),
+                        err
+                    );
+                }
             }
         }
     }
+    if conf.verify_copies {
+        info!(
+            "  verified {} dependencies ({} bytes)",
+            verified_count, verified_bytes
+        );
+    }
+    if conf.copy_sources && !in_place {
+        copy_sources(conf, sms, set_path, &out_base);
+    }
     Ok(())
 }
 
-fn copy_with_methods<'a>(
+/// Copy the source files of the given simfiles into the `osu-src` folder of the output folder.
+fn copy_sources(conf: &SimfileWrite, sms: &[Box<Simfile>], set_path: &Path, out_base: &Path) {
+    let mut sources = sms
+        .iter()
+        .filter_map(|sm| sm.source_file.as_deref())
+        .collect::<Vec<_>>();
+    sources.sort();
+    sources.dedup();
+    for source in sources {
+        let name = match source.file_name() {
+            Some(name) => name,
+            None => continue,
+        };
+        let src = set_path.join(source);
+        let dst = match scrubbed_name(&name.to_string_lossy()) {
+            Some(scrubbed) if conf.scrub_names => out_base.join("osu-src").join(scrubbed),
+            _ => out_base.join("osu-src").join(name),
+        };
+        if conf.skip_placeholders && is_cloud_placeholder(&src) {
+            warn!(
+                "  skipping source \"{}\", which is a cloud placeholder",
+                source.display()
+            );
+            continue;
+        }
+        if dst.exists() && assert_identical(&src, &dst).is_ok() {
+            continue;
+        }
+        if conf.dry_run {
+            dry_copy(conf, &src, &dst, "source");
+            continue;
+        }
+        //Sources are only ever copied by osu2sm, so outdated copies can be replaced
+        if fs::symlink_metadata(&dst).is_ok() {
+            if let Err(err) = fs::remove_file(&dst) {
+                warn!(
+                    "  failed to remove outdated source \"{}\": {:#}",
+                    dst.display(),
+                    err
+                );
+            }
+        }
+        match copy_file(conf, &conf.copy, &src, &dst) {
+            Ok(method) => {
+                info!(
+                    "  copied source \"{}\" using {:?}",
+                    source.display(),
+                    method
+                );
+            }
+            Err(err) => {
+                error!(
+                    "  failed to copy source \"{}\": {:#}",
+                    source.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Copy a file with the configured methods, keeping track of the files that could not be copied.
+fn copy_file<'a>(
+    conf: &SimfileWrite,
+    methods: &'a [CopyMethod],
+    src: &Path,
+    dst: &Path,
+) -> Result<&'a CopyMethod> {
+    let result = copy_with_methods(methods, &conf.copy_retry, src, dst);
+    if result.is_err() {
+        conf.copy_failures.lock().push(dst.to_path_buf());
+    }
+    result
+}
+
+/// Whether a file is a cloud placeholder, whose contents have to be downloaded before reading it.
+fn is_cloud_placeholder(path: &Path) -> bool {
+    #[cfg(target_family = "windows")]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_OFFLINE: u32 = 0x1000;
+        const FILE_ATTRIBUTE_RECALL_ON_OPEN: u32 = 0x40000;
+        const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x400000;
+        fs::metadata(path)
+            .map(|meta| {
+                meta.file_attributes()
+                    & (FILE_ATTRIBUTE_OFFLINE
+                        | FILE_ATTRIBUTE_RECALL_ON_OPEN
+                        | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS)
+                    != 0
+            })
+            .unwrap_or(false)
+    }
+    #[cfg(not(target_family = "windows"))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Whether a failed copy might succeed if tried again later.
+fn is_transient(err: &Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| match err.kind() {
+            io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::PermissionDenied => true,
+            //`ERROR_SHARING_VIOLATION` and `ERROR_LOCK_VIOLATION`, from files opened by someone
+            //else
+            _ => cfg!(target_family = "windows") && matches!(err.raw_os_error(), Some(32 | 33)),
+        })
+}
+
+/// The output folder of a beatmapset for writers of charts for other games (eg. `QuaWrite`), which
+/// write every chart into its own file and copy the dependencies of the charts along.
+pub(crate) struct ChartFolder {
+    pub set_path: PathBuf,
+    pub out_base: PathBuf,
+    /// The file names taken by charts so far.
+    names: HashSet<String>,
+    /// `(dependency path, generated file)` pairs, as taken by `copy_deps`.
+    deps: HashMap<PathBuf, Option<PathBuf>>,
+}
+impl ChartFolder {
+    /// Find the folder within `output` for the current beatmapset of the store, mirroring its
+    /// path within the input folder.
+    pub fn new(store: &SimfileStore, output: &str) -> Result<ChartFolder> {
+        let root_path = store.global_get_expect("root")?;
+        let set_path = Path::new(store.global_get_expect("base")?);
+        let set_rel = set_path.strip_prefix(root_path).unwrap_or(set_path);
+        Ok(ChartFolder {
+            set_path: set_path.to_path_buf(),
+            out_base: Path::new(output).join(set_rel),
+            names: default(),
+            deps: default(),
+        })
+    }
+
+    /// Create the output folder and get a path for a chart of the given simfile within it, named
+    /// after its keycount and difficulty, making sure names do not clash.
+    pub fn chart_path(&mut self, sm: &Simfile, ext: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.out_base)
+            .with_context(|| anyhow!("create output dir at \"{}\"", self.out_base.display()))?;
+        let stem = format!(
+            "osu2sm-{}k-{}",
+            sm.gamemode.key_count(),
+            sm.desc
+                .chars()
+                .map(|c| if c.is_alphanumeric() || c == ' ' {
+                    c
+                } else {
+                    '_'
+                })
+                .collect::<String>()
+        );
+        let mut name = format!("{}.{}", stem, ext);
+        let mut idx = 1;
+        while !self.names.insert(name.clone()) {
+            idx += 1;
+            name = format!("{} ({}).{}", stem, idx, ext);
+        }
+        Ok(self.out_base.join(name))
+    }
+
+    /// Remember the given dependencies of a simfile, to copy them over in `copy_deps`.
+    pub fn add_deps<'a>(&mut self, sm: &Simfile, deps: impl IntoIterator<Item = &'a PathBuf>) {
+        self.deps.extend(
+            deps.into_iter()
+                .filter(|dep| !dep.as_os_str().is_empty())
+                .map(|dep| (dep.clone(), sm.generated_file(dep).map(Path::to_path_buf))),
+        );
+    }
+
+    /// Copy over the dependencies of all charts.
+    pub fn copy_deps(self, methods: &[CopyMethod]) {
+        copy_deps(methods, &self.set_path, &self.out_base, self.deps);
+    }
+}
+
+/// Copy the dependencies of charts written by other writers (eg. `QuaWrite`) from the beatmapset
+/// folder into the output folder, skipping the ones that are already there.
+///
+/// `deps` are `(dependency path, generated file)` pairs, where generated dependencies are copied
+/// from their generated file instead (see `Simfile::generated`).
+fn copy_deps(
+    methods: &[CopyMethod],
+    set_path: &Path,
+    out_base: &Path,
+    deps: impl IntoIterator<Item = (PathBuf, Option<PathBuf>)>,
+) {
+    let generated_methods = generated_copy_methods(methods);
+    for (dep_name, generated) in deps {
+        let safe = dep_name.components().all(|comp| {
+            use std::path::Component;
+            matches!(comp, Component::Normal(_) | Component::CurDir)
+        });
+        if !safe {
+            warn!("  skipping invalid dependency \"{}\"", dep_name.display());
+            continue;
+        }
+        let (dep_src, methods) = match generated {
+            Some(file) => (file, &generated_methods[..]),
+            None => (set_path.join(&dep_name), methods),
+        };
+        let dep_dst = out_base.join(&dep_name);
+        if dep_dst.exists() && assert_identical(&dep_src, &dep_dst).is_ok() {
+            continue;
+        }
+        match copy_with_methods(methods, &default(), &dep_src, &dep_dst) {
+            Ok(method) => {
+                info!(
+                    "  copied dependency \"{}\" using {:?}",
+                    dep_name.display(),
+                    method
+                );
+            }
+            Err(err) => {
+                error!(
+                    "  failed to copy dependency \"{}\": {:#}",
+                    dep_name.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Generated files live in a cache folder that may be cleaned up at any time, so they are never
+/// symlinked, and copied instead if symlinks were the only way to copy them.
+fn generated_copy_methods(methods: &[CopyMethod]) -> Vec<CopyMethod> {
+    let mut generated = methods
+        .iter()
+        .copied()
+        .filter(|&method| method != CopyMethod::Symlink)
+        .collect::<Vec<_>>();
+    if methods.contains(&CopyMethod::Symlink) && !generated.contains(&CopyMethod::Copy) {
+        generated.push(CopyMethod::Copy);
+    }
+    generated
+}
+
+pub(crate) fn copy_with_methods<'a>(
     methods: &'a [CopyMethod],
+    retry: &CopyRetry,
     src: &Path,
     dst: &Path,
 ) -> Result<&'a CopyMethod> {
     debug!("  copying \"{}\" to \"{}\"", src.display(), dst.display());
+    let (src, dst) = (&*long_path(src), &*long_path(dst));
     if let Some(parent) = dst.parent() {
         fs::create_dir_all(parent).context("create parent directory")?;
     }
     let mut errors: Vec<Error> = Vec::new();
+    //Symlinks fail because of missing permissions rather than locked files, so they are not
+    //retried
     macro_rules! method {
         ($method:expr, $($code:tt)*) => {{
-            match {$($code)*} {
-                Ok(()) => {
-                    return Ok($method);
-                }
-                Err(err) => {
-                    debug!("    method {:?} failed: {:#}", $method, err);
-                    errors.push(err);
+            let mut attempt = 1;
+            loop {
+                match {$($code)*} {
+                    Ok(()) => {
+                        return Ok($method);
+                    }
+                    Err(err)
+                        if attempt < retry.attempts
+                            && *$method != CopyMethod::Symlink
+                            && is_transient(&err) =>
+                    {
+                        let wait = retry.backoff_ms.saturating_mul(1 << (attempt - 1).min(16));
+                        debug!(
+                            "    method {:?} failed (attempt {}/{}), retrying in {}ms: {:#}",
+                            $method, attempt, retry.attempts, wait, err
+                        );
+                        thread::sleep(Duration::from_millis(wait));
+                        attempt += 1;
+                    }
+                    Err(err) => {
+                        debug!("    method {:?} failed: {:#}", $method, err);
+                        errors.push(err);
+                        break;
+                    }
                 }
             }
         }};
@@ -373,7 +1922,7 @@ fn copy_with_methods<'a>(
     bail!(errstr)
 }
 
-fn assert_identical(src: &Path, dst: &Path) -> Result<()> {
+pub(crate) fn assert_identical(src: &Path, dst: &Path) -> Result<()> {
     let mut src = File::open(src).context("failed to open source file")?;
     let mut dst = File::open(dst).context("failed to open destination file")?;
     if let (Ok(src_meta), Ok(dst_meta)) = (src.metadata(), dst.metadata()) {
@@ -409,3 +1958,37 @@ fn assert_identical(src: &Path, dst: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+/// Hash the contents of a file, returning the hash and the length of the file.
+fn hash_file(path: &Path) -> Result<(u64, u64)> {
+    use std::{collections::hash_map::DefaultHasher, hash::Hasher};
+    let mut file = BufReader::new(File::open(path)?);
+    let mut hasher = DefaultHasher::new();
+    let mut len = 0;
+    loop {
+        let buf = file.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.write(buf);
+        let read = buf.len();
+        len += read as u64;
+        file.consume(read);
+    }
+    Ok((hasher.finish(), len))
+}
+
+/// Make sure a copied file has the same contents as its source, returning the amount of bytes
+/// verified.
+fn verify_copy(src: &Path, dst: &Path) -> Result<u64> {
+    let (src_hash, src_len) = hash_file(src).context("failed to hash source file")?;
+    let (dst_hash, dst_len) = hash_file(dst).context("failed to hash destination file")?;
+    ensure!(
+        src_len == dst_len,
+        "files are not the same size ({} != {})",
+        src_len,
+        dst_len
+    );
+    ensure!(src_hash == dst_hash, "files do not have the same contents");
+    Ok(dst_len)
+}
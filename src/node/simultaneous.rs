@@ -62,7 +62,7 @@ fn limit_simultaneous_keys(sm: &mut Simfile, conf: &Simultaneous) -> Result<()>
                 } else {
                     note.key = -1;
                 }
-            } else {
+            } else if !note.is_mine() {
                 beat_notes.push(note_idx);
                 if note.is_head() {
                     active_notes[note.key as usize] = true;
@@ -8,6 +8,15 @@ pub struct Space {
     pub from: BucketId,
     pub into: BucketId,
     pub min_dist: MinDist,
+    /// Never remove notes that are part of chords of at least this many notes.
+    pub protect_chords: Option<usize>,
+    /// Never remove hold heads (along with their tails) whose holds last at least this many
+    /// beats.
+    pub protect_holds: Option<f64>,
+    /// When choosing between notes of the same subdivision, remove the ones that land on weaker
+    /// beats of the measure first (eg. beat 1 before beat 2, and beat 2 before beat 0), instead
+    /// of choosing randomly.
+    pub prefer_offbeat: bool,
 }
 impl Default for Space {
     fn default() -> Self {
@@ -15,6 +24,9 @@ impl Default for Space {
             from: default(),
             into: default(),
             min_dist: MinDist::Bpm(120.),
+            protect_chords: None,
+            protect_holds: None,
+            prefer_offbeat: false,
         }
     }
 }
@@ -48,6 +60,64 @@ impl Node for Space {
     }
 }
 
+/// How weak of a beat of a 4-beat measure this beat falls on, as the denominator of its position
+/// in the measure (eg. `1` for beat 0, `2` for beat 2, `4` for beats 1 and 3).
+fn measure_denominator(beat: BeatPos) -> i64 {
+    let den = beat.denominator() as i64;
+    let num = (beat.as_num() * den as f64).round() as i64;
+    let mut measure_den = den * 4;
+    let mut num = num.rem_euclid(measure_den);
+    while num % 2 == 0 && measure_den > 1 {
+        num /= 2;
+        measure_den /= 2;
+    }
+    measure_den
+}
+
+/// Find the notes that should never be removed.
+fn find_protected(sm: &Simfile, conf: &Space) -> Vec<bool> {
+    let mut protected = vec![false; sm.notes.len()];
+    if let Some(min_chord) = conf.protect_chords {
+        let mut start = 0;
+        while start < sm.notes.len() {
+            let beat = sm.notes[start].beat;
+            let end = start
+                + sm.notes[start..]
+                    .iter()
+                    .position(|note| note.beat != beat)
+                    .unwrap_or(sm.notes.len() - start);
+            let chord = sm.notes[start..end]
+                .iter()
+                .filter(|note| !note.is_tail() && !note.is_mine())
+                .count();
+            if chord >= min_chord {
+                for (protected, note) in protected[start..end].iter_mut().zip(&sm.notes[start..end])
+                {
+                    *protected = !note.is_tail();
+                }
+            }
+            start = end;
+        }
+    }
+    if let Some(min_len) = conf.protect_holds {
+        let min_len = BeatPos::from(min_len);
+        for (idx, note) in sm.notes.iter().enumerate() {
+            if !note.is_head() {
+                continue;
+            }
+            let tail = sm.notes[idx + 1..]
+                .iter()
+                .find(|tail| tail.is_tail() && tail.key == note.key);
+            if let Some(tail) = tail {
+                if tail.beat - note.beat >= min_len {
+                    protected[idx] = true;
+                }
+            }
+        }
+    }
+    protected
+}
+
 fn make_space(sm: &mut Simfile, conf: &Space) -> Result<()> {
     // To prevent any recognizable patterns from forming
     let mut rng = simfile_rng(sm, "space");
@@ -89,12 +159,22 @@ fn make_space(sm: &mut Simfile, conf: &Space) -> Result<()> {
         }
     };
     //Create an array of references to notes, sorted from most removable to least removable
+    let protected = find_protected(sm, conf);
     let mut note_refs = (0..sm.notes.len())
-        .filter(|&idx| !sm.notes[idx].is_tail())
+        .filter(|&idx| !sm.notes[idx].is_tail() && !sm.notes[idx].is_mine() && !protected[idx])
         .collect::<Vec<_>>();
     note_refs.sort_by_cached_key(|&idx| {
-        ((64 - sm.notes[idx].beat.denominator() as u32) << (32 - 6))
-            | ((rng.gen::<u32>() << 6) >> 6)
+        let beat = sm.notes[idx].beat;
+        let offbeat = if conf.prefer_offbeat {
+            -measure_denominator(beat)
+        } else {
+            0
+        };
+        (
+            64 - beat.denominator() as u32,
+            offbeat,
+            (rng.gen::<u32>() << 6) >> 6,
+        )
     });
     // Remove any notes that have neighbors that are too close
     for &note_idx in note_refs.iter() {
@@ -102,21 +182,18 @@ fn make_space(sm: &mut Simfile, conf: &Space) -> Result<()> {
         let mut keep = true;
 
         //Check forward gap
-        if let Some(indices_to_next_note) = sm.notes[note_idx + 1..]
-            .iter()
-            .position(|note| !note.is_tail() && note.key >= 0 && note.beat > this_beat)
-        {
+        if let Some(indices_to_next_note) = sm.notes[note_idx + 1..].iter().position(|note| {
+            !note.is_tail() && !note.is_mine() && note.key >= 0 && note.beat > this_beat
+        }) {
             let next_note = note_idx + 1 + indices_to_next_note;
             keep = are_far_enough(&sm.notes, note_idx, next_note);
         }
 
         //Check backward gap
         if keep {
-            if let Some(indices_to_prev_note) = sm.notes[..note_idx]
-                .iter()
-                .rev()
-                .position(|note| !note.is_tail() && note.key >= 0 && note.beat < this_beat)
-            {
+            if let Some(indices_to_prev_note) = sm.notes[..note_idx].iter().rev().position(|note| {
+                !note.is_tail() && !note.is_mine() && note.key >= 0 && note.beat < this_beat
+            }) {
                 let prev_note = note_idx - 1 - indices_to_prev_note;
                 keep = are_far_enough(&sm.notes, prev_note, note_idx);
             }
@@ -139,6 +216,13 @@ fn make_space(sm: &mut Simfile, conf: &Space) -> Result<()> {
         }
     }
     //Actually remove notes
+    let protected = sm
+        .notes
+        .iter()
+        .zip(protected)
+        .filter(|(note, _)| note.key >= 0)
+        .map(|(_, protected)| protected)
+        .collect::<Vec<_>>();
     sm.notes.retain(|note| note.key >= 0);
     //*
     //Sanity check
@@ -147,13 +231,15 @@ fn make_space(sm: &mut Simfile, conf: &Space) -> Result<()> {
     let notes_without_tails = sm
         .notes
         .iter()
-        .filter(|note| !note.is_tail())
-        .cloned()
+        .zip(protected)
+        .filter(|(note, _)| !note.is_tail() && !note.is_mine())
+        .map(|(note, protected)| (note.clone(), protected))
         .collect::<Vec<_>>();
-    for (idx, note) in notes_without_tails.iter().enumerate() {
+    for (idx, (note, protected)) in notes_without_tails.iter().enumerate() {
         let time = to_time.beat_to_time(note.beat);
-        if idx > 0 {
-            let prev = &notes_without_tails[idx - 1];
+        //Protected notes may be as close together as they were originally
+        if idx > 0 && !protected && !notes_without_tails[idx - 1].1 {
+            let prev = &notes_without_tails[idx - 1].0;
             match conf.min_dist {
                 MinDist::Bpm(bpm) => {
                     let min_dist = 60. / bpm;
@@ -0,0 +1,100 @@
+//! Deterministically split simfiles into several buckets, for example to route the same library
+//! through two different transform chains and compare the results side by side.
+
+use crate::node::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Split {
+    pub from: BucketId,
+    /// The buckets to split simfiles into, along with their relative weights.
+    ///
+    /// For example, `[(Name("a"), 1), (Name("b"), 1)]` splits simfiles 50/50 between the `a` and
+    /// `b` buckets.
+    /// Since all automatic outputs are bound to the same bucket, outputs should be named or
+    /// nested.
+    pub into: Vec<(BucketId, f64)>,
+    /// Whether to keep whole beatmapsets together, instead of splitting individual simfiles.
+    pub whole_sets: bool,
+    /// The same seed always splits the same simfiles in the same way.
+    /// Change it to reshuffle the split.
+    pub seed: u64,
+}
+impl Default for Split {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: vec![],
+            whole_sets: true,
+            seed: 0,
+        }
+    }
+}
+
+impl Split {
+    /// Pick the output bucket for a point in `[0, 1)`.
+    fn pick(&self, point: f64) -> usize {
+        let total = self.into.iter().map(|(_, weight)| weight).sum::<f64>();
+        let mut acc = 0.;
+        for (idx, (_, weight)) in self.into.iter().enumerate() {
+            acc += weight / total;
+            if point < acc {
+                return idx;
+            }
+        }
+        self.into.len() - 1
+    }
+}
+
+impl Node for Split {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(!self.into.is_empty(), "no output buckets to split into");
+        for (_, weight) in self.into.iter() {
+            ensure!(
+                weight.is_finite() && *weight >= 0.,
+                "split weight {} is not a positive number",
+                weight
+            );
+        }
+        ensure!(
+            self.into.iter().any(|(_, weight)| *weight > 0.),
+            "all split weights are zero"
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        //Identify the beatmapset by its path relative to the input folder, which is the same
+        //across machines
+        let set_point = if self.whole_sets {
+            let root = store.global_get("root").unwrap_or_default();
+            let base = store.global_get("base").unwrap_or_default();
+            let set = Path::new(base).strip_prefix(root).unwrap_or(base.as_ref());
+            let mut rng = FastRng::seed_from_u64(fxhash::hash64(&(set, self.seed)));
+            Some(rng.gen::<f64>())
+        } else {
+            None
+        };
+        store.get(&self.from, |store, list| {
+            let mut out = vec![Vec::new(); self.into.len()];
+            for sm in list.drain(..) {
+                let point = set_point.unwrap_or_else(|| {
+                    simfile_rng(&sm, &format!("split {}", self.seed)).gen::<f64>()
+                });
+                out[self.pick(point)].push(sm);
+            }
+            for ((bucket, _), simfiles) in self.into.iter().zip(out) {
+                store.put(bucket, simfiles);
+            }
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from)).chain(
+                self.into
+                    .iter_mut()
+                    .map(|(bucket, _)| (BucketKind::Output, bucket)),
+            ),
+        )
+    }
+}
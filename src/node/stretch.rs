@@ -0,0 +1,226 @@
+//! Time-stretch simfiles to a fixed BPM, for training packs at uniform BPM.
+
+use crate::node::prelude::*;
+use std::process::Command;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stretch {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// The BPM to stretch simfiles to.
+    ///
+    /// The dominant BPM of the simfile (the one that lasts the longest) is mapped to this BPM,
+    /// and every other BPM is scaled proportionally.
+    pub bpm: f64,
+    /// How to stretch the audio file.
+    pub audio: StretchAudio,
+    /// The ffmpeg executable to use for `StretchAudio::Ffmpeg`.
+    pub ffmpeg: String,
+    /// Whether to leave simfiles untouched if their dominant BPM is already within this many BPM
+    /// of the target BPM.
+    pub tolerance: f64,
+}
+impl Default for Stretch {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            bpm: 120.,
+            audio: StretchAudio::Ffmpeg,
+            ffmpeg: "ffmpeg".into(),
+            tolerance: 0.5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StretchAudio {
+    /// Only stretch the chart, keeping the original audio.
+    /// The chart will be out of sync with the music, so this is only useful for silent practice.
+    ChartOnly,
+    /// Use ffmpeg's `atempo` filter to create a time-stretched copy of the audio file, preserving
+    /// pitch.
+    /// If ffmpeg fails, falls back to `ChartOnly`.
+    Ffmpeg,
+}
+
+impl Node for Stretch {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(
+            self.bpm.is_finite() && self.bpm > 0.,
+            "target bpm must be a positive number"
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        let base = store.global_get("base").map(PathBuf::from);
+        store.get(&self.from, |store, list| {
+            for sm in list.iter_mut() {
+                stretch(self, base.as_deref(), sm)?;
+            }
+            store.put(&self.into, mem::replace(list, default()));
+            Ok(())
+        })
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
+
+/// Find the BPM that lasts the longest within the notes of the simfile.
+fn dominant_bpm(sm: &Simfile) -> Option<f64> {
+    let last_beat = sm.notes.last().map(|note| note.beat)?;
+    let mut durations: Vec<(f64, f64)> = Vec::new();
+    for (idx, cp) in sm.bpms.iter().enumerate() {
+        let end = sm
+            .bpms
+            .get(idx + 1)
+            .map(|next| next.beat)
+            .unwrap_or(last_beat)
+            .min(last_beat);
+        let dur = (end - cp.beat).as_num().max(0.) * cp.beat_len;
+        let bpm = cp.bpm();
+        match durations
+            .iter_mut()
+            .find(|(other, _)| (other - bpm).abs() < 0.01)
+        {
+            Some((_, total)) => *total += dur,
+            None => durations.push((bpm, dur)),
+        }
+    }
+    durations
+        .into_iter()
+        .max_by_key(|&(_, dur)| SortableFloat(dur))
+        .map(|(bpm, _)| bpm)
+}
+
+fn stretch(conf: &Stretch, base: Option<&Path>, sm: &mut Simfile) -> Result<()> {
+    let orig_bpm = match dominant_bpm(sm) {
+        Some(bpm) => bpm,
+        None => return Ok(()),
+    };
+    if (orig_bpm - conf.bpm).abs() <= conf.tolerance {
+        return Ok(());
+    }
+    let ratio = conf.bpm / orig_bpm;
+    trace!(
+        "    stretching from {} BPM to {} BPM (x{})",
+        orig_bpm,
+        conf.bpm,
+        ratio
+    );
+    //Stretch audio first, so that the chart is left untouched if it fails badly
+    if conf.audio == StretchAudio::Ffmpeg {
+        if let Err(err) = stretch_music(&conf.ffmpeg, base, sm, ratio, &format!("{}bpm", conf.bpm))
+        {
+            warn!("    {:#}, stretching chart only", err);
+        }
+    }
+    scale_chart(sm, ratio);
+    Ok(())
+}
+
+/// Speed up the timing of a chart by the given ratio, leaving the audio file untouched.
+pub(crate) fn scale_chart(sm: &mut Simfile, ratio: f64) {
+    for cp in sm.bpms.iter_mut() {
+        cp.beat_len /= ratio;
+    }
+    for (_beat, dur) in sm.stops.iter_mut().chain(sm.delays.iter_mut()) {
+        *dur /= ratio;
+    }
+    sm.offset /= ratio;
+    sm.sample_start = sm.sample_start.map(|t| t / ratio);
+    sm.sample_len = sm.sample_len.map(|t| t / ratio);
+    sm.music_len = sm.music_len.map(|t| t / ratio);
+    if let Some(video) = &mut sm.video {
+        video.rate *= ratio;
+    }
+    sm.display_bpm = match sm.display_bpm {
+        DisplayBpm::Single(bpm) => DisplayBpm::Single(bpm * ratio),
+        DisplayBpm::Range(min, max) => DisplayBpm::Range(min * ratio, max * ratio),
+        DisplayBpm::Random => DisplayBpm::Random,
+    };
+}
+
+/// Replace the audio file of a simfile with a copy sped up by the given ratio, tagging the name
+/// of the copy with `tag`.
+/// Simfiles without audio are left untouched.
+pub(crate) fn stretch_music(
+    ffmpeg: &str,
+    base: Option<&Path>,
+    sm: &mut Simfile,
+    ratio: f64,
+    tag: &str,
+) -> Result<()> {
+    let music = match sm.music.as_deref() {
+        Some(music) => music,
+        None => return Ok(()),
+    };
+    let base = base.ok_or_else(|| anyhow!("no beatmapset folder to find the audio in"))?;
+    let (stretched, file) = stretch_audio(ffmpeg, base, sm, music, ratio, tag)
+        .with_context(|| anyhow!("failed to stretch audio \"{}\"", music.display()))?;
+    sm.music = Some(stretched.clone());
+    sm.set_generated(stretched, file);
+    //The fingerprint was taken from the original audio
+    sm.music_hash = None;
+    Ok(())
+}
+
+/// Create a stretched copy of the given audio file of a simfile, returning its path relative to
+/// `base` along with the path it was generated at (see `crate::generated_dir`).
+fn stretch_audio(
+    ffmpeg: &str,
+    base: &Path,
+    sm: &Simfile,
+    music: &Path,
+    ratio: f64,
+    tag: &str,
+) -> Result<(PathBuf, PathBuf)> {
+    let name = format!(
+        "osu2sm-{}-{}.{}",
+        music.file_stem().unwrap_or_default().to_string_lossy(),
+        tag,
+        music.extension().unwrap_or_default().to_string_lossy(),
+    );
+    let rel_out = music.with_file_name(&name);
+    let src = sm.dep_source(base, music);
+    let dst = crate::generated_dir(base)?.join(&name);
+    if dst.exists() {
+        trace!("    stretched audio \"{}\" already exists", dst.display());
+        return Ok((rel_out, dst));
+    }
+    //`atempo` only accepts factors in the `[0.5, 2]` range, so chain several filters
+    let mut filters = Vec::new();
+    let mut left = ratio;
+    while left > 2. {
+        filters.push("atempo=2".to_string());
+        left /= 2.;
+    }
+    while left < 0.5 {
+        filters.push("atempo=0.5".to_string());
+        left /= 0.5;
+    }
+    filters.push(format!("atempo={}", left));
+    debug!(
+        "    stretching audio \"{}\" into \"{}\"",
+        src.display(),
+        dst.display()
+    );
+    crate::generate_atomic(&dst, |tmp| {
+        let status = Command::new(ffmpeg)
+            .args(["-y", "-loglevel", "error", "-i"])
+            .arg(&src)
+            .arg("-filter:a")
+            .arg(filters.join(","))
+            .arg(tmp)
+            .status()
+            .with_context(|| anyhow!("failed to run \"{}\"", ffmpeg))?;
+        ensure!(status.success(), "ffmpeg exited with {}", status);
+        Ok(())
+    })?;
+    Ok((rel_out, dst))
+}
@@ -0,0 +1,205 @@
+//! Select the best charts of the whole library, to make a compact "best of" pack.
+//!
+//! Unlike most nodes, which only ever see one beatmapset at a time, `TopN` holds on to every
+//! candidate chart until all beatmapsets were loaded.
+//! The selected charts are then passed on to the next nodes one beatmapset at a time, as if they
+//! were loaded again.
+
+use crate::node::prelude::*;
+use std::{collections::BTreeMap, sync::Arc};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopN {
+    pub from: BucketId,
+    pub into: BucketId,
+    /// How many charts to keep for every keycount.
+    pub count: usize,
+    /// What makes a chart better than another one.
+    /// Charts without a value (eg. unrated charts) are never selected.
+    pub by: TopBy,
+    /// Only consider charts that play for at least this many seconds, from the first to the last
+    /// note.
+    pub min_secs: f64,
+    /// Only consider charts that play for at most this many seconds.
+    /// `0` means no limit.
+    pub max_secs: f64,
+    /// The candidate charts found so far, by keycount.
+    #[serde(skip)]
+    pub candidates: Shared<BTreeMap<i32, Vec<Candidate>>>,
+}
+impl Default for TopN {
+    fn default() -> Self {
+        Self {
+            from: default(),
+            into: default(),
+            count: 100,
+            by: TopBy::Meter,
+            min_secs: 60.,
+            max_secs: 0.,
+            candidates: default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum TopBy {
+    /// The numerical difficulty, as computed by a `Rate` or `Ratings` node.
+    Meter,
+    /// The osu! star rating (see `Simfile::star_rating`).
+    StarRating,
+    /// How well synced the chart is to its music (see `Simfile::sync_confidence`).
+    SyncConfidence,
+}
+impl TopBy {
+    fn score(&self, sm: &Simfile) -> f64 {
+        match self {
+            TopBy::Meter => sm.difficulty_num,
+            TopBy::StarRating => sm.star_rating,
+            TopBy::SyncConfidence => sm.sync_confidence,
+        }
+    }
+}
+
+/// The globals of a beatmapset, shared by all of its candidate charts.
+type Globals = Arc<Vec<(String, String)>>;
+
+/// A chart that may be selected, along with the globals of its beatmapset.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    score: f64,
+    globals: Globals,
+    sm: Box<Simfile>,
+}
+impl Candidate {
+    fn base(&self) -> &str {
+        self.globals
+            .iter()
+            .find(|(name, _)| name == "base")
+            .map(|(_, value)| &value[..])
+            .unwrap_or_default()
+    }
+}
+
+/// Keep only the best `count` candidates, best first.
+fn trim(candidates: &mut Vec<Candidate>, count: usize) {
+    //Break ties by path and name, so that the selection does not depend on the loading order
+    candidates.sort_by(|a, b| {
+        SortableFloat(b.score)
+            .cmp(&SortableFloat(a.score))
+            .then_with(|| a.base().cmp(b.base()))
+            .then_with(|| a.sm.desc.cmp(&b.sm.desc))
+    });
+    candidates.truncate(count);
+}
+
+impl Node for TopN {
+    fn prepare(&mut self) -> Result<()> {
+        ensure!(self.count > 0, "cannot select zero charts");
+        ensure!(
+            self.max_secs <= 0. || self.max_secs >= self.min_secs,
+            "max_secs ({}) is shorter than min_secs ({})",
+            self.max_secs,
+            self.min_secs
+        );
+        Ok(())
+    }
+    fn apply(&self, store: &mut SimfileStore) -> Result<()> {
+        //The output of this beatmapset depends on every other beatmapset, so it cannot be
+        //skipped when converting again
+        store.global_remove("source_hash");
+        let globals = Arc::new(
+            store
+                .globals()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>(),
+        );
+        let mut found = Vec::new();
+        store.get_each(&self.from, |_, sm| {
+            let score = self.by.score(&sm);
+            if score.is_nan() {
+                return Ok(());
+            }
+            let secs = match (sm.notes.first(), sm.notes.last()) {
+                (Some(first), Some(last)) => {
+                    let mut to_time = sm.beat_to_time();
+                    let start = to_time.beat_to_time(first.beat);
+                    to_time.beat_to_time(last.beat) - start
+                }
+                _ => return Ok(()),
+            };
+            if secs < self.min_secs || (self.max_secs > 0. && secs > self.max_secs) {
+                return Ok(());
+            }
+            found.push(Candidate {
+                score,
+                globals: globals.clone(),
+                sm,
+            });
+            Ok(())
+        })?;
+        if found.is_empty() {
+            return Ok(());
+        }
+        let mut candidates = self.candidates.lock();
+        for cand in found {
+            let list = candidates.entry(cand.sm.gamemode.key_count()).or_default();
+            list.push(cand);
+            //Trim every once in a while to keep memory usage bounded
+            if list.len() >= self.count * 2 {
+                trim(list, self.count);
+            }
+        }
+        Ok(())
+    }
+    fn entry(
+        &self,
+        store: &mut SimfileStore,
+        on_bmset: &(dyn Fn(&mut SimfileStore) -> Result<()> + Sync),
+    ) -> Result<()> {
+        let candidates = mem::take(&mut *self.candidates.lock());
+        //Group the selected charts back into their beatmapsets
+        let mut by_set: BTreeMap<String, (Globals, Vec<Box<Simfile>>)> = BTreeMap::new();
+        for (key_count, mut list) in candidates {
+            trim(&mut list, self.count);
+            info!(
+                "selected the best {} {}K charts (scores {:.2} to {:.2})",
+                list.len(),
+                key_count,
+                list.first().map(|cand| cand.score).unwrap_or(0.),
+                list.last().map(|cand| cand.score).unwrap_or(0.),
+            );
+            for cand in list {
+                by_set
+                    .entry(cand.base().to_string())
+                    .or_insert_with(|| (cand.globals.clone(), Vec::new()))
+                    .1
+                    .push(cand.sm);
+            }
+        }
+        info!("passing on charts from {} beatmapsets", by_set.len());
+        for (base, (globals, simfiles)) in by_set {
+            if crate::cancel::requested() {
+                warn!("interrupted, stopping before \"{}\"", base);
+                break;
+            }
+            crate::crash::set_beatmapset(Some(base.as_ref()));
+            info!("processing \"{}\":", base);
+            store.reset();
+            for (name, value) in globals.iter() {
+                store.global_set(name, value.clone());
+            }
+            store.put(&self.into, simfiles);
+            let result = on_bmset(store);
+            crate::crash::set_beatmapset(None);
+            result?;
+        }
+        Ok(())
+    }
+    fn buckets_mut<'a>(&'a mut self) -> BucketIter<'a> {
+        Box::new(
+            iter::once((BucketKind::Input, &mut self.from))
+                .chain(iter::once((BucketKind::Output, &mut self.into))),
+        )
+    }
+}
@@ -0,0 +1,315 @@
+//! Parse the `osu!.db` beatmap database and the `collection.db` collection database of an osu!
+//! installation.
+//!
+//! See https://github.com/ppy/osu/wiki/Legacy-database-file-structure for the format.
+
+use crate::prelude::*;
+
+/// The first database version with float difficulty stats and star ratings.
+const VERSION_FLOAT_STATS: i32 = 20140609;
+/// The first database version without the size of each beatmap entry.
+const VERSION_NO_ENTRY_SIZE: i32 = 20191106;
+/// The first database version with single-precision star ratings.
+const VERSION_FLOAT_STARS: i32 = 20250107;
+
+#[derive(Debug, Clone, Default)]
+pub struct OsuDb {
+    pub version: i32,
+    pub player: String,
+    pub beatmaps: Vec<DbBeatmap>,
+}
+
+/// A beatmap entry in the database.
+#[derive(Debug, Clone, Default)]
+pub struct DbBeatmap {
+    pub artist: String,
+    pub artist_unicode: String,
+    pub title: String,
+    pub title_unicode: String,
+    pub creator: String,
+    pub version: String,
+    pub audio: String,
+    pub md5: String,
+    /// The name of the `.osu` file, within `folder`.
+    pub osu_file: String,
+    pub ranked_status: RankedStatus,
+    pub mode: i32,
+    /// The nomod star rating in the beatmap's own gamemode, or `NaN` if osu! has not computed it
+    /// yet.
+    pub star_rating: f64,
+    /// The star ratings of each gamemode, by mod combination.
+    pub star_ratings: [Vec<(i32, f64)>; 4],
+    pub drain_secs: i32,
+    pub total_ms: i32,
+    pub preview_ms: i32,
+    pub id: i32,
+    pub set_id: i32,
+    /// The beatmapset folder, relative to the song folder.
+    pub folder: String,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RankedStatus {
+    #[default]
+    Unknown,
+    Unsubmitted,
+    /// Pending, WIP or graveyarded.
+    Pending,
+    Ranked,
+    Approved,
+    Qualified,
+    Loved,
+}
+impl RankedStatus {
+    fn from_byte(byte: u8) -> Self {
+        use RankedStatus::*;
+        match byte {
+            1 => Unsubmitted,
+            2 => Pending,
+            4 => Ranked,
+            5 => Approved,
+            6 => Qualified,
+            7 => Loved,
+            _ => Unknown,
+        }
+    }
+}
+
+impl OsuDb {
+    pub fn load(path: &Path) -> Result<OsuDb> {
+        let bytes = fs::read(path).context("failed to read osu! database")?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<OsuDb> {
+        let mut r = DbReader { bytes, pos: 0 };
+        let version = r.int()?;
+        let _folder_count = r.int()?;
+        let _unlocked = r.byte()?;
+        let _unlock_date = r.long()?;
+        let player = r.string()?;
+        let count = r.int()?;
+        ensure!(count >= 0, "negative beatmap count {}", count);
+        //The count comes straight from the file, so do not trust it to preallocate
+        let mut beatmaps = Vec::new();
+        for idx in 0..count {
+            let bm = r
+                .beatmap(version)
+                .with_context(|| anyhow!("failed to read beatmap {} of {}", idx + 1, count))?;
+            beatmaps.push(bm);
+        }
+        Ok(OsuDb {
+            version,
+            player,
+            beatmaps,
+        })
+    }
+}
+
+/// The beatmap collections made by the player, from `collection.db`.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionDb {
+    pub version: i32,
+    pub collections: Vec<Collection>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Collection {
+    pub name: String,
+    /// The MD5 hashes of the beatmaps in the collection (see `DbBeatmap::md5`).
+    pub md5s: Vec<String>,
+}
+
+impl CollectionDb {
+    pub fn load(path: &Path) -> Result<CollectionDb> {
+        let bytes = fs::read(path).context("failed to read osu! collections")?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<CollectionDb> {
+        let mut r = DbReader { bytes, pos: 0 };
+        let version = r.int()?;
+        let count = r.int()?;
+        ensure!(count >= 0, "negative collection count {}", count);
+        let mut collections = Vec::new();
+        for idx in 0..count {
+            let collection = r
+                .collection()
+                .with_context(|| anyhow!("failed to read collection {} of {}", idx + 1, count))?;
+            collections.push(collection);
+        }
+        Ok(CollectionDb {
+            version,
+            collections,
+        })
+    }
+}
+
+struct DbReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl DbReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or_else(|| anyhow!("invalid length {} at byte {}", len, self.pos))?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or_else(|| anyhow!("unexpected end of database at byte {}", self.pos))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn short(&mut self) -> Result<i16> {
+        Ok(i16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn int(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn long(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn single(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn double(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn uleb128(&mut self) -> Result<usize> {
+        let mut val = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            ensure!(shift < 64, "string length too long");
+            val |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+            shift += 7;
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        match self.byte()? {
+            0x00 => Ok(String::new()),
+            0x0b => {
+                let len = self.uleb128()?;
+                Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+            }
+            tag => bail!("invalid string tag 0x{:02x} at byte {}", tag, self.pos - 1),
+        }
+    }
+
+    /// Read a list of `(mods, star rating)` pairs.
+    fn star_ratings(&mut self, version: i32) -> Result<Vec<(i32, f64)>> {
+        let count = self.int()?;
+        ensure!(count >= 0, "negative star rating count {}", count);
+        let mut ratings = Vec::new();
+        for _ in 0..count {
+            ensure!(self.byte()? == 0x08, "invalid star rating mods tag");
+            let mods = self.int()?;
+            let stars = if version >= VERSION_FLOAT_STARS {
+                ensure!(self.byte()? == 0x0c, "invalid star rating tag");
+                self.single()? as f64
+            } else {
+                ensure!(self.byte()? == 0x0d, "invalid star rating tag");
+                self.double()?
+            };
+            ratings.push((mods, stars));
+        }
+        Ok(ratings)
+    }
+
+    fn collection(&mut self) -> Result<Collection> {
+        let name = self.string()?;
+        let count = self.int()?;
+        ensure!(count >= 0, "negative beatmap count {}", count);
+        let md5s = (0..count)
+            .map(|_| self.string())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Collection { name, md5s })
+    }
+
+    fn beatmap(&mut self, version: i32) -> Result<DbBeatmap> {
+        let mut bm = DbBeatmap::default();
+        if version < VERSION_NO_ENTRY_SIZE {
+            let _size = self.int()?;
+        }
+        bm.artist = self.string()?;
+        bm.artist_unicode = self.string()?;
+        bm.title = self.string()?;
+        bm.title_unicode = self.string()?;
+        bm.creator = self.string()?;
+        bm.version = self.string()?;
+        bm.audio = self.string()?;
+        bm.md5 = self.string()?;
+        bm.osu_file = self.string()?;
+        bm.ranked_status = RankedStatus::from_byte(self.byte()?);
+        let _circles = self.short()?;
+        let _sliders = self.short()?;
+        let _spinners = self.short()?;
+        let _modified = self.long()?;
+        //Approach rate, circle size, HP drain and overall difficulty
+        if version < VERSION_FLOAT_STATS {
+            self.take(4)?;
+        } else {
+            self.take(4 * 4)?;
+        }
+        let _slider_velocity = self.double()?;
+        if version >= VERSION_FLOAT_STATS {
+            for mode in 0..4 {
+                bm.star_ratings[mode] = self.star_ratings(version)?;
+            }
+        }
+        bm.drain_secs = self.int()?;
+        bm.total_ms = self.int()?;
+        bm.preview_ms = self.int()?;
+        let timing_points = self.int()?;
+        ensure!(timing_points >= 0, "negative timing point count");
+        //BPM, offset and whether the timing point is inherited
+        self.take(timing_points as usize * 17)?;
+        bm.id = self.int()?;
+        bm.set_id = self.int()?;
+        let _thread_id = self.int()?;
+        //Grades in each gamemode
+        self.take(4)?;
+        let _local_offset = self.short()?;
+        let _stack_leniency = self.single()?;
+        bm.mode = self.byte()? as i32;
+        let _source = self.string()?;
+        let _tags = self.string()?;
+        let _online_offset = self.short()?;
+        let _title_font = self.string()?;
+        let _unplayed = self.byte()?;
+        let _last_played = self.long()?;
+        let _osz2 = self.byte()?;
+        bm.folder = self.string()?;
+        let _last_checked = self.long()?;
+        //Ignore hitsounds, ignore skin, disable storyboard, disable video and visual override
+        self.take(5)?;
+        if version < VERSION_FLOAT_STATS {
+            let _unknown = self.short()?;
+        }
+        let _modified = self.int()?;
+        let _scroll_speed = self.byte()?;
+        bm.star_rating = bm
+            .star_ratings
+            .get(bm.mode as usize)
+            .and_then(|ratings| ratings.iter().find(|(mods, _)| *mods == 0))
+            .map(|&(_, stars)| stars)
+            .unwrap_or(f64::NAN);
+        Ok(bm)
+    }
+}
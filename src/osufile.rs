@@ -26,6 +26,8 @@ pub struct Beatmap {
     pub slider_tickrate: f64,
     pub background: String,
     pub video: String,
+    /// When the video starts playing, in milliseconds.
+    pub video_start: f64,
     pub timing_points: Vec<TimingPoint>,
     pub hit_objects: Vec<HitObject>,
     pub offset_ms: f64,
@@ -55,6 +57,7 @@ impl Default for Beatmap {
             slider_tickrate: 1.,
             background: default(),
             video: default(),
+            video_start: 0.,
             timing_points: default(),
             hit_objects: default(),
             offset_ms: 0.,
@@ -63,6 +66,17 @@ impl Default for Beatmap {
 }
 impl Beatmap {
     pub fn parse(offset_ms: f64, path: &Path) -> Result<Beatmap> {
+        let file = BufReader::new(File::open(path).context("open file")?);
+        Self::parse_reader(offset_ms, file, &path.display().to_string())
+    }
+
+    /// Parse a beatmap from the contents of a `.osu` file.
+    pub fn parse_str(offset_ms: f64, contents: &str) -> Result<Beatmap> {
+        Self::parse_reader(offset_ms, contents.as_bytes(), "<string>")
+    }
+
+    /// Parse a beatmap from a reader, using `name` to refer to the beatmap in warnings.
+    pub fn parse_reader(offset_ms: f64, reader: impl BufRead, name: &str) -> Result<Beatmap> {
         use Category::*;
 
         #[derive(Copy, Clone, Debug)]
@@ -109,7 +123,7 @@ impl Beatmap {
 
         let mut category = Category::Unknown;
         let mut bm = Beatmap::default();
-        let mut lines = BufReader::new(File::open(path).context("open file")?).lines();
+        let mut lines = reader.lines();
         let mut line_num = 0;
 
         //Find osu header
@@ -210,7 +224,7 @@ impl Beatmap {
                             let mut comps = line.split(',');
                             match &get_component::<String, _>(&mut comps, "event type")?[..] {
                                 ty @ "0" | ty @ "1" | ty @ "Video" => {
-                                    let _start_time: String =
+                                    let start_time: String =
                                         get_component(&mut comps, "start time")?;
                                     let filename: String = get_component(&mut comps, "filename")?;
                                     let filename = parse_filename(&filename);
@@ -218,6 +232,7 @@ impl Beatmap {
                                         bm.background = filename;
                                     } else {
                                         bm.video = filename;
+                                        bm.video_start = start_time.trim().parse().unwrap_or(0.);
                                     }
                                 }
                                 _ => {}
@@ -233,27 +248,49 @@ impl Beatmap {
                                 .trim()
                                 .parse::<i32>()
                                 .unwrap_or(4);
+                            //Skip sample set, sample index, volume and uninherited
+                            let effects = comps
+                                .nth(4)
+                                .unwrap_or_default()
+                                .trim()
+                                .parse::<i32>()
+                                .unwrap_or(0);
                             bm.timing_points.push(TimingPoint {
                                 time,
                                 beat_len,
                                 meter,
+                                omit_barline: effects & EFFECT_OMIT_BARLINE != 0,
                             });
                         }
                         HitObjects => {
-                            let mut comps = line.splitn(6, ',');
-                            let x = get_component(&mut comps, "x")?;
-                            let y = get_component(&mut comps, "y")?;
-                            let time = get_component::<f64, _>(&mut comps, "time")? + bm.offset_ms;
-                            let ty = get_component(&mut comps, "type")?;
-                            let _hitsound: String = get_component(&mut comps, "hitsound")?;
-                            let extras = comps.next().unwrap_or_default().trim().to_string();
-                            bm.hit_objects.push(HitObject {
-                                x,
-                                y,
-                                time,
-                                ty,
-                                extras,
-                            });
+                            let obj = match HitObject::parse_fast(line, bm.offset_ms) {
+                                Some(obj) => obj,
+                                None => {
+                                    let mut comps = line.splitn(6, ',');
+                                    let x = get_component(&mut comps, "x")?;
+                                    let y = get_component(&mut comps, "y")?;
+                                    let time =
+                                        get_component::<f64, _>(&mut comps, "time")? + bm.offset_ms;
+                                    let ty = get_component(&mut comps, "type")?;
+                                    //Be lenient with hitsounds, they are only used by taiko
+                                    let hitsound =
+                                        get_component::<String, _>(&mut comps, "hitsound")?
+                                            .parse()
+                                            .unwrap_or(0);
+                                    let extras =
+                                        comps.next().unwrap_or_default().trim().to_string();
+                                    HitObject {
+                                        x,
+                                        y,
+                                        time,
+                                        ty,
+                                        hitsound,
+                                        extras,
+                                    }
+                                }
+                            };
+                            let time = obj.time;
+                            bm.hit_objects.push(obj);
                             if time < last_time {
                                 requires_sort = true;
                             }
@@ -269,7 +306,7 @@ impl Beatmap {
             }
         }
         if !errors.is_empty() {
-            warn!("  warnings parsing \"{}\":", path.display());
+            warn!("  warnings parsing \"{}\":", name);
             for (line_num, line, err) in errors.iter() {
                 warn!("    line {} (\"{}\"): {:#}", line_num, line, err);
             }
@@ -288,6 +325,9 @@ pub struct TimingPoint {
     pub time: f64,
     pub beat_len: f64,
     pub meter: i32,
+    /// Whether the "omit first barline" effect is set, ie. the timing point does not start a new
+    /// bar.
+    pub omit_barline: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -296,15 +336,120 @@ pub struct HitObject {
     pub y: f64,
     pub time: f64,
     pub ty: u32,
+    /// A combination of the `HITSOUND_*` flags.
+    pub hitsound: u32,
     pub extras: String,
 }
+impl HitObject {
+    /// Parse a hitobject line consisting of plain integer fields, as almost all hitobjects are.
+    ///
+    /// Returns `None` if the line has anything unusual (decimals, whitespace, missing fields),
+    /// in which case the general parser should be used instead.
+    fn parse_fast(line: &str, offset_ms: f64) -> Option<HitObject> {
+        //Parse an optionally negative integer followed by a comma, advancing `pos` past the comma
+        fn int_field(bytes: &[u8], pos: &mut usize) -> Option<i64> {
+            let mut idx = *pos;
+            let neg = bytes.get(idx) == Some(&b'-');
+            if neg {
+                idx += 1;
+            }
+            let start = idx;
+            let mut num: i64 = 0;
+            while let Some(&b) = bytes.get(idx) {
+                match b {
+                    b'0'..=b'9' => num = num.checked_mul(10)?.checked_add((b - b'0') as i64)?,
+                    b',' => break,
+                    _ => return None,
+                }
+                idx += 1;
+            }
+            if idx == start || idx >= bytes.len() {
+                return None;
+            }
+            *pos = idx + 1;
+            Some(if neg { -num } else { num })
+        }
+
+        let bytes = line.as_bytes();
+        let mut pos = 0;
+        let x = int_field(bytes, &mut pos)?;
+        let y = int_field(bytes, &mut pos)?;
+        let time = int_field(bytes, &mut pos)?;
+        let ty = int_field(bytes, &mut pos)?;
+        if ty < 0 || ty > u32::MAX as i64 {
+            return None;
+        }
+        //The hitsound is followed by a comma or the end of the line
+        let (hitsound, extras) = match line[pos..].find(',') {
+            Some(comma) => (&line[pos..pos + comma], line[pos + comma + 1..].trim()),
+            None => (&line[pos..], ""),
+        };
+        if hitsound.is_empty() || !hitsound.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let hitsound = hitsound.parse().ok()?;
+        Some(HitObject {
+            x: x as f64,
+            y: y as f64,
+            time: time as f64 + offset_ms,
+            ty: ty as u32,
+            hitsound,
+            extras: extras.to_string(),
+        })
+    }
+
+    /// The custom sample file (keysound) played by this hitobject, if any.
+    ///
+    /// The hit sample is always the last component of the extras, in the
+    /// `normalSet:additionSet:index:volume:filename` format.
+    pub fn sample_file(&self) -> Option<&str> {
+        let sample = self.extras.rsplit(',').next()?;
+        let mut comps = sample.rsplit(':');
+        let filename = comps.next()?.trim();
+        if comps.count() < 4 || filename.is_empty() {
+            return None;
+        }
+        Some(filename)
+    }
+}
 
 pub const MODE_STD: i32 = 0;
 pub const MODE_TAIKO: i32 = 1;
 pub const MODE_CATCH: i32 = 2;
 pub const MODE_MANIA: i32 = 3;
 
+/// Find the gamemode of a beatmap file without parsing all of it.
+///
+/// Defaults to osu!standard, like osu! does.
+pub fn peek_mode(contents: &[u8]) -> i32 {
+    let mut in_general = false;
+    for line in contents.split(|&b| b == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            if in_general {
+                break;
+            }
+            in_general = line == "[General]";
+        } else if in_general {
+            if let Some(("Mode", mode)) = line.split_once(':') {
+                if let Ok(mode) = mode.trim().parse() {
+                    return mode;
+                }
+            }
+        }
+    }
+    MODE_STD
+}
+
 pub const TYPE_HIT: u32 = 1 << 0;
 pub const TYPE_SLIDER: u32 = 1 << 1;
 pub const TYPE_SPINNER: u32 = 1 << 3;
 pub const TYPE_LONG: u32 = 1 << 7;
+
+pub const HITSOUND_NORMAL: u32 = 1 << 0;
+pub const HITSOUND_WHISTLE: u32 = 1 << 1;
+pub const HITSOUND_FINISH: u32 = 1 << 2;
+pub const HITSOUND_CLAP: u32 = 1 << 3;
+
+pub const EFFECT_OMIT_BARLINE: i32 = 1 << 3;
@@ -0,0 +1,201 @@
+//! Song group metadata, so that converted packs show up with a proper name and banner in the song
+//! wheel.
+//!
+//! StepMania forks that understand packs (OutFox and ITGmania) read a `Pack.ini` file in the
+//! song group folder, and every StepMania version uses the image in the song group folder as the
+//! group banner.
+//!
+//! Banners are made with ffmpeg, just like the banners of the `Artwork` node.
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackMeta {
+    /// Whether to write a `Pack.ini` and a banner into the song group folder.
+    pub enable: bool,
+    /// The name of the pack as shown in the song wheel.
+    /// If empty, the name of the song group folder is used.
+    pub name: String,
+    /// The series that the pack belongs to, if any.
+    pub series: String,
+    /// Where to get the banner of the pack from.
+    pub banner: PackBanner,
+    /// The ffmpeg executable to use to make the banner.
+    pub ffmpeg: String,
+}
+impl Default for PackMeta {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            name: "".into(),
+            series: "".into(),
+            banner: PackBanner::Text,
+            ffmpeg: "ffmpeg".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PackBanner {
+    /// Do not write a banner.
+    None,
+    /// Draw the name of the pack on a plain background.
+    Text,
+    /// Fit this image file to the banner size, cutting off the edges that do not fit.
+    Copy(String),
+}
+
+/// The size of StepMania banners, in pixels.
+const BANNER_SIZE: (u32, u32) = (418, 164);
+
+/// Write the pack metadata into a song group folder.
+///
+/// `sync` is the sync convention that the simfiles were written for, as a `Pack.ini`
+/// `SyncOffset` value.
+pub fn write_pack(
+    meta: &PackMeta,
+    group: &Path,
+    sync: Option<&str>,
+    fsync: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let name = if meta.name.is_empty() {
+        group
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        meta.name.clone()
+    };
+    let ini_path = group.join("Pack.ini");
+    let banner_path = match meta.banner {
+        PackBanner::None => None,
+        PackBanner::Text | PackBanner::Copy(_) => Some(group.join("banner.png")),
+    };
+    if dry_run {
+        info!("would write pack metadata \"{}\"", ini_path.display());
+        if let Some(banner_path) = &banner_path {
+            info!("would write pack banner \"{}\"", banner_path.display());
+        }
+        return Ok(());
+    }
+    fs::create_dir_all(group)
+        .with_context(|| anyhow!("failed to create song group at \"{}\"", group.display()))?;
+    //A pack without a banner is still better than no pack at all
+    let banner_path =
+        banner_path.filter(
+            |banner_path| match make_banner(meta, &name, group, banner_path) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!(
+                        "failed to make pack banner \"{}\": {:#}",
+                        banner_path.display(),
+                        err
+                    );
+                    false
+                }
+            },
+        );
+    let mut ini = String::new();
+    writeln!(ini, "[Group]").unwrap();
+    writeln!(ini, "Version=1").unwrap();
+    writeln!(ini, "DisplayTitle={}", name).unwrap();
+    writeln!(ini, "TranslitTitle={}", name).unwrap();
+    writeln!(ini, "Series={}", meta.series).unwrap();
+    writeln!(
+        ini,
+        "Banner={}",
+        banner_path
+            .as_deref()
+            .and_then(Path::file_name)
+            .unwrap_or_default()
+            .to_string_lossy()
+    )
+    .unwrap();
+    if let Some(sync) = sync {
+        writeln!(ini, "SyncOffset={}", sync).unwrap();
+    }
+    crate::write_atomic(&ini_path, fsync, |file| {
+        file.write_all(ini.as_bytes())?;
+        Ok(())
+    })
+    .with_context(|| anyhow!("failed to write \"{}\"", ini_path.display()))?;
+    debug!("wrote pack metadata into \"{}\"", group.display());
+    Ok(())
+}
+
+/// Make the pack banner with ffmpeg.
+fn make_banner(meta: &PackMeta, name: &str, group: &Path, dst: &Path) -> Result<()> {
+    let (width, height) = BANNER_SIZE;
+    //The working directory of ffmpeg changes below, so the destination must be absolute
+    let dst = std::env::current_dir()
+        .context("get current directory")?
+        .join(dst);
+    match &meta.banner {
+        PackBanner::None => Ok(()),
+        PackBanner::Text => {
+            //The name is read from a file rather than given inline, since escaping arbitrary text
+            //for ffmpeg filter options is a nightmare
+            let lines = wrap_name(name);
+            let text_dir = crate::generated_dir(group)?;
+            fs::write(text_dir.join("pack-name.txt"), lines.join("\n"))
+                .context("write pack name")?;
+            let longest = lines
+                .iter()
+                .map(|line| line.chars().count())
+                .max()
+                .unwrap_or(0)
+                .max(1) as u32;
+            //Glyphs are roughly 0.6 times as wide as the font size
+            let size = ((width - 40) * 5 / 3 / longest)
+                .min((height - 30) / lines.len() as u32 * 3 / 4)
+                .max(8);
+            let filter = format!(
+                "drawtext=textfile=pack-name.txt:expansion=none:fontsize={}:fontcolor=white:\
+                 shadowcolor=black:shadowx={1}:shadowy={1}:x=(w-tw)/2:y=(h-th)/2",
+                size,
+                (size / 16).max(1),
+            );
+            crate::node::artwork::render_image(
+                &meta.ffmpeg,
+                |cmd| {
+                    cmd.current_dir(&text_dir).args([
+                        "-f",
+                        "lavfi",
+                        "-i",
+                        &format!("color=c=0x48185a:s={}x{}", width, height),
+                    ]);
+                },
+                &filter,
+                &dst,
+            )
+        }
+        PackBanner::Copy(path) => crate::node::artwork::render_image(
+            &meta.ffmpeg,
+            |cmd| {
+                cmd.arg("-i").arg(path);
+            },
+            &crate::node::artwork::Shape::Size(width, height, crate::node::artwork::ImageFit::Crop)
+                .filter(),
+            &dst,
+        ),
+    }
+}
+
+/// Wrap long names into two lines, at the space closest to the middle.
+fn wrap_name(name: &str) -> Vec<&str> {
+    let text = name.trim();
+    let split = text
+        .char_indices()
+        .filter(|&(_, c)| c == ' ')
+        .map(|(idx, _)| idx)
+        .min_by_key(|&idx| (2 * idx as isize - text.len() as isize).abs());
+    match split {
+        Some(split) if text.chars().count() > 16 => {
+            vec![text[..split].trim(), text[split..].trim()]
+        }
+        _ => vec![text],
+    }
+}
@@ -0,0 +1,87 @@
+//! Keep track of how far along the conversion is, so that converting huge libraries does not look
+//! frozen between log lines.
+//!
+//! Loader nodes report every beatmapset they find and finish, and the current progress is passed
+//! on to a callback (see `set_callback`), which the command-line converter uses to draw a progress
+//! bar.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+static ERRORED: AtomicUsize = AtomicUsize::new(0);
+static CALLBACK: Mutex<Option<Callback>> = Mutex::new(None);
+
+type Callback = Box<dyn Fn(Progress) + Send + Sync>;
+
+/// A snapshot of the conversion progress.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// How many beatmapsets (or StepMania songs) were found so far.
+    pub total: usize,
+    /// How many of them were processed, including the ones that failed or were skipped.
+    pub processed: usize,
+    /// How many of the processed beatmapsets had errors.
+    pub errored: usize,
+}
+impl Progress {
+    /// The processed fraction, from `0` to `1`.
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            0.
+        } else {
+            (self.processed as f64 / self.total as f64).min(1.)
+        }
+    }
+}
+
+/// Call the given function every time the progress changes, possibly from several threads at
+/// once.
+///
+/// Replaces any previous callback.
+/// The callback must not call `set_callback` itself.
+pub fn set_callback(callback: impl Fn(Progress) + Send + Sync + 'static) {
+    *CALLBACK.lock().unwrap_or_else(|err| err.into_inner()) = Some(Box::new(callback));
+}
+
+/// Get the current progress.
+pub fn get() -> Progress {
+    Progress {
+        total: TOTAL.load(Ordering::SeqCst),
+        processed: PROCESSED.load(Ordering::SeqCst),
+        errored: ERRORED.load(Ordering::SeqCst),
+    }
+}
+
+/// Start counting from zero again.
+pub fn reset() {
+    TOTAL.store(0, Ordering::SeqCst);
+    PROCESSED.store(0, Ordering::SeqCst);
+    ERRORED.store(0, Ordering::SeqCst);
+    notify();
+}
+
+/// Report that `count` more beatmapsets were found.
+/// Called by loader nodes, such as `OsuLoad`.
+pub fn discovered(count: usize) {
+    TOTAL.fetch_add(count, Ordering::SeqCst);
+    notify();
+}
+
+/// Report that a beatmapset was processed, successfully or not.
+pub fn processed(ok: bool) {
+    if !ok {
+        ERRORED.fetch_add(1, Ordering::SeqCst);
+    }
+    PROCESSED.fetch_add(1, Ordering::SeqCst);
+    notify();
+}
+
+fn notify() {
+    if let Some(callback) = &*CALLBACK.lock().unwrap_or_else(|err| err.into_inner()) {
+        callback(get());
+    }
+}
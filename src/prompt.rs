@@ -0,0 +1,78 @@
+//! Ask the user to resolve ambiguous decisions interactively, remembering their answers.
+
+use crate::prelude::*;
+use std::{collections::BTreeMap, sync::Mutex};
+
+struct Decisions {
+    interactive: bool,
+    answers: BTreeMap<String, String>,
+    changed: bool,
+}
+
+static DECISIONS: Mutex<Decisions> = Mutex::new(Decisions {
+    interactive: false,
+    answers: BTreeMap::new(),
+    changed: false,
+});
+
+/// Enable or disable interactive prompts, and load previously remembered answers.
+pub fn init(interactive: bool, answers: BTreeMap<String, String>) {
+    let mut decisions = DECISIONS.lock().unwrap_or_else(|err| err.into_inner());
+    decisions.interactive = interactive;
+    decisions.answers = answers;
+    decisions.changed = false;
+}
+
+/// Get all remembered answers, if any new answer was given since `init`.
+pub fn new_answers() -> Option<BTreeMap<String, String>> {
+    let decisions = DECISIONS.lock().unwrap_or_else(|err| err.into_inner());
+    if decisions.changed {
+        Some(decisions.answers.clone())
+    } else {
+        None
+    }
+}
+
+/// Ask the user to pick one of several options, returning the index of the chosen option.
+///
+/// If an answer was remembered for `key`, it is returned without asking.
+/// Otherwise, if interactive mode is disabled, `default` is returned.
+pub fn choose(key: &str, question: &str, options: &[&str], default: usize) -> usize {
+    //Hold the lock while asking, so that concurrent prompts do not interleave
+    let mut decisions = DECISIONS.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(answer) = decisions.answers.get(key) {
+        if let Some(idx) = options.iter().position(|opt| opt == answer) {
+            debug!("using remembered answer \"{}\" for \"{}\"", answer, key);
+            return idx;
+        }
+    }
+    if !decisions.interactive {
+        return default;
+    }
+    eprintln!();
+    eprintln!("{}", question);
+    for (idx, opt) in options.iter().enumerate() {
+        eprintln!("  {}) {}", idx + 1, opt);
+    }
+    let idx = loop {
+        eprint!("choose an option (1-{}): ", options.len());
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                //No way to ask
+                break default;
+            }
+            Ok(_) => {}
+        }
+        match line.trim().parse::<usize>() {
+            Ok(num) if num >= 1 && num <= options.len() => break num - 1,
+            _ => eprintln!("invalid option \"{}\"", line.trim()),
+        }
+    };
+    info!("chose \"{}\" for \"{}\"", options[idx], key);
+    decisions
+        .answers
+        .insert(key.to_string(), options[idx].to_string());
+    decisions.changed = true;
+    idx
+}
@@ -0,0 +1,397 @@
+//! Estimate the difficulty of charts.
+//!
+//! These are the rating methods behind the `Rate` node, but they work on any `Simfile`, so they
+//! can also score charts without running a node graph (eg. `rating::compute(&method, &sm)` or
+//! `rating::mania_star_rating(&sm)`).
+//! Every function gives a raw rating, before the scaling that the `Rate` node applies on top.
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RateMethod {
+    /// Use the raw total amount of non-tail notes.
+    Count(NoteCount),
+    /// Use a weighted norm of note densities, where each note may have several rectangular "halos".
+    ///
+    /// Outputs the density in note units / sec.
+    /// Scale `x60` to obtain effective BPM.
+    Density(NoteDensity),
+    /// Use the norm of the gaps between notes.
+    ///
+    /// Outputs the "average" note density in notes / sec.
+    /// Scale `x60` to obtain effective BPM.
+    Gap(NoteGap),
+    /// Use the osu! star rating, which is only known for beatmaps loaded through `osu!.db` (see
+    /// `OsuLoad::database`) and for osu!mania beatmaps (see `OsuLoad::recompute_stars`).
+    #[serde(alias = "OsuStars")]
+    Stars(OsuStars),
+    /// Add up the ratings of several methods, each one multiplied by its weight.
+    ///
+    /// The weights also scale the methods into a common range, eg. `(Count(( log: 2 )), 0.5)`.
+    /// If the weights of methods with the same scale add up to `1`, the output is a weighted
+    /// average on that same scale.
+    Hybrid(Vec<(RateMethod, f64)>),
+}
+impl Default for RateMethod {
+    fn default() -> Self {
+        Self::Density(default())
+    }
+}
+impl RateMethod {
+    /// Whether the output of this method is a note frequency, which can be shown as an effective
+    /// BPM.
+    pub fn outputs_frequency(&self) -> bool {
+        match self {
+            RateMethod::Density(_) | RateMethod::Gap(_) => true,
+            RateMethod::Count(_) | RateMethod::Stars(_) => false,
+            RateMethod::Hybrid(parts) => parts.iter().all(|(method, _)| method.outputs_frequency()),
+        }
+    }
+
+    /// Make sure the method parameters make sense.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            RateMethod::Stars(conf) => {
+                ensure!(
+                    conf.calibration
+                        .windows(2)
+                        .all(|pair| pair[0].0 < pair[1].0),
+                    "star rating calibration points must be sorted by increasing star rating"
+                );
+                conf.fallback.check()
+            }
+            RateMethod::Hybrid(parts) => parts.iter().try_for_each(|(method, _)| method.check()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteCount {
+    /// Whether to take the logarithm of the amount of notes, instead of the raw amount
+    /// itself.
+    ///
+    /// More specifically, if `log` is greater than zero, take the logarithm base `log` of the
+    /// amount of non-tail notes.
+    pub log: f64,
+}
+impl Default for NoteCount {
+    fn default() -> Self {
+        Self { log: 0. }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteDensity {
+    /// A list of `(duration, weight)` pairs.
+    pub halos: Vec<(f64, f64)>,
+    /// A list of weights for each additional simultaneous note.
+    ///
+    /// If more than the length of this `Vec` simultaneous notes occur, the last weight (or `1` if
+    /// there are no weights) will be used.
+    pub simultaneous: Vec<f64>,
+    /// How much weight to give to short high densities over long low densities.
+    pub exponent: f64,
+}
+impl Default for NoteDensity {
+    fn default() -> Self {
+        Self {
+            halos: vec![(2., 0.), (1., 1.)],
+            simultaneous: vec![1., 0.75, 0.5],
+            exponent: 2.,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OsuStars {
+    /// The method to rate charts without a star rating with.
+    /// Note densities are roughly on the same scale as star ratings.
+    pub fallback: Box<RateMethod>,
+    /// `(stars, rating)` points to map star ratings through, interpolating linearly between
+    /// them (and past the ends along the closest two points), to match other difficulty scales.
+    /// Sorted by star rating.
+    /// If empty, star ratings are used as-is.
+    pub calibration: Vec<(f64, f64)>,
+}
+impl Default for OsuStars {
+    fn default() -> Self {
+        Self {
+            fallback: Box::new(RateMethod::default()),
+            calibration: vec![],
+        }
+    }
+}
+impl OsuStars {
+    /// Map a star rating through the calibration points.
+    pub fn calibrate(&self, stars: f64) -> f64 {
+        let points = &self.calibration;
+        match points.len() {
+            0 => stars,
+            1 => stars - points[0].0 + points[0].1,
+            len => {
+                let idx = points
+                    .iter()
+                    .position(|&(point, _)| point > stars)
+                    .unwrap_or(len)
+                    .clamp(1, len - 1);
+                let ((in_min, out_min), (in_max, out_max)) = (points[idx - 1], points[idx]);
+                linear_map(in_min, in_max, out_min, out_max)(stars)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteGap {
+    /// How much weight to give to few very short gaps over many not-so-short gaps.
+    pub exponent: f64,
+}
+impl Default for NoteGap {
+    fn default() -> Self {
+        Self { exponent: 2. }
+    }
+}
+
+/// Compute the raw numerical rating of a simfile, before any scaling.
+pub fn compute(method: &RateMethod, sm: &Simfile) -> f64 {
+    match method {
+        RateMethod::Count(conf) => note_count(conf, sm),
+        RateMethod::Density(conf) => note_density(conf, sm),
+        RateMethod::Gap(conf) => note_gap(conf, sm),
+        RateMethod::Stars(conf) => {
+            if sm.star_rating.is_nan() {
+                compute(&conf.fallback, sm)
+            } else {
+                conf.calibrate(sm.star_rating)
+            }
+        }
+        RateMethod::Hybrid(parts) => parts
+            .iter()
+            .map(|(method, weight)| weight * compute(method, sm))
+            .sum(),
+    }
+}
+
+/// Count the non-tail, non-mine notes of a chart (or take their logarithm).
+pub fn note_count(conf: &NoteCount, sm: &Simfile) -> f64 {
+    let mut count = 0;
+    for note in sm.notes.iter() {
+        if !note.is_tail() && !note.is_mine() {
+            count += 1;
+        }
+    }
+    if conf.log > 0. {
+        (count as f64).log(conf.log)
+    } else {
+        count as f64
+    }
+}
+
+/// Compute a weighted norm of the note densities of a chart, in note units / sec.
+pub fn note_density(conf: &NoteDensity, sm: &Simfile) -> f64 {
+    let mut to_time = sm.beat_to_time();
+    let halo_densities = conf
+        .halos
+        .iter()
+        .map(|&(duration, weight)| (duration / 2., (weight / duration) as f32))
+        .collect::<Vec<_>>();
+    let mut default_base_weight = 0.;
+    let mut default_key_weight = 1.;
+    let mut key_weights = Vec::with_capacity(conf.simultaneous.len());
+    {
+        let mut acc = 0.;
+        for &w in conf.simultaneous.iter() {
+            acc += w;
+            key_weights.push(acc as f32);
+            default_base_weight = acc as f32;
+            default_key_weight = w as f32;
+        }
+    }
+    let mut last_id: u32 = 0;
+    let mut weight_changes = Vec::with_capacity(2 * sm.notes.len() * conf.halos.len());
+    for beat in sm.iter_beats() {
+        let time = to_time.beat_to_time(beat.pos);
+        //Calculate a weight for the notes on this beat
+        let note_count = beat.count_heads(&sm.notes);
+        if note_count > 0 {
+            let weight = key_weights.get(note_count - 1).copied().unwrap_or_else(|| {
+                default_base_weight + default_key_weight * (note_count - key_weights.len()) as f32
+            });
+            //Create halos for this note weight
+            for &(radius, density) in halo_densities.iter() {
+                last_id += 1;
+                weight_changes.push((time - radius, last_id, weight * density));
+                weight_changes.push((time + radius, last_id, f32::NAN));
+            }
+        }
+    }
+    weight_changes.sort_unstable_by_key(|(time, _id, _change)| SortableFloat(*time));
+    if weight_changes.is_empty() {
+        return 0.;
+    }
+    let mut total_density = 0.;
+    let mut cur_time = weight_changes[0].0;
+    // OPTIMIZE: Use fixed-point for density, keeping track of `cur_density` without keeping track
+    // of individual halos. Fixed-point would allow for the needed precision.
+    let mut active_halos = Vec::new();
+    let mut total_time: f64 = 0.;
+    for (time, id, change) in weight_changes {
+        //Sum density
+        let mut cur_density: f32 = 0.;
+        for &(_halo_id, halo_density) in active_halos.iter() {
+            cur_density += halo_density;
+        }
+        let dt = time - cur_time;
+        total_density += dt as f32 * cur_density.powf(conf.exponent as f32);
+        if !active_halos.is_empty() {
+            total_time += dt;
+        }
+        //Update for next iteration
+        cur_time = time;
+        if change.is_nan() {
+            for i in 0..active_halos.len() {
+                if active_halos[i].0 == id {
+                    active_halos.remove(i);
+                    break;
+                }
+            }
+        } else {
+            active_halos.push((id, change));
+        }
+    }
+    if total_time > 0. {
+        (total_density as f64 / total_time).powf(1. / conf.exponent)
+    } else {
+        0.
+    }
+}
+
+/// Compute a norm of the gaps between the notes of a chart, as an "average" note density in
+/// notes / sec.
+pub fn note_gap(conf: &NoteGap, sm: &Simfile) -> f64 {
+    let exp = conf.exponent as f32;
+    let mut last_time = None;
+    let mut to_time = sm.beat_to_time();
+    let mut total_freq = 0.;
+    let mut total_gaps = 0;
+    for beat in sm.iter_beats() {
+        if beat.count_heads(&sm.notes) == 0 {
+            continue;
+        }
+        let time = to_time.beat_to_time(beat.pos);
+        if let Some(last_time) = last_time {
+            let gap = (time - last_time) as f32;
+            if gap > 0. {
+                let freq = 1. / gap;
+                total_freq += freq.powf(exp);
+                total_gaps += 1;
+            }
+        }
+        last_time = Some(time);
+    }
+    if total_gaps <= 0 {
+        total_freq = 0.;
+    } else {
+        total_freq = (total_freq / total_gaps as f32).powf(1. / exp);
+    }
+    total_freq as f64
+}
+
+/// Compute the osu!mania star rating of a chart, as computed by osu! before the 2022 rework.
+///
+/// Every note adds strain to its own column and to the chart as a whole, and the strain decays
+/// over time.
+/// The rating is a weighted sum of the highest strains of every 400ms section.
+pub fn mania_star_rating(sm: &Simfile) -> f64 {
+    const SECTION_LEN: f64 = 400.;
+    const INDIVIDUAL_DECAY_BASE: f64 = 0.125;
+    const OVERALL_DECAY_BASE: f64 = 0.3;
+    const STAR_SCALING_FACTOR: f64 = 0.018;
+    //Empty columns do not affect the rating, so only the used ones matter
+    let key_count = sm
+        .notes
+        .iter()
+        .map(|note| note.key + 1)
+        .max()
+        .unwrap_or(0)
+        .max(0) as usize;
+    //Gather `(start, end, key)` objects, in milliseconds
+    let mut objects: Vec<(f64, f64, usize)> = Vec::with_capacity(sm.notes.len());
+    {
+        let mut to_time = sm.beat_to_time();
+        let mut open: Vec<Option<usize>> = vec![None; key_count];
+        for note in sm.notes.iter() {
+            let key = note.key as usize;
+            if note.key < 0 || note.is_mine() {
+                continue;
+            }
+            let time = to_time.beat_to_time(note.beat) * 1000.;
+            if note.is_tail() {
+                if let Some(idx) = open[key].take() {
+                    objects[idx].1 = time;
+                }
+            } else {
+                if note.is_head() {
+                    open[key] = Some(objects.len());
+                }
+                objects.push((time, time, key));
+            }
+        }
+    }
+    let decay = |value: f64, delta: f64, base: f64| value * base.powf(delta / 1000.);
+    let mut hold_ends = vec![0.; key_count];
+    let mut individual = vec![0.; key_count];
+    let mut overall = 1.;
+    let mut strain = 0.;
+    let mut peaks = Vec::new();
+    let mut section_peak: f64 = 0.;
+    let mut section_end = 0.;
+    for (idx, pair) in objects.windows(2).enumerate() {
+        let (prev_start, _, _) = pair[0];
+        let (start, end, key) = pair[1];
+        let delta = start - prev_start;
+        if idx == 0 {
+            section_end = (start / SECTION_LEN).ceil() * SECTION_LEN;
+        }
+        while start > section_end {
+            peaks.push(section_peak);
+            //Strain does not decay on its own, only its parts do
+            section_peak = strain;
+            section_end += SECTION_LEN;
+        }
+        let mut hold_factor = 1.;
+        let mut hold_addition = 0.;
+        for k in 0..key_count {
+            //Releasing a hold while another one is held is awkward, unless both end together
+            if hold_ends[k] > start + 1. && end > hold_ends[k] + 1. {
+                hold_addition = 1.;
+            }
+            if (end - hold_ends[k]).abs() < 1. {
+                hold_addition = 0.;
+            }
+            if hold_ends[k] > end + 1. {
+                hold_factor = 1.25;
+            }
+            individual[k] = decay(individual[k], delta, INDIVIDUAL_DECAY_BASE);
+        }
+        hold_ends[key] = end;
+        individual[key] += 2. * hold_factor;
+        overall = decay(overall, delta, OVERALL_DECAY_BASE) + (1. + hold_addition) * hold_factor;
+        strain = individual[key] + overall;
+        section_peak = section_peak.max(strain);
+    }
+    peaks.push(section_peak);
+    peaks.sort_unstable_by_key(|&peak| cmp::Reverse(SortableFloat(peak)));
+    let mut weight = 1.;
+    let mut difficulty = 0.;
+    for peak in peaks {
+        difficulty += peak * weight;
+        weight *= 0.9;
+    }
+    difficulty * STAR_SCALING_FACTOR
+}
@@ -0,0 +1,299 @@
+//! Keep track of the simfiles written into an output folder, and find the ones that broke since
+//! (eg. because the osu! folder moved, leaving dangling symlinks behind).
+
+use crate::prelude::*;
+use std::collections::BTreeMap;
+#[cfg(feature = "fs")]
+use std::collections::BTreeSet;
+
+/// The name of the manifest file, written into the output folder.
+pub const MANIFEST_NAME: &str = "osu2sm-manifest.json";
+
+/// A record of the simfiles written into an output folder, and where they came from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    /// The osu2sm version that last wrote into the output folder.
+    pub version: String,
+    /// The input folder that beatmapset paths are relative to.
+    pub root: String,
+    /// Whether simfiles were written in-place into the input folder.
+    pub in_place: bool,
+    /// Simfiles by their path relative to the beatmapset folder.
+    pub simfiles: BTreeMap<String, ManifestEntry>,
+    /// Output folders and files that were renamed, since their names are reserved on Windows or
+    /// were scrubbed for removable media, mapped to the beatmapset folder or file they came from
+    /// (both relative to their root folders).
+    pub renamed: BTreeMap<String, String>,
+    /// A hash of the source files and conversion settings of every beatmapset that was
+    /// converted, by beatmapset folder (relative to the input folder).
+    /// Used to skip unchanged beatmapsets when converting incrementally.
+    pub sources: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManifestEntry {
+    /// The source beatmapset folder, relative to the input folder.
+    pub set: String,
+    /// The files that the simfile depends on, relative to the simfile folder.
+    pub deps: Vec<String>,
+    /// The lowest sync confidence among the charts in the simfile, if known.
+    pub sync_confidence: Option<f64>,
+    /// The charts in the simfile, to look for similar charts (see `find_similar`).
+    pub charts: Vec<ManifestChart>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManifestChart {
+    /// The name of the chart (its `desc`).
+    pub name: String,
+    /// The gamemode of the chart, as a StepMania id (eg. `dance-single`).
+    pub gamemode: String,
+    /// A signature of the note rows of the chart, as obtained from `simfile::row_signature`.
+    pub signature: Vec<u64>,
+}
+
+/// A chart found by `find_similar`.
+#[derive(Clone, Debug)]
+pub struct SimilarChart {
+    /// The simfile that the chart is in.
+    pub simfile: PathBuf,
+    /// The name of the chart.
+    pub name: String,
+    /// The estimated similarity, from `0` to `1` (see `simfile::row_similarity`).
+    pub similarity: f64,
+}
+
+impl Manifest {
+    /// Load the manifest of the given output folder.
+    /// If there is no manifest, an empty one is returned.
+    pub fn load(output: &Path) -> Result<Manifest> {
+        let path = output.join(MANIFEST_NAME);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let file = BufReader::new(File::open(&path).context("failed to open manifest")?);
+        serde_json::from_reader(file)
+            .with_context(|| anyhow!("failed to parse manifest at \"{}\"", path.display()))
+    }
+
+    /// Write the manifest into the given output folder.
+    ///
+    /// If `fsync` is set, the manifest is flushed to disk before replacing the old one.
+    pub fn save(&self, output: &Path, fsync: bool) -> Result<()> {
+        fs::create_dir_all(output).context("failed to create output folder")?;
+        let path = output.join(MANIFEST_NAME);
+        crate::write_atomic(&path, fsync, |file| {
+            serde_json::to_writer_pretty(file, self).map_err(Error::from)
+        })
+        .with_context(|| anyhow!("failed to write manifest at \"{}\"", path.display()))
+    }
+
+    /// Where a simfile in the manifest is expected to be.
+    #[cfg(feature = "fs")]
+    fn simfile_path(&self, output: &Path, sm_path: &str) -> PathBuf {
+        if self.in_place {
+            Path::new(&self.root).join(sm_path)
+        } else {
+            output.join(sm_path)
+        }
+    }
+}
+
+/// Make sure a `.sm` or `.ssc` file is structurally sound: every tag is terminated, and there is
+/// at least one complete `#NOTES` tag.
+pub fn check_simfile(path: &Path) -> Result<()> {
+    let txt = fs::read_to_string(path).context("failed to read simfile")?;
+    //In `.ssc` files the chart fields are separate tags, and `#NOTES` only holds the note data
+    let ssc = path.extension().map(|ext| ext == "ssc").unwrap_or(false);
+    let mut uncommented = String::with_capacity(txt.len());
+    for line in txt.lines() {
+        uncommented.push_str(line.find("//").map(|c| &line[..c]).unwrap_or(line));
+        uncommented.push('\n');
+    }
+    let mut tags = uncommented.split(';').map(str::trim).peekable();
+    let mut charts = 0;
+    while let Some(tag) = tags.next() {
+        if tag.is_empty() && tags.peek().is_none() {
+            break;
+        }
+        ensure!(
+            tag.starts_with('#') && tag.contains(':'),
+            "malformed tag \"{}\"",
+            tag.chars().take(40).collect::<String>()
+        );
+        if tag.starts_with("#NOTES:") {
+            ensure!(
+                ssc || tag.split(':').count() >= 7,
+                "malformed #NOTES tag (expected 6 fields)"
+            );
+            charts += 1;
+        }
+    }
+    ensure!(charts > 0, "no charts found");
+    Ok(())
+}
+
+/// Check whether a dependency file is available, removing it if it is a dangling symlink so that
+/// it can be linked again.
+#[cfg(feature = "fs")]
+fn check_dep(path: &Path) -> Result<()> {
+    if fs::metadata(path).is_ok() {
+        return Ok(());
+    }
+    if fs::symlink_metadata(path).is_ok() {
+        if let Err(err) = fs::remove_file(path) {
+            warn!(
+                "  failed to remove dangling symlink \"{}\": {:#}",
+                path.display(),
+                err
+            );
+        }
+        bail!("dangling symlink");
+    }
+    bail!("file not found")
+}
+
+/// Check all simfiles in an output folder, and find the beatmapsets (relative to the input
+/// folder) that have to be converted again.
+///
+/// Simfiles in the output folder that are not in the manifest are checked too, but they cannot
+/// be repaired since their source is unknown.
+///
+/// Returns `None` if the whole output is broken and everything has to be converted again.
+#[cfg(feature = "fs")]
+pub fn find_broken(output: &Path) -> Result<Option<BTreeSet<PathBuf>>> {
+    if check_dep(output).is_err() {
+        warn!(
+            "output folder \"{}\" is missing or a dangling symlink (did the osu! folder move?)",
+            output.display()
+        );
+        return Ok(None);
+    }
+    let manifest = Manifest::load(output)?;
+    let mut broken = BTreeSet::new();
+    let mut checked = 0;
+    info!("checking simfiles in \"{}\"", output.display());
+    for (sm_path, entry) in manifest.simfiles.iter() {
+        checked += 1;
+        let path = manifest.simfile_path(output, sm_path);
+        let sm_dir = path.parent().unwrap_or(output);
+        let result = check_simfile(&path).and_then(|()| {
+            for dep in entry.deps.iter() {
+                check_dep(&sm_dir.join(dep))
+                    .with_context(|| anyhow!("dependency \"{}\" is missing", dep))?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("  simfile \"{}\" is broken: {:#}", path.display(), err);
+            broken.insert(PathBuf::from(&entry.set));
+        }
+    }
+    //Look for simfiles that are not in the manifest
+    let known = manifest
+        .simfiles
+        .keys()
+        .map(|sm_path| manifest.simfile_path(output, sm_path))
+        .collect::<HashSet<_>>();
+    let scan = if manifest.in_place && !manifest.root.is_empty() {
+        Path::new(&manifest.root)
+    } else {
+        output
+    };
+    for file in WalkDir::new(scan).into_iter().filter_map(|file| file.ok()) {
+        let filename = file.file_name().to_string_lossy();
+        if !file.file_type().is_file()
+            || !filename.starts_with("osu2sm-")
+            || !(filename.ends_with(".sm") || filename.ends_with(".ssc"))
+            || known.contains(file.path())
+        {
+            continue;
+        }
+        checked += 1;
+        if let Err(err) = check_simfile(file.path()) {
+            warn!(
+                "  simfile \"{}\" is broken, but cannot be repaired since it is not in the manifest: {:#}",
+                file.path().display(),
+                err
+            );
+        }
+    }
+    info!(
+        "  checked {} simfiles, {} beatmapsets need to be converted again",
+        checked,
+        broken.len()
+    );
+    Ok(Some(broken))
+}
+
+/// Find the charts in the given output folders that are most similar to each chart of the given
+/// simfile, going by the note patterns recorded in their manifests.
+///
+/// Returns the name of every chart in the simfile along with up to `count` of the most similar
+/// charts of the same gamemode, most similar first.
+#[cfg(feature = "fs")]
+pub fn find_similar(
+    outputs: &[&Path],
+    simfile: &Path,
+    count: usize,
+) -> Result<Vec<(String, Vec<SimilarChart>)>> {
+    let target = fs::canonicalize(simfile)
+        .with_context(|| anyhow!("failed to open \"{}\"", simfile.display()))?;
+    let mut library = Vec::new();
+    for &output in outputs {
+        let manifest = Manifest::load(output)?;
+        for (sm_path, entry) in manifest.simfiles.iter() {
+            library.push((manifest.simfile_path(output, sm_path), entry.charts.clone()));
+        }
+    }
+    //Canonicalize every path only once, rather than for every pair of charts
+    let target_idx = library
+        .iter()
+        .position(|(path, _)| {
+            fs::canonicalize(path)
+                .map(|path| path == target)
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "\"{}\" is not in the manifest of any output folder",
+                simfile.display()
+            )
+        })?;
+    let charts = &library[target_idx].1;
+    ensure!(
+        !charts.is_empty(),
+        "\"{}\" has no pattern data in the manifest (convert it again to add it)",
+        simfile.display()
+    );
+    Ok(charts
+        .iter()
+        .map(|chart| {
+            let mut similar = library
+                .iter()
+                .enumerate()
+                .flat_map(|(idx, (path, others))| {
+                    others.iter().map(move |other| (idx, path, other))
+                })
+                .filter(|(idx, _, other)| {
+                    other.gamemode == chart.gamemode
+                        && !(other.name == chart.name && *idx == target_idx)
+                })
+                .map(|(_, path, other)| SimilarChart {
+                    simfile: path.clone(),
+                    name: other.name.clone(),
+                    similarity: crate::simfile::signature_similarity(
+                        &chart.signature,
+                        &other.signature,
+                    ),
+                })
+                .collect::<Vec<_>>();
+            similar.sort_by_key(|other| SortableFloat(-other.similarity));
+            similar.truncate(count);
+            (chart.name.clone(), similar)
+        })
+        .collect())
+}
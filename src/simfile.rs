@@ -1,11 +1,74 @@
 //! Create and write stepmania simfiles.
 
-use crate::prelude::*;
+use crate::{osudb::RankedStatus, prelude::*};
 
 /// Forced to be 4 by the godlike simfile format.
 const BEATS_IN_MEASURE: i32 = 4;
 
-#[derive(Debug, Clone)]
+/// Current version of the simfile interchange format.
+///
+/// Bump whenever a field is renamed, removed or changes meaning.
+/// Adding fields with sensible defaults does not require a version bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A versioned list of simfiles, as used to exchange simfiles with external tools (for example
+/// through the `Exec` node).
+///
+/// Serialized as JSON, this looks like:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "simfiles": [
+///     {
+///       "title": "Song",
+///       "music": "audio.mp3",
+///       "offset": -0.05,
+///       "bpms": [{ "beat": 0.0, "beat_len": 0.5 }],
+///       "gamemode": "DanceSingle",
+///       "difficulty": "Hard",
+///       "difficulty_num": 9.0,
+///       "notes": [{ "kind": "1", "beat": 4.5, "key": 2 }],
+///       ...
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Beats are plain numbers of beats (snapped to 1/48th of a beat when read back), times are
+/// in seconds, and `difficulty_num` is `null` for simfiles that have not been rated yet.
+/// Missing simfile fields take their default values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimfileSet {
+    pub version: u32,
+    pub simfiles: Vec<Box<Simfile>>,
+}
+impl SimfileSet {
+    pub fn new(simfiles: Vec<Box<Simfile>>) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            simfiles,
+        }
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self> {
+        let set: SimfileSet = serde_json::from_slice(json).context("invalid simfile json")?;
+        ensure!(
+            set.version <= SCHEMA_VERSION,
+            "unsupported simfile schema version {} (max supported is {})",
+            set.version,
+            SCHEMA_VERSION
+        );
+        Ok(set)
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).context("failed to serialize simfiles")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Simfile {
     pub title: String,
     pub subtitle: String,
@@ -15,29 +78,215 @@ pub struct Simfile {
     pub artist_trans: String,
     pub genre: String,
     pub credit: String,
+    /// All paths are relative to the beatmapset folder.
     pub banner: Option<PathBuf>,
     pub background: Option<PathBuf>,
+    /// The background video, played through `#BGCHANGES`.
+    pub video: Option<Video>,
+    /// A short video clip to play in the song wheel, only written for forks that support it (see
+    /// `Compat`).
+    pub preview_video: Option<PathBuf>,
     pub lyrics: Option<PathBuf>,
     pub cdtitle: Option<PathBuf>,
     pub music: Option<PathBuf>,
+    /// In seconds.
     pub offset: f64,
     pub bpms: Vec<ControlPoint>,
+    /// `(beat, duration in seconds)` pairs, sorted by beat.
+    /// Notes on the beat of a stop play before the stop.
     pub stops: Vec<(f64, f64)>,
+    /// `(beat, duration in seconds)` pairs, sorted by beat.
+    /// Unlike stops, notes on the beat of a delay play after the delay.
+    /// Only `.ssc` files support delays, so `.sm` files get a stop right before the beat instead.
+    pub delays: Vec<(f64, f64)>,
+    /// `(beat, length in beats)` pairs, sorted by beat.
+    /// The beats within a warp are skipped instantly, so their notes play right when the warp
+    /// starts (and StepMania does not judge them).
+    /// Only `.ssc` files support warps, so `.sm` files get a negative stop instead.
+    pub warps: Vec<(f64, f64)>,
+    /// In seconds.
     pub sample_start: Option<f64>,
+    /// In seconds.
     pub sample_len: Option<f64>,
+    /// The length of the music file in seconds, if it was measured.
+    pub music_len: Option<f64>,
+    /// A fingerprint of the contents of the music file, so that charts of the same song can be
+    /// told apart even if their music files have different names (see `audio::fingerprint`).
+    pub music_hash: Option<u64>,
     pub display_bpm: DisplayBpm,
     pub gamemode: Gamemode,
     pub desc: String,
+    /// The name of the source osu! difficulty.
+    /// Not written to the `.sm` file, but kept untouched so that nodes can always refer to it.
+    pub version: String,
+    /// The file this simfile was converted from (eg. the `.osu` file), relative to the
+    /// beatmapset folder.
+    /// Not written to the `.sm` file.
+    pub source_file: Option<PathBuf>,
+    /// The `(start, end)` beats of the osu! spinners (and banana showers) in the beatmap that
+    /// the simfile was converted from, sorted by start beat.
+    /// Not written to the `.sm` file.
+    pub spinners: Vec<(BeatPos, BeatPos)>,
+    /// The title in its original writing system (ie. the osu! unicode title), even if `title` is
+    /// romanized.
+    /// Not written to the `.sm` file, and `title` is used instead if empty.
+    pub title_native: String,
+    /// The artist in its original writing system, like `title_native`.
+    pub artist_native: String,
     pub difficulty: Difficulty,
+    /// `NaN` if the simfile has not been rated yet.
+    #[serde(with = "nan_as_null")]
     pub difficulty_num: f64,
     pub radar: [f64; 5],
+    /// An estimate from 0 to 100 of how well synced the chart is to its music, as measured while
+    /// converting it from osu!.
+    /// `NaN` if unknown.
+    #[serde(with = "nan_as_null")]
+    pub sync_confidence: f64,
+    /// The average distance in milliseconds between the notes of the chart and the osu! objects
+    /// they were converted from, caused by rounding timing points onto the beat grid.
+    /// `NaN` if unknown.
+    #[serde(with = "nan_as_null")]
+    pub sync_error: f64,
+    /// The nomod star rating of the source osu! beatmap, as computed by osu!.
+    /// Only known for beatmaps loaded through `osu!.db`, `NaN` otherwise.
+    #[serde(with = "nan_as_null")]
+    pub star_rating: f64,
+    /// The ranked status of the source osu! beatmap.
+    /// Only known for beatmaps loaded through `osu!.db`.
+    pub ranked_status: Option<RankedStatus>,
+    /// Sorted by beat.
     pub notes: Vec<Note>,
+    /// Additional `#NAME:value;` header fields, for fields that only some StepMania forks or themes
+    /// understand (eg. `("MUSICBPM", "180")`).
+    /// Only the fields of the first simfile in a `.sm` file are written, and only if enabled in
+    /// the `SaveOpts`.
+    pub extra_fields: Vec<(String, String)>,
+    /// Dependencies generated by nodes (eg. silent audio or resized banners), as
+    /// `(dependency path, generated file)` pairs.
+    /// Generated files live outside the beatmapset folder (see `crate::generated_dir`), and
+    /// writers copy them into the output folder under their dependency path.
+    /// Not written to the `.sm` file.
+    pub generated: Vec<(PathBuf, PathBuf)>,
 }
+impl Default for Simfile {
+    fn default() -> Self {
+        Simfile {
+            title: default(),
+            subtitle: default(),
+            artist: default(),
+            title_trans: default(),
+            subtitle_trans: default(),
+            artist_trans: default(),
+            genre: default(),
+            credit: default(),
+            banner: None,
+            background: None,
+            video: None,
+            preview_video: None,
+            lyrics: None,
+            cdtitle: None,
+            music: None,
+            offset: 0.,
+            bpms: vec![],
+            stops: vec![],
+            delays: vec![],
+            warps: vec![],
+            sample_start: None,
+            sample_len: None,
+            music_len: None,
+            music_hash: None,
+            display_bpm: DisplayBpm::Random,
+            gamemode: Gamemode::DanceSingle,
+            desc: default(),
+            version: default(),
+            source_file: None,
+            spinners: vec![],
+            title_native: default(),
+            artist_native: default(),
+            difficulty: Difficulty::Edit,
+            difficulty_num: f64::NAN,
+            radar: [0.; 5],
+            sync_confidence: f64::NAN,
+            sync_error: f64::NAN,
+            star_rating: f64::NAN,
+            ranked_status: None,
+            notes: vec![],
+            extra_fields: vec![],
+            generated: vec![],
+        }
+    }
+}
+
+/// Options to customize how simfiles are written.
+#[derive(Debug, Clone, Default)]
+pub struct SaveOpts {
+    /// Comment lines to write in the simfile header.
+    pub comments: Vec<String>,
+    /// Whether to write the extra header fields of the simfiles.
+    pub extra_fields: bool,
+    /// The file format to write.
+    pub format: SimfileFormat,
+    /// Whether to flush simfiles to disk before moving them into place.
+    pub fsync: bool,
+    /// The StepMania version or fork to write simfiles for.
+    pub compat: Compat,
+    /// Whether to log the layout of every measure as it is written.
+    pub trace_measures: bool,
+}
+
+/// A StepMania version or fork, which may understand more simfile fields than others.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compat {
+    /// Only write fields that every StepMania version understands.
+    #[default]
+    StepMania,
+    /// Also write the fields that Project OutFox understands, such as `#PREVIEWVID` (see
+    /// `Simfile::preview_video`).
+    /// `#SONGTYPE` is left out, since it sorts songs into categories rather than describing their
+    /// previews, and converted songs have no category to put there.
+    OutFox,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SimfileFormat {
+    /// The classic `.sm` format, where all charts share the timing data of the first chart.
+    #[default]
+    Sm,
+    /// The newer `.ssc` format, where every chart has its own timing data and name.
+    /// Understood by StepMania 5 and its forks, and by Etterna.
+    Ssc,
+}
+impl SimfileFormat {
+    /// The file extension of this format, without a leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SimfileFormat::Sm => "sm",
+            SimfileFormat::Ssc => "ssc",
+        }
+    }
+}
+
 impl Simfile {
-    pub fn save<'a>(path: &Path, simfiles: impl IntoIterator<Item = &'a Simfile>) -> Result<()> {
+    pub fn save<'a>(
+        path: &Path,
+        simfiles: impl IntoIterator<Item = &'a Simfile>,
+        opts: &SaveOpts,
+    ) -> Result<()> {
+        crate::write_atomic(path, opts.fsync, |file| {
+            Self::write_to(file, simfiles, opts)
+        })
+    }
+
+    /// Write the given simfiles in `.sm` or `.ssc` format, with the first simfile providing the
+    /// song metadata (and the timing data of all charts, in the case of `.sm`).
+    pub fn write_to<'a>(
+        file: &mut impl Write,
+        simfiles: impl IntoIterator<Item = &'a Simfile>,
+        opts: &SaveOpts,
+    ) -> Result<()> {
         let mut simfiles = simfiles.into_iter();
         let main_sm = simfiles.next().ok_or(anyhow!("zero simfiles supplied"))?;
-        let mut file = BufWriter::new(File::create(path).context("create file")?);
         fn as_utf8<'a>(path: &'a Option<PathBuf>, name: &str) -> Result<&'a str> {
             path.as_deref()
                 .unwrap_or_else(|| "".as_ref())
@@ -47,8 +296,7 @@ impl Simfile {
         write!(
             file,
             r#"
-// Simfile converted from osu! automatically using `osu2sm` by negamartin
-#TITLE:{title};
+{comments}{version}#TITLE:{title};
 #SUBTITLE:{subtitle};
 #ARTIST:{artist};
 #TITLETRANSLIT:{title_t};
@@ -67,11 +315,22 @@ impl Simfile {
 #DISPLAYBPM:{display_bpm};
 #SELECTABLE:YES;
 #BPMS:{bpms};
-#STOPS:;
-#BGCHANGES:;
+#STOPS:{stops};
+{ssc_timing}#BGCHANGES:{bgchanges};
 #KEYSOUNDS:;
 #ATTACKS:;
 "#,
+            comments = {
+                let mut comments = String::new();
+                for line in opts.comments.iter() {
+                    writeln!(comments, "// {}", line).unwrap();
+                }
+                comments
+            },
+            version = match opts.format {
+                SimfileFormat::Sm => "",
+                SimfileFormat::Ssc => "#VERSION:0.83;\n",
+            },
             title = main_sm.title,
             subtitle = main_sm.subtitle,
             artist = main_sm.artist,
@@ -82,6 +341,7 @@ impl Simfile {
             credit = main_sm.credit,
             banner = as_utf8(&main_sm.banner, "BANNER")?,
             bg = as_utf8(&main_sm.background, "BACKGROUND")?,
+            bgchanges = main_sm.bgchanges_tag(false)?,
             lyrics = as_utf8(&main_sm.lyrics, "LYRICSPATH")?,
             cdtitle = as_utf8(&main_sm.cdtitle, "CDTITLE")?,
             music = as_utf8(&main_sm.music, "MUSIC")?,
@@ -95,57 +355,423 @@ impl Simfile {
                 .map(|l| format!("{}", l))
                 .unwrap_or_else(String::new),
             display_bpm = main_sm.display_bpm.to_string(),
-            bpms = {
-                let mut bpms = String::new();
-                let mut first = true;
-                for point in main_sm.bpms.iter() {
-                    if first {
-                        first = false;
-                    } else {
-                        bpms.push(',');
-                    }
-                    write!(bpms, "{}={}", point.beat.as_num(), point.bpm()).unwrap();
-                }
-                bpms
+            bpms = main_sm.bpms_tag(),
+            stops = main_sm.stops_tag(opts.format),
+            ssc_timing = match opts.format {
+                SimfileFormat::Sm => String::new(),
+                SimfileFormat::Ssc => format!(
+                    "#DELAYS:{};\n#WARPS:{};\n",
+                    main_sm.delays_tag(),
+                    pairs_tag(&main_sm.warps)
+                ),
             },
         )?;
-        for sm in iter::once(main_sm).chain(simfiles) {
-            write!(
+        if opts.compat == Compat::OutFox && main_sm.preview_video.is_some() {
+            writeln!(
                 file,
-                r#"
+                "#PREVIEWVID:{};",
+                as_utf8(&main_sm.preview_video, "PREVIEWVID")?
+            )?;
+        }
+        if opts.extra_fields {
+            for (name, value) in main_sm.extra_fields.iter() {
+                ensure!(
+                    !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                    "invalid extra header field name \"{}\"",
+                    name
+                );
+                ensure!(
+                    !value.contains(';'),
+                    "extra header field {} contains a semicolon (\"{}\")",
+                    name,
+                    value
+                );
+                writeln!(file, "#{}:{};", name.to_ascii_uppercase(), value)?;
+            }
+        }
+        let mut measure_buf = MeasureBuf {
+            trace: opts.trace_measures,
+            ..default()
+        };
+        for sm in iter::once(main_sm).chain(simfiles) {
+            if !sm.sync_confidence.is_nan() {
+                write!(file, "\n// sync confidence: {:.0}/100", sm.sync_confidence)?;
+            }
+            match opts.format {
+                SimfileFormat::Sm => {
+                    write!(
+                        file,
+                        r#"
 #NOTES:
     {gamemode}:
     {desc}:
     {diff_name}:
     {diff_num}:
     {radar0}, {radar1}, {radar2}, {radar3}, {radar4}:"#,
-                gamemode = sm.gamemode.id(),
-                desc = sm.desc,
-                diff_name = sm.difficulty.name(),
-                diff_num = sm.difficulty_num.round(),
-                radar0 = sm.radar[0],
-                radar1 = sm.radar[1],
-                radar2 = sm.radar[2],
-                radar3 = sm.radar[3],
-                radar4 = sm.radar[4],
-            )?;
-            write_notedata(&mut file, &sm)?;
+                        gamemode = sm.gamemode.id(),
+                        desc = sm.desc,
+                        diff_name = sm.difficulty.name(),
+                        diff_num = sm.difficulty_num.round(),
+                        radar0 = sm.radar[0],
+                        radar1 = sm.radar[1],
+                        radar2 = sm.radar[2],
+                        radar3 = sm.radar[3],
+                        radar4 = sm.radar[4],
+                    )?;
+                }
+                SimfileFormat::Ssc => {
+                    write!(
+                        file,
+                        r#"
+#NOTEDATA:;
+#CHARTNAME:{desc};
+#STEPSTYPE:{gamemode};
+#DESCRIPTION:{desc};
+#DIFFICULTY:{diff_name};
+#METER:{diff_num};
+#RADARVALUES:{radar0},{radar1},{radar2},{radar3},{radar4};
+#OFFSET:{offset};
+#BPMS:{bpms};
+#STOPS:{stops};
+#DELAYS:{delays};
+#WARPS:{warps};
+#DISPLAYBPM:{display_bpm};
+{bgchanges}#NOTES:"#,
+                        desc = sm.desc,
+                        gamemode = sm.gamemode.id(),
+                        diff_name = sm.difficulty.name(),
+                        diff_num = sm.difficulty_num.round(),
+                        radar0 = sm.radar[0],
+                        radar1 = sm.radar[1],
+                        radar2 = sm.radar[2],
+                        radar3 = sm.radar[3],
+                        radar4 = sm.radar[4],
+                        offset = sm.offset,
+                        bpms = sm.bpms_tag(),
+                        stops = sm.stops_tag(opts.format),
+                        delays = sm.delays_tag(),
+                        warps = pairs_tag(&sm.warps),
+                        display_bpm = sm.display_bpm.to_string(),
+                        bgchanges = if sm.background == main_sm.background {
+                            String::new()
+                        } else {
+                            format!("#BGCHANGES:{};\n", sm.bgchanges_tag(true)?)
+                        },
+                    )?;
+                }
+            }
+            if measure_buf.trace {
+                info!(
+                    "  measures of \"{}\" ({} {}):",
+                    sm.title,
+                    sm.gamemode.id(),
+                    sm.desc
+                );
+            }
+            write_notedata(file, &mut measure_buf, &sm)?;
             write!(file, ";")?;
         }
         Ok(())
     }
 
+    /// Format the BPM changes as the value of a `#BPMS` tag.
+    fn bpms_tag(&self) -> String {
+        let mut bpms = String::new();
+        for (i, point) in self.bpms.iter().enumerate() {
+            if i > 0 {
+                bpms.push(',');
+            }
+            write!(bpms, "{}={}", point.beat.as_num(), point.bpm()).unwrap();
+        }
+        bpms
+    }
+
+    /// Format the stops as the value of a `#STOPS` tag.
+    ///
+    /// `.sm` files have no delays, so they become stops on the smallest beat unit before them.
+    /// They have no warps either, so warps become negative stops that take back the time of the
+    /// warped beats.
+    fn stops_tag(&self, format: SimfileFormat) -> String {
+        let mut pauses = self.stops.clone();
+        if format == SimfileFormat::Sm {
+            pauses.extend(
+                self.delays
+                    .iter()
+                    .map(|&(beat, secs)| ((BeatPos::from(beat) - BeatPos::EPSILON).as_num(), secs)),
+            );
+            pauses.extend(
+                self.warp_spans()
+                    .into_iter()
+                    .map(|(start, _, start_time, end_time)| (start, start_time - end_time)),
+            );
+            pauses.sort_by_key(|&(beat, _)| SortableFloat(beat));
+        }
+        pairs_tag(&pauses)
+    }
+
+    /// Format the background video as the value of a `#BGCHANGES` tag.
+    ///
+    /// With `with_image`, the background image is shown from the start too, for charts whose
+    /// background differs from the song background.
+    fn bgchanges_tag(&self, with_image: bool) -> Result<String> {
+        let mut changes = Vec::new();
+        if with_image {
+            if let Some(bg) = &self.background {
+                let path = bg.to_str().ok_or_else(|| anyhow!("non-utf8 BGCHANGES"))?;
+                changes.push(format!("0.000={}=1.000=0=0=1", path));
+            }
+        }
+        if let Some(video) = &self.video {
+            let path = video
+                .path
+                .to_str()
+                .ok_or_else(|| anyhow!("non-utf8 BGCHANGES"))?;
+            //Play the video once from its start, with no crossfade
+            changes.push(format!(
+                "{:.3}={}={:.3}=0=1=0",
+                video.beat, path, video.rate
+            ));
+        }
+        Ok(changes.join(","))
+    }
+
+    /// Format the delays as the value of a `#DELAYS` tag.
+    fn delays_tag(&self) -> String {
+        pairs_tag(&self.delays)
+    }
+
+    /// Read the charts of a `.sm` or `.ssc` file.
+    pub fn load(path: &Path) -> Result<Vec<Simfile>> {
+        let raw = fs::read(path).context("failed to read simfile")?;
+        Self::parse(&String::from_utf8_lossy(&raw))
+    }
+
+    /// Parse the charts of a `.sm` or `.ssc` file, which share the song metadata.
+    ///
+    /// Charts with a gamemode that is not supported are skipped, and notes that have no
+    /// equivalent (such as fakes) are dropped, while mines are kept.
+    /// Rolls are kept as rolls, and lifts are read as hits.
+    pub fn parse(txt: &str) -> Result<Vec<Simfile>> {
+        let mut song = Simfile::default();
+        let mut display_bpm = None;
+        let mut simfiles = Vec::new();
+        //In `.ssc` files, the fields of each chart follow its `#NOTEDATA` tag
+        let mut chart: Option<(Simfile, Option<DisplayBpm>)> = None;
+        //Whether the current `.ssc` chart has an unsupported gamemode
+        let mut skip_chart = false;
+        fn path(value: &str) -> Option<PathBuf> {
+            if value.is_empty() {
+                None
+            } else {
+                Some(value.into())
+            }
+        }
+        fn num(name: &str, value: &str) -> Result<f64> {
+            value
+                .parse()
+                .with_context(|| anyhow!("invalid #{} value \"{}\"", name, value))
+        }
+        let mut finish_chart = |sm: Simfile, display_bpm: Option<DisplayBpm>| {
+            simfiles.push(finish_parsed(sm, display_bpm));
+        };
+        for (name, value) in parse_tags(txt) {
+            let value = value.trim();
+            if skip_chart && name != "NOTEDATA" {
+                continue;
+            }
+            //Fields that can appear both in the song header and in `.ssc` charts
+            let (sm, dbpm) = match &mut chart {
+                Some((sm, dbpm)) => (sm, dbpm),
+                None => (&mut song, &mut display_bpm),
+            };
+            match &name[..] {
+                "OFFSET" => sm.offset = num(&name, value)?,
+                "BPMS" => {
+                    let (bpms, warps) = parse_bpms(parse_pairs(&name, value)?);
+                    ensure!(!bpms.is_empty(), "#BPMS has no positive BPM");
+                    sm.bpms = bpms;
+                    //Only `.sm` files use negative BPMs, `.ssc` files have `#WARPS` instead
+                    if !warps.is_empty() {
+                        sm.warps = warps;
+                    }
+                }
+                "STOPS" | "FREEZES" => sm.stops = parse_pairs(&name, value)?,
+                "DELAYS" => sm.delays = parse_pairs(&name, value)?,
+                "WARPS" => sm.warps = parse_pairs(&name, value)?,
+                "DISPLAYBPM" => *dbpm = parse_display_bpm(value),
+                _ => {}
+            }
+            match (&name[..], &mut chart) {
+                ("NOTEDATA", _) => {
+                    if let Some((sm, dbpm)) = chart.take() {
+                        finish_chart(sm, dbpm);
+                    }
+                    chart = Some((song.clone(), display_bpm));
+                    skip_chart = false;
+                }
+                ("STEPSTYPE", Some((sm, _))) => {
+                    sm.gamemode = match Gamemode::from_id(value) {
+                        Some(gamemode) => gamemode,
+                        None => {
+                            //Skip the rest of the chart
+                            chart = None;
+                            skip_chart = true;
+                            continue;
+                        }
+                    }
+                }
+                ("DESCRIPTION", Some((sm, _))) => sm.desc = value.to_string(),
+                ("CHARTNAME", Some((sm, _))) => sm.version = value.to_string(),
+                ("DIFFICULTY", Some((sm, _))) => sm.difficulty = Difficulty::from_name(value),
+                ("METER", Some((sm, _))) => sm.difficulty_num = num(&name, value)?,
+                ("RADARVALUES", Some((sm, _))) => sm.radar = parse_radar(value),
+                ("NOTES", Some((sm, dbpm))) => {
+                    sm.notes = parse_notedata(value, sm.gamemode.key_count())?;
+                    finish_chart(sm.clone(), *dbpm);
+                    chart = None;
+                }
+                ("NOTES", None) => {
+                    //A whole `.sm` chart in a single tag
+                    let fields = value.splitn(6, ':').map(str::trim).collect::<Vec<_>>();
+                    ensure!(
+                        fields.len() == 6,
+                        "malformed #NOTES tag (expected 6 fields)"
+                    );
+                    let gamemode = match Gamemode::from_id(fields[0]) {
+                        Some(gamemode) => gamemode,
+                        None => continue,
+                    };
+                    let sm = Simfile {
+                        gamemode,
+                        desc: fields[1].to_string(),
+                        version: fields[1].to_string(),
+                        difficulty: Difficulty::from_name(fields[2]),
+                        difficulty_num: num(&name, fields[3])?,
+                        radar: parse_radar(fields[4]),
+                        notes: parse_notedata(fields[5], gamemode.key_count())?,
+                        ..song.clone()
+                    };
+                    finish_chart(sm, display_bpm);
+                }
+                (_, Some(_)) => {}
+                ("TITLE", None) => song.title = value.to_string(),
+                ("SUBTITLE", None) => song.subtitle = value.to_string(),
+                ("ARTIST", None) => song.artist = value.to_string(),
+                ("TITLETRANSLIT", None) => song.title_trans = value.to_string(),
+                ("SUBTITLETRANSLIT", None) => song.subtitle_trans = value.to_string(),
+                ("ARTISTTRANSLIT", None) => song.artist_trans = value.to_string(),
+                ("GENRE", None) => song.genre = value.to_string(),
+                ("CREDIT", None) => song.credit = value.to_string(),
+                ("BANNER", None) => song.banner = path(value),
+                ("BACKGROUND", None) => song.background = path(value),
+                ("BGCHANGES", None) => song.video = parse_video(value),
+                ("PREVIEWVID", None) => song.preview_video = path(value),
+                ("LYRICSPATH", None) => song.lyrics = path(value),
+                ("CDTITLE", None) => song.cdtitle = path(value),
+                ("MUSIC", None) => song.music = path(value),
+                ("SAMPLESTART", None) if !value.is_empty() => {
+                    song.sample_start = Some(num(&name, value)?)
+                }
+                ("SAMPLELENGTH", None) if !value.is_empty() => {
+                    song.sample_len = Some(num(&name, value)?)
+                }
+                ("OFFSET", None)
+                | ("BPMS", None)
+                | ("STOPS", None)
+                | ("FREEZES", None)
+                | ("DELAYS", None)
+                | ("WARPS", None)
+                | ("DISPLAYBPM", None)
+                | ("SAMPLESTART", None)
+                | ("SAMPLELENGTH", None)
+                | ("VERSION", None)
+                | ("SELECTABLE", None)
+                | ("KEYSOUNDS", None)
+                | ("ATTACKS", None) => {}
+                (_, None) => {
+                    //Keep any other header fields around, in case they are written back
+                    song.extra_fields.push((name.clone(), value.to_string()));
+                }
+            }
+        }
+        if let Some((sm, dbpm)) = chart {
+            //A chart without notes
+            finish_chart(sm, dbpm);
+        }
+        Ok(simfiles)
+    }
+
     /// Get the files that this simfile references.
     pub fn file_deps(&self) -> impl Iterator<Item = &Path> {
         self.banner
             .as_deref()
             .into_iter()
             .chain(self.background.as_deref().into_iter())
+            .chain(self.video.as_ref().map(|video| video.path.as_path()))
+            .chain(self.preview_video.as_deref().into_iter())
             .chain(self.lyrics.as_deref().into_iter())
             .chain(self.cdtitle.as_deref().into_iter())
             .chain(self.music.as_deref().into_iter())
     }
 
+    /// Get the file to read the given dependency from, which is either a generated file (see
+    /// `generated`) or a file within the beatmapset folder.
+    pub fn dep_source(&self, set_path: &Path, dep: &Path) -> PathBuf {
+        self.generated_file(dep)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| set_path.join(dep))
+    }
+
+    /// Get the generated file that the given dependency path refers to, if any.
+    pub fn generated_file(&self, dep: &Path) -> Option<&Path> {
+        self.generated
+            .iter()
+            .find(|(name, _)| name == dep)
+            .map(|(_, file)| file.as_path())
+    }
+
+    /// Record that the given dependency path refers to a generated file, replacing any previous
+    /// file generated for the same path.
+    pub fn set_generated(&mut self, dep: PathBuf, file: PathBuf) {
+        self.generated.retain(|(name, _)| *name != dep);
+        self.generated.push((dep, file));
+    }
+
+    /// Roughly how many bytes of memory this simfile takes up, including its heap allocations.
+    pub fn estimated_size(&self) -> usize {
+        let strings = [
+            &self.title,
+            &self.subtitle,
+            &self.artist,
+            &self.title_trans,
+            &self.subtitle_trans,
+            &self.artist_trans,
+            &self.genre,
+            &self.credit,
+            &self.desc,
+            &self.version,
+        ];
+        mem::size_of::<Simfile>()
+            + strings.iter().map(|s| s.capacity()).sum::<usize>()
+            + self
+                .file_deps()
+                .map(|path| path.as_os_str().len())
+                .sum::<usize>()
+            + self.bpms.capacity() * mem::size_of::<ControlPoint>()
+            + self.stops.capacity() * mem::size_of::<(f64, f64)>()
+            + self.delays.capacity() * mem::size_of::<(f64, f64)>()
+            + self.warps.capacity() * mem::size_of::<(f64, f64)>()
+            + self.notes.capacity() * mem::size_of::<Note>()
+            + self
+                .extra_fields
+                .iter()
+                .map(|(k, v)| mem::size_of::<(String, String)>() + k.capacity() + v.capacity())
+                .sum::<usize>()
+    }
+
     /// Iterate over the populated beats in a simfile.
     pub fn iter_beats(&self) -> BeatIter {
         BeatIter::new(&self.notes)
@@ -156,6 +782,240 @@ impl Simfile {
         ToTime::new(self)
     }
 
+    /// Get the `(start beat, end beat, start time, end time)` of every warp, where times do not
+    /// account for stops and delays.
+    pub fn warp_spans(&self) -> Vec<(f64, f64, f64, f64)> {
+        let mut to_time = ToTime::from_raw(&self.bpms, self.offset);
+        self.warps
+            .iter()
+            .map(|&(beat, len)| {
+                let end = beat + len.max(0.);
+                (
+                    beat,
+                    end,
+                    to_time.beat_to_time(BeatPos::from(beat)),
+                    to_time.beat_to_time(BeatPos::from(end)),
+                )
+            })
+            .collect()
+    }
+
+    /// Get the beat (rounded down) that plays at the given time in seconds.
+    ///
+    /// Unlike `beat_to_time`, this is a one-off lookup, with no monotonicity requirements.
+    pub fn time_to_beat(&self, mut time: f64) -> BeatPos {
+        //Take out the time spent in stops and delays
+        //Both pause the beat at the same point in time, they only differ in the side of the
+        //pause that notes on their beat play at
+        //Warps work like negative pauses, putting back the time of the beats they skip
+        let mut pauses = self
+            .stops
+            .iter()
+            .chain(self.delays.iter())
+            .copied()
+            .chain(
+                self.warp_spans()
+                    .into_iter()
+                    .map(|(start, _, start_time, end_time)| (start, start_time - end_time)),
+            )
+            .collect::<Vec<_>>();
+        pauses.sort_by_key(|&(beat, _)| SortableFloat(beat));
+        let mut to_time = ToTime::from_raw(&self.bpms, self.offset);
+        let mut stopped = 0.;
+        for &(beat, dur) in pauses.iter() {
+            let stop_time = to_time.beat_to_time(BeatPos::from(beat)) + stopped;
+            if time < stop_time {
+                break;
+            } else if time < stop_time + dur {
+                return BeatPos::from(beat);
+            }
+            stopped += dur;
+        }
+        time -= stopped;
+        let mut cur_time = -self.offset;
+        let mut cur_idx = 0;
+        while cur_idx + 1 < self.bpms.len() {
+            let cur_bpm = &self.bpms[cur_idx];
+            let next_bpm = &self.bpms[cur_idx + 1];
+            let next_time = cur_time + (next_bpm.beat - cur_bpm.beat).as_num() * cur_bpm.beat_len;
+            if time < next_time {
+                break;
+            }
+            cur_time = next_time;
+            cur_idx += 1;
+        }
+        match self.bpms.get(cur_idx) {
+            Some(cur_bpm) => BeatPos::from_num_floor(
+                cur_bpm.beat.as_num() + (time - cur_time) / cur_bpm.beat_len,
+            ),
+            None => BeatPos::from(0.),
+        }
+    }
+
+    /// Get the notes starting within the given beat range (inclusive), along with any long note
+    /// tails that belong to them.
+    pub fn slice_beats(&self, start: BeatPos, end: BeatPos) -> Vec<Note> {
+        let mut held: HashSet<i32> = default();
+        let mut notes = Vec::new();
+        for note in self.notes.iter() {
+            if note.is_tail() {
+                if held.remove(&note.key) {
+                    notes.push(note.clone());
+                }
+            } else if note.beat >= start && note.beat <= end {
+                if note.is_head() {
+                    held.insert(note.key);
+                }
+                notes.push(note.clone());
+            }
+        }
+        notes
+    }
+
+    /// Like `slice_beats`, but taking a time range in seconds.
+    pub fn slice_time(&self, start: f64, end: f64) -> Vec<Note> {
+        self.slice_beats(self.time_to_beat(start), self.time_to_beat(end))
+    }
+
+    /// Cut the chart down to the notes within the given beat range (inclusive, like
+    /// `slice_beats`), moving the measure that contains `start` to the start of the chart.
+    ///
+    /// The offset and timing data are adjusted so that the remaining notes still play at the same
+    /// time into the music.
+    pub fn crop(&mut self, start: BeatPos, end: BeatPos) {
+        //Keep whole measures, so that notes stay on the same grid
+        let base = start.floor(BeatPos::from(BEATS_IN_MEASURE as f64));
+        let mut to_time = self.beat_to_time();
+        let base_time = to_time.beat_to_time(base);
+        let start_time = to_time.beat_to_time(start);
+        let end_time = to_time.beat_to_time(end);
+        drop(to_time);
+        let shift = |beat: f64| (BeatPos::from(beat) - base).as_num();
+        //Keep the BPM that is in effect at the base
+        let first_bpm = self
+            .bpms
+            .iter()
+            .take_while(|cp| cp.beat <= base)
+            .last()
+            .or_else(|| self.bpms.first())
+            .map(|cp| ControlPoint {
+                beat: BeatPos::from(0.),
+                beat_len: cp.beat_len,
+            });
+        self.bpms = first_bpm
+            .into_iter()
+            .chain(
+                self.bpms
+                    .iter()
+                    .filter(|cp| cp.beat > base && cp.beat <= end)
+                    .map(|cp| ControlPoint {
+                        beat: cp.beat - base,
+                        beat_len: cp.beat_len,
+                    }),
+            )
+            .collect();
+        //Stops on the base beat are still ahead, but delays on it are already over
+        self.stops
+            .retain(|&(beat, _)| BeatPos::from(beat) >= base && BeatPos::from(beat) <= end);
+        self.delays
+            .retain(|&(beat, _)| BeatPos::from(beat) > base && BeatPos::from(beat) <= end);
+        for (beat, _) in self.stops.iter_mut().chain(self.delays.iter_mut()) {
+            *beat = shift(*beat);
+        }
+        //Warps that contain the base beat are cut short
+        self.warps = self
+            .warps
+            .iter()
+            .filter(|&&(beat, len)| BeatPos::from(beat + len) > base && BeatPos::from(beat) <= end)
+            .map(|&(beat, len)| {
+                let from = BeatPos::from(beat).max(base);
+                ((from - base).as_num(), beat + len - from.as_num())
+            })
+            .collect();
+        self.spinners = self
+            .spinners
+            .iter()
+            .filter(|&&(from, to)| to >= start && from <= end)
+            .map(|&(from, to)| (from.max(start) - base, to.min(end) - base))
+            .collect();
+        self.notes = self.slice_beats(start, end);
+        for note in self.notes.iter_mut() {
+            note.beat -= base;
+        }
+        if let Some(video) = &mut self.video {
+            video.beat = shift(video.beat);
+        }
+        self.offset = -base_time;
+        if !matches!(self.sample_start, Some(t) if t >= start_time && t <= end_time) {
+            self.sample_start = Some(start_time);
+        }
+    }
+
+    /// Get a sorted list of hashes, one for each distinct populated row of non-tail notes.
+    ///
+    /// Used to compare the note content of simfiles (see `row_similarity`).
+    pub fn row_hashes(&self) -> Vec<u64> {
+        let mut hashes = self
+            .iter_beats()
+            .filter_map(|beat| {
+                let mut mask = 0u128;
+                for note in self.notes[beat.start_idx..beat.end_idx].iter() {
+                    if !note.is_tail() {
+                        mask |= 1 << (note.key as u32 & 127);
+                    }
+                }
+                if mask == 0 {
+                    None
+                } else {
+                    Some(fxhash::hash64(&(beat.pos.frac, mask)))
+                }
+            })
+            .collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes.dedup();
+        hashes
+    }
+
+    /// Find the longest stream in the simfile: the longest run of evenly spaced rows.
+    ///
+    /// Gaps longer than `Stream::MAX_GAP` do not count as a stream.
+    pub fn longest_stream(&self) -> Stream {
+        let mut to_time = self.beat_to_time();
+        let times = self
+            .iter_beats()
+            .filter(|beat| beat.count_heads(&self.notes) > 0)
+            .map(|beat| to_time.beat_to_time(beat.pos))
+            .collect::<Vec<_>>();
+        let mut best = Stream::default();
+        let mut start = 0;
+        let mut gap: Option<f64> = None;
+        for i in 1..times.len() {
+            let cur = times[i] - times[i - 1];
+            if cur <= 0. || cur > Stream::MAX_GAP {
+                start = i;
+                gap = None;
+                continue;
+            }
+            //Compare against the first gap of the run, so that the tempo cannot drift
+            let run_gap = match gap {
+                Some(gap) if (cur - gap).abs() <= gap * Stream::GAP_TOLERANCE => gap,
+                _ => {
+                    start = i - 1;
+                    gap = Some(cur);
+                    cur
+                }
+            };
+            let rows = i - start + 1;
+            if rows > best.rows {
+                best = Stream {
+                    rows,
+                    bpm: 60. / (run_gap * 4.),
+                };
+            }
+        }
+        best
+    }
+
     /// Naive difficulty calculation.
     pub fn difficulty_naive(&self) -> f64 {
         fn adapt_range(src: (f64, f64), dst: (f64, f64), val: f64) -> f64 {
@@ -196,6 +1056,106 @@ impl Simfile {
         Ok(())
     }
 
+    /// Repair the common problems that `check` complains about, returning a description of
+    /// every change made.
+    ///
+    /// Notes are sorted, notes on invalid keys and duplicate notes are removed, tails without a
+    /// head are removed, heads without a tail become hits, and mines inside holds or on top of
+    /// other notes are removed.
+    /// Problems with the control points are left untouched.
+    pub fn fix(&mut self) -> Vec<String> {
+        let key_count = self.gamemode.key_count();
+        let mut changes = Vec::new();
+        let count = |changes: &mut Vec<String>, what: &str, n: usize| {
+            if n > 0 {
+                changes.push(format!("{} {}", what, n));
+            }
+        };
+        //Keep tails before anything else on the same beat, so that holds end before a new
+        //note starts
+        if self
+            .notes
+            .windows(2)
+            .any(|pair| (pair[0].beat, !pair[0].is_tail()) > (pair[1].beat, !pair[1].is_tail()))
+        {
+            self.notes.sort_by_key(|note| (note.beat, !note.is_tail()));
+            changes.push("sorted notes".to_string());
+        }
+        let before = self.notes.len();
+        self.notes.retain(|note| {
+            note.key >= 0
+                && note.key < key_count
+                && (note.is_hit() || note.is_head() || note.is_tail() || note.is_mine())
+        });
+        count(
+            &mut changes,
+            "removed invalid notes:",
+            before - self.notes.len(),
+        );
+        //Walk through the notes keeping track of open holds
+        let mut open: Vec<Option<usize>> = vec![None; key_count as usize];
+        let mut last_press = vec![None; key_count as usize];
+        let mut last_mine: Vec<Option<(BeatPos, usize)>> = vec![None; key_count as usize];
+        let mut keep = vec![true; self.notes.len()];
+        let (mut duplicates, mut orphans, mut unterminated, mut mines) = (0, 0, 0, 0);
+        for idx in 0..self.notes.len() {
+            let note = &self.notes[idx];
+            let key = note.key as usize;
+            if note.is_mine() {
+                //Mines cannot be inside holds or share a beat with another note
+                if open[key].is_some()
+                    || last_press[key] == Some(note.beat)
+                    || last_mine[key].map(|(beat, _)| beat) == Some(note.beat)
+                {
+                    keep[idx] = false;
+                    mines += 1;
+                } else {
+                    last_mine[key] = Some((note.beat, idx));
+                }
+            } else if note.is_tail() {
+                //Since tails are sorted first, zero-length holds end up as an orphan tail and
+                //an unterminated head
+                if open[key].take().is_none() {
+                    keep[idx] = false;
+                    orphans += 1;
+                }
+            } else if last_press[key] == Some(note.beat) {
+                keep[idx] = false;
+                duplicates += 1;
+            } else {
+                last_press[key] = Some(note.beat);
+                if let Some((beat, mine)) = last_mine[key] {
+                    if beat == note.beat && keep[mine] {
+                        keep[mine] = false;
+                        mines += 1;
+                    }
+                }
+                if let Some(head) = open[key].take() {
+                    self.notes[head].kind = Note::KIND_HIT;
+                    unterminated += 1;
+                }
+                if self.notes[idx].is_head() {
+                    open[key] = Some(idx);
+                }
+            }
+        }
+        for head in open.into_iter().flatten() {
+            self.notes[head].kind = Note::KIND_HIT;
+            unterminated += 1;
+        }
+        let mut keep = keep.into_iter();
+        self.notes.retain(|_| keep.next().unwrap_or(true));
+        count(&mut changes, "removed duplicate notes:", duplicates);
+        count(&mut changes, "removed tails without a head:", orphans);
+        count(&mut changes, "removed mines over other notes:", mines);
+        count(
+            &mut changes,
+            "turned holds without a tail into hits:",
+            unterminated,
+        );
+        changes
+    }
+
     /// Sanity-check a simfile.
     ///
     /// These checks prioritize correctness over speed, and as such should only be used for
@@ -248,7 +1208,7 @@ impl Simfile {
                 } else {
                     ensure!(
                         !beat_notes[key],
-                        "two hit/head notes on beat {}, key {} (beat {:?})",
+                        "two hit/head/mine notes on beat {}, key {} (beat {:?})",
                         beat,
                         key,
                         &self.notes[start_idx..end_idx]
@@ -270,7 +1230,7 @@ impl Simfile {
                 last_beat
             );
             ensure!(
-                note.is_hit() || note.is_head() || note.is_tail(),
+                note.is_hit() || note.is_head() || note.is_tail() || note.is_mine(),
                 "unknown note kind '{}'",
                 note.kind
             );
@@ -339,8 +1299,80 @@ impl Simfile {
     }
 }
 
+/// Compare two lists of row hashes (as obtained from `Simfile::row_hashes`), yielding a number
+/// from `0` (no rows in common) to `1` (the exact same rows).
+pub fn row_similarity(a: &[u64], b: &[u64]) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.;
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut common = 0;
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            cmp::Ordering::Less => i += 1,
+            cmp::Ordering::Greater => j += 1,
+            cmp::Ordering::Equal => {
+                common += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    common as f64 / (a.len() + b.len() - common) as f64
+}
+
+/// The amount of hashes in a pattern signature (see `row_signature`).
+const SIGNATURE_LEN: u64 = 32;
+
+/// Summarize a list of row hashes (as obtained from `Simfile::row_hashes`) into a short
+/// signature, so that the similarity of charts can be estimated without their notes (see
+/// `signature_similarity`).
+///
+/// This is a MinHash: the lowest hash of all rows, under a few different hash functions.
+pub fn row_signature(hashes: &[u64]) -> Vec<u64> {
+    (0..SIGNATURE_LEN)
+        .map(|seed| {
+            hashes
+                .iter()
+                .map(|&hash| fxhash::hash64(&(seed, hash)))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+/// Estimate the `row_similarity` of two charts from their signatures (as obtained from
+/// `row_signature`).
+pub fn signature_similarity(a: &[u64], b: &[u64]) -> f64 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.;
+    }
+    a.iter().zip(b).filter(|(a, b)| a == b).count() as f64 / a.len() as f64
+}
+
+/// Buffers reused across measures (and across charts) while writing note data.
+#[derive(Default)]
+struct MeasureBuf {
+    /// The note grid of the current measure, one byte per key per row.
+    grid: Vec<u8>,
+    /// Serialized text that has not been written out yet.
+    text: Vec<u8>,
+    /// Whether to log the layout of every measure.
+    trace: bool,
+}
+impl MeasureBuf {
+    /// Write out the serialized text once it grows past this many bytes.
+    const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+    fn flush_to(&mut self, file: &mut impl Write) -> Result<()> {
+        file.write_all(&self.text)?;
+        self.text.clear();
+        Ok(())
+    }
+}
+
 fn write_measure(
-    file: &mut impl Write,
+    buf: &mut MeasureBuf,
     key_count: i32,
     measure_idx: usize,
     measure_start: BeatPos,
@@ -380,9 +1412,11 @@ fn write_measure(
         2i32.pow(max_simplify_by[0]) * 3i32.pow(max_simplify_by[1])
     };
     let rows_per_beat = BeatPos::FIXED_POINT / simplify_by;
+    let row_count = (BEATS_IN_MEASURE * rows_per_beat) as usize;
+    let key_count = key_count as usize;
     //Output 4x this amount of rows (if 4 beats in measure)
-    let mut out_measure =
-        vec![b'0'; (BEATS_IN_MEASURE * rows_per_beat) as usize * key_count as usize];
+    buf.grid.clear();
+    buf.grid.resize(row_count * key_count, b'0');
     for note in notes {
         let rel_pos = note.beat - measure_start;
         let idx = (rel_pos.frac / simplify_by) as usize;
@@ -394,35 +1428,55 @@ fn write_measure(
             rel_pos.frac % simplify_by
         );
         ensure!(
-            idx < (BEATS_IN_MEASURE * rows_per_beat) as usize,
+            idx < row_count,
             "called `flush_measure` with more than one measure in buffer (rel_pos = {} out of max {})",
             rel_pos,
-            BEATS_IN_MEASURE * rows_per_beat,
+            row_count,
         );
         ensure!(
-            note.key >= 0 && note.key < key_count,
+            note.key >= 0 && (note.key as usize) < key_count,
             "note key {} outside range [0, {})",
             note.key,
             key_count
         );
-        out_measure[idx * key_count as usize + note.key as usize] = note.kind as u8;
+        buf.grid[idx * key_count + note.key as usize] = note.kind as u8;
     }
-    //Convert map into a string
+    if buf.trace {
+        info!(
+            "    measure {} at beat {}: simplify_by {}, {} rows, {} notes",
+            measure_idx,
+            measure_start,
+            simplify_by,
+            row_count,
+            notes.len()
+        );
+        for note in notes {
+            let rel_pos = note.beat - measure_start;
+            info!(
+                "      beat {} key {} '{}' -> row {}/{}",
+                note.beat,
+                note.key,
+                note.kind as u8 as char,
+                rel_pos.frac / simplify_by,
+                row_count
+            );
+        }
+    }
+    //Convert map into text
     if measure_idx > 0 {
         //Add separator from previous measure
-        write!(file, ",")?;
+        buf.text.push(b',');
     }
-    write!(file, "\n// Measure {}", measure_idx)?;
-    for row in 0..(BEATS_IN_MEASURE * rows_per_beat) as usize {
-        write!(file, "\n")?;
-        for key in 0..key_count as usize {
-            file.write_all(&[out_measure[row * key_count as usize + key]])?;
-        }
+    write!(buf.text, "\n// Measure {}", measure_idx)?;
+    buf.text.reserve(row_count * (key_count + 1));
+    for row in buf.grid.chunks_exact(key_count) {
+        buf.text.push(b'\n');
+        buf.text.extend_from_slice(row);
     }
     Ok(())
 }
 
-fn write_notedata(file: &mut impl Write, sm: &Simfile) -> Result<()> {
+fn write_notedata(file: &mut impl Write, buf: &mut MeasureBuf, sm: &Simfile) -> Result<()> {
     struct CurMeasure {
         first_note: usize,
         start_beat: BeatPos,
@@ -438,12 +1492,15 @@ fn write_notedata(file: &mut impl Write, sm: &Simfile) -> Result<()> {
         //Finish any pending measures
         while (note.beat - cur_measure.start_beat) >= BeatPos::from(BEATS_IN_MEASURE as f64) {
             write_measure(
-                file,
+                buf,
                 key_count,
                 measure_counter,
                 cur_measure.start_beat,
                 &sm.notes[cur_measure.first_note..note_idx],
             )?;
+            if buf.text.len() >= MeasureBuf::FLUSH_THRESHOLD {
+                buf.flush_to(file)?;
+            }
             measure_counter += 1;
             cur_measure.first_note = note_idx;
             cur_measure.start_beat =
@@ -452,15 +1509,235 @@ fn write_notedata(file: &mut impl Write, sm: &Simfile) -> Result<()> {
     }
     //Finish the last pending measure
     write_measure(
-        file,
+        buf,
         key_count,
         measure_counter,
         cur_measure.start_beat,
         &sm.notes[cur_measure.first_note..sm.notes.len()],
     )?;
+    buf.flush_to(file)?;
     Ok(())
 }
 
+/// Split the text of a simfile into `(NAME, value)` tags, ignoring comments.
+fn parse_tags(txt: &str) -> Vec<(String, String)> {
+    let mut uncommented = String::with_capacity(txt.len());
+    for line in txt.lines() {
+        uncommented.push_str(line.find("//").map(|c| &line[..c]).unwrap_or(line));
+        uncommented.push('\n');
+    }
+    let mut tags = Vec::new();
+    let mut rest = &uncommented[..];
+    while let Some(start) = rest.find('#') {
+        rest = &rest[start + 1..];
+        let end = rest.find(';').unwrap_or(rest.len());
+        let tag = &rest[..end];
+        rest = &rest[(end + 1).min(rest.len())..];
+        let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+        tags.push((name.trim().to_ascii_uppercase(), value.to_string()));
+    }
+    tags
+}
+
+/// Format a list of `beat=value` pairs, as used by `#STOPS`, `#DELAYS` and `#WARPS`.
+fn pairs_tag(pairs: &[(f64, f64)]) -> String {
+    let mut tag = String::new();
+    for (i, (beat, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            tag.push(',');
+        }
+        write!(tag, "{}={}", beat, value).unwrap();
+    }
+    tag
+}
+
+/// Parse a list of `beat=value` pairs, as used by `#BPMS` and `#STOPS`.
+fn parse_pairs(name: &str, value: &str) -> Result<Vec<(f64, f64)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (beat, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid #{} entry \"{}\"", name, pair))?;
+            Ok((
+                beat.trim()
+                    .parse()
+                    .with_context(|| anyhow!("invalid #{} beat \"{}\"", name, beat))?,
+                value
+                    .trim()
+                    .parse()
+                    .with_context(|| anyhow!("invalid #{} value \"{}\"", name, value))?,
+            ))
+        })
+        .collect()
+}
+
+/// Turn the `beat=bpm` pairs of a `#BPMS` tag into control points, along with the warps that
+/// negative BPMs stand for.
+///
+/// Like in StepMania, a negative BPM turns back time until the next BPM change, and the beats
+/// until time catches up again at the next BPM are warped.
+/// A negative BPM with no BPM change after it warps over the rest of the chart, which is only
+/// known once the notes are parsed, so its warp is left with an infinite length until then (see
+/// `finish_parsed`).
+fn parse_bpms(pairs: Vec<(f64, f64)>) -> (Vec<ControlPoint>, Vec<(f64, f64)>) {
+    let mut bpms = Vec::with_capacity(pairs.len());
+    let mut warps = Vec::new();
+    let mut negative = None;
+    for (beat, bpm) in pairs {
+        if bpm < 0. {
+            negative = Some((beat, bpm));
+            continue;
+        }
+        if let Some((neg_beat, neg_bpm)) = negative.take() {
+            let end = beat + bpm / -neg_bpm * (beat - neg_beat);
+            warps.push((neg_beat, end - neg_beat));
+        }
+        bpms.push(ControlPoint {
+            beat: beat.into(),
+            beat_len: 60. / bpm,
+        });
+    }
+    if let Some((neg_beat, _)) = negative {
+        warps.push((neg_beat, f64::INFINITY));
+    }
+    (bpms, warps)
+}
+
+fn parse_display_bpm(value: &str) -> Option<DisplayBpm> {
+    if value == "*" {
+        return Some(DisplayBpm::Random);
+    }
+    match value.split_once(':') {
+        Some((min, max)) => Some(DisplayBpm::Range(
+            min.trim().parse().ok()?,
+            max.trim().parse().ok()?,
+        )),
+        None => Some(DisplayBpm::Single(value.parse().ok()?)),
+    }
+}
+
+/// Parse the first 5 radar values, ignoring the extra values that newer StepMania versions
+/// write.
+fn parse_radar(value: &str) -> [f64; 5] {
+    let mut radar = [0.; 5];
+    for (out, num) in radar.iter_mut().zip(value.split(',')) {
+        *out = num.trim().parse().unwrap_or(0.);
+    }
+    radar
+}
+
+/// Find the first background video in a `#BGCHANGES` tag.
+/// Images, animation folders and special backgrounds (eg. `-nosongbg-`) are ignored.
+fn parse_video(value: &str) -> Option<Video> {
+    const VIDEO_EXTS: &[&str] = &[
+        "avi", "mp4", "m4v", "mpg", "mpeg", "wmv", "flv", "mkv", "webm", "mov", "ogv",
+    ];
+    value.split(',').find_map(|change| {
+        let mut fields = change.split('=').map(str::trim);
+        let beat = fields.next()?.parse().ok()?;
+        let path = PathBuf::from(fields.next()?);
+        let rate = fields
+            .next()
+            .and_then(|rate| rate.parse().ok())
+            .unwrap_or(1.);
+        let is_video = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .map(|ext| {
+                VIDEO_EXTS
+                    .iter()
+                    .any(|video| ext.eq_ignore_ascii_case(video))
+            })
+            .unwrap_or(false);
+        if is_video {
+            Some(Video { path, beat, rate })
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse the note data of a chart, one measure per comma-separated block.
+///
+/// Only the notes of the first player are read from routine charts.
+fn parse_notedata(data: &str, key_count: i32) -> Result<Vec<Note>> {
+    let key_count = key_count as usize;
+    let data = data.split('&').next().unwrap_or_default();
+    let mut notes = Vec::new();
+    let mut row = Vec::with_capacity(key_count);
+    for (measure_idx, measure) in data.split(',').enumerate() {
+        let rows = measure.split_whitespace().collect::<Vec<_>>();
+        for (row_idx, row_txt) in rows.iter().enumerate() {
+            //Skip keysounds (`[...]`) and attacks (`{...}`) attached to notes
+            row.clear();
+            let mut chars = row_txt.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '[' => while chars.next().map(|c| c != ']').unwrap_or(false) {},
+                    '{' => while chars.next().map(|c| c != '}').unwrap_or(false) {},
+                    c => row.push(c),
+                }
+            }
+            ensure!(
+                row.len() == key_count,
+                "row \"{}\" in measure {} has {} columns, expected {}",
+                row_txt,
+                measure_idx,
+                row.len(),
+                key_count
+            );
+            let beat = BeatPos::from(
+                ((measure_idx * rows.len() + row_idx) * BEATS_IN_MEASURE as usize) as f64
+                    / rows.len() as f64,
+            );
+            for (key, &c) in row.iter().enumerate() {
+                let kind = match c {
+                    '1' | 'L' => Note::KIND_HIT,
+                    '2' => Note::KIND_HEAD,
+                    '4' => Note::KIND_ROLL,
+                    '3' => Note::KIND_TAIL,
+                    'M' => Note::KIND_MINE,
+                    _ => continue,
+                };
+                notes.push(Note {
+                    kind,
+                    beat,
+                    key: key as i32,
+                });
+            }
+        }
+    }
+    Ok(notes)
+}
+
+/// Fill in the fields of a parsed chart that depend on the rest of the chart.
+fn finish_parsed(mut sm: Simfile, display_bpm: Option<DisplayBpm>) -> Simfile {
+    sm.display_bpm = display_bpm.unwrap_or_else(|| {
+        let bpms = sm.bpms.iter().map(ControlPoint::bpm);
+        let min = bpms.clone().fold(f64::INFINITY, f64::min);
+        let max = bpms.fold(f64::NEG_INFINITY, f64::max);
+        if !min.is_finite() {
+            DisplayBpm::Random
+        } else if min == max {
+            DisplayBpm::Single(min)
+        } else {
+            DisplayBpm::Range(min, max)
+        }
+    });
+    //Close the warp of a trailing negative BPM just past the last note
+    let end = sm.notes.last().map(|note| note.beat.as_num()).unwrap_or(0.);
+    for warp in sm.warps.iter_mut().filter(|(_, len)| len.is_infinite()) {
+        warp.1 = (end - warp.0).max(0.) + 1.;
+    }
+    if sm.version.is_empty() {
+        sm.version = sm.desc.clone();
+    }
+    sm
+}
+
 #[derive(Debug, Clone)]
 pub struct BeatIter<'a> {
     notes: &'a [Note],
@@ -506,10 +1783,11 @@ pub struct Beat {
     pub end_idx: usize,
 }
 impl Beat {
+    /// Count the notes on this beat that have to be hit (ie. neither tails nor mines).
     pub fn count_heads(&self, notes: &[Note]) -> usize {
         notes[self.start_idx..self.end_idx]
             .iter()
-            .filter(|note| !note.is_tail())
+            .filter(|note| !note.is_tail() && !note.is_mine())
             .count()
     }
 }
@@ -517,7 +1795,7 @@ impl Beat {
 /// From the StepMania source,
 /// [`GameManager.cpp`](https://github.com/stepmania/stepmania/blob/5_1-new/src/GameManager.cpp):
 ///
-/// ```
+/// ```text
 /// // dance
 /// { "dance-single",	4,	true,	StepsTypeCategory_Single },
 /// { "dance-double",	8,	true,	StepsTypeCategory_Double },
@@ -571,7 +1849,7 @@ impl Beat {
 /// { "kickbox-insect", 6, true, StepsTypeCategory_Single },
 /// { "kickbox-arachnid", 8, true, StepsTypeCategory_Single },
 /// ```
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Gamemode {
     DanceSingle,
@@ -613,6 +1891,57 @@ pub enum Gamemode {
     KickboxArachnid,
 }
 impl Gamemode {
+    const ALL: [Gamemode; 37] = {
+        use Gamemode::*;
+        [
+            DanceSingle,
+            DanceDouble,
+            DanceCouple,
+            DanceSolo,
+            DanceThreepanel,
+            DanceRoutine,
+            PumpSingle,
+            PumpHalfdouble,
+            PumpDouble,
+            PumpCouple,
+            PumpRoutine,
+            Kb7Single,
+            Ez2Single,
+            Ez2Double,
+            Ez2Real,
+            ParaSingle,
+            Ds3ddxSingle,
+            BmSingle5,
+            BmVersus5,
+            BmDouble5,
+            BmSingle7,
+            BmVersus7,
+            BmDouble7,
+            ManiaxSingle,
+            ManiaxDouble,
+            TechnoSingle4,
+            TechnoSingle5,
+            TechnoSingle8,
+            TechnoDouble4,
+            TechnoDouble5,
+            TechnoDouble8,
+            PnmFive,
+            PnmNine,
+            KickboxHuman,
+            KickboxQuadarm,
+            KickboxInsect,
+            KickboxArachnid,
+        ]
+    };
+
+    /// Find the gamemode with the given StepMania id (eg. `dance-single`), ignoring case.
+    pub fn from_id(id: &str) -> Option<Gamemode> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|gamemode| gamemode.id().eq_ignore_ascii_case(id))
+    }
+
     pub fn key_count(&self) -> i32 {
         use Gamemode::*;
         match self {
@@ -710,7 +2039,7 @@ pub enum Difficulty {
     Edit,
 }
 impl Difficulty {
-    fn name(&self) -> &'static str {
+    pub fn name(&self) -> &'static str {
         use Difficulty::*;
         match self {
             Beginner => "Beginner",
@@ -721,6 +2050,21 @@ impl Difficulty {
             Edit => "Edit",
         }
     }
+
+    /// Parse a StepMania difficulty name, including the names used by older versions (eg.
+    /// `Heavy` or `Expert`).
+    /// Unknown names are read as `Edit`.
+    pub fn from_name(name: &str) -> Difficulty {
+        use Difficulty::*;
+        match &name.to_ascii_lowercase()[..] {
+            "beginner" => Beginner,
+            "easy" | "basic" | "light" => Easy,
+            "medium" | "another" | "trick" | "standard" | "difficult" => Medium,
+            "hard" | "ssr" | "maniac" | "heavy" => Hard,
+            "challenge" | "smaniac" | "expert" | "oni" => Challenge,
+            _ => Edit,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -741,7 +2085,10 @@ impl DisplayBpm {
 }
 
 /// Represents an absolute position in beats, where 0 is the first beat of the song.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+///
+/// Serialized as a plain number of beats.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "f64", into = "f64")]
 pub struct BeatPos {
     frac: i32,
 }
@@ -849,7 +2196,22 @@ impl fmt::Display for BeatPos {
     }
 }
 
-#[derive(Debug, Clone)]
+/// A run of evenly spaced rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stream {
+    /// How many rows make up the stream.
+    pub rows: usize,
+    /// The BPM of the stream, assuming the rows are 16th notes.
+    pub bpm: f64,
+}
+impl Stream {
+    /// The longest gap between rows that is still considered a stream, in seconds.
+    pub const MAX_GAP: f64 = 0.2;
+    /// How much can gaps differ from each other within a stream, relative to the gap.
+    pub const GAP_TOLERANCE: f64 = 0.05;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
     pub kind: char,
     pub beat: BeatPos,
@@ -859,21 +2221,47 @@ impl Note {
     pub const KIND_HIT: char = '1';
     pub const KIND_HEAD: char = '2';
     pub const KIND_TAIL: char = '3';
+    /// The head of a roll, a hold that must be tapped repeatedly instead of held.
+    /// Rolls end with a regular `KIND_TAIL`.
+    pub const KIND_ROLL: char = '4';
+    /// A mine, which must not be pressed as it passes.
+    /// Mines are not part of holds, and never share a beat and key with another note.
+    pub const KIND_MINE: char = 'M';
 
     pub fn is_hit(&self) -> bool {
         self.kind == Self::KIND_HIT
     }
 
+    /// Whether this note starts a hold or a roll.
     pub fn is_head(&self) -> bool {
-        self.kind == Self::KIND_HEAD
+        self.kind == Self::KIND_HEAD || self.kind == Self::KIND_ROLL
+    }
+
+    pub fn is_roll(&self) -> bool {
+        self.kind == Self::KIND_ROLL
     }
 
     pub fn is_tail(&self) -> bool {
         self.kind == Self::KIND_TAIL
     }
+
+    pub fn is_mine(&self) -> bool {
+        self.kind == Self::KIND_MINE
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A background video.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Video {
+    /// Relative to the beatmapset folder, like every other path.
+    pub path: PathBuf,
+    /// The beat at which the video starts playing.
+    pub beat: f64,
+    /// How fast the video plays (eg. `1.5` for a sped up song).
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ControlPoint {
     /// First beat of the control point.
     pub beat: BeatPos,
@@ -891,6 +2279,13 @@ pub struct ToTime<'a> {
     bpms: &'a [ControlPoint],
     cur_idx: usize,
     cur_time: f64,
+    stops: &'a [(f64, f64)],
+    stop_idx: usize,
+    delays: &'a [(f64, f64)],
+    delay_idx: usize,
+    warps: Vec<(f64, f64, f64, f64)>,
+    warp_idx: usize,
+    stop_time: f64,
 }
 impl ToTime<'_> {
     pub fn new(sm: &Simfile) -> ToTime {
@@ -898,14 +2293,29 @@ impl ToTime<'_> {
             bpms: &sm.bpms,
             cur_idx: 0,
             cur_time: -sm.offset,
+            stops: &sm.stops,
+            stop_idx: 0,
+            delays: &sm.delays,
+            delay_idx: 0,
+            warps: sm.warp_spans(),
+            warp_idx: 0,
+            stop_time: 0.,
         }
     }
 
+    /// Ignores stops, delays and warps.
     pub fn from_raw(bpms: &[ControlPoint], offset: f64) -> ToTime {
         ToTime {
             bpms,
             cur_idx: 0,
             cur_time: -offset,
+            stops: &[],
+            stop_idx: 0,
+            delays: &[],
+            delay_idx: 0,
+            warps: Vec::new(),
+            warp_idx: 0,
+            stop_time: 0.,
         }
     }
 
@@ -926,8 +2336,58 @@ impl ToTime<'_> {
                 break;
             }
         }
+        //Add up the stops before this beat
+        while let Some(&(stop_beat, dur)) = self.stops.get(self.stop_idx) {
+            if beat.as_num() > stop_beat {
+                self.stop_time += dur;
+                self.stop_idx += 1;
+            } else {
+                break;
+            }
+        }
+        //Delays on this beat count too, since they happen before its notes
+        while let Some(&(delay_beat, dur)) = self.delays.get(self.delay_idx) {
+            if beat.as_num() >= delay_beat {
+                self.stop_time += dur;
+                self.delay_idx += 1;
+            } else {
+                break;
+            }
+        }
+        //Take out the time of the beats skipped by warps
+        while let Some(&(_, end, start_time, end_time)) = self.warps.get(self.warp_idx) {
+            if beat.as_num() >= end {
+                self.stop_time -= end_time - start_time;
+                self.warp_idx += 1;
+            } else {
+                break;
+            }
+        }
+        //Beats within a warp play right when it starts
+        if let Some(&(start, _, start_time, _)) = self.warps.get(self.warp_idx) {
+            if beat.as_num() >= start {
+                return start_time + self.stop_time;
+            }
+        }
         //Use the current control point to determine the time corresponding to this beat
         let cur_bpm = &self.bpms[self.cur_idx];
-        self.cur_time + (beat - cur_bpm.beat).as_num() * cur_bpm.beat_len
+        self.cur_time + self.stop_time + (beat - cur_bpm.beat).as_num() * cur_bpm.beat_len
+    }
+}
+
+/// JSON cannot represent `NaN`, so represent it as `null` instead.
+mod nan_as_null {
+    use crate::prelude::*;
+
+    pub fn serialize<S: serde::Serializer>(num: &f64, ser: S) -> Result<S::Ok, S::Error> {
+        if num.is_nan() {
+            ser.serialize_none()
+        } else {
+            ser.serialize_some(num)
+        }
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(de: D) -> Result<f64, D::Error> {
+        Ok(Option::<f64>::deserialize(de)?.unwrap_or(f64::NAN))
     }
 }
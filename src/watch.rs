@@ -0,0 +1,81 @@
+//! Watch the input folders for new or changed beatmapsets, so that they can be converted as soon
+//! as they are downloaded.
+
+use crate::prelude::*;
+use notify::{RecursiveMode, Watcher as _};
+use std::{
+    collections::BTreeSet,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+/// Watches folders (and all of their subfolders) for changes to beatmaps.
+pub struct Watcher {
+    //Stops watching when dropped
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+}
+impl Watcher {
+    pub fn new<'a>(roots: impl IntoIterator<Item = &'a Path>) -> Result<Watcher> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(tx).context("failed to set up folder watcher")?;
+        for root in roots {
+            watcher
+                .watch(root, RecursiveMode::Recursive)
+                .with_context(|| anyhow!("failed to watch \"{}\"", root.display()))?;
+            info!("watching \"{}\" for new beatmaps", root.display());
+        }
+        Ok(Watcher {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Wait until some beatmapsets change, and then until nothing changes for `settle` time, so
+    /// that beatmapsets are not converted halfway through being downloaded or extracted.
+    ///
+    /// Returns the folders of the changed beatmapsets, or `None` if cancellation was requested.
+    pub fn wait(&self, settle: Duration) -> Option<BTreeSet<PathBuf>> {
+        let tick = Duration::from_millis(250);
+        let mut changed = BTreeSet::new();
+        let mut quiet = Duration::from_secs(0);
+        loop {
+            if crate::cancel::requested() {
+                return None;
+            }
+            match self.events.recv_timeout(tick) {
+                Ok(Ok(event)) if !event.kind.is_access() => {
+                    quiet = Duration::from_secs(0);
+                    for set_path in event.paths.iter().filter_map(|path| beatmapset_of(path)) {
+                        if changed.insert(set_path.clone()) {
+                            debug!("beatmapset \"{}\" changed", set_path.display());
+                        }
+                    }
+                }
+                Ok(Ok(_access)) => {}
+                Ok(Err(err)) => warn!("error while watching folders: {:#}", err),
+                Err(RecvTimeoutError::Timeout) => {
+                    quiet += tick;
+                    if !changed.is_empty() && quiet >= settle {
+                        return Some(changed);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    warn!("folder watcher stopped");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Get the beatmapset folder that a changed file belongs to, if it is a beatmap.
+fn beatmapset_of(path: &Path) -> Option<PathBuf> {
+    match path.extension()?.to_str()? {
+        "osu" => path.parent().map(Path::to_path_buf),
+        //`.osz` archives are extracted into a folder of the same name
+        "osz" => Some(path.with_extension("")),
+        _ => None,
+    }
+}